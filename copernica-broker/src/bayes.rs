@@ -5,13 +5,95 @@ use {
             HashSet,
             hash_map::Keys
         },
+        fs,
         iter::FromIterator,
+        path::Path,
+        time::{Duration, Instant},
         vec::Vec
     },
-    copernica_common::{LinkId, BFIS},
+    copernica_common::{LinkId, ReplyTo, BFIS, PublicIdentity, PublicIdentityInterface},
+    serde::{Deserialize, Serialize},
+    anyhow::{anyhow, Result},
 };
+const SNAPSHOT_VERSION: u8 = 2;
+// decayed or negatively trained counts at or below this are dropped
+const FORGET_BELOW: f64 = 1e-3;
+/// A `LinkId` without its private key or per-run `lookup_id`, so what was
+/// learnt about a neighbour can be written to disk and found again after a restart.
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub enum LinkKey {
+    Identity {
+        link_pid: PublicIdentity,
+        remote_link_pid: PublicIdentityInterface,
+        reply_to: ReplyTo,
+    },
+    Choke,
+}
+impl LinkKey {
+    pub fn from_link_id(link_id: &LinkId) -> Result<Self> {
+        match link_id {
+            LinkId::Choke => Ok(LinkKey::Choke),
+            LinkId::Identity { .. } => Ok(LinkKey::Identity {
+                link_pid: link_id.link_pid()?,
+                remote_link_pid: link_id.remote_link_pid()?,
+                reply_to: link_id.reply_to()?,
+            }),
+        }
+    }
+    fn matches(&self, other: &LinkKey, remap: LinkRemap) -> bool {
+        match (self, other) {
+            (LinkKey::Choke, LinkKey::Choke) => true,
+            (LinkKey::Identity { link_pid, remote_link_pid, reply_to },
+             LinkKey::Identity { link_pid: other_link_pid, remote_link_pid: other_remote_link_pid, reply_to: other_reply_to }) => {
+                match remap {
+                    LinkRemap::Exact => link_pid == other_link_pid && remote_link_pid == other_remote_link_pid && reply_to == other_reply_to,
+                    LinkRemap::RemoteIdentity => remote_link_pid == other_remote_link_pid && reply_to == other_reply_to,
+                    LinkRemap::ReplyTo => reply_to == other_reply_to,
+                }
+            },
+            _ => false,
+        }
+    }
+}
+/// How a restored link is matched to a link attached in this run.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LinkRemap {
+    // same local link identity, remote identity and address
+    Exact,
+    // same remote identity and address, the local link key may have been regenerated
+    RemoteIdentity,
+    // same address only, for cleartext neighbours whose keys change every run
+    ReplyTo,
+}
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct LearntLink {
+    count: f64,
+    bfis: Vec<(BFIS, f64)>,
+}
+/// What a `Bayes` has learnt, keyed by `LinkKey` so it can outlive the run.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BayesSnapshot {
+    version: u8,
+    links: Vec<(LinkKey, LearntLink)>,
+}
+impl BayesSnapshot {
+    pub fn load(path: &Path) -> Result<Self> {
+        let snapshot: BayesSnapshot = bincode::deserialize(&fs::read(path)?)?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(anyhow!("Bayes snapshot {:?} is version {}, expected {}", path, snapshot.version, SNAPSHOT_VERSION))
+        }
+        Ok(snapshot)
+    }
+    // written beside the target and renamed over it so a crash never leaves half a snapshot
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, bincode::serialize(self)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
 struct BFIs {
-    bfis: HashMap<BFIS, HashMap<LinkId, i64>>,
+    bfis: HashMap<BFIS, HashMap<LinkId, f64>>,
 }
 impl BFIs {
     pub fn new() -> BFIs {
@@ -24,18 +106,62 @@ impl BFIs {
         let linkids = self.bfis
             .entry(*bfis)
             .or_insert(HashMap::new());
-        let value = linkids.entry(link.clone()).or_insert(0);
-        *value += 1;
+        let value = linkids.entry(link.clone()).or_insert(0.0);
+        *value += 1.0;
     }
     fn super_train(&mut self, bfis: &BFIS, link: &LinkId) {
         //debug!("supertrain {:?}", bfis);
         let linkids = self.bfis
             .entry(*bfis)
             .or_insert(HashMap::new());
-        let value = linkids.entry(link.clone()).or_insert(0);
-        *value += 4;
+        let value = linkids.entry(link.clone()).or_insert(0.0);
+        *value += 4.0;
+    }
+    fn remove_link(&mut self, link: &LinkId) {
+        for linkids in self.bfis.values_mut() {
+            linkids.remove(link);
+        }
+        self.bfis.retain(|_, linkids| !linkids.is_empty());
     }
-    fn get_frequency(&mut self, bfis: &BFIS, linkid: &LinkId) -> (Option<&i64>, bool) {
+    fn replace_link(&mut self, old: &LinkId, new: &LinkId) {
+        for linkids in self.bfis.values_mut() {
+            if let Some(value) = linkids.remove(old) {
+                *linkids.entry(new.clone()).or_insert(0.0) += value;
+            }
+        }
+    }
+    fn negative_train(&mut self, bfis: &BFIS, link: &LinkId) {
+        if let Some(linkids) = self.bfis.get_mut(bfis) {
+            if let Some(value) = linkids.get_mut(link) {
+                *value -= 1.0;
+                if *value <= FORGET_BELOW {
+                    linkids.remove(link);
+                }
+            }
+            if linkids.is_empty() {
+                self.bfis.remove(bfis);
+            }
+        }
+    }
+    fn negative_train_where<F: Fn(&BFIS) -> bool>(&mut self, link: &LinkId, expired: F) {
+        let bfis: Vec<BFIS> = self.bfis.iter()
+            .filter(|(bfis, linkids)| linkids.contains_key(link) && expired(bfis))
+            .map(|(bfis, _)| *bfis)
+            .collect();
+        for bfis in bfis {
+            self.negative_train(&bfis, link);
+        }
+    }
+    fn decay(&mut self, factor: f64) {
+        for linkids in self.bfis.values_mut() {
+            for value in linkids.values_mut() {
+                *value *= factor;
+            }
+            linkids.retain(|_, value| *value > FORGET_BELOW);
+        }
+        self.bfis.retain(|_, linkids| !linkids.is_empty());
+    }
+    fn get_frequency(&mut self, bfis: &BFIS, linkid: &LinkId) -> (Option<&f64>, bool) {
         match self.bfis.get(bfis) {
             Some(linkids) => match linkids.get(linkid) {
                 Some(value) => return (Some(value), true),
@@ -46,7 +172,7 @@ impl BFIs {
     }
 }
 struct Links {
-    count: HashMap<LinkId, i64>,
+    count: HashMap<LinkId, f64>,
 }
 impl Links {
     pub fn new() -> Links {
@@ -55,21 +181,35 @@ impl Links {
         }
     }
     fn train(&mut self, link: &LinkId) {
-        let value = self.count.entry(link.clone()).or_insert(0);
-        *value += 1;
+        let value = self.count.entry(link.clone()).or_insert(0.0);
+        *value += 1.0;
     }
     fn super_train(&mut self, link: &LinkId) {
-        let value = self.count.entry(link.clone()).or_insert(0);
-        *value += 4;
+        let value = self.count.entry(link.clone()).or_insert(0.0);
+        *value += 4.0;
+    }
+    fn remove_link(&mut self, link: &LinkId) {
+        self.count.remove(link);
+    }
+    fn replace_link(&mut self, old: &LinkId, new: &LinkId) {
+        if let Some(value) = self.count.remove(old) {
+            *self.count.entry(new.clone()).or_insert(0.0) += value;
+        }
     }
-    fn get_count(&mut self, link: &LinkId) -> Option<&i64> {
+    // links are never forgotten here, an attached link must stay classifiable
+    fn decay(&mut self, factor: f64) {
+        for value in self.count.values_mut() {
+            *value = (*value * factor).max(FORGET_BELOW);
+        }
+    }
+    fn get_count(&mut self, link: &LinkId) -> Option<&f64> {
         return self.count.get(link);
     }
-    fn get_linkids(&mut self) -> Keys<LinkId, i64> {
+    fn get_linkids(&mut self) -> Keys<LinkId, f64> {
         return self.count.keys();
     }
-    fn get_total(&mut self) -> i64 {
-        return self.count.values().fold(0, |acc, x| acc + x);
+    fn get_total(&mut self) -> f64 {
+        return self.count.values().fold(0.0, |acc, x| acc + x);
     }
 }
 struct Model {
@@ -94,6 +234,28 @@ impl Model {
         self.links.super_train(linkid);
         self.bfis.super_train(data, linkid);
     }
+    fn remove_link(&mut self, linkid: &LinkId) {
+        self.links.remove_link(linkid);
+        self.bfis.remove_link(linkid);
+    }
+    fn replace_link(&mut self, old: &LinkId, new: &LinkId) {
+        self.links.replace_link(old, new);
+        self.bfis.replace_link(old, new);
+    }
+    fn learnt(&self, linkid: &LinkId) -> LearntLink {
+        LearntLink {
+            count: *self.links.count.get(linkid).unwrap_or(&0),
+            bfis: self.bfis.bfis.iter()
+                .filter_map(|(bfis, linkids)| linkids.get(linkid).map(|value| (*bfis, *value)))
+                .collect(),
+        }
+    }
+    fn merge(&mut self, linkid: &LinkId, learnt: LearntLink) {
+        *self.links.count.entry(linkid.clone()).or_insert(0.0) += learnt.count;
+        for (bfis, value) in learnt.bfis {
+            *self.bfis.bfis.entry(bfis).or_insert(HashMap::new()).entry(linkid.clone()).or_insert(0.0) += value;
+        }
+    }
 }
 #[derive(Debug)]
 pub struct LinkWeight{
@@ -103,33 +265,124 @@ pub struct LinkWeight{
 pub struct Bayes {
     model: Model,
     min_prob: f64,
-    min_log_prob: f64
+    min_log_prob: f64,
+    // restored links that haven't been attached yet this run
+    dormant: Vec<(LinkKey, LearntLink)>,
+    remap: LinkRemap,
+    half_life: Option<Duration>,
+    last_decay: Instant,
 }
 impl Bayes {
     pub fn new() -> Bayes {
+        Self::new_with_half_life(None)
+    }
+    /// Counts halve every `half_life` once `decay` is called regularly,
+    /// so links that stopped answering lose out to ones that still do.
+    pub fn new_with_half_life(half_life: Option<Duration>) -> Bayes {
         Bayes {
             model: Model::new(),
             min_prob: 1e-9,
             min_log_prob: -100.0,
+            dormant: vec![],
+            remap: LinkRemap::Exact,
+            half_life,
+            last_decay: Instant::now(),
         }
     }
+    pub fn decay(&mut self, now: Instant) {
+        let half_life = match self.half_life {
+            Some(half_life) if half_life > Duration::from_secs(0) => half_life,
+            _ => return,
+        };
+        let elapsed = now.saturating_duration_since(self.last_decay);
+        self.last_decay = now;
+        let factor = 0.5f64.powf(elapsed.as_secs_f64() / half_life.as_secs_f64());
+        self.model.links.decay(factor);
+        self.model.bfis.decay(factor);
+    }
+    // a request forwarded on `linkid` expired without a response
+    pub fn negative_train(&mut self, data: &BFIS, linkid: &LinkId) {
+        self.model.bfis.negative_train(data, linkid);
+    }
+    // requests forwarded on `linkid` expired, but only as Bloom generations
+    // that can't be listed, so every name learnt for the link is tested
+    pub fn negative_train_where<F: Fn(&BFIS) -> bool>(&mut self, linkid: &LinkId, expired: F) {
+        self.model.bfis.negative_train_where(linkid, expired);
+    }
     pub fn add_link(&mut self, linkid: &LinkId) {
         self.model.add_link(&linkid);
+        let key = match LinkKey::from_link_id(linkid) {
+            Ok(key) => key,
+            Err(_) => return,
+        };
+        let remap = self.remap;
+        let mut matching = self.dormant.iter().enumerate().filter(|(_, (dormant, _))| dormant.matches(&key, remap));
+        let i = match (matching.next(), matching.next()) {
+            (Some((i, _)), None) => i,
+            _ => return,
+        };
+        // another live link could be the one the dormant entry was learnt on
+        let ambiguous = self.model.links.count.keys()
+            .filter(|live| *live != linkid)
+            .filter_map(|live| LinkKey::from_link_id(live).ok())
+            .any(|live| live.matches(&key, remap));
+        if !ambiguous {
+            let (_, learnt) = self.dormant.remove(i);
+            self.model.merge(linkid, learnt);
+        }
+    }
+    pub fn snapshot(&self) -> Result<BayesSnapshot> {
+        let mut links = self.dormant.clone();
+        for linkid in self.model.links.count.keys() {
+            links.push((LinkKey::from_link_id(linkid)?, self.model.learnt(linkid)));
+        }
+        Ok(BayesSnapshot { version: SNAPSHOT_VERSION, links })
+    }
+    /// Warm start from an earlier run. Links already known are merged now,
+    /// the rest wait until `add_link` sees a link that `remap` matches.
+    /// A link is only remapped when exactly one live or dormant link matches
+    /// it, otherwise its counts stay dormant rather than land on the wrong link.
+    pub fn restore(&mut self, snapshot: BayesSnapshot, remap: LinkRemap) -> Result<()> {
+        self.remap = remap;
+        let known: Vec<(LinkKey, LinkId)> = self.model.links.count.keys()
+            .map(|linkid| Ok((LinkKey::from_link_id(linkid)?, linkid.clone())))
+            .collect::<Result<_>>()?;
+        let keys: Vec<LinkKey> = snapshot.links.iter().map(|(key, _)| key.clone()).collect();
+        for (key, learnt) in snapshot.links {
+            if key == LinkKey::Choke {
+                self.model.merge(&LinkId::choke(), learnt);
+                continue
+            }
+            let matching: Vec<&(LinkKey, LinkId)> = known.iter().filter(|(k, _)| key.matches(k, remap)).collect();
+            match matching.as_slice() {
+                [(k, linkid)] if keys.iter().filter(|other| other.matches(k, remap)).count() == 1 => self.model.merge(linkid, learnt),
+                _ => self.dormant.push((key, learnt)),
+            }
+        }
+        Ok(())
+    }
+    pub fn remove_link(&mut self, linkid: &LinkId) {
+        self.model.remove_link(&linkid);
+    }
+    /// Carry everything learnt about `old` over to `new`, e.g. when a
+    /// neighbour reappears behind a replacement link.
+    pub fn replace_link(&mut self, old: &LinkId, new: &LinkId) {
+        self.model.replace_link(old, new);
     }
     fn prior(&mut self, linkid: &LinkId) -> Option<f64> {
-        let total = *(&self.model.links.get_total()) as f64;
+        let total = self.model.links.get_total();
         let linkid = &self.model.links.get_count(linkid);
         if linkid.is_some() && total > 0.0 {
-            return Some(*linkid.unwrap() as f64 / total);
+            return Some(*linkid.unwrap() / total);
         } else {
             return None;
         }
     }
     fn log_prior(&mut self, linkid: &LinkId) -> Option<f64> {
-        let total = *(&self.model.links.get_total()) as f64;
+        let total = self.model.links.get_total();
         let linkid = &self.model.links.get_count(linkid);
         if linkid.is_some() && total > 0.0 {
-            return Some((*linkid.unwrap() as f64).ln() - total.ln());
+            return Some(linkid.unwrap().ln() - total.ln());
         } else {
             return None;
         }
@@ -137,7 +390,7 @@ impl Bayes {
     fn calculate_attr_prob(&mut self, bfis: &BFIS, linkid: &LinkId) -> Option<f64> {
         match self.model.bfis.get_frequency(bfis, linkid) {
             (Some(frequency), true) => match self.model.links.get_count(linkid) {
-                Some(count) => return Some((*frequency as f64) / (*count as f64)),
+                Some(count) => return Some(*frequency / *count),
                 None => return None,
             },
             (None, true) => return Some(self.min_prob),
@@ -148,7 +401,7 @@ impl Bayes {
     fn calculate_attr_log_prob(&mut self, bfis: &BFIS, linkid: &LinkId) -> Option<f64> {
         match self.model.bfis.get_frequency(bfis, linkid) {
             (Some(frequency), true) => match self.model.links.get_count(linkid) {
-                Some(count) => return Some((*frequency as f64).ln() - (*count as f64).ln()),
+                Some(count) => return Some(frequency.ln() - count.ln()),
                 None => return None,
             },
             (None, true) => return Some(self.min_log_prob),
@@ -252,7 +505,7 @@ mod test_bfis {
                 .get_frequency(&h1, &li)
                 .0
                 .unwrap(),
-            1
+            1.0
         );
     }
     #[test]
@@ -279,7 +532,7 @@ mod test_linkids {
         let private_identity = PrivateIdentityInterface::new_key();
         let h1 = LinkId::listen(private_identity, None, ReplyTo::Rf(0));
         linkids.train(&h1);
-        assert_eq!(*linkids.get_count(&h1).unwrap(), 1);
+        assert_eq!(*linkids.get_count(&h1).unwrap(), 1.0);
     }
     #[test]
     fn linkid_get_nonexistent() {
@@ -305,7 +558,7 @@ mod test_linkids {
         linkids.train(&h1);
         linkids.train(&h1);
         assert_eq!(linkids.get_linkids().len(), 1);
-        assert_eq!(*linkids.get_count(&h1).unwrap(), 2);
+        assert_eq!(*linkids.get_count(&h1).unwrap(), 2.0);
     }
     #[test]
     fn get_nonexistent_counts() {
@@ -318,7 +571,7 @@ mod test_linkids {
     #[test]
     fn get_nonexistent_total() {
         let mut linkids = Links::new();
-        assert_eq!(linkids.get_total(), 0);
+        assert_eq!(linkids.get_total(), 0.0);
     }
     #[test]
     fn get_total() {
@@ -333,7 +586,7 @@ mod test_linkids {
         linkids.train(&h1);
         linkids.train(&h2);
         linkids.train(&h3);
-        assert_eq!(linkids.get_total(), 4);
+        assert_eq!(linkids.get_total(), 4.0);
     }
 }
 #[cfg(test)]
@@ -444,4 +697,102 @@ mod test_bayes {
         assert_eq!(classes[0].weight, -LN_2);
         assert_eq!(classes[1].weight, -100.69314718055995);
     }
+    #[test]
+    fn test_remove_link() {
+        let mut nb = Bayes::new();
+        let h1: BFIS = generate_min_bfis();
+        let private_identity = PrivateIdentityInterface::new_key();
+        let l1 = LinkId::listen(private_identity, None, ReplyTo::Rf(0));
+        nb.model.train(&h1, &l1);
+        let private_identity = PrivateIdentityInterface::new_key();
+        let l2 = LinkId::listen(private_identity, None, ReplyTo::Rf(1));
+        nb.model.train(&h1, &l2);
+        nb.remove_link(&l1);
+        let classes = nb.classify(&h1);
+        assert_eq!(classes.len(), 1);
+        assert_eq!(classes[0].linkid, l2);
+        assert_eq!(nb.prior(&l1), None);
+    }
+    #[test]
+    fn test_replace_link() {
+        let mut nb = Bayes::new();
+        let h1: BFIS = generate_min_bfis();
+        let private_identity = PrivateIdentityInterface::new_key();
+        let l1 = LinkId::listen(private_identity, None, ReplyTo::Rf(0));
+        nb.model.train(&h1, &l1);
+        let private_identity = PrivateIdentityInterface::new_key();
+        let l2 = LinkId::listen(private_identity, None, ReplyTo::Rf(1));
+        nb.replace_link(&l1, &l2);
+        assert_eq!(nb.prior(&l1), None);
+        assert_eq!(nb.prior(&l2), Some(1.0));
+        assert_eq!(*nb.model.bfis.get_frequency(&h1, &l2).0.unwrap(), 1.0);
+    }
+    #[test]
+    fn test_snapshot_survives_restart() {
+        let h1: BFIS = generate_max_bfis();
+        let private_identity = PrivateIdentityInterface::new_key();
+        let l1 = LinkId::new(1, private_identity.clone(), PublicIdentityInterface::Absent, ReplyTo::Rf(0));
+        let mut nb = Bayes::new();
+        nb.add_link(&l1);
+        nb.super_train(&h1, &l1);
+        nb.train(&h1, &LinkId::choke());
+        let snapshot = nb.snapshot().unwrap();
+        let mut restarted = Bayes::new();
+        restarted.restore(snapshot, LinkRemap::Exact).unwrap();
+        assert_eq!(*restarted.model.bfis.get_frequency(&h1, &LinkId::choke()).0.unwrap(), 1.0);
+        // the same link comes back with a new lookup id
+        let l2 = LinkId::new(2, private_identity, PublicIdentityInterface::Absent, ReplyTo::Rf(0));
+        assert_ne!(l1, l2);
+        restarted.add_link(&l2);
+        assert_eq!(*restarted.model.bfis.get_frequency(&h1, &l2).0.unwrap(), 4.0);
+        assert_eq!(*restarted.model.links.get_count(&l2).unwrap(), 6.0);
+        assert!(restarted.dormant.is_empty());
+    }
+    #[test]
+    fn test_ambiguous_links_are_not_remapped() {
+        let h1: BFIS = generate_max_bfis();
+        let mut nb = Bayes::new();
+        // cleartext neighbours on the same kind of channel share everything but the local key
+        let l1 = LinkId::new(1, PrivateIdentityInterface::new_key(), PublicIdentityInterface::Absent, ReplyTo::Mpsc);
+        let l2 = LinkId::new(2, PrivateIdentityInterface::new_key(), PublicIdentityInterface::Absent, ReplyTo::Mpsc);
+        nb.add_link(&l1);
+        nb.add_link(&l2);
+        nb.super_train(&h1, &l1);
+        let snapshot = nb.snapshot().unwrap();
+        let mut restarted = Bayes::new();
+        restarted.restore(snapshot, LinkRemap::RemoteIdentity).unwrap();
+        let l3 = LinkId::new(3, PrivateIdentityInterface::new_key(), PublicIdentityInterface::Absent, ReplyTo::Mpsc);
+        restarted.add_link(&l3);
+        assert_eq!(restarted.model.bfis.get_frequency(&h1, &l3), (None, false));
+        assert_eq!(restarted.dormant.len(), 2);
+    }
+    #[test]
+    fn test_decay_halves_counts() {
+        let h1: BFIS = generate_max_bfis();
+        let private_identity = PrivateIdentityInterface::new_key();
+        let l1 = LinkId::new(1, private_identity, PublicIdentityInterface::Absent, ReplyTo::Rf(0));
+        let mut nb = Bayes::new_with_half_life(Some(Duration::from_secs(10)));
+        nb.super_train(&h1, &l1);
+        let start = nb.last_decay;
+        nb.decay(start + Duration::from_secs(10));
+        assert!((*nb.model.bfis.get_frequency(&h1, &l1).0.unwrap() - 2.0).abs() < 1e-9);
+        nb.decay(start + Duration::from_secs(20));
+        assert!((*nb.model.links.get_count(&l1).unwrap() - 1.0).abs() < 1e-9);
+    }
+    #[test]
+    fn test_negative_train_forgets() {
+        let h1: BFIS = generate_max_bfis();
+        let private_identity = PrivateIdentityInterface::new_key();
+        let l1 = LinkId::new(1, private_identity, PublicIdentityInterface::Absent, ReplyTo::Rf(0));
+        let mut nb = Bayes::new();
+        nb.train(&h1, &l1);
+        nb.negative_train(&h1, &l1);
+        assert_eq!(nb.model.bfis.get_frequency(&h1, &l1), (None, false));
+        assert_eq!(*nb.model.links.get_count(&l1).unwrap(), 1.0);
+        nb.train(&h1, &l1);
+        nb.negative_train_where(&l1, |bfis| *bfis != h1);
+        assert!(nb.model.bfis.get_frequency(&h1, &l1).0.is_some());
+        nb.negative_train_where(&l1, |bfis| *bfis == h1);
+        assert_eq!(nb.model.bfis.get_frequency(&h1, &l1), (None, false));
+    }
 }
@@ -1,40 +1,287 @@
 use {
-    copernica_common::{HBFI, HBFIExcludeFrame, constants},
-    uluru::LRUCache,
+    copernica_common::{HBFI, BFIS, constants},
+    std::{
+        collections::BTreeMap,
+        time::{Duration, Instant},
+    },
 };
-pub type Pheromone = LRUCache<HBFIExcludeFrame, { constants::RESPONSE_STORE_SIZE }>;
+/// A counting Bloom filter over `BLOOM_FILTER_LENGTH` counters, indexed
+/// directly by the `u16`s of an `HBFI`'s `BFIS`. The frame isn't part of the
+/// `BFIS`, so every frame of an `HBFI` lands on the same counters; each
+/// `insert` should be paired with a `remove` once its response is delivered.
+#[derive(Clone)]
+pub struct CountingBloomFilter {
+    counters: Vec<u8>,
+    hashes: usize,
+    occupied: usize,
+}
+impl CountingBloomFilter {
+    // the fewest counters per entry that still meets `false_positive_rate`
+    // once the filter holds as many entries as it was sized for
+    pub fn new(false_positive_rate: f64) -> Self {
+        let max_hashes = constants::BFI_COUNT * constants::BLOOM_FILTER_INDEX_ELEMENT_LENGTH;
+        let hashes = (-false_positive_rate.log2()).ceil() as usize;
+        Self {
+            counters: vec![0; constants::BLOOM_FILTER_LENGTH],
+            hashes: hashes.max(constants::BFI_COUNT).min(max_hashes),
+            occupied: 0,
+        }
+    }
+    // taken round robin across the BFIs so every part of the name is covered
+    fn indices(&self, bfis: &BFIS) -> Vec<usize> {
+        (0..self.hashes)
+            .map(|i| bfis[i % constants::BFI_COUNT][i / constants::BFI_COUNT] as usize % constants::BLOOM_FILTER_LENGTH)
+            .collect()
+    }
+    pub fn insert(&mut self, bfis: &BFIS) {
+        for i in self.indices(bfis) {
+            if self.counters[i] == 0 {
+                self.occupied += 1;
+            }
+            // a saturated counter sticks, decrementing it could drop other entries
+            self.counters[i] = self.counters[i].saturating_add(1);
+        }
+    }
+    pub fn contains(&self, bfis: &BFIS) -> bool {
+        self.indices(bfis).iter().all(|i| self.counters[*i] > 0)
+    }
+    pub fn remove(&mut self, bfis: &BFIS) -> bool {
+        if !self.contains(bfis) {
+            return false
+        }
+        for i in self.indices(bfis) {
+            match self.counters[i] {
+                u8::MAX => {},
+                1 => {
+                    self.counters[i] = 0;
+                    self.occupied -= 1;
+                },
+                _ => self.counters[i] -= 1,
+            }
+        }
+        true
+    }
+    pub fn fill_ratio(&self) -> f64 {
+        self.occupied as f64 / self.counters.len() as f64
+    }
+    pub fn false_positive_rate(&self) -> f64 {
+        self.fill_ratio().powi(self.hashes as i32)
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BloomMetrics {
+    pub pending_fill_ratio: f64,
+    pub pending_false_positive_rate: f64,
+    pub forwarded_fill_ratio: f64,
+    pub forwarded_false_positive_rate: f64,
+}
+// the requests whose lifetimes run out within the same sweep interval
+#[derive(Clone)]
+struct Generation {
+    filter: CountingBloomFilter,
+    entries: usize,
+}
+/// Counting Bloom filters bucketed by when their entries expire, one per
+/// `REQUEST_SWEEP_INTERVAL_MS`, so entries that are never removed can be
+/// expired by dropping their generation whole. An entry may outlive its
+/// lifetime by up to one interval.
+#[derive(Clone)]
+struct ExpiringBloomFilter {
+    false_positive_rate: f64,
+    epoch: Instant,
+    generations: BTreeMap<u64, Generation>,
+}
+impl ExpiringBloomFilter {
+    fn new(false_positive_rate: f64) -> Self {
+        Self { false_positive_rate, epoch: Instant::now(), generations: BTreeMap::new() }
+    }
+    fn interval(&self, at: Instant) -> u64 {
+        at.saturating_duration_since(self.epoch).as_millis() as u64 / constants::REQUEST_SWEEP_INTERVAL_MS
+    }
+    fn insert(&mut self, hbfi: HBFI, lifetime: Duration) {
+        // the first interval to start after the deadline
+        let generation = self.interval(Instant::now() + lifetime) + 1;
+        let false_positive_rate = self.false_positive_rate;
+        let generation = self.generations.entry(generation).or_insert_with(|| Generation {
+            filter: CountingBloomFilter::new(false_positive_rate),
+            entries: 0,
+        });
+        generation.filter.insert(&hbfi.to_bfis());
+        generation.entries += 1;
+    }
+    fn contains(&self, hbfi: &HBFI) -> bool {
+        let bfis = hbfi.to_bfis();
+        self.generations.values().any(|generation| generation.filter.contains(&bfis))
+    }
+    // removes one entry from the generation due to expire soonest
+    fn remove(&mut self, hbfi: &HBFI) -> bool {
+        let bfis = hbfi.to_bfis();
+        let found = self.generations.iter_mut().find_map(|(key, generation)| {
+            if generation.filter.remove(&bfis) {
+                generation.entries -= 1;
+                Some((*key, generation.entries))
+            } else {
+                None
+            }
+        });
+        match found {
+            Some((key, 0)) => {
+                self.generations.remove(&key);
+                true
+            },
+            Some(_) => true,
+            None => false,
+        }
+    }
+    fn expire(&mut self, now: Instant) -> Vec<Generation> {
+        let live = self.generations.split_off(&(self.interval(now) + 1));
+        std::mem::replace(&mut self.generations, live).into_iter().map(|(_, generation)| generation).collect()
+    }
+    fn fill_ratio(&self) -> f64 {
+        let occupied = (0..constants::BLOOM_FILTER_LENGTH)
+            .filter(|i| self.generations.values().any(|generation| generation.filter.counters[*i] > 0))
+            .count();
+        occupied as f64 / constants::BLOOM_FILTER_LENGTH as f64
+    }
+    // a lookup is a false positive when any generation gives one
+    fn false_positive_rate(&self) -> f64 {
+        1.0 - self.generations.values().map(|generation| 1.0 - generation.filter.false_positive_rate()).product::<f64>()
+    }
+}
+/// The requests `Blooms::expire` dropped because no response came back in time.
+/// Only their generations are kept, so they can be tested for but not listed.
+#[derive(Clone, Default)]
+pub struct Expired {
+    pub pending: usize,
+    pub forwarded: usize,
+    forwarded_requests: Vec<CountingBloomFilter>,
+}
+impl Expired {
+    pub fn was_forwarded(&self, bfis: &BFIS) -> bool {
+        self.forwarded_requests.iter().any(|filter| filter.contains(bfis))
+    }
+}
 #[derive(Clone)]
 pub struct Blooms {
-    pending_request: Pheromone,
-    forwarded_request: Pheromone,
+    pending_request: ExpiringBloomFilter,
+    forwarded_request: ExpiringBloomFilter,
 }
 impl Blooms {
-    #[allow(dead_code)]
-    pub fn new() -> Self {
+    pub fn new(false_positive_rate: f64) -> Self {
         Self {
-            pending_request: Pheromone::default(),
-            forwarded_request: Pheromone::default(),
+            pending_request: ExpiringBloomFilter::new(false_positive_rate),
+            forwarded_request: ExpiringBloomFilter::new(false_positive_rate),
         }
     }
     // Pending Request Sparse Distributed Representation
     // Used to determine the direction of upstream and shouldn't be conflated
     // with Forwarded Request which determines which faces are downstream nodes,
     // specifically which nodes to not forward to again.
-    pub fn create_pending_request(&mut self, hbfi: HBFI) {
-        self.pending_request.insert(HBFIExcludeFrame(hbfi));
+    pub fn create_pending_request(&mut self, hbfi: HBFI, lifetime: Duration) {
+        self.pending_request.insert(hbfi, lifetime);
     }
     pub fn contains_pending_request(&mut self, hbfi: HBFI) -> bool {
-        self.pending_request.touch(|n| n == &HBFIExcludeFrame(hbfi.clone()))
+        self.pending_request.contains(&hbfi)
+    }
+    // called once the response has been sent back down this face
+    pub fn complete_pending_request(&mut self, hbfi: HBFI) -> bool {
+        self.pending_request.remove(&hbfi)
     }
     // Forwarded Request Sparse Distributed Representation
     // Used to determine if a request has been forwarded on this face so as
     // not to forward the request on the face again. It's easy to get
     // this mixed up with Pending Requests, which has the specific purpose
     // of determining which faces are upstream nodes
-    pub fn create_forwarded_request(&mut self, hbfi: HBFI) {
-        self.forwarded_request.insert(HBFIExcludeFrame(hbfi));
+    pub fn create_forwarded_request(&mut self, hbfi: HBFI, lifetime: Duration) {
+        self.forwarded_request.insert(hbfi, lifetime);
     }
     pub fn contains_forwarded_request(&mut self, hbfi: HBFI) -> bool {
-        self.forwarded_request.touch(|n| n == &HBFIExcludeFrame(hbfi.clone()))
+        self.forwarded_request.contains(&hbfi)
+    }
+    // called once the response has come back up this face
+    pub fn complete_forwarded_request(&mut self, hbfi: HBFI) -> bool {
+        self.forwarded_request.remove(&hbfi)
+    }
+    pub fn expire(&mut self, now: Instant) -> Expired {
+        let pending = self.pending_request.expire(now);
+        let forwarded = self.forwarded_request.expire(now);
+        Expired {
+            pending: pending.iter().map(|generation| generation.entries).sum(),
+            forwarded: forwarded.iter().map(|generation| generation.entries).sum(),
+            forwarded_requests: forwarded.into_iter().map(|generation| generation.filter).collect(),
+        }
+    }
+    pub fn metrics(&self) -> BloomMetrics {
+        BloomMetrics {
+            pending_fill_ratio: self.pending_request.fill_ratio(),
+            pending_false_positive_rate: self.pending_request.false_positive_rate(),
+            forwarded_fill_ratio: self.forwarded_request.fill_ratio(),
+            forwarded_false_positive_rate: self.forwarded_request.false_positive_rate(),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use copernica_common::{PrivateIdentityInterface, PublicIdentityInterface};
+    fn hbfi(arg: &str) -> HBFI {
+        let sid = PrivateIdentityInterface::new_key();
+        HBFI::new(PublicIdentityInterface::Absent, sid.public_id(), "app", "m0d", "fun", arg).unwrap()
+    }
+    #[test]
+    fn counts_down_to_empty() {
+        let mut filter = CountingBloomFilter::new(constants::BLOOM_FALSE_POSITIVE_RATE);
+        let a = hbfi("a").to_bfis();
+        filter.insert(&a);
+        filter.insert(&a);
+        assert!(filter.remove(&a));
+        assert!(filter.contains(&a));
+        assert!(filter.remove(&a));
+        assert!(!filter.contains(&a));
+        assert_eq!(filter.fill_ratio(), 0.0);
+    }
+    #[test]
+    fn removing_one_keeps_the_other() {
+        let mut filter = CountingBloomFilter::new(constants::BLOOM_FALSE_POSITIVE_RATE);
+        let a = hbfi("a").to_bfis();
+        let b = hbfi("b").to_bfis();
+        filter.insert(&a);
+        filter.insert(&b);
+        assert!(filter.remove(&a));
+        assert!(!filter.contains(&a));
+        assert!(filter.contains(&b));
+        assert!(!filter.remove(&a));
+    }
+    #[test]
+    fn every_frame_shares_the_counters() {
+        let mut blooms = Blooms::new(constants::BLOOM_FALSE_POSITIVE_RATE);
+        let lifetime = Duration::from_secs(60);
+        let h = hbfi("a");
+        blooms.create_pending_request(h.clone().offset(0), lifetime);
+        blooms.create_pending_request(h.clone().offset(1), lifetime);
+        // any frame completes one of them
+        assert!(blooms.complete_pending_request(h.clone().offset(7)));
+        assert!(blooms.contains_pending_request(h.clone().offset(7)));
+        assert!(blooms.complete_pending_request(h.clone().offset(0)));
+        assert!(!blooms.complete_pending_request(h.clone().offset(1)));
+        assert!(!blooms.contains_pending_request(h));
+        assert!(blooms.metrics().pending_fill_ratio == 0.0);
+    }
+    #[test]
+    fn unanswered_requests_expire() {
+        let mut blooms = Blooms::new(constants::BLOOM_FALSE_POSITIVE_RATE);
+        let a = hbfi("a");
+        let b = hbfi("b");
+        blooms.create_forwarded_request(a.clone(), Duration::from_secs(0));
+        blooms.create_forwarded_request(b.clone(), Duration::from_secs(60));
+        let expired = blooms.expire(Instant::now() + Duration::from_millis(constants::REQUEST_SWEEP_INTERVAL_MS));
+        assert_eq!(expired.forwarded, 1);
+        assert_eq!(expired.pending, 0);
+        assert!(expired.was_forwarded(&a.to_bfis()));
+        assert!(!expired.was_forwarded(&b.to_bfis()));
+        assert!(!blooms.contains_forwarded_request(a.clone()));
+        assert!(blooms.contains_forwarded_request(b.clone()));
+        assert!(!blooms.complete_forwarded_request(a));
+        assert!(blooms.complete_forwarded_request(b));
     }
 }
@@ -1,16 +1,24 @@
 use {
     crate::{
-        bloom_filter::Blooms,
         router::Router,
-        Bayes,
+        control::{BrokerControl, LinkCommand, LinkTable, Routing},
+        bloom_filter::BloomMetrics,
+        defcon::{DefconPolicy, DefconState},
+        forwarding::{BayesStrategy, ForwardingStrategy},
+        nonce_cache::NonceCache,
+        bayes::{Bayes, BayesSnapshot, LinkRemap},
+        content_store::{ContentStore, IndexedContentStore},
+        verification::{ResponseVerifier, VerificationPolicy},
     },
-    copernica_common::{LinkId, InterLinkPacket, NarrowWaistPacket, constants, Operations },
-    anyhow::{anyhow, Result},
-    std::sync::mpsc::{Receiver, SyncSender, sync_channel as channel},
-    uluru::LRUCache,
+    copernica_common::{LinkId, InterLinkPacket, NodeHandle, RequestPolicy, RequestVerifier, RevocationList, RevocationRecord, RotationCertificate, constants, Operations },
+    anyhow::{Result},
+    std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender, sync_channel as channel},
     std::{
         collections::HashMap,
+        path::PathBuf,
         sync::{Arc, Mutex},
+        thread,
+        time::{Duration, Instant},
     },
     log::{
         error, trace,
@@ -41,25 +49,101 @@ use {
     +-----------+               +-----------+               |           Broker           |   +-----------+   +-----------+
                                                             +----------------------------+
 */
-pub type ResponseStore = LRUCache<NarrowWaistPacket, { constants::RESPONSE_STORE_SIZE }>;
+/// Where the Bayes routing model is snapshotted, and how links from the
+/// snapshot are matched to links attached after a restart.
+#[derive(Clone, Debug)]
+pub struct BayesPersistence {
+    pub path: PathBuf,
+    pub interval: Duration,
+    pub remap: LinkRemap,
+}
+impl BayesPersistence {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            interval: Duration::from_secs(constants::BAYES_SNAPSHOT_INTERVAL_SECS),
+            remap: LinkRemap::Exact,
+        }
+    }
+}
+pub struct BrokerConfig {
+    pub content_store: Box<dyn ContentStore>,
+    pub forwarding: Box<dyn ForwardingStrategy>,
+    // per link target for the pending and forwarded request filters
+    pub bloom_false_positive_rate: f64,
+    // how long a request stays pending when it doesn't carry its own lifetime
+    pub request_lifetime: Duration,
+    pub defcon: DefconPolicy,
+    // apps whose requests must be signed, and the replay window for signed requests
+    pub request_policy: RequestPolicy,
+    // which responses are checked against their signature before they are cached
+    pub verification: VerificationPolicy,
+    // responses signed by these identities are dropped
+    pub revocations: RevocationList,
+    pub bayes_persistence: Option<BayesPersistence>,
+    // None keeps every count forever
+    pub bayes_half_life: Option<Duration>,
+}
+impl Default for BrokerConfig {
+    fn default() -> Self {
+        Self {
+            content_store: Box::new(IndexedContentStore::default()),
+            forwarding: Box::new(BayesStrategy::new()),
+            bloom_false_positive_rate: constants::BLOOM_FALSE_POSITIVE_RATE,
+            request_lifetime: Duration::from_secs(constants::REQUEST_LIFETIME_SECS as u64),
+            defcon: DefconPolicy::default(),
+            request_policy: RequestPolicy::default(),
+            verification: VerificationPolicy::default(),
+            revocations: RevocationList::new(),
+            bayes_persistence: None,
+            bayes_half_life: Some(Duration::from_secs(constants::BAYES_HALF_LIFE_SECS)),
+        }
+    }
+}
 pub struct Broker {
     label:  String,
     ops: Operations,
-    rs:     ResponseStore,
+    rs:     Arc<Mutex<Box<dyn ContentStore>>>,
     l2b_tx: SyncSender<InterLinkPacket>,                         // give to link
     l2b_rx: Arc<Mutex<Receiver<InterLinkPacket>>>,                       // keep in broker
-    b2l:    HashMap<u32, SyncSender<InterLinkPacket>>,           // keep in broker
     r2b_tx: SyncSender<InterLinkPacket>,                // give to router
     r2b_rx: Arc<Mutex<Receiver<InterLinkPacket>>>,  // keep in broker
-    blooms: HashMap<LinkId, Blooms>,
+    ctl_tx: SyncSender<LinkCommand>,                    // give to BrokerControl
+    ctl_rx: Arc<Mutex<Receiver<LinkCommand>>>,          // keep in broker
+    links:  LinkTable,                                  // b2l, blooms and bayes shared by every thread
+    bayes_persistence: Option<BayesPersistence>,
 }
 impl Broker {
     pub fn new((label, ops): (String, Operations)) -> Self {
+        Self::new_with_config((label, ops), BrokerConfig::default())
+    }
+    pub fn new_with_config((label, ops): (String, Operations), config: BrokerConfig) -> Self {
         let (l2b_tx, l2b_rx) = channel::<InterLinkPacket>(constants::BOUNDED_BUFFER_SIZE);
         let (r2b_tx, r2b_rx) = channel::<InterLinkPacket>(constants::BOUNDED_BUFFER_SIZE);
-        let b2l = HashMap::new();
-        let blooms = HashMap::new();
-        let rs = ResponseStore::default();
+        let (ctl_tx, ctl_rx) = channel::<LinkCommand>(constants::BOUNDED_BUFFER_SIZE);
+        let links = LinkTable::new(Routing {
+            blooms: HashMap::new(),
+            bayes: Bayes::new_with_half_life(config.bayes_half_life),
+            defcon: DefconState::new(config.defcon),
+            nonces: NonceCache::new(constants::NONCE_CACHE_SIZE),
+            requests: RequestVerifier::new(config.request_policy),
+            responses: ResponseVerifier::new_with_revocations(config.verification, constants::VERIFICATION_CACHE_SIZE, config.revocations),
+            strategy: config.forwarding,
+            request_lifetime: config.request_lifetime,
+            bloom_false_positive_rate: config.bloom_false_positive_rate,
+        });
+        if let Some(persistence) = &config.bayes_persistence {
+            if persistence.path.exists() {
+                let warm_start = BayesSnapshot::load(&persistence.path)
+                    .and_then(|snapshot| links.routing()?.bayes.restore(snapshot, persistence.remap));
+                match warm_start {
+                    Ok(()) => trace!("WARM START {} from {:?}", label, persistence.path),
+                    // a bad snapshot costs some flooding, not the broker
+                    Err(e) => error!("broker {} ignoring Bayes snapshot {:?}: {}", label, persistence.path, e),
+                }
+            }
+        }
+        let rs = Arc::new(Mutex::new(config.content_store));
         ops.register_router(label.clone());
         Self {
             label,
@@ -68,85 +152,183 @@ impl Broker {
             l2b_rx: Arc::new(Mutex::new(l2b_rx)),
             r2b_tx,
             r2b_rx: Arc::new(Mutex::new(r2b_rx)),
-            b2l,
-            blooms,
+            ctl_tx,
+            ctl_rx: Arc::new(Mutex::new(ctl_rx)),
+            links,
             ops,
+            bayes_persistence: config.bayes_persistence,
         }
     }
     pub fn peer_with_link(
         &mut self,
         link_id: LinkId,
     ) -> Result<(SyncSender<InterLinkPacket>, Receiver<InterLinkPacket>)> {
-        match self.blooms.get(&link_id) {
-            Some(_) => Err(anyhow!("Channel already initialized")),
-            None => {
-                let (b2l_tx, b2l_rx) = channel::<InterLinkPacket>(constants::BOUNDED_BUFFER_SIZE);
-                self.b2l.insert(link_id.lookup_id()?, b2l_tx.clone());
-                self.blooms.insert(link_id, Blooms::new());
-                Ok((self.l2b_tx.clone(), b2l_rx))
-            }
-        }
+        let (b2l_tx, b2l_rx) = channel::<InterLinkPacket>(constants::BOUNDED_BUFFER_SIZE);
+        self.links.apply(LinkCommand::Attach { link_id, b2l_tx })?;
+        Ok((self.l2b_tx.clone(), b2l_rx))
+    }
+    pub fn unpeer_link(&mut self, link_id: LinkId) -> Result<()> {
+        self.links.apply(LinkCommand::Detach { link_id })
+    }
+    pub fn replace_link(
+        &mut self,
+        old: LinkId,
+        new: LinkId,
+    ) -> Result<(SyncSender<InterLinkPacket>, Receiver<InterLinkPacket>)> {
+        let (b2l_tx, b2l_rx) = channel::<InterLinkPacket>(constants::BOUNDED_BUFFER_SIZE);
+        self.links.apply(LinkCommand::Replace { old, new, b2l_tx })?;
+        Ok((self.l2b_tx.clone(), b2l_rx))
+    }
+    pub fn bloom_metrics(&self) -> Result<HashMap<LinkId, BloomMetrics>> {
+        self.links.bloom_metrics()
+    }
+    // responses per link that failed their signature check
+    pub fn verification_failures(&self) -> Result<HashMap<LinkId, u64>> {
+        self.links.verification_failures()
     }
-    #[allow(unreachable_code)]
-    pub fn run(&mut self) -> Result<()> {
+    // takes effect for responses arriving from now on
+    pub fn revoke(&self, record: RevocationRecord) -> Result<()> {
+        self.links.revoke(record)
+    }
+    pub fn rotate(&self, certificate: RotationCertificate) -> Result<()> {
+        self.links.rotate(certificate)
+    }
+    // hand this to whatever discovers neighbours so it can change links while the broker runs
+    pub fn control(&self) -> BrokerControl {
+        BrokerControl::new(self.l2b_tx.clone(), self.ctl_tx.clone())
+    }
+    pub fn run(&mut self) -> Result<NodeHandle> {
+        let mut handle = NodeHandle::new(&self.label);
         let l2b_rx = self.l2b_rx.clone();
-        let mut blooms = self.blooms.clone();
-        let choke = LinkId::choke();
-        let mut b2l = self.b2l.clone();
+        let links = self.links.clone();
         let r2b_tx = self.r2b_tx.clone();
         let r2b_rx_mutex = Arc::clone(&self.r2b_rx);
-        let mut bayes = Bayes::new();
-        for (link_id, _) in &blooms {
-            bayes.add_link(&link_id);
-        }
         let rs = self.rs.clone();
         let ops = self.ops.clone();
         let label = self.label.clone();
-        std::thread::spawn(move || {
+        handle.spawn(move |shutdown| {
             let l2b_rx = l2b_rx.lock().unwrap();
             loop {
-                match l2b_rx.recv() {
+                match l2b_rx.recv_timeout(shutdown.poll_interval()) {
                     Ok(ilp) => {
                         trace!("\t\t|  |  |  broker-to-router");
                         ops.message_from(label.clone());
-                        if !blooms.contains_key(&ilp.link_id()) {
-                            trace!("ADDING {:?} to BLOOMS", ilp);
-                            blooms.insert(ilp.link_id(), Blooms::new());
-                            bayes.add_link(&ilp.link_id());
+                        if !links.is_attached(&ilp.link_id())? {
+                            trace!("DROPPING {:?} from a detached link", ilp);
+                            continue;
                         }
-                        Router::handle_packet(&label, &ops, &ilp, r2b_tx.clone(), &mut rs.clone(), &mut blooms, &mut bayes, &choke)?;
+                        let mut routing = links.routing()?;
+                        routing.add_neighbour(&ilp.link_id());
+                        let mut rs = rs.lock().unwrap();
+                        Router::handle_packet(&label, &ops, &ilp, r2b_tx.clone(), rs.as_mut(), &mut routing)?;
                     }
-                    Err(error) => error!("{}", error),
+                    // keep draining until the queue has been quiet for a whole poll interval
+                    Err(RecvTimeoutError::Timeout) if !shutdown.is_triggered() => continue,
+                    Err(_) => break,
                 }
             }
-            Ok::<(), anyhow::Error>(())
+            rs.lock().unwrap().flush()
         });
+        let links = self.links.clone();
         let ops = self.ops.clone();
         let label = self.label.clone();
-        std::thread::spawn(move || {
+        handle.spawn(move |shutdown| {
+            let r2b_rx = r2b_rx_mutex.lock().unwrap();
+            loop {
+                let ilp = match r2b_rx.recv_timeout(shutdown.poll_interval()) {
+                    Ok(ilp) => ilp,
+                    Err(RecvTimeoutError::Timeout) if !shutdown.is_triggered() => continue,
+                    Err(_) => break,
+                };
+                match &ilp.link_id().lookup_id() {
+                    Ok(id) => {
+                        match links.sender(*id) {
+                            Ok(Some(b2l_tx)) => {
+                                trace!("\t\t|  |  |  router-to-broker");
+                                ops.message_from(label.clone());
+                                match shutdown.send(&b2l_tx, ilp) {
+                                    Ok(_) => {},
+                                    Err(e) => error!("broker {}", e),
+                                }
+                            },
+                            Ok(None) | Err(_) => { continue }
+                        }
+                    },
+                    Err(_e) => { continue },
+                };
+            }
+            Ok(())
+        });
+        let links = self.links.clone();
+        let ctl_rx = self.ctl_rx.clone();
+        handle.spawn(move |shutdown| {
+            let ctl_rx = ctl_rx.lock().unwrap();
             loop {
-                let r2b_rx_mutex = r2b_rx_mutex.clone();
-                let r2b_rx_ref = r2b_rx_mutex.lock().unwrap();
-                if let Ok(ilp) = r2b_rx_ref.recv() {
-                    match &ilp.link_id().lookup_id() {
-                        Ok(id) => {
-                            match b2l.get_mut(id) {
-                                Some(b2l_tx) => {
-                                    trace!("\t\t|  |  |  router-to-broker");
-                                    ops.message_from(label.clone());
-                                    match b2l_tx.send(ilp) {
-                                        Ok(_) => {},
-                                        Err(e) => error!("broker {:?}", e),
-                                    }
-                                },
-                                None => { continue }
-                            }
-                        },
-                        Err(_e) => { continue },
-                    };
+                match ctl_rx.recv_timeout(shutdown.poll_interval()) {
+                    Ok(command) => {
+                        if let Err(error) = links.apply(command) {
+                            error!("broker control {}", error);
+                        }
+                    },
+                    Err(RecvTimeoutError::Timeout) if !shutdown.is_triggered() => continue,
+                    Err(_) => break,
                 }
             }
+            Ok(())
         });
-        Ok(())
+        let links = self.links.clone();
+        let ops = self.ops.clone();
+        let label = self.label.clone();
+        handle.spawn(move |shutdown| {
+            let sweep_interval = Duration::from_millis(constants::REQUEST_SWEEP_INTERVAL_MS);
+            let mut last_sweep = Instant::now();
+            while !shutdown.is_triggered() {
+                thread::sleep(shutdown.poll_interval());
+                if last_sweep.elapsed() < sweep_interval {
+                    continue
+                }
+                last_sweep = Instant::now();
+                let mut routing = links.routing()?;
+                let Routing { blooms, bayes, nonces, .. } = &mut *routing;
+                bayes.decay(last_sweep);
+                nonces.expire(last_sweep);
+                for (link_id, blooms) in blooms.iter_mut() {
+                    let expired = blooms.expire(last_sweep);
+                    if expired.pending > 0 || expired.forwarded > 0 {
+                        trace!("EXPIRED {} pending and {} forwarded on {:?}", expired.pending, expired.forwarded, link_id);
+                    }
+                    for _ in 0..expired.pending {
+                        ops.pending_request_expired(label.clone());
+                    }
+                    for _ in 0..expired.forwarded {
+                        ops.forwarded_request_expired(label.clone());
+                    }
+                    if expired.forwarded > 0 {
+                        bayes.negative_train_where(link_id, |bfis| expired.was_forwarded(bfis));
+                    }
+                }
+            }
+            Ok(())
+        });
+        if let Some(persistence) = self.bayes_persistence.clone() {
+            let links = self.links.clone();
+            handle.spawn(move |shutdown| {
+                let mut last_snapshot = Instant::now();
+                loop {
+                    thread::sleep(shutdown.poll_interval());
+                    let stopping = shutdown.is_triggered();
+                    if stopping || last_snapshot.elapsed() >= persistence.interval {
+                        last_snapshot = Instant::now();
+                        let snapshot = links.routing()?.bayes.snapshot()?;
+                        snapshot.save(&persistence.path)?;
+                    }
+                    if stopping {
+                        break
+                    }
+                }
+                Ok(())
+            });
+        }
+        Ok(handle)
     }
 }
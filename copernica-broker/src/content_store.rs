@@ -0,0 +1,185 @@
+use {
+    copernica_common::{HBFI, HBFIExcludeFrame, NarrowWaistPacket, constants},
+    anyhow::{anyhow, Result},
+    std::collections::{BTreeMap, BTreeSet, HashMap},
+};
+/// Where a broker keeps the responses it has seen so it can answer
+/// later requests for the same `HBFI` without forwarding them upstream.
+pub trait ContentStore: Send {
+    fn get(&mut self, hbfi: &HBFI) -> Result<Option<NarrowWaistPacket>>;
+    // every stored frame of `hbfi`, ordered by frame
+    fn get_frames(&mut self, hbfi: &HBFIExcludeFrame) -> Result<Vec<NarrowWaistPacket>>;
+    // only responses are stored, a request or nack is an error
+    fn insert(&mut self, nw: NarrowWaistPacket) -> Result<()>;
+    fn len(&self) -> usize;
+    // called when the broker shuts down
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Eviction {
+    LeastRecentlyUsed,
+    LeastFrequentlyUsed,
+}
+struct Entry {
+    nw: NarrowWaistPacket,
+    rank: (u64, u64),
+    hits: u64,
+}
+/// An in-memory store indexed by `HBFI`, with a second index from
+/// `HBFIExcludeFrame` to the frames held. Lookups are O(1) and keeping the
+/// eviction order costs O(log n), whatever the capacity.
+pub struct IndexedContentStore {
+    capacity: usize,
+    eviction: Eviction,
+    tick: u64,
+    entries: HashMap<HBFI, Entry>,
+    // (hits or last use, tick of last use) -> HBFI; the first key is evicted next
+    order: BTreeMap<(u64, u64), HBFI>,
+    frames: HashMap<HBFIExcludeFrame, BTreeSet<u64>>,
+}
+impl Default for IndexedContentStore {
+    fn default() -> Self {
+        Self::new(constants::RESPONSE_STORE_SIZE, Eviction::LeastRecentlyUsed)
+    }
+}
+impl IndexedContentStore {
+    pub fn new(capacity: usize, eviction: Eviction) -> Self {
+        Self {
+            capacity,
+            eviction,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            frames: HashMap::new(),
+        }
+    }
+    fn rank(&mut self, hits: u64) -> (u64, u64) {
+        self.tick += 1;
+        match self.eviction {
+            Eviction::LeastRecentlyUsed => (self.tick, self.tick),
+            Eviction::LeastFrequentlyUsed => (hits, self.tick),
+        }
+    }
+    fn touch(&mut self, hbfi: &HBFI) -> Option<NarrowWaistPacket> {
+        let (old_rank, hits) = match self.entries.get(hbfi) {
+            Some(entry) => (entry.rank, entry.hits + 1),
+            None => return None,
+        };
+        let rank = self.rank(hits);
+        self.order.remove(&old_rank);
+        self.order.insert(rank, hbfi.clone());
+        let entry = self.entries.get_mut(hbfi)?;
+        entry.rank = rank;
+        entry.hits = hits;
+        Some(entry.nw.clone())
+    }
+    fn evict(&mut self, keep: usize) {
+        while self.entries.len() > keep {
+            let rank = match self.order.keys().next() {
+                Some(rank) => *rank,
+                None => return,
+            };
+            if let Some(hbfi) = self.order.remove(&rank) {
+                self.entries.remove(&hbfi);
+                let key = HBFIExcludeFrame(hbfi.clone());
+                if let Some(frames) = self.frames.get_mut(&key) {
+                    frames.remove(&hbfi.frm);
+                    if frames.is_empty() {
+                        self.frames.remove(&key);
+                    }
+                }
+            }
+        }
+    }
+}
+impl ContentStore for IndexedContentStore {
+    fn get(&mut self, hbfi: &HBFI) -> Result<Option<NarrowWaistPacket>> {
+        Ok(self.touch(hbfi))
+    }
+    fn get_frames(&mut self, hbfi: &HBFIExcludeFrame) -> Result<Vec<NarrowWaistPacket>> {
+        let frames: Vec<u64> = match self.frames.get(hbfi) {
+            Some(frames) => frames.iter().cloned().collect(),
+            None => return Ok(vec![]),
+        };
+        let mut out = Vec::with_capacity(frames.len());
+        for frm in frames {
+            if let Some(nw) = self.touch(&hbfi.0.clone().offset(frm)) {
+                out.push(nw);
+            }
+        }
+        Ok(out)
+    }
+    fn insert(&mut self, nw: NarrowWaistPacket) -> Result<()> {
+        let hbfi = match &nw {
+            NarrowWaistPacket::Response { hbfi, .. } => hbfi.clone(),
+            NarrowWaistPacket::Request { .. } | NarrowWaistPacket::Nack { .. } => return Err(anyhow!("Only responses belong in a ContentStore")),
+        };
+        if self.touch(&hbfi).is_some() {
+            return Ok(())
+        }
+        // make room first so a fresh entry isn't the first thing LFU throws out
+        self.evict(self.capacity.saturating_sub(1));
+        let rank = self.rank(1);
+        self.order.insert(rank, hbfi.clone());
+        self.frames.entry(HBFIExcludeFrame(hbfi.clone())).or_insert_with(BTreeSet::new).insert(hbfi.frm);
+        self.entries.insert(hbfi, Entry { nw, rank, hits: 1 });
+        Ok(())
+    }
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use copernica_common::{PrivateIdentityInterface, PublicIdentityInterface};
+    fn response(sid: &PrivateIdentityInterface, frame: u64) -> (HBFI, NarrowWaistPacket) {
+        let hbfi = HBFI::new(PublicIdentityInterface::Absent, sid.public_id(), "app", "m0d", "fun", "arg").unwrap().offset(frame);
+        (hbfi.clone(), NarrowWaistPacket::response(sid.clone(), hbfi, vec![frame as u8; 10]).unwrap())
+    }
+    #[test]
+    fn lru_evicts_least_recently_used() {
+        let sid = PrivateIdentityInterface::new_key();
+        let mut store = IndexedContentStore::new(2, Eviction::LeastRecentlyUsed);
+        let (h0, r0) = response(&sid, 0);
+        let (h1, r1) = response(&sid, 1);
+        let (h2, r2) = response(&sid, 2);
+        store.insert(r0.clone()).unwrap();
+        store.insert(r1).unwrap();
+        assert_eq!(store.get(&h0).unwrap(), Some(r0));
+        store.insert(r2).unwrap();
+        assert_eq!(store.len(), 2);
+        assert!(store.get(&h0).unwrap().is_some());
+        assert!(store.get(&h1).unwrap().is_none());
+        assert!(store.get(&h2).unwrap().is_some());
+    }
+    #[test]
+    fn lfu_evicts_least_frequently_used() {
+        let sid = PrivateIdentityInterface::new_key();
+        let mut store = IndexedContentStore::new(2, Eviction::LeastFrequentlyUsed);
+        let (h0, r0) = response(&sid, 0);
+        let (h1, r1) = response(&sid, 1);
+        let (h2, r2) = response(&sid, 2);
+        store.insert(r0).unwrap();
+        store.insert(r1).unwrap();
+        store.get(&h0).unwrap();
+        store.get(&h0).unwrap();
+        store.get(&h1).unwrap();
+        store.insert(r2).unwrap();
+        assert!(store.get(&h0).unwrap().is_some());
+        assert!(store.get(&h1).unwrap().is_none());
+        assert!(store.get(&h2).unwrap().is_some());
+    }
+    #[test]
+    fn frames_are_found_without_a_frame() {
+        let sid = PrivateIdentityInterface::new_key();
+        let mut store = IndexedContentStore::new(10, Eviction::LeastRecentlyUsed);
+        let (h0, r0) = response(&sid, 0);
+        let (_, r3) = response(&sid, 3);
+        store.insert(r3.clone()).unwrap();
+        store.insert(r0.clone()).unwrap();
+        assert_eq!(store.get_frames(&HBFIExcludeFrame(h0)).unwrap(), vec![r0, r3]);
+    }
+}
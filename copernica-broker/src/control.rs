@@ -0,0 +1,174 @@
+use {
+    crate::{
+        bloom_filter::{Blooms, BloomMetrics},
+        defcon::DefconState,
+        forwarding::ForwardingStrategy,
+        nonce_cache::NonceCache,
+        verification::ResponseVerifier,
+        Bayes,
+    },
+    copernica_common::{LinkId, InterLinkPacket, RequestVerifier, RevocationRecord, RotationCertificate, constants},
+    anyhow::{anyhow, Result},
+    std::{
+        collections::HashMap,
+        sync::{Arc, Mutex, MutexGuard, mpsc::{Receiver, SyncSender, sync_channel as channel}},
+        time::Duration,
+    },
+    log::{trace},
+};
+/// Changes to the set of links a running `Broker` forwards to.
+/// `Detach` and `Replace` act on every neighbour sharing the link's `lookup_id`,
+/// `DetachNeighbour` forgets a single `reply_to` on an otherwise healthy link.
+pub enum LinkCommand {
+    Attach { link_id: LinkId, b2l_tx: SyncSender<InterLinkPacket> },
+    Detach { link_id: LinkId },
+    DetachNeighbour { link_id: LinkId },
+    Replace { old: LinkId, new: LinkId, b2l_tx: SyncSender<InterLinkPacket> },
+}
+/// What the router learns per neighbour and how it forwards.
+pub struct Routing {
+    pub blooms: HashMap<LinkId, Blooms>,
+    pub bayes: Bayes,
+    pub defcon: DefconState,
+    pub nonces: NonceCache,
+    pub requests: RequestVerifier,
+    pub responses: ResponseVerifier,
+    pub strategy: Box<dyn ForwardingStrategy>,
+    // how long a request stays pending when it doesn't carry its own lifetime
+    pub request_lifetime: Duration,
+    pub bloom_false_positive_rate: f64,
+}
+impl Routing {
+    pub fn add_neighbour(&mut self, link_id: &LinkId) {
+        if !self.blooms.contains_key(link_id) {
+            trace!("ADDING {:?} to BLOOMS", link_id);
+            self.blooms.insert(link_id.clone(), Blooms::new(self.bloom_false_positive_rate));
+            self.bayes.add_link(link_id);
+        }
+    }
+    fn neighbours_of(&self, link_id: &LinkId) -> Result<Vec<LinkId>> {
+        let id = link_id.lookup_id()?;
+        Ok(self.blooms.keys()
+            .filter(|n| n.lookup_id().map(|n| n == id).unwrap_or(false))
+            .cloned()
+            .collect())
+    }
+}
+/// The link state shared between the `Broker`'s threads. The outbound senders
+/// and the routing state sit behind separate locks so forwarding a packet to a
+/// busy link never holds up the router; when both are needed `routing` is taken first.
+#[derive(Clone)]
+pub struct LinkTable {
+    b2l: Arc<Mutex<HashMap<u32, SyncSender<InterLinkPacket>>>>,
+    routing: Arc<Mutex<Routing>>,
+}
+impl LinkTable {
+    pub fn new(routing: Routing) -> Self {
+        Self {
+            b2l: Arc::new(Mutex::new(HashMap::new())),
+            routing: Arc::new(Mutex::new(routing)),
+        }
+    }
+    pub fn routing(&self) -> Result<MutexGuard<Routing>> {
+        self.routing.lock().map_err(|_| anyhow!("Broker routing table is poisoned"))
+    }
+    fn b2l(&self) -> Result<MutexGuard<HashMap<u32, SyncSender<InterLinkPacket>>>> {
+        self.b2l.lock().map_err(|_| anyhow!("Broker link table is poisoned"))
+    }
+    pub fn bloom_metrics(&self) -> Result<HashMap<LinkId, BloomMetrics>> {
+        Ok(self.routing()?.blooms.iter().map(|(link_id, blooms)| (link_id.clone(), blooms.metrics())).collect())
+    }
+    pub fn verification_failures(&self) -> Result<HashMap<LinkId, u64>> {
+        Ok(self.routing()?.responses.failures())
+    }
+    pub fn revoke(&self, record: RevocationRecord) -> Result<()> {
+        self.routing()?.responses.revoke(record)
+    }
+    pub fn rotate(&self, certificate: RotationCertificate) -> Result<()> {
+        self.routing()?.responses.rotate(certificate)
+    }
+    pub fn is_attached(&self, link_id: &LinkId) -> Result<bool> {
+        Ok(self.b2l()?.contains_key(&link_id.lookup_id()?))
+    }
+    pub fn sender(&self, lookup_id: u32) -> Result<Option<SyncSender<InterLinkPacket>>> {
+        Ok(self.b2l()?.get(&lookup_id).cloned())
+    }
+    pub fn apply(&self, command: LinkCommand) -> Result<()> {
+        let mut routing = self.routing()?;
+        let mut b2l = self.b2l()?;
+        match command {
+            LinkCommand::Attach { link_id, b2l_tx } => {
+                if routing.blooms.contains_key(&link_id) {
+                    return Err(anyhow!("Channel already initialized"))
+                }
+                b2l.insert(link_id.lookup_id()?, b2l_tx);
+                routing.add_neighbour(&link_id);
+            },
+            LinkCommand::Detach { link_id } => {
+                for neighbour in routing.neighbours_of(&link_id)? {
+                    routing.blooms.remove(&neighbour);
+                    routing.bayes.remove_link(&neighbour);
+                    routing.defcon.remove_link(&neighbour);
+                    routing.nonces.remove_link(&neighbour);
+                    routing.responses.remove_link(&neighbour);
+                }
+                // dropping the sender lets the link's outbound thread see a disconnect
+                b2l.remove(&link_id.lookup_id()?);
+            },
+            LinkCommand::DetachNeighbour { link_id } => {
+                routing.blooms.remove(&link_id);
+                routing.bayes.remove_link(&link_id);
+                routing.defcon.remove_link(&link_id);
+                routing.nonces.remove_link(&link_id);
+                routing.responses.remove_link(&link_id);
+            },
+            LinkCommand::Replace { old, new, b2l_tx } => {
+                // the same neighbours behind a new link keep what was learnt about them
+                for neighbour in routing.neighbours_of(&old)? {
+                    let replacement = new.remote(neighbour.reply_to()?)?;
+                    if let Some(blooms) = routing.blooms.remove(&neighbour) {
+                        routing.blooms.insert(replacement.clone(), blooms);
+                    }
+                    routing.bayes.replace_link(&neighbour, &replacement);
+                    routing.defcon.replace_link(&neighbour, &replacement);
+                    routing.nonces.replace_link(&neighbour, &replacement);
+                    routing.responses.replace_link(&neighbour, &replacement);
+                }
+                b2l.remove(&old.lookup_id()?);
+                b2l.insert(new.lookup_id()?, b2l_tx);
+                routing.add_neighbour(&new);
+            },
+        }
+        Ok(())
+    }
+}
+/// A cloneable handle for attaching, detaching and replacing links on a `Broker`
+/// from any thread, before or after `Broker::run`.
+#[derive(Clone)]
+pub struct BrokerControl {
+    l2b_tx: SyncSender<InterLinkPacket>,
+    ctl_tx: SyncSender<LinkCommand>,
+}
+impl BrokerControl {
+    pub fn new(l2b_tx: SyncSender<InterLinkPacket>, ctl_tx: SyncSender<LinkCommand>) -> Self {
+        Self { l2b_tx, ctl_tx }
+    }
+    pub fn attach_link(&self, link_id: LinkId) -> Result<(SyncSender<InterLinkPacket>, Receiver<InterLinkPacket>)> {
+        let (b2l_tx, b2l_rx) = channel::<InterLinkPacket>(constants::BOUNDED_BUFFER_SIZE);
+        self.ctl_tx.send(LinkCommand::Attach { link_id, b2l_tx }).map_err(|_| anyhow!("Broker control channel is closed"))?;
+        Ok((self.l2b_tx.clone(), b2l_rx))
+    }
+    pub fn detach_link(&self, link_id: LinkId) -> Result<()> {
+        self.ctl_tx.send(LinkCommand::Detach { link_id }).map_err(|_| anyhow!("Broker control channel is closed"))?;
+        Ok(())
+    }
+    pub fn detach_neighbour(&self, link_id: LinkId) -> Result<()> {
+        self.ctl_tx.send(LinkCommand::DetachNeighbour { link_id }).map_err(|_| anyhow!("Broker control channel is closed"))?;
+        Ok(())
+    }
+    pub fn replace_link(&self, old: LinkId, new: LinkId) -> Result<(SyncSender<InterLinkPacket>, Receiver<InterLinkPacket>)> {
+        let (b2l_tx, b2l_rx) = channel::<InterLinkPacket>(constants::BOUNDED_BUFFER_SIZE);
+        self.ctl_tx.send(LinkCommand::Replace { old, new, b2l_tx }).map_err(|_| anyhow!("Broker control channel is closed"))?;
+        Ok((self.l2b_tx.clone(), b2l_rx))
+    }
+}
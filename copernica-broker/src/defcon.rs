@@ -0,0 +1,234 @@
+use {
+    copernica_common::{LinkId, NackReason, NarrowWaistPacket, Operations},
+    anyhow::Result,
+    rand::Rng,
+    std::{
+        collections::HashMap,
+        time::{Duration, Instant},
+    },
+    log::{warn},
+};
+/// How strongly the choke link's Bayes weight says a request looks like an attack,
+/// from `Four` (suspicious) to `One` (certain).
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub enum Defcon {
+    One,
+    Two,
+    Three,
+    Four,
+}
+impl Defcon {
+    pub fn from_weight(weight: f64) -> Self {
+        let litmus_weight = (weight * 100.00) as u64;
+        match litmus_weight {
+            0..=35 => Defcon::Four,
+            36..=59 => Defcon::Three,
+            60..=89 => Defcon::Two,
+            90..=u64::MAX => Defcon::One,
+        }
+    }
+}
+#[derive(Clone, Debug, PartialEq)]
+pub enum Mitigation {
+    // token bucket per inbound link
+    RateLimit { requests_per_second: f64, burst: f64 },
+    // unsigned or unverifiable requests are dropped
+    RequireSignature,
+    Drop { probability: f64 },
+    // every request from the inbound link is dropped for `duration`
+    Choke { duration: Duration },
+}
+impl Mitigation {
+    // what the requester is told when this mitigation drops its request,
+    // a probabilistic drop stays silent
+    pub fn nack_reason(&self) -> Option<NackReason> {
+        match self {
+            Mitigation::RateLimit { .. } => Some(NackReason::RateLimited),
+            Mitigation::RequireSignature => Some(NackReason::SignatureRequired),
+            Mitigation::Drop { probability } if *probability >= 1.0 => Some(NackReason::NoRoute),
+            Mitigation::Drop { .. } | Mitigation::Choke { .. } => None,
+        }
+    }
+}
+/// The mitigations a `Broker` applies at each `Defcon` level, in order.
+/// Chokes are applied before anything that drops the request, wherever they're listed.
+#[derive(Clone, Debug)]
+pub struct DefconPolicy {
+    pub levels: HashMap<Defcon, Vec<Mitigation>>,
+}
+impl Default for DefconPolicy {
+    // rate limits until the model is certain, then the same drop as before plus a choke
+    fn default() -> Self {
+        let mut levels = HashMap::new();
+        levels.insert(Defcon::Four, vec![Mitigation::RateLimit { requests_per_second: 200.0, burst: 400.0 }]);
+        levels.insert(Defcon::Three, vec![Mitigation::RateLimit { requests_per_second: 100.0, burst: 200.0 }]);
+        levels.insert(Defcon::Two, vec![Mitigation::RateLimit { requests_per_second: 50.0, burst: 100.0 }]);
+        levels.insert(Defcon::One, vec![
+            Mitigation::Choke { duration: Duration::from_secs(30) },
+            Mitigation::Drop { probability: 1.0 },
+        ]);
+        Self { levels }
+    }
+}
+impl DefconPolicy {
+    pub fn strict() -> Self {
+        let mut levels = HashMap::new();
+        levels.insert(Defcon::Four, vec![Mitigation::RateLimit { requests_per_second: 50.0, burst: 100.0 }]);
+        levels.insert(Defcon::Three, vec![
+            Mitigation::RateLimit { requests_per_second: 20.0, burst: 40.0 },
+            Mitigation::RequireSignature,
+        ]);
+        levels.insert(Defcon::Two, vec![
+            Mitigation::RateLimit { requests_per_second: 10.0, burst: 20.0 },
+            Mitigation::RequireSignature,
+            Mitigation::Drop { probability: 0.5 },
+        ]);
+        levels.insert(Defcon::One, vec![
+            Mitigation::Choke { duration: Duration::from_secs(60) },
+            Mitigation::Drop { probability: 1.0 },
+        ]);
+        Self { levels }
+    }
+    pub fn none() -> Self {
+        Self { levels: HashMap::new() }
+    }
+}
+struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+impl TokenBucket {
+    fn take(&mut self, requests_per_second: f64, burst: f64) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * requests_per_second;
+        self.tokens = (self.tokens + refill).min(burst);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+/// Per link state for the mitigations in a `DefconPolicy`.
+pub struct DefconState {
+    policy: DefconPolicy,
+    buckets: HashMap<LinkId, TokenBucket>,
+    choked: HashMap<LinkId, Instant>,
+}
+impl DefconState {
+    pub fn new(policy: DefconPolicy) -> Self {
+        Self { policy, buckets: HashMap::new(), choked: HashMap::new() }
+    }
+    pub fn is_choked(&mut self, link_id: &LinkId) -> bool {
+        match self.choked.get(link_id) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                self.choked.remove(link_id);
+                false
+            },
+            None => false,
+        }
+    }
+    // the mitigation that dropped the request, if any
+    pub fn mitigate(&mut self, label: &String, ops: &Operations, level: Defcon, link_id: &LinkId, nw: &NarrowWaistPacket) -> Result<Option<Mitigation>> {
+        let mitigations = match self.policy.levels.get(&level) {
+            Some(mitigations) => mitigations.clone(),
+            None => return Ok(None),
+        };
+        for mitigation in &mitigations {
+            if let Mitigation::Choke { duration } = mitigation {
+                warn!("Defcon {:?}: choked {:?} for {:?}", level, link_id, duration);
+                ops.defcon_link_choked(label.clone());
+                self.choked.insert(link_id.clone(), Instant::now() + *duration);
+            }
+        }
+        for mitigation in mitigations {
+            match mitigation.clone() {
+                Mitigation::RateLimit { requests_per_second, burst } => {
+                    let bucket = self.buckets.entry(link_id.clone())
+                        .or_insert(TokenBucket { tokens: burst, last: Instant::now() });
+                    if !bucket.take(requests_per_second, burst) {
+                        warn!("Defcon {:?}: rate limited {:?}", level, link_id);
+                        ops.defcon_rate_limited(label.clone());
+                        return Ok(Some(mitigation))
+                    }
+                },
+                Mitigation::RequireSignature => {
+                    let named = match nw {
+                        NarrowWaistPacket::Request { signature, .. } => signature.is_some(),
+                        NarrowWaistPacket::Response { .. } => true,
+                        NarrowWaistPacket::Nack { signature, .. } => signature.is_some(),
+                    };
+                    if !named || !nw.verify()? {
+                        warn!("Defcon {:?}: dropped unsigned {:?}", level, nw);
+                        ops.defcon_unsigned_dropped(label.clone());
+                        return Ok(Some(mitigation))
+                    }
+                },
+                Mitigation::Drop { probability } => {
+                    if rand::thread_rng().gen_bool(probability.max(0.0).min(1.0)) {
+                        warn!("Defcon {:?}: dropped {:?}", level, nw);
+                        ops.defcon_dropped(label.clone());
+                        return Ok(Some(mitigation))
+                    }
+                },
+                Mitigation::Choke { .. } => {},
+            }
+        }
+        Ok(None)
+    }
+    pub fn remove_link(&mut self, link_id: &LinkId) {
+        self.buckets.remove(link_id);
+        self.choked.remove(link_id);
+    }
+    // a choke follows the neighbour onto its new link
+    pub fn replace_link(&mut self, old: &LinkId, new: &LinkId) {
+        if let Some(bucket) = self.buckets.remove(old) {
+            self.buckets.insert(new.clone(), bucket);
+        }
+        if let Some(until) = self.choked.remove(old) {
+            self.choked.insert(new.clone(), until);
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use copernica_common::{HBFI, PrivateIdentityInterface, PublicIdentityInterface, ReplyTo};
+    #[test]
+    fn levels_follow_the_litmus_weight() {
+        assert_eq!(Defcon::from_weight(0.10), Defcon::Four);
+        assert_eq!(Defcon::from_weight(0.40), Defcon::Three);
+        assert_eq!(Defcon::from_weight(0.75), Defcon::Two);
+        assert_eq!(Defcon::from_weight(0.95), Defcon::One);
+    }
+    #[test]
+    fn token_bucket_refuses_past_the_burst() {
+        let mut bucket = TokenBucket { tokens: 2.0, last: Instant::now() };
+        assert!(bucket.take(0.0, 2.0));
+        assert!(bucket.take(0.0, 2.0));
+        assert!(!bucket.take(0.0, 2.0));
+    }
+    #[test]
+    fn only_deliberate_refusals_are_nacked() {
+        assert_eq!(Mitigation::RateLimit { requests_per_second: 1.0, burst: 1.0 }.nack_reason(), Some(NackReason::RateLimited));
+        assert_eq!(Mitigation::RequireSignature.nack_reason(), Some(NackReason::SignatureRequired));
+        assert_eq!(Mitigation::Drop { probability: 1.0 }.nack_reason(), Some(NackReason::NoRoute));
+        assert_eq!(Mitigation::Drop { probability: 0.5 }.nack_reason(), None);
+    }
+    #[test]
+    fn defcon_one_chokes_the_link() {
+        let sid = PrivateIdentityInterface::new_key();
+        let link_id = LinkId::new(1, sid.clone(), PublicIdentityInterface::Absent, ReplyTo::Mpsc);
+        let hbfi = HBFI::new(PublicIdentityInterface::Absent, sid.public_id(), "app", "m0d", "fun", "arg").unwrap();
+        let nw = NarrowWaistPacket::request(hbfi).unwrap();
+        for policy in [DefconPolicy::default(), DefconPolicy::strict()].iter().cloned() {
+            let mut state = DefconState::new(policy);
+            let fired = state.mitigate(&"broker".to_string(), &Operations::turned_off(), Defcon::One, &link_id, &nw).unwrap();
+            assert_eq!(fired, Some(Mitigation::Drop { probability: 1.0 }));
+            assert!(state.is_choked(&link_id));
+        }
+    }
+}
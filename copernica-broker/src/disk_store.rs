@@ -0,0 +1,378 @@
+use {
+    crate::content_store::ContentStore,
+    copernica_common::{
+        HBFI, HBFIExcludeFrame, NarrowWaistPacket, NarrowWaistPacketKind,
+        serialization::{serialize_narrow_waist_packet, deserialize_narrow_waist_packet},
+    },
+    anyhow::{anyhow, Result},
+    std::{
+        collections::{BTreeMap, BTreeSet, HashMap},
+        convert::TryFrom,
+        fs::{self, File, OpenOptions},
+        io::{Read, Seek, SeekFrom, Write},
+        path::{Path, PathBuf},
+    },
+    log::{debug, warn},
+};
+// kind: u8, payload length: u32, crc32 of kind and payload: u32
+const RECORD_HEADER_SIZE: u64 = 9;
+// far above any narrow waist packet, it only stops a corrupt length from allocating gigabytes
+const MAX_RECORD_PAYLOAD: usize = u16::MAX as usize;
+const SEGMENT_EXTENSION: &str = "seg";
+pub const DEFAULT_SEGMENT_BYTES: u64 = 64 * 1024 * 1024;
+#[derive(Clone, Copy, Debug)]
+struct Location {
+    segment: u64,
+    offset: u64,
+    length: u64,
+}
+#[derive(Debug, Default)]
+struct Segment {
+    bytes: u64,
+    hbfis: Vec<HBFI>,
+}
+/// An append-only log of responses split into numbered segment files.
+/// Every record is checksummed; on open the segments are replayed to rebuild the
+/// index and a torn or corrupt tail is truncated away, so a crash costs at most
+/// the responses that were still being written. Once the log exceeds `max_bytes`
+/// the oldest segment is deleted whole.
+pub struct DiskContentStore {
+    dir: PathBuf,
+    max_bytes: u64,
+    segment_bytes: u64,
+    total_bytes: u64,
+    segments: BTreeMap<u64, Segment>,
+    index: HashMap<HBFI, Location>,
+    frames: HashMap<HBFIExcludeFrame, BTreeSet<u64>>,
+    active: File,
+}
+impl DiskContentStore {
+    pub fn new<P: AsRef<Path>>(dir: P, max_bytes: u64) -> Result<Self> {
+        Self::with_segment_size(dir, max_bytes, DEFAULT_SEGMENT_BYTES.min(max_bytes))
+    }
+    pub fn with_segment_size<P: AsRef<Path>>(dir: P, max_bytes: u64, segment_bytes: u64) -> Result<Self> {
+        if segment_bytes == 0 || segment_bytes > max_bytes {
+            return Err(anyhow!("segment size {} must be non-zero and no larger than the quota {}", segment_bytes, max_bytes))
+        }
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let mut ids: Vec<u64> = vec![];
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            if let Some(id) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse::<u64>().ok()) {
+                ids.push(id);
+            }
+        }
+        ids.sort();
+        let mut segments = BTreeMap::new();
+        let mut index = HashMap::new();
+        let mut total_bytes = 0;
+        for id in &ids {
+            let segment = recover_segment(&segment_path(&dir, *id), *id, &mut index)?;
+            total_bytes += segment.bytes;
+            segments.insert(*id, segment);
+        }
+        let active_id = ids.last().cloned().unwrap_or(0);
+        segments.entry(active_id).or_insert_with(Segment::default);
+        let active = OpenOptions::new().create(true).append(true).open(segment_path(&dir, active_id))?;
+        debug!("opened content store {:?}: {} responses in {} segments", dir, index.len(), segments.len());
+        let mut frames: HashMap<HBFIExcludeFrame, BTreeSet<u64>> = HashMap::new();
+        for hbfi in index.keys() {
+            frames.entry(HBFIExcludeFrame(hbfi.clone())).or_insert_with(BTreeSet::new).insert(hbfi.frm);
+        }
+        let mut store = Self { dir, max_bytes, segment_bytes, total_bytes, segments, index, frames, active };
+        store.evict()?;
+        Ok(store)
+    }
+    pub fn bytes(&self) -> u64 {
+        self.total_bytes
+    }
+    pub fn sync(&mut self) -> Result<()> {
+        self.active.sync_data()?;
+        Ok(())
+    }
+    fn active_id(&self) -> u64 {
+        *self.segments.keys().next_back().unwrap_or(&0)
+    }
+    fn rotate(&mut self) -> Result<()> {
+        self.active.sync_data()?;
+        let next = self.active_id() + 1;
+        self.active = OpenOptions::new().create(true).append(true).open(segment_path(&self.dir, next))?;
+        self.segments.insert(next, Segment::default());
+        Ok(())
+    }
+    fn forget(&mut self, hbfi: &HBFI) {
+        self.index.remove(hbfi);
+        let key = HBFIExcludeFrame(hbfi.clone());
+        if let Some(frames) = self.frames.get_mut(&key) {
+            frames.remove(&hbfi.frm);
+            if frames.is_empty() {
+                self.frames.remove(&key);
+            }
+        }
+    }
+    fn evict(&mut self) -> Result<()> {
+        while self.total_bytes > self.max_bytes && self.segments.len() > 1 {
+            let oldest = *self.segments.keys().next().unwrap();
+            if let Some(segment) = self.segments.remove(&oldest) {
+                for hbfi in segment.hbfis {
+                    if let Some(location) = self.index.get(&hbfi) {
+                        if location.segment == oldest {
+                            self.forget(&hbfi);
+                        }
+                    }
+                }
+                self.total_bytes -= segment.bytes;
+                fs::remove_file(segment_path(&self.dir, oldest))?;
+                debug!("evicted content store segment {}", oldest);
+            }
+        }
+        Ok(())
+    }
+}
+impl ContentStore for DiskContentStore {
+    fn get(&mut self, hbfi: &HBFI) -> Result<Option<NarrowWaistPacket>> {
+        let location = match self.index.get(hbfi) {
+            Some(location) => *location,
+            None => return Ok(None),
+        };
+        let mut file = File::open(segment_path(&self.dir, location.segment))?;
+        file.seek(SeekFrom::Start(location.offset))?;
+        match read_record(&mut file)? {
+            Some((nw, length)) if length == location.length => Ok(Some(nw)),
+            _ => {
+                warn!("content store record for {:?} is unreadable, dropping it", hbfi);
+                self.forget(hbfi);
+                Ok(None)
+            },
+        }
+    }
+    fn get_frames(&mut self, hbfi: &HBFIExcludeFrame) -> Result<Vec<NarrowWaistPacket>> {
+        let frames: Vec<u64> = match self.frames.get(hbfi) {
+            Some(frames) => frames.iter().cloned().collect(),
+            None => return Ok(vec![]),
+        };
+        let mut out = Vec::with_capacity(frames.len());
+        for frm in frames {
+            if let Some(nw) = self.get(&hbfi.0.clone().offset(frm))? {
+                out.push(nw);
+            }
+        }
+        Ok(out)
+    }
+    fn insert(&mut self, nw: NarrowWaistPacket) -> Result<()> {
+        let hbfi = match &nw {
+            NarrowWaistPacket::Response { hbfi, .. } => hbfi.clone(),
+            NarrowWaistPacket::Request { .. } | NarrowWaistPacket::Nack { .. } => return Err(anyhow!("Only responses belong in a ContentStore")),
+        };
+        // responses are signed and immutable, the first copy is as good as any other
+        if self.index.contains_key(&hbfi) {
+            return Ok(())
+        }
+        let record = encode_record(&nw)?;
+        let length = record.len() as u64;
+        if self.segments[&self.active_id()].bytes + length > self.segment_bytes {
+            self.rotate()?;
+        }
+        let id = self.active_id();
+        let segment = self.segments.get_mut(&id).unwrap();
+        let offset = segment.bytes;
+        // a write that failed partway may have left a torn record behind, which
+        // would shift every later record and be truncated along with them on reopen
+        if self.active.metadata()?.len() != offset {
+            self.active.set_len(offset)?;
+        }
+        if let Err(e) = self.active.write_all(&record) {
+            let _ = self.active.set_len(offset);
+            return Err(e.into())
+        }
+        segment.bytes += length;
+        segment.hbfis.push(hbfi.clone());
+        self.total_bytes += length;
+        self.frames.entry(HBFIExcludeFrame(hbfi.clone())).or_insert_with(BTreeSet::new).insert(hbfi.frm);
+        self.index.insert(hbfi, Location { segment: id, offset, length });
+        self.evict()
+    }
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+    fn flush(&mut self) -> Result<()> {
+        self.sync()
+    }
+}
+fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", id, SEGMENT_EXTENSION))
+}
+fn encode_record(nw: &NarrowWaistPacket) -> Result<Vec<u8>> {
+    let kind = NarrowWaistPacketKind::from_narrow_waist(nw) as u8;
+    let (_, payload) = serialize_narrow_waist_packet(nw)?;
+    let mut checked = vec![kind];
+    checked.extend(&payload);
+    let mut record = Vec::with_capacity(RECORD_HEADER_SIZE as usize + payload.len());
+    record.push(kind);
+    record.extend(&(payload.len() as u32).to_be_bytes());
+    record.extend(&crc32fast::hash(&checked).to_be_bytes());
+    record.extend(&payload);
+    Ok(record)
+}
+// Ok(None) means the record at this position is missing, torn or corrupt.
+fn read_record(file: &mut File) -> Result<Option<(NarrowWaistPacket, u64)>> {
+    let mut header = [0u8; RECORD_HEADER_SIZE as usize];
+    if read_fully(file, &mut header)? < header.len() {
+        return Ok(None)
+    }
+    let mut length = [0u8; 4];
+    length.copy_from_slice(&header[1..5]);
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_RECORD_PAYLOAD {
+        return Ok(None)
+    }
+    let mut checksum = [0u8; 4];
+    checksum.copy_from_slice(&header[5..9]);
+    let checksum = u32::from_be_bytes(checksum);
+    let mut checked = vec![0u8; 1 + length];
+    checked[0] = header[0];
+    if read_fully(file, &mut checked[1..])? < length {
+        return Ok(None)
+    }
+    if crc32fast::hash(&checked) != checksum {
+        return Ok(None)
+    }
+    let kind = match NarrowWaistPacketKind::try_from(header[0]) {
+        Ok(kind) => kind,
+        Err(_) => return Ok(None),
+    };
+    match deserialize_narrow_waist_packet(kind, &checked[1..]) {
+        Ok(nw) => Ok(Some((nw, RECORD_HEADER_SIZE + length as u64))),
+        Err(_) => Ok(None),
+    }
+}
+fn read_fully(file: &mut File, buf: &mut [u8]) -> Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read(&mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+fn recover_segment(path: &Path, id: u64, index: &mut HashMap<HBFI, Location>) -> Result<Segment> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let file_length = file.metadata()?.len();
+    let mut segment = Segment::default();
+    while segment.bytes < file_length {
+        match read_record(&mut file)? {
+            Some((nw, length)) => {
+                if let NarrowWaistPacket::Response { hbfi, .. } = nw {
+                    index.insert(hbfi.clone(), Location { segment: id, offset: segment.bytes, length });
+                    segment.hbfis.push(hbfi);
+                }
+                segment.bytes += length;
+            },
+            None => {
+                warn!("truncating content store segment {:?} at byte {} of {}", path, segment.bytes, file_length);
+                file.set_len(segment.bytes)?;
+                file.sync_data()?;
+                break;
+            },
+        }
+    }
+    Ok(segment)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use copernica_common::{PrivateIdentityInterface, PublicIdentityInterface};
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("copernica-{}-{}", name, rand::random::<u64>()))
+    }
+    fn response(sid: &PrivateIdentityInterface, frame: u64) -> NarrowWaistPacket {
+        let mut hbfi = HBFI::new(PublicIdentityInterface::Absent, sid.public_id(), "app", "m0d", "fun", "arg").unwrap();
+        hbfi.frm = frame;
+        NarrowWaistPacket::response(sid.clone(), hbfi, vec![frame as u8; 10]).unwrap()
+    }
+    fn hbfi(nw: &NarrowWaistPacket) -> HBFI {
+        match nw {
+            NarrowWaistPacket::Response { hbfi, .. } => hbfi.clone(),
+            NarrowWaistPacket::Request { hbfi, .. } => hbfi.clone(),
+            NarrowWaistPacket::Nack { hbfi, .. } => hbfi.clone(),
+        }
+    }
+    #[test]
+    fn survives_reopen() {
+        let dir = temp_dir("reopen");
+        let sid = PrivateIdentityInterface::new_key();
+        let expected = response(&sid, 0);
+        {
+            let mut store = DiskContentStore::new(&dir, 1024 * 1024).unwrap();
+            store.insert(expected.clone()).unwrap();
+            assert!(store.insert(NarrowWaistPacket::request(hbfi(&expected)).unwrap()).is_err());
+            store.sync().unwrap();
+        }
+        let mut store = DiskContentStore::new(&dir, 1024 * 1024).unwrap();
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.get(&hbfi(&expected)).unwrap(), Some(expected));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn truncates_torn_tail() {
+        let dir = temp_dir("torn");
+        let sid = PrivateIdentityInterface::new_key();
+        let first = response(&sid, 0);
+        let second = response(&sid, 1);
+        {
+            let mut store = DiskContentStore::new(&dir, 1024 * 1024).unwrap();
+            store.insert(first.clone()).unwrap();
+            store.insert(second.clone()).unwrap();
+            store.sync().unwrap();
+        }
+        let path = segment_path(&dir, 0);
+        let length = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(length - 5).unwrap();
+        let mut store = DiskContentStore::new(&dir, 1024 * 1024).unwrap();
+        assert_eq!(store.get(&hbfi(&first)).unwrap(), Some(first));
+        assert_eq!(store.get(&hbfi(&second)).unwrap(), None);
+        assert_eq!(fs::metadata(&path).unwrap().len(), store.bytes());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn a_torn_write_is_cut_before_the_next_record() {
+        let dir = temp_dir("torn-write");
+        let sid = PrivateIdentityInterface::new_key();
+        let first = response(&sid, 0);
+        let second = response(&sid, 1);
+        {
+            let mut store = DiskContentStore::new(&dir, 1024 * 1024).unwrap();
+            store.insert(first.clone()).unwrap();
+            // what a write that failed partway leaves in the segment
+            let torn = encode_record(&response(&sid, 2)).unwrap();
+            OpenOptions::new().append(true).open(segment_path(&dir, 0)).unwrap().write_all(&torn[..torn.len() / 2]).unwrap();
+            store.insert(second.clone()).unwrap();
+            assert_eq!(store.get(&hbfi(&second)).unwrap(), Some(second.clone()));
+            store.sync().unwrap();
+        }
+        let mut store = DiskContentStore::new(&dir, 1024 * 1024).unwrap();
+        assert_eq!(store.get(&hbfi(&first)).unwrap(), Some(first));
+        assert_eq!(store.get(&hbfi(&second)).unwrap(), Some(second));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn evicts_oldest_segment_over_quota() {
+        let dir = temp_dir("quota");
+        let sid = PrivateIdentityInterface::new_key();
+        let record = encode_record(&response(&sid, 0)).unwrap().len() as u64;
+        let mut store = DiskContentStore::with_segment_size(&dir, record * 4, record * 2).unwrap();
+        let responses: Vec<NarrowWaistPacket> = (0..6).map(|frame| response(&sid, frame)).collect();
+        for nw in &responses {
+            store.insert(nw.clone()).unwrap();
+        }
+        assert!(store.bytes() <= record * 4);
+        assert_eq!(store.get(&hbfi(&responses[0])).unwrap(), None);
+        assert_eq!(store.get(&hbfi(&responses[5])).unwrap(), Some(responses[5].clone()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,116 @@
+use {
+    crate::LinkWeight,
+    copernica_common::{HBFI, LinkId},
+    rand::Rng,
+};
+/// Decides which links a request is forwarded on. The `Router` hands over
+/// the links it may use, best Bayes weight first, with the choke link, the
+/// inbound link and links already holding the request pending removed.
+pub trait ForwardingStrategy: Send {
+    fn select(&mut self, hbfi: &HBFI, inbound: &LinkId, candidates: &[LinkWeight]) -> Vec<LinkId>;
+}
+/// What the router has always done: every candidate, best first.
+#[derive(Clone, Debug, Default)]
+pub struct BayesStrategy;
+impl BayesStrategy {
+    pub fn new() -> Self {
+        Self
+    }
+}
+impl ForwardingStrategy for BayesStrategy {
+    fn select(&mut self, _hbfi: &HBFI, _inbound: &LinkId, candidates: &[LinkWeight]) -> Vec<LinkId> {
+        candidates.iter().map(|c| c.linkid.clone()).collect()
+    }
+}
+/// Every candidate regardless of weight, in no particular order.
+#[derive(Clone, Debug, Default)]
+pub struct FloodStrategy;
+impl FloodStrategy {
+    pub fn new() -> Self {
+        Self
+    }
+}
+impl ForwardingStrategy for FloodStrategy {
+    fn select(&mut self, _hbfi: &HBFI, _inbound: &LinkId, candidates: &[LinkWeight]) -> Vec<LinkId> {
+        let mut links: Vec<LinkId> = candidates.iter().map(|c| c.linkid.clone()).collect();
+        let mut rng = rand::thread_rng();
+        for i in (1..links.len()).rev() {
+            links.swap(i, rng.gen_range(0..=i));
+        }
+        links
+    }
+}
+#[derive(Clone, Debug, Default)]
+pub struct BestLinkStrategy;
+impl BestLinkStrategy {
+    pub fn new() -> Self {
+        Self
+    }
+}
+impl ForwardingStrategy for BestLinkStrategy {
+    fn select(&mut self, _hbfi: &HBFI, _inbound: &LinkId, candidates: &[LinkWeight]) -> Vec<LinkId> {
+        candidates.iter().take(1).map(|c| c.linkid.clone()).collect()
+    }
+}
+/// The `k` best candidates, for multipath.
+#[derive(Clone, Debug)]
+pub struct KBestStrategy {
+    k: usize,
+}
+impl KBestStrategy {
+    pub fn new(k: usize) -> Self {
+        Self { k }
+    }
+}
+impl ForwardingStrategy for KBestStrategy {
+    fn select(&mut self, _hbfi: &HBFI, _inbound: &LinkId, candidates: &[LinkWeight]) -> Vec<LinkId> {
+        candidates.iter().take(self.k).map(|c| c.linkid.clone()).collect()
+    }
+}
+/// The best candidate, except with probability `epsilon` a random one,
+/// so links the model has given up on still get a chance to prove otherwise.
+#[derive(Clone, Debug)]
+pub struct EpsilonGreedyStrategy {
+    epsilon: f64,
+}
+impl EpsilonGreedyStrategy {
+    pub fn new(epsilon: f64) -> Self {
+        Self { epsilon: epsilon.max(0.0).min(1.0) }
+    }
+}
+impl ForwardingStrategy for EpsilonGreedyStrategy {
+    fn select(&mut self, _hbfi: &HBFI, _inbound: &LinkId, candidates: &[LinkWeight]) -> Vec<LinkId> {
+        if candidates.is_empty() {
+            return vec![]
+        }
+        let mut rng = rand::thread_rng();
+        let i = if rng.gen_bool(self.epsilon) { rng.gen_range(0..candidates.len()) } else { 0 };
+        vec![candidates[i].linkid.clone()]
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use copernica_common::{PrivateIdentityInterface, PublicIdentityInterface, ReplyTo};
+    fn candidates() -> (HBFI, LinkId, Vec<LinkWeight>) {
+        let sid = PrivateIdentityInterface::new_key();
+        let hbfi = HBFI::new(PublicIdentityInterface::Absent, sid.public_id(), "app", "m0d", "fun", "arg").unwrap();
+        let link = |i| LinkId::new(i, sid.clone(), PublicIdentityInterface::Absent, ReplyTo::Rf(i));
+        let weights = vec![
+            LinkWeight { linkid: link(1), weight: 0.6 },
+            LinkWeight { linkid: link(2), weight: 0.3 },
+            LinkWeight { linkid: link(3), weight: 0.1 },
+        ];
+        (hbfi, link(0), weights)
+    }
+    #[test]
+    fn strategies_pick_from_the_best() {
+        let (hbfi, inbound, weights) = candidates();
+        assert_eq!(BayesStrategy::new().select(&hbfi, &inbound, &weights).len(), 3);
+        assert_eq!(FloodStrategy::new().select(&hbfi, &inbound, &weights).len(), 3);
+        assert_eq!(BestLinkStrategy::new().select(&hbfi, &inbound, &weights), vec![weights[0].linkid.clone()]);
+        assert_eq!(KBestStrategy::new(2).select(&hbfi, &inbound, &weights), vec![weights[0].linkid.clone(), weights[1].linkid.clone()]);
+        assert_eq!(EpsilonGreedyStrategy::new(0.0).select(&hbfi, &inbound, &weights), vec![weights[0].linkid.clone()]);
+        assert!(EpsilonGreedyStrategy::new(1.0).select(&hbfi, &inbound, &[]).is_empty());
+    }
+}
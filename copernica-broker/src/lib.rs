@@ -2,10 +2,25 @@
 
 mod bloom_filter;
 mod broker;
+mod control;
+mod defcon;
+mod forwarding;
+mod content_store;
+mod disk_store;
+mod nonce_cache;
 pub mod bayes;
 mod router;
+mod verification;
 pub use crate::{
-    broker::{Broker, ResponseStore},
+    broker::{Broker, BrokerConfig, BayesPersistence},
+    bloom_filter::{BloomMetrics, Blooms, CountingBloomFilter, Expired},
+    content_store::{ContentStore, IndexedContentStore, Eviction},
+    disk_store::{DiskContentStore},
+    nonce_cache::NonceCache,
+    control::{BrokerControl, LinkCommand},
+    defcon::{Defcon, DefconPolicy, Mitigation},
+    forwarding::{ForwardingStrategy, BayesStrategy, FloodStrategy, BestLinkStrategy, KBestStrategy, EpsilonGreedyStrategy},
     router::Router,
-    bayes::{Bayes, LinkWeight},
+    verification::{ResponseVerifier, VerificationPolicy},
+    bayes::{Bayes, BayesSnapshot, LinkKey, LinkRemap, LinkWeight},
 };
@@ -0,0 +1,90 @@
+use {
+    copernica_common::{LinkId, Nonce},
+    std::{
+        collections::{HashMap, VecDeque},
+        time::{Duration, Instant},
+    },
+};
+struct Seen {
+    link_id: LinkId,
+    inserted: Instant,
+    deadline: Instant,
+}
+/// The nonces of requests a `Broker` has recently routed and the link each
+/// first arrived on. A request arriving again on a different link has looped
+/// or come round a second path and shouldn't be routed twice.
+pub struct NonceCache {
+    capacity: usize,
+    seen: HashMap<Nonce, Seen>,
+    order: VecDeque<(Instant, Nonce)>,
+}
+impl NonceCache {
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, seen: HashMap::new(), order: VecDeque::new() }
+    }
+    // true when the nonce is still remembered from another link,
+    // a retransmission on the same link is let through
+    pub fn is_duplicate(&mut self, nonce: &Nonce, link_id: &LinkId, lifetime: Duration) -> bool {
+        let now = Instant::now();
+        match self.seen.get(nonce) {
+            Some(seen) if seen.deadline > now => return seen.link_id != *link_id,
+            _ => {},
+        }
+        while self.seen.len() >= self.capacity.max(1) {
+            match self.order.pop_front() {
+                Some((inserted, oldest)) => {
+                    if self.seen.get(&oldest).map(|seen| seen.inserted == inserted).unwrap_or(false) {
+                        self.seen.remove(&oldest);
+                    }
+                },
+                None => break,
+            }
+        }
+        self.seen.insert(nonce.clone(), Seen { link_id: link_id.clone(), inserted: now, deadline: now + lifetime });
+        self.order.push_back((now, nonce.clone()));
+        false
+    }
+    pub fn expire(&mut self, now: Instant) {
+        self.seen.retain(|_, seen| seen.deadline > now);
+        let seen = &self.seen;
+        self.order.retain(|(inserted, nonce)| seen.get(nonce).map(|s| s.inserted == *inserted).unwrap_or(false));
+    }
+    pub fn remove_link(&mut self, link_id: &LinkId) {
+        self.seen.retain(|_, seen| seen.link_id != *link_id);
+    }
+    pub fn replace_link(&mut self, old: &LinkId, new: &LinkId) {
+        for seen in self.seen.values_mut() {
+            if seen.link_id == *old {
+                seen.link_id = new.clone();
+            }
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use copernica_common::{PrivateIdentityInterface, PublicIdentityInterface, ReplyTo};
+    #[test]
+    fn drops_a_nonce_seen_on_another_link() {
+        let sid = PrivateIdentityInterface::new_key();
+        let a = LinkId::new(0, sid.clone(), PublicIdentityInterface::Absent, ReplyTo::Rf(0));
+        let b = LinkId::new(1, sid, PublicIdentityInterface::Absent, ReplyTo::Rf(1));
+        let lifetime = Duration::from_secs(60);
+        let mut cache = NonceCache::new(2);
+        assert!(!cache.is_duplicate(&Nonce([0; 8]), &a, lifetime));
+        assert!(!cache.is_duplicate(&Nonce([0; 8]), &a, lifetime));
+        assert!(cache.is_duplicate(&Nonce([0; 8]), &b, lifetime));
+        assert!(!cache.is_duplicate(&Nonce([1; 8]), &a, lifetime));
+        assert!(!cache.is_duplicate(&Nonce([2; 8]), &a, lifetime));
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.is_duplicate(&Nonce([0; 8]), &b, lifetime));
+        cache.expire(Instant::now() + lifetime);
+        assert!(cache.is_empty());
+    }
+}
@@ -1,13 +1,14 @@
 use {
     crate::{
-        bloom_filter::{Blooms},
-        Bayes, LinkWeight, ResponseStore
+        control::Routing,
+        defcon::Defcon,
+        LinkWeight, ContentStore
     },
-    copernica_common::{LinkId, InterLinkPacket, LinkPacket, NarrowWaistPacket, Operations},
+    copernica_common::{LinkId, InterLinkPacket, LinkPacket, NarrowWaistPacket, NackReason, Operations, RequestCheck, constants},
     anyhow::Result,
     std::sync::mpsc::{SyncSender},
-    log::{warn, trace},
-    std::collections::HashMap,
+    std::time::Duration,
+    log::{error, trace},
 };
 #[derive(Clone)]
 pub struct Router {}
@@ -17,22 +18,62 @@ impl Router {
         ops: &Operations,
         ilp: &InterLinkPacket,
         r2b_tx: SyncSender<InterLinkPacket>,
-        rs: &mut ResponseStore,
-        blooms: &mut HashMap<LinkId, Blooms>,
-        bayes: &mut Bayes,
-        choke: &LinkId,
+        rs: &mut dyn ContentStore,
+        routing: &mut Routing,
     ) -> Result<()> {
+        let Routing { blooms, bayes, defcon, nonces, requests, responses, strategy, request_lifetime, .. } = routing;
+        let choke = &LinkId::choke();
         let this_link: LinkId = ilp.link_id();
         let nw: NarrowWaistPacket = ilp.narrow_waist();
         if let Some(this_bloom) = blooms.get_mut(&this_link) {
             match nw.clone() {
-                NarrowWaistPacket::Request { hbfi, .. } => {
-                    match rs.find(|n|
-                        match n {
-                            NarrowWaistPacket::Response { hbfi: compare_hbfi, .. } => { compare_hbfi == &hbfi },
-                            NarrowWaistPacket::Request  { .. } => { false }
-                        }) {
-
+                NarrowWaistPacket::Request { hbfi, nonce, lifetime, .. } => {
+                    if defcon.is_choked(&this_link) {
+                        trace!("\t\t|  |  |  |  DROP REQUEST FROM CHOKED LINK");
+                        ops.defcon_dropped(label.clone());
+                        return Ok(());
+                    }
+                    // a requester may ask for less or more time than the default, up to a bound
+                    let lifetime = match lifetime {
+                        0 => *request_lifetime,
+                        secs => Duration::from_secs(secs.min(constants::MAX_REQUEST_LIFETIME_SECS) as u64),
+                    };
+                    if nonces.is_duplicate(&nonce, &this_link, lifetime) {
+                        trace!("\t\t|  |  |  |  DROP DUPLICATE REQUEST");
+                        ops.duplicate_request_dropped(label.clone());
+                        return Ok(());
+                    }
+                    match requests.check(&nw) {
+                        RequestCheck::Accepted => {},
+                        RequestCheck::Unsigned => {
+                            trace!("\t\t|  |  |  |  DROP UNSIGNED REQUEST");
+                            ops.unsigned_request_dropped(label.clone());
+                            return Ok(());
+                        },
+                        RequestCheck::Forged => {
+                            trace!("\t\t|  |  |  |  DROP FORGED REQUEST");
+                            ops.forged_request_dropped(label.clone());
+                            return Ok(());
+                        },
+                        RequestCheck::Replayed => {
+                            trace!("\t\t|  |  |  |  DROP REPLAYED REQUEST");
+                            ops.replayed_request_dropped(label.clone());
+                            return Ok(());
+                        },
+                    }
+                    // a failing store costs a cache miss, not the routing thread
+                    let cached = rs.get(&hbfi).unwrap_or_else(|e| {
+                        error!("broker {} content store lookup for {:?} failed: {}", label, hbfi, e);
+                        ops.content_store_failed(label.clone());
+                        None
+                    });
+                    match cached {
+                        // cached before its identity was revoked
+                        Some(nw) if responses.is_revoked(&nw) => {
+                            trace!("\t\t|  |  |  |  DROP CACHED RESPONSE FROM REVOKED IDENTITY");
+                            ops.revoked_response_dropped(label.clone());
+                            return Ok(());
+                        }
                         Some(nw) => {
                             trace!("\t\t|  |  |  |  RESPONSE PACKET FOUND");
                             ops.found_response_upstream(label.clone());
@@ -42,62 +83,80 @@ impl Router {
                             return Ok(());
                         }
                         None => {
+                            let next_hop = match nw.next_hop() {
+                                Some(next_hop) => ilp.change_narrow_waist(next_hop),
+                                None => {
+                                    trace!("\t\t|  |  |  |  DROP REQUEST OUT OF HOPS");
+                                    ops.hop_limit_exceeded(label.clone());
+                                    return Ok(());
+                                },
+                            };
                             trace!("\t\t|  |  |  |  FORWARD REQUEST UPSTREAM");
                             ops.forward_request_upstream(label.clone());
-                            this_bloom.create_pending_request(hbfi.clone());
+                            this_bloom.create_pending_request(hbfi.clone(), lifetime);
                             let link_weights = bayes.classify(&hbfi.to_bfis());
                             bayes.train(&hbfi.to_bfis(), choke);
                             if link_weights[0].linkid == *choke {
-                                //warn!("{}, {:?}", link_weights[0].weight, link_weights[0].linkid);
-                                let litmus_weight = (link_weights[0].weight * 100.00) as u64;
-                                match litmus_weight {
-                                    0..=35 => {
-                                        warn!("Defcon 4: Do something")
-                                    },
-                                    36..=59 => {
-                                        warn!("Defcon 3: Do something")
-                                        // packets need a nonce and it needs to be signed. So as to avert the
-                                        // scenario whereby an attacker replays requests thus shutting down
-                                        // the flow of legitimate information.
-                                    },
-                                    60..=89 => {
-                                        warn!("Defcon 2: Do something")
-                                    },
-                                    90..=u64::MAX => {
-                                        warn!("Defcon 1: Deep Sixed packet: {:?}", hbfi);
-                                        return Ok(())
-                                    },
+                                let level = Defcon::from_weight(link_weights[0].weight);
+                                if let Some(mitigation) = defcon.mitigate(label, ops, level, &this_link, &nw)? {
+                                    this_bloom.complete_pending_request(hbfi.clone());
+                                    if let Some(reason) = mitigation.nack_reason() {
+                                        Self::nack(label, ops, &this_link, NarrowWaistPacket::nack(hbfi, nonce, reason), &r2b_tx)?;
+                                    }
+                                    return Ok(())
                                 }
                             }
-                            let mut forwarded = false;
-                            for LinkWeight { linkid: that_link, weight} in link_weights {
-                                //warn!("{}, {:?}", weight, that_link);
-                                if that_link == *choke {
+                            let mut candidates: Vec<LinkWeight> = vec![];
+                            for link_weight in link_weights {
+                                let that_link = &link_weight.linkid;
+                                if *that_link == *choke {
                                     continue;
                                 }
                                 if that_link.link_pid()? == this_link.link_pid()? {
                                     continue;
                                 }
+                                match blooms.get_mut(that_link) {
+                                    Some(that_bloom) if !that_bloom.contains_pending_request(hbfi.clone()) => {
+                                        candidates.push(link_weight)
+                                    },
+                                    _ => continue,
+                                }
+                            }
+                            let mut forwarded = false;
+                            for that_link in strategy.select(&hbfi, &this_link, &candidates) {
                                 if let Some(that_bloom) = blooms.get_mut(&that_link) {
-                                    if that_bloom.contains_pending_request(hbfi.clone()) {
-                                        continue;
-                                    }
-                                    if (weight < 0.00) && (forwarded == false) {
-                                        that_bloom.create_forwarded_request(hbfi.clone());
-                                        r2b_tx.send(ilp.change_destination(that_link))?;
-                                        continue;
-                                    }
-                                    that_bloom.create_forwarded_request(hbfi.clone());
-                                    r2b_tx.send(ilp.change_destination(that_link))?;
+                                    that_bloom.create_forwarded_request(hbfi.clone(), lifetime);
+                                    r2b_tx.send(next_hop.change_destination(that_link))?;
                                     forwarded = true;
                                 }
                             }
+                            if !forwarded {
+                                if let Some(this_bloom) = blooms.get_mut(&this_link) {
+                                    this_bloom.complete_pending_request(hbfi.clone());
+                                }
+                                Self::nack(label, ops, &this_link, NarrowWaistPacket::nack(hbfi, nonce, NackReason::NoRoute), &r2b_tx)?;
+                            }
                         }
                     }
                 }
                 NarrowWaistPacket::Response { hbfi, .. } => {
                     if this_bloom.contains_forwarded_request(hbfi.clone()) {
-                        rs.insert(nw);
+                        if responses.is_revoked(&nw) {
+                            trace!("\t\t|  |  |  |  DROP RESPONSE FROM REVOKED IDENTITY");
+                            ops.revoked_response_dropped(label.clone());
+                            return Ok(());
+                        }
+                        // keep waiting on a genuine response rather than caching a forged one
+                        if !responses.verify(&this_link, &nw) {
+                            trace!("\t\t|  |  |  |  DROP UNVERIFIED RESPONSE");
+                            ops.response_verification_failed(label.clone());
+                            return Ok(());
+                        }
+                        this_bloom.complete_forwarded_request(hbfi.clone());
+                        if let Err(e) = rs.insert(nw) {
+                            error!("broker {} content store didn't cache {:?}: {}", label, hbfi, e);
+                            ops.content_store_failed(label.clone());
+                        }
                         bayes.super_train(&hbfi.to_bfis(), &this_link);
                         for (that_link, that_bloom) in blooms.iter_mut() {
                             if that_link.link_pid()? == this_link.link_pid()? {
@@ -107,12 +166,58 @@ impl Router {
                                 trace!("\t\t|  |  |  |  FORWARD RESPONSE DOWNSTREAM");
                                 ops.forward_response_downstream(label.clone());
                                 r2b_tx.send(ilp.change_destination(that_link.clone()))?;
+                                that_bloom.complete_pending_request(hbfi.clone());
                             }
                         }
                     }
                 }
+                NarrowWaistPacket::Nack { hbfi, reason, .. } => {
+                    if !this_bloom.contains_forwarded_request(hbfi.clone()) {
+                        return Ok(())
+                    }
+                    if responses.is_revoked(&nw) {
+                        trace!("\t\t|  |  |  |  DROP NACK FROM REVOKED IDENTITY");
+                        ops.revoked_response_dropped(label.clone());
+                        return Ok(());
+                    }
+                    if !responses.verify(&this_link, &nw) {
+                        trace!("\t\t|  |  |  |  DROP UNVERIFIED NACK");
+                        ops.response_verification_failed(label.clone());
+                        return Ok(());
+                    }
+                    this_bloom.complete_forwarded_request(hbfi.clone());
+                    trace!("\t\t|  |  |  |  NACK {} RECEIVED", reason);
+                    ops.nack_received(label.clone());
+                    match reason {
+                        NackReason::NoRoute | NackReason::NotFound => bayes.negative_train(&hbfi.to_bfis(), &this_link),
+                        NackReason::Congestion | NackReason::RateLimited | NackReason::SignatureRequired => {},
+                    }
+                    // another upstream link may still answer
+                    if blooms.values_mut().any(|that_bloom| that_bloom.contains_forwarded_request(hbfi.clone())) {
+                        return Ok(())
+                    }
+                    for (that_link, that_bloom) in blooms.iter_mut() {
+                        if that_link.link_pid()? == this_link.link_pid()? {
+                            continue;
+                        }
+                        if that_bloom.contains_pending_request(hbfi.clone()) {
+                            trace!("\t\t|  |  |  |  FORWARD NACK DOWNSTREAM");
+                            ops.forward_nack_downstream(label.clone());
+                            r2b_tx.send(ilp.change_destination(that_link.clone()))?;
+                            that_bloom.complete_pending_request(hbfi.clone());
+                        }
+                    }
+                }
             }
         }
         Ok::<(), anyhow::Error>(())
     }
+    // refusals go straight back down the link the request came in on
+    fn nack(label: &String, ops: &Operations, this_link: &LinkId, nack: NarrowWaistPacket, r2b_tx: &SyncSender<InterLinkPacket>) -> Result<()> {
+        trace!("\t\t|  |  |  |  NACK {:?}", nack);
+        ops.nack_sent(label.clone());
+        let lp = LinkPacket::new(this_link.reply_to()?, nack);
+        r2b_tx.send(InterLinkPacket::new(this_link.clone(), lp))?;
+        Ok(())
+    }
 }
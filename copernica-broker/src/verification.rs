@@ -0,0 +1,126 @@
+use {
+    copernica_common::{LinkId, NarrowWaistPacket, PublicIdentityInterface, RevocationList, RevocationRecord, RotationCertificate},
+    anyhow::Result,
+    std::collections::{HashMap, VecDeque},
+    log::{warn},
+};
+/// Which inbound responses a `Broker` checks against their signature before
+/// caching or forwarding them.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum VerificationPolicy {
+    Always,
+    // cyphertext links already verify while decrypting
+    CleartextLinks,
+    Never,
+}
+impl Default for VerificationPolicy {
+    fn default() -> Self {
+        VerificationPolicy::Always
+    }
+}
+/// Verifies responses under a `VerificationPolicy`, remembering recent
+/// results so a response arriving on several links is only checked once,
+/// and counting failures per link. Responses signed by a revoked identity
+/// are refused whatever the policy.
+pub struct ResponseVerifier {
+    policy: VerificationPolicy,
+    capacity: usize,
+    verified: HashMap<NarrowWaistPacket, bool>,
+    order: VecDeque<NarrowWaistPacket>,
+    failures: HashMap<LinkId, u64>,
+    revocations: RevocationList,
+}
+impl ResponseVerifier {
+    pub fn new(policy: VerificationPolicy, capacity: usize) -> Self {
+        Self::new_with_revocations(policy, capacity, RevocationList::new())
+    }
+    pub fn new_with_revocations(policy: VerificationPolicy, capacity: usize, revocations: RevocationList) -> Self {
+        Self { policy, capacity, verified: HashMap::new(), order: VecDeque::new(), failures: HashMap::new(), revocations }
+    }
+    pub fn revoke(&mut self, record: RevocationRecord) -> Result<()> {
+        self.revocations.revoke(record)
+    }
+    pub fn rotate(&mut self, certificate: RotationCertificate) -> Result<()> {
+        self.revocations.rotate(certificate)
+    }
+    // responses and nacks are signed by the HBFI's response_pid
+    pub fn is_revoked(&self, nw: &NarrowWaistPacket) -> bool {
+        match nw {
+            NarrowWaistPacket::Response { hbfi, .. } => self.revocations.is_revoked(&hbfi.response_pid),
+            NarrowWaistPacket::Nack { hbfi, signature: Some(_), .. } => self.revocations.is_revoked(&hbfi.response_pid),
+            NarrowWaistPacket::Nack { signature: None, .. } | NarrowWaistPacket::Request { .. } => false,
+        }
+    }
+    fn applies_to(&self, link_id: &LinkId) -> bool {
+        match self.policy {
+            VerificationPolicy::Always => true,
+            VerificationPolicy::CleartextLinks => matches!(link_id.remote_link_pid(), Ok(PublicIdentityInterface::Absent) | Err(_)),
+            VerificationPolicy::Never => false,
+        }
+    }
+    pub fn verify(&mut self, link_id: &LinkId, nw: &NarrowWaistPacket) -> bool {
+        if !self.applies_to(link_id) {
+            return true
+        }
+        let verified = match self.verified.get(nw) {
+            Some(verified) => *verified,
+            None => {
+                let verified = nw.verify().unwrap_or(false);
+                if self.capacity > 0 {
+                    while self.verified.len() >= self.capacity {
+                        match self.order.pop_front() {
+                            Some(oldest) => { self.verified.remove(&oldest); },
+                            None => break,
+                        }
+                    }
+                    self.verified.insert(nw.clone(), verified);
+                    self.order.push_back(nw.clone());
+                }
+                verified
+            },
+        };
+        if !verified {
+            warn!("signature check failed on {:?} from {:?}", nw, link_id);
+            *self.failures.entry(link_id.clone()).or_insert(0) += 1;
+        }
+        verified
+    }
+    pub fn failures(&self) -> HashMap<LinkId, u64> {
+        self.failures.clone()
+    }
+    pub fn remove_link(&mut self, link_id: &LinkId) {
+        self.failures.remove(link_id);
+    }
+    pub fn replace_link(&mut self, old: &LinkId, new: &LinkId) {
+        if let Some(failures) = self.failures.remove(old) {
+            self.failures.insert(new.clone(), failures);
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use copernica_common::{HBFI, PrivateIdentityInterface, ReplyTo};
+    #[test]
+    fn counts_forged_responses_per_link() {
+        let responder = PrivateIdentityInterface::new_key();
+        let forger = PrivateIdentityInterface::new_key();
+        let link = LinkId::new(0, responder.clone(), PublicIdentityInterface::Absent, ReplyTo::Rf(0));
+        let hbfi = HBFI::new(PublicIdentityInterface::Absent, responder.public_id(), "app", "m0d", "fun", "arg").unwrap();
+        let genuine = NarrowWaistPacket::response(responder, hbfi.clone(), vec![0; 10]).unwrap();
+        let mut forged = NarrowWaistPacket::response(forger.clone(), HBFI { response_pid: forger.public_id(), ..hbfi.clone() }, vec![1; 10]).unwrap();
+        if let NarrowWaistPacket::Response { hbfi: forged_hbfi, .. } = &mut forged {
+            *forged_hbfi = hbfi;
+        }
+        let mut verifier = ResponseVerifier::new(VerificationPolicy::Always, 1);
+        assert!(verifier.verify(&link, &genuine));
+        assert!(!verifier.verify(&link, &forged));
+        assert!(!verifier.verify(&link, &forged));
+        assert_eq!(verifier.failures().get(&link), Some(&2));
+        let mut verifier = ResponseVerifier::new(VerificationPolicy::Never, 1);
+        assert!(verifier.verify(&link, &forged));
+        assert!(!verifier.is_revoked(&genuine));
+        verifier.revoke(RevocationRecord::new(&responder)).unwrap();
+        assert!(verifier.is_revoked(&genuine));
+    }
+}
@@ -0,0 +1,131 @@
+use {
+    crate::{constants::{AES_GCM_NONCE_SIZE, NONCE_SIZE, TAG_SIZE, XNONCE_SIZE}, DecodeError, Tag},
+    anyhow::{anyhow, Result},
+    aes_gcm::Aes256Gcm,
+    chacha20poly1305::{
+        aead::{AeadInPlace, NewAead},
+        Key, XChaCha20Poly1305, XNonce,
+    },
+    cryptoxide::chacha20poly1305::ChaCha20Poly1305,
+    std::convert::TryFrom,
+};
+/// The AEAD constructions link packets and responses can be sealed with.
+/// `ChaCha20Poly1305` takes the original 8 byte nonce, `XChaCha20Poly1305`
+/// a 24 byte one that is safe to pick at random for every packet and
+/// `Aes256Gcm` a 12 byte one, for hardware with AES acceleration or
+/// deployments that require it. Responses only use the ChaCha suites.
+///
+/// The discriminant is the suite's identifier on the wire.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum CipherSuite {
+    ChaCha20Poly1305 = 0,
+    XChaCha20Poly1305 = 1,
+    Aes256Gcm = 2,
+}
+impl Default for CipherSuite {
+    fn default() -> Self {
+        CipherSuite::ChaCha20Poly1305
+    }
+}
+impl TryFrom<u8> for CipherSuite {
+    type Error = DecodeError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(CipherSuite::ChaCha20Poly1305),
+            1 => Ok(CipherSuite::XChaCha20Poly1305),
+            2 => Ok(CipherSuite::Aes256Gcm),
+            value => Err(DecodeError::UnknownKind { what: "CipherSuite", value }),
+        }
+    }
+}
+impl CipherSuite {
+    // every registered suite, in the order a link prefers them by default
+    pub fn all() -> Vec<CipherSuite> {
+        vec![CipherSuite::XChaCha20Poly1305, CipherSuite::ChaCha20Poly1305, CipherSuite::Aes256Gcm]
+    }
+    // the bitmap a link header advertises its accepted suites with
+    pub fn mask(suites: &[CipherSuite]) -> u8 {
+        suites.iter().fold(0, |mask, suite| mask | (1 << *suite as u8))
+    }
+    pub fn in_mask(&self, mask: u8) -> bool {
+        mask & (1 << *self as u8) != 0
+    }
+    pub fn nonce_size(&self) -> usize {
+        match self {
+            CipherSuite::ChaCha20Poly1305 => NONCE_SIZE,
+            CipherSuite::XChaCha20Poly1305 => XNONCE_SIZE,
+            CipherSuite::Aes256Gcm => AES_GCM_NONCE_SIZE,
+        }
+    }
+    pub fn seal(&self, key: &[u8], nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> Result<(Vec<u8>, Tag)> {
+        if nonce.len() != self.nonce_size() {
+            return Err(anyhow!("{:?} needs a {} byte nonce, not {}", self, self.nonce_size(), nonce.len()))
+        }
+        let mut tag = Tag([0; TAG_SIZE]);
+        let mut encrypted = plaintext.to_vec();
+        let sealed = match self {
+            CipherSuite::ChaCha20Poly1305 => {
+                let mut ctx = ChaCha20Poly1305::new(key, nonce, aad);
+                ctx.encrypt(plaintext, &mut encrypted[..], &mut tag.0);
+                return Ok((encrypted, tag))
+            },
+            CipherSuite::XChaCha20Poly1305 => {
+                XChaCha20Poly1305::new(Key::from_slice(key))
+                    .encrypt_in_place_detached(XNonce::from_slice(nonce), aad, &mut encrypted)
+            },
+            CipherSuite::Aes256Gcm => {
+                Aes256Gcm::new(aes_gcm::Key::from_slice(key))
+                    .encrypt_in_place_detached(aes_gcm::Nonce::from_slice(nonce), aad, &mut encrypted)
+            },
+        };
+        let sealed = sealed.map_err(|_| anyhow!("{:?} failed to encrypt", self))?;
+        tag.0.copy_from_slice(sealed.as_slice());
+        Ok((encrypted, tag))
+    }
+    // None when the packet doesn't authenticate
+    pub fn open(&self, key: &[u8], nonce: &[u8], aad: &[u8], encrypted: &[u8], tag: &[u8]) -> Option<Vec<u8>> {
+        if nonce.len() != self.nonce_size() || tag.len() != TAG_SIZE {
+            return None
+        }
+        let mut decrypted = encrypted.to_vec();
+        let opened = match self {
+            CipherSuite::ChaCha20Poly1305 => {
+                let mut ctx = ChaCha20Poly1305::new(key, nonce, aad);
+                return match ctx.decrypt(encrypted, &mut decrypted[..], tag) {
+                    true => Some(decrypted),
+                    false => None,
+                }
+            },
+            CipherSuite::XChaCha20Poly1305 => {
+                XChaCha20Poly1305::new(Key::from_slice(key))
+                    .decrypt_in_place_detached(XNonce::from_slice(nonce), aad, &mut decrypted, chacha20poly1305::Tag::from_slice(tag))
+            },
+            CipherSuite::Aes256Gcm => {
+                Aes256Gcm::new(aes_gcm::Key::from_slice(key))
+                    .decrypt_in_place_detached(aes_gcm::Nonce::from_slice(nonce), aad, &mut decrypted, aes_gcm::Tag::from_slice(tag))
+            },
+        };
+        opened.ok().map(|_| decrypted)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn suites_round_trip_and_reject_tampering() {
+        let key = [7u8; 32];
+        for suite in &CipherSuite::all() {
+            let nonce = vec![1u8; suite.nonce_size()];
+            let (mut encrypted, tag) = suite.seal(&key, &nonce, b"header", b"narrow waist").unwrap();
+            assert_eq!(suite.open(&key, &nonce, b"header", &encrypted, &tag.0), Some(b"narrow waist".to_vec()));
+            assert_eq!(suite.open(&key, &nonce, b"HEADER", &encrypted, &tag.0), None);
+            encrypted[0] ^= 1;
+            assert_eq!(suite.open(&key, &nonce, b"header", &encrypted, &tag.0), None);
+            assert!(suite.seal(&key, &[0u8; 16], b"", b"").is_err());
+            assert_eq!(CipherSuite::try_from(*suite as u8).unwrap(), *suite);
+            assert!(suite.in_mask(CipherSuite::mask(&CipherSuite::all())));
+        }
+        assert!(!CipherSuite::Aes256Gcm.in_mask(CipherSuite::mask(&[CipherSuite::ChaCha20Poly1305])));
+        assert!(CipherSuite::try_from(3).is_err());
+    }
+}
@@ -13,6 +13,8 @@ use {
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub struct Nonce(pub [u8; constants::NONCE_SIZE]);
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct XNonce(pub [u8; constants::XNONCE_SIZE]);
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
 pub struct Tag(pub [u8; constants::TAG_SIZE]);
 // the below is a hack, I don't know how to implement Serialize/Deserialize for struct Data(pub [u8; constants::FRAGMENT_SIZE])
 #[derive(Clone, PartialOrd, Ord, Hash, Debug, Serialize, Deserialize)]
@@ -29,8 +31,11 @@ impl Data {
     }
     pub fn data(&self) -> Result<Vec<u8>> {
         let length_combined = format!("{:02x}{:02x}", self.0[constants::LENGTH_OF_DATA_STARTING_POSITION], self.0[constants::LENGTH_OF_DATA_ENDING_POSITION]);
-        let length = u16::from_str_radix(&length_combined, 16)?;
-        let (data, _) = self.0.split_at(length as usize);
+        let length = u16::from_str_radix(&length_combined, 16)? as usize;
+        if length > constants::LENGTH_OF_DATA_STARTING_POSITION {
+            return Err(anyhow!("Data claims a length of {} which exceeds the {} bytes available", length, constants::LENGTH_OF_DATA_STARTING_POSITION))
+        }
+        let (data, _) = self.0.split_at(length);
         Ok(data.to_vec())
     }
 }
@@ -70,3 +75,12 @@ where
     rng.fill_bytes(&mut nonce.0);
     nonce
 }
+
+pub fn generate_xnonce<R>(rng: &mut R) -> XNonce
+where
+    R: RngCore + CryptoRng,
+{
+    let mut nonce = XNonce([0; constants::XNONCE_SIZE]);
+    rng.fill_bytes(&mut nonce.0);
+    nonce
+}
@@ -8,18 +8,52 @@ pub const DATA_SIZE: usize = FRAGMENT_SIZE-3;
 
 pub const ONE_BYTE: usize = 1;
 pub const TWO_BYTE: usize = 2;
+pub const FOUR_BYTE: usize = 4;
 pub const BLOOM_FILTER_LENGTH: usize = u16::MAX as usize;
 pub const BLOOM_FILTER_INDEX_ELEMENT_LENGTH: usize = 4;
+pub const BLOOM_FALSE_POSITIVE_RATE: f64 = 0.001;
 pub const NONCE_SIZE: usize = 8;
+pub const XNONCE_SIZE: usize = 24;
+pub const AES_GCM_NONCE_SIZE: usize = 12;
 pub const TAG_SIZE: usize = 16;
 pub const ID_SIZE: usize = 32;
 pub const CC_SIZE: usize = 32;
 pub const SIG_SIZE: usize = 64;
+pub const SEED_SIZE: usize = 32;
 pub const BFI_BYTE_SIZE: usize = BLOOM_FILTER_INDEX_ELEMENT_LENGTH * 2;
 pub const BFI_COUNT: usize = 6; // RES, REQ, APP, MOD, FUN, ARG
 pub const U64_SIZE: usize = 8;
 
-pub const CYPHERTEXT_LINK_TX_PK_START: usize = 0;
+pub const PROTOCOL_VERSION: u8 = 8;
+pub const MIN_PROTOCOL_VERSION: u8 = 3;
+// session framing needs a remote that speaks at least this version
+pub const SESSION_PROTOCOL_VERSION: u8 = 5;
+// requests carry a signed flag, timestamp and signature at this version and
+// above, older requests end at the hop limit
+pub const SIGNED_REQUEST_PROTOCOL_VERSION: u8 = 4;
+// link packets at this version and above are sealed with XChaCha20Poly1305
+pub const XNONCE_PROTOCOL_VERSION: u8 = 6;
+// link headers at this version and above name their cipher suite and the suites the sender accepts
+pub const SUITE_PROTOCOL_VERSION: u8 = 7;
+// link nonces at this version and above end in the sender's clock in microseconds
+pub const TIMESTAMP_PROTOCOL_VERSION: u8 = 8;
+
+pub const LINK_HEADER_VERSION_START: usize = 0;
+pub const LINK_HEADER_VERSION_END: usize = LINK_HEADER_VERSION_START + ONE_BYTE;
+pub const LINK_HEADER_LINK_KIND_START: usize = LINK_HEADER_VERSION_END;
+pub const LINK_HEADER_LINK_KIND_END: usize = LINK_HEADER_LINK_KIND_START + ONE_BYTE;
+pub const LINK_HEADER_NARROW_WAIST_KIND_START: usize = LINK_HEADER_LINK_KIND_END;
+pub const LINK_HEADER_NARROW_WAIST_KIND_END: usize = LINK_HEADER_NARROW_WAIST_KIND_START + ONE_BYTE;
+pub const LINK_HEADER_SIZE: usize = LINK_HEADER_NARROW_WAIST_KIND_END;
+pub const LINK_HEADER_SUITE_START: usize = LINK_HEADER_NARROW_WAIST_KIND_END;
+pub const LINK_HEADER_SUITE_END: usize = LINK_HEADER_SUITE_START + ONE_BYTE;
+pub const LINK_HEADER_ACCEPTS_START: usize = LINK_HEADER_SUITE_END;
+pub const LINK_HEADER_ACCEPTS_END: usize = LINK_HEADER_ACCEPTS_START + ONE_BYTE;
+pub const SUITE_LINK_HEADER_SIZE: usize = LINK_HEADER_ACCEPTS_END;
+// the link framing offsets below follow a LINK_HEADER_SIZE header, a
+// SUITE_LINK_HEADER_SIZE header shifts them along
+
+pub const CYPHERTEXT_LINK_TX_PK_START: usize = LINK_HEADER_SIZE;
 pub const CYPHERTEXT_LINK_TX_PK_END: usize = CYPHERTEXT_LINK_TX_PK_START + ID_SIZE + CC_SIZE;
 pub const CYPHERTEXT_LINK_NONCE_START: usize = CYPHERTEXT_LINK_TX_PK_END;
 pub const CYPHERTEXT_LINK_NONCE_END: usize = CYPHERTEXT_LINK_NONCE_START + NONCE_SIZE;
@@ -30,7 +64,27 @@ pub const CYPHERTEXT_LINK_REPLY_TO_SIZE_END: usize = CYPHERTEXT_LINK_REPLY_TO_SI
 pub const CYPHERTEXT_LINK_NARROW_WAIST_SIZE_START: usize = CYPHERTEXT_LINK_REPLY_TO_SIZE_END;
 pub const CYPHERTEXT_LINK_NARROW_WAIST_SIZE_END: usize = CYPHERTEXT_LINK_NARROW_WAIST_SIZE_START + TWO_BYTE;
 
-pub const CLEARTEXT_LINK_TX_PK_START: usize = 0;
+pub const SESSION_LINK_TX_EPHEMERAL_START: usize = LINK_HEADER_SIZE;
+pub const SESSION_LINK_TX_EPHEMERAL_END: usize = SESSION_LINK_TX_EPHEMERAL_START + ID_SIZE + CC_SIZE;
+pub const SESSION_LINK_TX_EPOCH_START: usize = SESSION_LINK_TX_EPHEMERAL_END;
+pub const SESSION_LINK_TX_EPOCH_END: usize = SESSION_LINK_TX_EPOCH_START + FOUR_BYTE;
+pub const SESSION_LINK_RX_EPOCH_START: usize = SESSION_LINK_TX_EPOCH_END;
+pub const SESSION_LINK_RX_EPOCH_END: usize = SESSION_LINK_RX_EPOCH_START + FOUR_BYTE;
+pub const SESSION_LINK_NONCE_START: usize = SESSION_LINK_RX_EPOCH_END;
+pub const SESSION_LINK_NONCE_END: usize = SESSION_LINK_NONCE_START + NONCE_SIZE;
+pub const SESSION_LINK_TAG_START: usize = SESSION_LINK_NONCE_END;
+pub const SESSION_LINK_TAG_END: usize = SESSION_LINK_TAG_START + TAG_SIZE;
+pub const SESSION_LINK_REPLY_TO_SIZE_START: usize = SESSION_LINK_TAG_END;
+pub const SESSION_LINK_REPLY_TO_SIZE_END: usize = SESSION_LINK_REPLY_TO_SIZE_START + ONE_BYTE;
+pub const SESSION_LINK_NARROW_WAIST_SIZE_START: usize = SESSION_LINK_REPLY_TO_SIZE_END;
+pub const SESSION_LINK_NARROW_WAIST_SIZE_END: usize = SESSION_LINK_NARROW_WAIST_SIZE_START + TWO_BYTE;
+pub const LINK_REKEY_INTERVAL_SECS: u64 = 120;
+pub const LINK_REKEY_PACKETS: u64 = 1 << 16;
+pub const LINK_REPLAY_WINDOW_PACKETS: u64 = 64;
+pub const LINK_REPLAY_NONCES: usize = 4096;
+pub const LINK_FRESHNESS_SECS: u64 = 300;
+
+pub const CLEARTEXT_LINK_TX_PK_START: usize = LINK_HEADER_SIZE;
 pub const CLEARTEXT_LINK_TX_PK_END: usize = CLEARTEXT_LINK_TX_PK_START + ID_SIZE + CC_SIZE;
 pub const CLEARTEXT_LINK_REPLY_TO_SIZE_START: usize = CLEARTEXT_LINK_TX_PK_END;
 pub const CLEARTEXT_LINK_REPLY_TO_SIZE_END: usize = CLEARTEXT_LINK_REPLY_TO_SIZE_START + ONE_BYTE;
@@ -47,12 +101,42 @@ pub const TO_REPLY_TO_RF: usize = 4;
 pub const CYPHERTEXT_HBFI_SIZE: usize = ((BLOOM_FILTER_INDEX_ELEMENT_LENGTH * 2) * BFI_COUNT) + U64_SIZE + (ID_SIZE * 2) + (CC_SIZE * 2);
 pub const CYPHERTEXT_RESPONSE_DATA_SIZE: usize = FRAGMENT_SIZE + TAG_SIZE;
 pub const CYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_SIZE: usize = CYPHERTEXT_RESPONSE_DATA_SIZE + CYPHERTEXT_HBFI_SIZE + NONCE_SIZE + SIG_SIZE;
-pub const CYPHERTEXT_NARROW_WAIST_PACKET_REQUEST_SIZE: usize = CYPHERTEXT_HBFI_SIZE + NONCE_SIZE;
+pub const XCYPHERTEXT_RESPONSE_DATA_SIZE: usize = XNONCE_SIZE + FRAGMENT_SIZE + TAG_SIZE;
+pub const XCYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_SIZE: usize = XCYPHERTEXT_RESPONSE_DATA_SIZE + CYPHERTEXT_HBFI_SIZE + NONCE_SIZE + SIG_SIZE;
+pub const CYPHERTEXT_NARROW_WAIST_PACKET_REQUEST_SIZE: usize = CYPHERTEXT_HBFI_SIZE + NARROW_WAIST_PACKET_REQUEST_SIG_END;
+pub const CYPHERTEXT_NARROW_WAIST_PACKET_UNSIGNED_REQUEST_SIZE: usize = CYPHERTEXT_HBFI_SIZE + NARROW_WAIST_PACKET_REQUEST_HOP_LIMIT_END;
+pub const CYPHERTEXT_NARROW_WAIST_PACKET_NACK_SIZE: usize = CYPHERTEXT_HBFI_SIZE + NARROW_WAIST_PACKET_NACK_SIG_END;
 
 pub const CLEARTEXT_HBFI_SIZE: usize = ((BLOOM_FILTER_INDEX_ELEMENT_LENGTH * 2) * BFI_COUNT) + U64_SIZE + ID_SIZE + CC_SIZE;
 pub const CLEARTEXT_RESPONSE_DATA_SIZE: usize = FRAGMENT_SIZE;
 pub const CLEARTEXT_NARROW_WAIST_PACKET_RESPONSE_SIZE: usize = CLEARTEXT_RESPONSE_DATA_SIZE + CLEARTEXT_HBFI_SIZE + NONCE_SIZE + SIG_SIZE;
-pub const CLEARTEXT_NARROW_WAIST_PACKET_REQUEST_SIZE: usize = CLEARTEXT_HBFI_SIZE + NONCE_SIZE;
+pub const CLEARTEXT_NARROW_WAIST_PACKET_REQUEST_SIZE: usize = CLEARTEXT_HBFI_SIZE + NARROW_WAIST_PACKET_REQUEST_SIG_END;
+pub const CLEARTEXT_NARROW_WAIST_PACKET_UNSIGNED_REQUEST_SIZE: usize = CLEARTEXT_HBFI_SIZE + NARROW_WAIST_PACKET_REQUEST_HOP_LIMIT_END;
+pub const CLEARTEXT_NARROW_WAIST_PACKET_NACK_SIZE: usize = CLEARTEXT_HBFI_SIZE + NARROW_WAIST_PACKET_NACK_SIG_END;
+
+pub const REQUEST_LIFETIME_SIZE: usize = TWO_BYTE;
+pub const REQUEST_HOP_LIMIT_SIZE: usize = ONE_BYTE;
+pub const NARROW_WAIST_PACKET_REQUEST_NONCE_START: usize = 0;
+pub const NARROW_WAIST_PACKET_REQUEST_NONCE_END: usize = NARROW_WAIST_PACKET_REQUEST_NONCE_START + NONCE_SIZE;
+pub const NARROW_WAIST_PACKET_REQUEST_LIFETIME_START: usize = NARROW_WAIST_PACKET_REQUEST_NONCE_END;
+pub const NARROW_WAIST_PACKET_REQUEST_LIFETIME_END: usize = NARROW_WAIST_PACKET_REQUEST_LIFETIME_START + REQUEST_LIFETIME_SIZE;
+pub const NARROW_WAIST_PACKET_REQUEST_HOP_LIMIT_START: usize = NARROW_WAIST_PACKET_REQUEST_LIFETIME_END;
+pub const NARROW_WAIST_PACKET_REQUEST_HOP_LIMIT_END: usize = NARROW_WAIST_PACKET_REQUEST_HOP_LIMIT_START + REQUEST_HOP_LIMIT_SIZE;
+pub const NARROW_WAIST_PACKET_REQUEST_SIGNED_START: usize = NARROW_WAIST_PACKET_REQUEST_HOP_LIMIT_END;
+pub const NARROW_WAIST_PACKET_REQUEST_SIGNED_END: usize = NARROW_WAIST_PACKET_REQUEST_SIGNED_START + ONE_BYTE;
+pub const NARROW_WAIST_PACKET_REQUEST_TIMESTAMP_START: usize = NARROW_WAIST_PACKET_REQUEST_SIGNED_END;
+pub const NARROW_WAIST_PACKET_REQUEST_TIMESTAMP_END: usize = NARROW_WAIST_PACKET_REQUEST_TIMESTAMP_START + U64_SIZE;
+pub const NARROW_WAIST_PACKET_REQUEST_SIG_START: usize = NARROW_WAIST_PACKET_REQUEST_TIMESTAMP_END;
+pub const NARROW_WAIST_PACKET_REQUEST_SIG_END: usize = NARROW_WAIST_PACKET_REQUEST_SIG_START + SIG_SIZE;
+
+pub const NARROW_WAIST_PACKET_NACK_NONCE_START: usize = 0;
+pub const NARROW_WAIST_PACKET_NACK_NONCE_END: usize = NARROW_WAIST_PACKET_NACK_NONCE_START + NONCE_SIZE;
+pub const NARROW_WAIST_PACKET_NACK_REASON_START: usize = NARROW_WAIST_PACKET_NACK_NONCE_END;
+pub const NARROW_WAIST_PACKET_NACK_REASON_END: usize = NARROW_WAIST_PACKET_NACK_REASON_START + ONE_BYTE;
+pub const NARROW_WAIST_PACKET_NACK_SIGNED_START: usize = NARROW_WAIST_PACKET_NACK_REASON_END;
+pub const NARROW_WAIST_PACKET_NACK_SIGNED_END: usize = NARROW_WAIST_PACKET_NACK_SIGNED_START + ONE_BYTE;
+pub const NARROW_WAIST_PACKET_NACK_SIG_START: usize = NARROW_WAIST_PACKET_NACK_SIGNED_END;
+pub const NARROW_WAIST_PACKET_NACK_SIG_END: usize = NARROW_WAIST_PACKET_NACK_SIG_START + SIG_SIZE;
 
 pub const CYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_SIG_START: usize = 0;
 pub const CYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_SIG_END: usize = CYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_SIG_START + SIG_SIZE;
@@ -78,3 +162,14 @@ pub const CONGESTION_CONTROL_SIZE: usize = 100;
 pub const LOG_SESSION_START: &str = "Log Session Start";
 pub const LOG_ERASE: &str = "Erase Log";
 pub const LINE_TO_INCH: f32 = 0.0833; // graphviz uses inches ffs
+pub const SHUTDOWN_POLL_INTERVAL_MS: u64 = 100;
+pub const REQUEST_LIFETIME_SECS: u16 = 4;
+pub const MAX_REQUEST_LIFETIME_SECS: u16 = 60;
+pub const REQUEST_HOP_LIMIT: u8 = 32;
+pub const REQUEST_REPLAY_WINDOW_SECS: u64 = 30;
+pub const REQUEST_REPLAY_NONCES: usize = 4096;
+pub const NONCE_CACHE_SIZE: usize = 4096;
+pub const VERIFICATION_CACHE_SIZE: usize = 1024;
+pub const REQUEST_SWEEP_INTERVAL_MS: u64 = 500;
+pub const BAYES_SNAPSHOT_INTERVAL_SECS: u64 = 60;
+pub const BAYES_HALF_LIFE_SECS: u64 = 60 * 60;
@@ -0,0 +1,52 @@
+use {
+    crate::CipherSuite,
+    thiserror::Error,
+};
+/// Every way an inbound packet can fail to decode. Decoders return this
+/// instead of panicking so a link can count the failure and drop the packet.
+#[derive(Debug, Error)]
+pub enum DecodeError {
+    #[error("Truncated {what}: needed {needed} bytes but only {available} are available")]
+    Truncated { what: &'static str, needed: usize, available: usize },
+
+    #[error("Bad length for {what}: expected {expected} bytes but found {found}")]
+    BadLength { what: &'static str, expected: usize, found: usize },
+
+    #[error("Bad reply_to of {size} bytes")]
+    BadReplyTo { size: usize },
+
+    #[error("Unsupported protocol version {version}, supported versions are {min} to {max}")]
+    UnsupportedVersion { version: u8, min: u8, max: u8 },
+
+    #[error("Unknown {what} discriminant {value}")]
+    UnknownKind { what: &'static str, value: u8 },
+
+    #[error("A {link_kind} link packet cannot be received on a link whose remote identity is {remote}")]
+    LinkKindMismatch { link_kind: String, remote: String },
+
+    #[error("Reed-Solomon could not correct the packet")]
+    RsUncorrectable,
+
+    #[error("Authentication failed: {0}")]
+    AuthFailure(&'static str),
+
+    #[error("The remote link key changed from the pinned {pinned} to {presented}")]
+    PinMismatch { pinned: String, presented: String },
+
+    #[error("Cleartext link packet refused, the remote link key {pinned} is pinned so only cyphertext is accepted")]
+    PinnedCleartext { pinned: String },
+
+    #[error("Replayed {0} link packet")]
+    Replayed(&'static str),
+
+    #[error("The {0:?} cipher suite is not allowed on this link")]
+    SuiteRefused(CipherSuite),
+
+    #[error("Invalid link: {0}")]
+    InvalidLink(String),
+}
+impl DecodeError {
+    pub fn invalid_link(error: anyhow::Error) -> Self {
+        DecodeError::InvalidLink(error.to_string())
+    }
+}
@@ -8,8 +8,11 @@ use {
         },
         Seed,
     },
-    crate::{ Nonce, bloom_filter_index, BFI, constants },
+    crate::{ Nonce, bloom_filter_index, BFI, constants, KeyStore, ChildCertificate },
+    bip39::Mnemonic,
+    cryptoxide::{blake2b::Blake2b, digest::Digest as _},
     anyhow::{Result, anyhow},
+    rand::RngCore as _,
     serde::{Deserialize, Serialize},
     std::{
         convert::{TryFrom, TryInto as _},
@@ -17,17 +20,43 @@ use {
         str::FromStr,
     },
     thiserror::Error,
+    zeroize::{Zeroize, Zeroizing},
 };
 const SIGNING_PATH_V1: &[u8] = b"/copernica/v1/signing";
 const EXCHANGE_PATH_ROOT_V1: &[u8] = b"/copernica/v1/exchange";
+const CHILD_PATH_ROOT_V1: &[u8] = b"/copernica/v1/child/";
 
 /// private identity, to keep close to you, privately and securely
 ///
 /// From this root key multiple key may be generated depending on
 /// the needs and protocols.
 ///
+/// Derivation, every step deterministic:
+///
+/// * the 32 byte seed, also the entropy of the 24 word BIP39 mnemonic
+///   (the BIP39 passphrase and PBKDF2 stretch are not used),
+/// * seeds a ChaCha20 rng which generates the `ed25519_hd` root key,
+/// * the signing key is derived from the root at `/copernica/v1/signing`,
+/// * exchange keys are derived at `/copernica/v1/exchange` followed by
+///   the purpose, the packet nonce,
+/// * a child identity is seeded from the Blake2b hash of
+///   `/copernica/v1/child/`, the parent's seed and the child's name, and
+///   derives its own keys the same way. This is hardened: nothing public
+///   links a child to its parent, a `ChildCertificate` does that.
+///
+/// The same seed therefore always yields the same `PublicIdentity`.
+///
 #[derive(Debug, Eq, PartialEq, Hash, Clone)]
-struct PrivateIdentity(ed25519_hd::SecretKey);
+struct PrivateIdentity {
+    seed: SecretSeed,
+    // false for a child, whose seed is derived rather than backed up
+    root: bool,
+    key: ed25519_hd::SecretKey,
+}
+
+/// The bytes a `PrivateIdentity` is generated from, wiped when dropped.
+#[derive(Eq, PartialEq, Hash, Clone)]
+struct SecretSeed([u8; constants::SEED_SIZE]);
 
 /// Public identity
 ///
@@ -112,19 +141,73 @@ struct SecretKey(ed25519_extended::SecretKey);
 #[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Hash, Clone)]
 pub struct PublicKey(ed25519::PublicKey);
 
+impl SecretSeed {
+    fn generate() -> Self {
+        let mut seed = [0u8; constants::SEED_SIZE];
+        rand::thread_rng().fill_bytes(&mut seed);
+        Self(seed)
+    }
+
+    fn from_slice(seed: &[u8]) -> Result<Self> {
+        if seed.len() != constants::SEED_SIZE {
+            return Err(anyhow!("a seed is {} bytes, not {}", constants::SEED_SIZE, seed.len()))
+        }
+        let mut bytes = [0u8; constants::SEED_SIZE];
+        bytes.copy_from_slice(seed);
+        Ok(Self(bytes))
+    }
+}
+
+fn child_seed(parent: &SecretSeed, name: &str) -> SecretSeed {
+    let mut seed = [0u8; constants::SEED_SIZE];
+    let mut b = Blake2b::new(constants::SEED_SIZE);
+    b.input(CHILD_PATH_ROOT_V1);
+    b.input(&parent.0);
+    b.input(name.as_bytes());
+    b.result(&mut seed);
+    let child = SecretSeed(seed);
+    seed.zeroize();
+    child
+}
+
+fn mnemonic_to_seed(mnemonic: &str) -> Result<Zeroizing<Vec<u8>>> {
+    let mnemonic = Mnemonic::parse(mnemonic).map_err(|e| anyhow!("Not a valid mnemonic: {}", e))?;
+    let seed = Zeroizing::new(mnemonic.to_entropy());
+    if seed.len() != constants::SEED_SIZE {
+        return Err(anyhow!("A mnemonic for an identity has 24 words, not {}", mnemonic.word_count()))
+    }
+    Ok(seed)
+}
+
+impl Drop for SecretSeed {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl fmt::Debug for SecretSeed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretSeed(..)")
+    }
+}
+
 impl PrivateIdentity {
-    /// build a private identity from the given `Seed`
+    /// build a private identity from the given seed
     ///
-    pub fn from_seed(seed: Seed) -> Self {
-        let mut rng = seed.into_rand_chacha();
-        Self(ed25519_hd::SecretKey::new(&mut rng))
+    fn from_seed(seed: SecretSeed) -> Self {
+        let mut rng = Seed::from(seed.0).into_rand_chacha();
+        let key = ed25519_hd::SecretKey::new(&mut rng);
+        Self { seed, root: true, key }
+    }
+    fn derive_child(&self, name: &str) -> Self {
+        Self { root: false, ..Self::from_seed(child_seed(&self.seed, name)) }
     }
     pub fn public_id(&self) -> PublicIdentity {
-        PublicIdentity(self.0.public_key())
+        PublicIdentity(self.key.public_key())
     }
 
     pub fn signing_key(&self) -> PrivateSigningKey {
-        PrivateSigningKey(self.0.derive(SIGNING_PATH_V1).into_key())
+        PrivateSigningKey(self.key.derive(SIGNING_PATH_V1).into_key())
     }
 
     pub fn derive<P>(&self, purpose: P) -> SecretKey
@@ -133,7 +216,7 @@ impl PrivateIdentity {
     {
         let mut path = EXCHANGE_PATH_ROOT_V1.to_vec();
         path.extend_from_slice(purpose.as_ref());
-        SecretKey(self.0.derive(path).into_key())
+        SecretKey(self.key.derive(path).into_key())
     }
 }
 
@@ -158,6 +241,12 @@ impl PublicIdentity {
         PublicKey(self.0.derive(path).unwrap().into_key())
     }
 
+    /// Whether `certificate` shows `parent` derived this identity as its
+    /// child under `name`.
+    pub fn is_child(&self, parent: &PublicIdentity, name: &str, certificate: &ChildCertificate) -> bool {
+        certificate.child == *self && certificate.parent == *parent && certificate.name == name && certificate.verify()
+    }
+
     pub fn key(&self) -> &ed25519_extended::PublicKey {
         self.0.key()
     }
@@ -362,20 +451,78 @@ pub struct PrivateIdentityInterface {
 }
 impl PrivateIdentityInterface {
     pub fn new_key() -> Self {
-        let mut rng = rand::thread_rng();
-        let key = PrivateIdentity::from_seed(Seed::generate(&mut rng));
+        let key = PrivateIdentity::from_seed(SecretSeed::generate());
         Self { inner: PrivateIdentityState::Key { key } }
     }
-    pub fn new_fs() -> Self {
-        let mut rng = rand::thread_rng();
-        let key = PrivateIdentity::from_seed(Seed::generate(&mut rng));
-        Self { inner: PrivateIdentityState::FileSystem { key } }
+    /// Loads the identity kept in `store`, generating and saving one on first use.
+    pub fn new_fs(store: &KeyStore) -> Result<Self> {
+        let seed = match store.load()? {
+            Some(seed) => SecretSeed::from_slice(&seed[..])?,
+            None => {
+                let seed = SecretSeed::generate();
+                store.save(&seed.0)?;
+                seed
+            },
+        };
+        let key = PrivateIdentity::from_seed(seed);
+        Ok(Self { inner: PrivateIdentityState::FileSystem { key } })
     }
     pub fn new_sentinel() -> Self  {
-        let mut rng = rand::thread_rng();
-        let key = PrivateIdentity::from_seed(Seed::generate(&mut rng));
+        let key = PrivateIdentity::from_seed(SecretSeed::generate());
         Self { inner: PrivateIdentityState::SentinelOne { key } }
     }
+    /// Writes an exported seed into `store`, replacing whatever identity it held.
+    pub fn import_seed(store: &KeyStore, seed: &[u8]) -> Result<Self> {
+        let seed = SecretSeed::from_slice(seed)?;
+        store.save(&seed.0)?;
+        let key = PrivateIdentity::from_seed(seed);
+        Ok(Self { inner: PrivateIdentityState::FileSystem { key } })
+    }
+    /// Rebuilds the identity a seed exported with `export_seed` came from.
+    pub fn from_seed(seed: &[u8]) -> Result<Self> {
+        let key = PrivateIdentity::from_seed(SecretSeed::from_slice(seed)?);
+        Ok(Self { inner: PrivateIdentityState::Key { key } })
+    }
+    pub fn from_mnemonic(mnemonic: &str) -> Result<Self> {
+        let seed = mnemonic_to_seed(mnemonic)?;
+        Self::from_seed(&seed[..])
+    }
+    pub fn import_mnemonic(store: &KeyStore, mnemonic: &str) -> Result<Self> {
+        let seed = mnemonic_to_seed(mnemonic)?;
+        Self::import_seed(store, &seed[..])
+    }
+    /// The seed as 24 English BIP39 words, for writing down.
+    pub fn export_mnemonic(&self) -> Result<Zeroizing<String>> {
+        let seed = self.export_seed()?;
+        let mnemonic = Mnemonic::from_entropy(&seed[..]).map_err(|e| anyhow!("{}", e))?;
+        Ok(Zeroizing::new(mnemonic.to_string()))
+    }
+    pub fn export_seed(&self) -> Result<Zeroizing<[u8; constants::SEED_SIZE]>> {
+        let key = self.key();
+        match key.root {
+            true => Ok(Zeroizing::new(key.seed.0)),
+            false => Err(anyhow!("A child identity has no seed of its own, back up its parent instead")),
+        }
+    }
+    /// A child identity for one application or device. Derivation is
+    /// hardened, so a leaked child exposes neither its parent nor its
+    /// siblings, and only a `ChildCertificate` from the parent shows whose
+    /// child it is.
+    pub fn derive_child(&self, name: &str) -> Self {
+        let inner = match &self.inner {
+            PrivateIdentityState::Key { key } => PrivateIdentityState::Key { key: key.derive_child(name) },
+            PrivateIdentityState::FileSystem { key } => PrivateIdentityState::FileSystem { key: key.derive_child(name) },
+            PrivateIdentityState::SentinelOne { key } => PrivateIdentityState::SentinelOne { key: key.derive_child(name) },
+        };
+        Self { inner }
+    }
+    fn key(&self) -> &PrivateIdentity {
+        match &self.inner {
+            PrivateIdentityState::Key { key } => key,
+            PrivateIdentityState::FileSystem { key } => key,
+            PrivateIdentityState::SentinelOne { key } => key,
+        }
+    }
     pub fn public_id(&self) -> PublicIdentity {
         match &self.inner {
             PrivateIdentityState::Key { key } => {
@@ -424,5 +571,45 @@ impl PrivateIdentityInterface {
     }
 }
 
-
-
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn mnemonic_restores_the_same_identity() {
+        let sid = PrivateIdentityInterface::new_key();
+        let mnemonic = sid.export_mnemonic().unwrap();
+        assert_eq!(mnemonic.split_whitespace().count(), 24);
+        let restored = PrivateIdentityInterface::from_mnemonic(&mnemonic).unwrap();
+        assert_eq!(sid.public_id(), restored.public_id());
+        let from_seed = PrivateIdentityInterface::from_seed(&sid.export_seed().unwrap()[..]).unwrap();
+        assert_eq!(sid.public_id(), from_seed.public_id());
+        let bad_checksum = ["abandon"; 24].join(" ");
+        assert!(PrivateIdentityInterface::from_mnemonic(&bad_checksum).is_err());
+        assert!(PrivateIdentityInterface::from_mnemonic("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about").is_err());
+        assert!(PrivateIdentityInterface::from_seed(&[0; 16]).is_err());
+    }
+    #[test]
+    fn children_are_verifiable_against_their_parent() {
+        let root = PrivateIdentityInterface::new_key();
+        let other = PrivateIdentityInterface::new_key();
+        let child = root.derive_child("echo");
+        assert_eq!(child.public_id(), root.derive_child("echo").public_id());
+        assert_ne!(child.public_id(), root.derive_child("ftp").public_id());
+        let certificate = ChildCertificate::new(&root, "echo");
+        let certificate = ChildCertificate::try_from(&certificate.to_bytes()[..]).unwrap();
+        assert!(child.public_id().is_child(&root.public_id(), "echo", &certificate));
+        assert!(!child.public_id().is_child(&root.public_id(), "ftp", &certificate));
+        assert!(!child.public_id().is_child(&other.public_id(), "echo", &certificate));
+        // a certificate naming the right parent must also be signed by it
+        let forged = ChildCertificate { parent: root.public_id(), ..ChildCertificate::new(&other, "echo") };
+        assert!(!forged.verify());
+        let forged = ChildCertificate { child: child.public_id(), ..ChildCertificate::new(&other, "echo") };
+        assert!(!child.public_id().is_child(&other.public_id(), "echo", &forged));
+        // and the grandchildren of a child are its own
+        let grandchild = child.derive_child("ping");
+        assert!(grandchild.public_id().is_child(&child.public_id(), "ping", &ChildCertificate::new(&child, "ping")));
+        let signature = child.signing_key().sign(b"hello");
+        assert!(child.public_id().verify_key().unwrap().verify(&signature, b"hello"));
+        assert!(child.export_seed().is_err());
+    }
+}
@@ -23,6 +23,9 @@ impl InterLinkPacket {
     pub fn change_destination(&self, link_id: LinkId) -> Self {
         Self { link_id, lp: self.lp.clone() }
     }
+    pub fn change_narrow_waist(&self, nw: NarrowWaistPacket) -> Self {
+        Self { link_id: self.link_id.clone(), lp: LinkPacket::new(self.lp.reply_to(), nw) }
+    }
     pub fn reply_to(&self) -> Result<ReplyTo> {
         self.link_id.reply_to()
     }
@@ -0,0 +1,205 @@
+use {
+    crate::{
+        constants::{NONCE_SIZE, SEED_SIZE, TAG_SIZE},
+        generate_nonce,
+    },
+    anyhow::{anyhow, Result},
+    cryptoxide::chacha20poly1305::ChaCha20Poly1305,
+    rand::RngCore as _,
+    std::{
+        fs::{self, OpenOptions},
+        io::Write,
+        path::{Path, PathBuf},
+    },
+    zeroize::Zeroizing,
+    log::debug,
+};
+const KEY_FILE: &str = "identity.key";
+const MAGIC: &[u8; 4] = b"cpks";
+const VERSION: u8 = 1;
+const SALT_SIZE: usize = 16;
+// magic, version, log_n: u8, r: u32, p: u32, salt, nonce
+const HEADER_SIZE: usize = 4 + 1 + 1 + 4 + 4 + SALT_SIZE + NONCE_SIZE;
+const FILE_SIZE: usize = HEADER_SIZE + TAG_SIZE + SEED_SIZE;
+pub const DEFAULT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+// the parameters are read back from the file, these bounds stop a corrupt
+// or hostile file from asking for gigabytes of memory
+const MAX_LOG_N: u8 = 22;
+const MAX_R: u32 = 32;
+const MAX_P: u32 = 16;
+/// A directory holding one `PrivateIdentity` seed, encrypted under a passphrase.
+/// The key is stretched with scrypt and the seed sealed with ChaCha20Poly1305,
+/// the header is authenticated as associated data. On unix the directory must
+/// not be writable and the file not readable by anyone but the owner.
+pub struct KeyStore {
+    dir: PathBuf,
+    passphrase: Zeroizing<String>,
+    log_n: u8,
+}
+impl KeyStore {
+    pub fn new<P: AsRef<Path>>(dir: P, passphrase: &str) -> Result<Self> {
+        Self::new_with_work_factor(dir, passphrase, DEFAULT_LOG_N)
+    }
+    pub fn new_with_work_factor<P: AsRef<Path>>(dir: P, passphrase: &str, log_n: u8) -> Result<Self> {
+        if passphrase.is_empty() {
+            return Err(anyhow!("a key store needs a non-empty passphrase"))
+        }
+        if log_n == 0 || log_n > MAX_LOG_N {
+            return Err(anyhow!("scrypt work factor {} must be between 1 and {}", log_n, MAX_LOG_N))
+        }
+        Ok(Self { dir: dir.as_ref().to_path_buf(), passphrase: Zeroizing::new(passphrase.to_string()), log_n })
+    }
+    pub fn path(&self) -> PathBuf {
+        self.dir.join(KEY_FILE)
+    }
+    pub fn exists(&self) -> bool {
+        self.path().exists()
+    }
+    fn derive_key(&self, salt: &[u8], log_n: u8, r: u32, p: u32) -> Result<Zeroizing<[u8; 32]>> {
+        if log_n > MAX_LOG_N || r > MAX_R || p > MAX_P {
+            return Err(anyhow!("scrypt parameters log_n {} r {} p {} in {:?} are out of bounds", log_n, r, p, self.path()))
+        }
+        let params = scrypt::Params::new(log_n, r, p).map_err(|e| anyhow!("{}", e))?;
+        let mut key = Zeroizing::new([0u8; 32]);
+        scrypt::scrypt(self.passphrase.as_bytes(), salt, &params, &mut key[..]).map_err(|e| anyhow!("{}", e))?;
+        Ok(key)
+    }
+    // None when no key file has been written yet
+    pub fn load(&self) -> Result<Option<Zeroizing<[u8; SEED_SIZE]>>> {
+        let path = self.path();
+        if !path.exists() {
+            return Ok(None)
+        }
+        check_permissions(&self.dir, &path)?;
+        let data = Zeroizing::new(fs::read(&path)?);
+        if data.len() != FILE_SIZE {
+            return Err(anyhow!("key file {:?} is {} bytes, expected {}", path, data.len(), FILE_SIZE))
+        }
+        if &data[0..4] != MAGIC {
+            return Err(anyhow!("{:?} is not a copernica key file", path))
+        }
+        if data[4] != VERSION {
+            return Err(anyhow!("key file {:?} has unsupported version {}", path, data[4]))
+        }
+        let log_n = data[5];
+        let r = u32::from_be_bytes([data[6], data[7], data[8], data[9]]);
+        let p = u32::from_be_bytes([data[10], data[11], data[12], data[13]]);
+        let salt = &data[14..14 + SALT_SIZE];
+        let nonce = &data[14 + SALT_SIZE..HEADER_SIZE];
+        let tag = &data[HEADER_SIZE..HEADER_SIZE + TAG_SIZE];
+        let encrypted = &data[HEADER_SIZE + TAG_SIZE..];
+        let key = self.derive_key(salt, log_n, r, p)?;
+        let mut ctx = ChaCha20Poly1305::new(&key[..], nonce, &data[..HEADER_SIZE]);
+        let mut seed = Zeroizing::new([0u8; SEED_SIZE]);
+        if !ctx.decrypt(encrypted, &mut seed[..], tag) {
+            return Err(anyhow!("couldn't decrypt {:?}, the passphrase is wrong or the file is corrupt", path))
+        }
+        debug!("loaded identity seed from {:?}", path);
+        Ok(Some(seed))
+    }
+    // written to a temporary file first so a crash never leaves a torn key behind
+    pub fn save(&self, seed: &[u8; SEED_SIZE]) -> Result<()> {
+        create_dir(&self.dir)?;
+        let mut rng = rand::thread_rng();
+        let mut salt = [0u8; SALT_SIZE];
+        rng.fill_bytes(&mut salt);
+        let nonce = generate_nonce(&mut rng);
+        let mut data: Vec<u8> = Vec::with_capacity(FILE_SIZE);
+        data.extend_from_slice(MAGIC);
+        data.push(VERSION);
+        data.push(self.log_n);
+        data.extend_from_slice(&SCRYPT_R.to_be_bytes());
+        data.extend_from_slice(&SCRYPT_P.to_be_bytes());
+        data.extend_from_slice(&salt);
+        data.extend_from_slice(&nonce.0);
+        let key = self.derive_key(&salt, self.log_n, SCRYPT_R, SCRYPT_P)?;
+        let mut ctx = ChaCha20Poly1305::new(&key[..], &nonce.0, &data[..]);
+        let mut tag = [0u8; TAG_SIZE];
+        let mut encrypted = [0u8; SEED_SIZE];
+        ctx.encrypt(&seed[..], &mut encrypted[..], &mut tag);
+        data.extend_from_slice(&tag);
+        data.extend_from_slice(&encrypted);
+        let path = self.path();
+        let tmp = path.with_extension("tmp");
+        if tmp.exists() {
+            fs::remove_file(&tmp)?;
+        }
+        let mut file = open_private(&tmp)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        debug!("saved identity seed to {:?}", path);
+        Ok(())
+    }
+}
+#[cfg(unix)]
+fn create_dir(dir: &Path) -> Result<()> {
+    use std::os::unix::fs::DirBuilderExt as _;
+    if !dir.exists() {
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    }
+    Ok(())
+}
+#[cfg(not(unix))]
+fn create_dir(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    Ok(())
+}
+#[cfg(unix)]
+fn open_private(path: &Path) -> Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt as _;
+    Ok(OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?)
+}
+#[cfg(not(unix))]
+fn open_private(path: &Path) -> Result<fs::File> {
+    Ok(OpenOptions::new().write(true).create_new(true).open(path)?)
+}
+#[cfg(unix)]
+fn check_permissions(dir: &Path, path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt as _;
+    let mode = fs::metadata(path)?.permissions().mode() & 0o777;
+    if mode & 0o077 != 0 {
+        return Err(anyhow!("key file {:?} is accessible by other users (mode {:o}), it must be 600", path, mode))
+    }
+    let mode = fs::metadata(dir)?.permissions().mode() & 0o777;
+    if mode & 0o022 != 0 {
+        return Err(anyhow!("key directory {:?} is writable by other users (mode {:o})", dir, mode))
+    }
+    Ok(())
+}
+#[cfg(not(unix))]
+fn check_permissions(_dir: &Path, _path: &Path) -> Result<()> {
+    Ok(())
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PrivateIdentityInterface;
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("copernica-{}-{}", name, rand::random::<u64>()))
+    }
+    #[test]
+    fn identity_survives_restart() {
+        let dir = temp_dir("keystore");
+        let store = KeyStore::new_with_work_factor(&dir, "hunter2", 4).unwrap();
+        let sid = PrivateIdentityInterface::new_fs(&store).unwrap();
+        assert!(store.exists());
+        let reopened = PrivateIdentityInterface::new_fs(&store).unwrap();
+        assert_eq!(sid.public_id(), reopened.public_id());
+        let wrong = KeyStore::new_with_work_factor(&dir, "hunter3", 4).unwrap();
+        assert!(PrivateIdentityInterface::new_fs(&wrong).is_err());
+        let elsewhere = KeyStore::new_with_work_factor(temp_dir("keystore-import"), "other", 4).unwrap();
+        let imported = PrivateIdentityInterface::import_seed(&elsewhere, &sid.export_seed().unwrap()[..]).unwrap();
+        assert_eq!(sid.public_id(), imported.public_id());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt as _;
+            fs::set_permissions(store.path(), fs::Permissions::from_mode(0o644)).unwrap();
+            assert!(PrivateIdentityInterface::new_fs(&store).is_err());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&elsewhere.dir).unwrap();
+    }
+}
@@ -1,26 +1,44 @@
 mod link;
 mod hbfi;
 mod link_packet;
+mod link_header;
+mod decode_error;
 mod common;
 mod response_data;
 mod narrow_waist_packet;
 mod inter_link_packet;
 mod operations;
+mod node_handle;
 pub mod constants;
 pub mod log;
 pub mod serialization;
 mod identity;
+mod keystore;
+mod revocation;
+mod session;
+mod link_replay;
+mod cipher_suite;
+mod request_policy;
 pub use crate::{
     hbfi::{HBFIExcludeFrame, HBFI, BFI, BFIS,bloom_filter_index},
     link::{LinkId, ReplyTo},
-    common::{Data, Nonce, Tag, generate_nonce, manifest},
+    common::{Data, Nonce, XNonce, Tag, generate_nonce, generate_xnonce, manifest},
     operations::{Operations, LogEntry},
+    node_handle::{NodeHandle, Shutdown, ShutdownError},
     response_data::{ResponseData},
     link_packet::{LinkPacket},
+    link_header::{LinkHeader, LinkPacketKind, NarrowWaistPacketKind},
+    decode_error::{DecodeError},
     inter_link_packet::{InterLinkPacket},
-    narrow_waist_packet::{NarrowWaistPacket, NarrowWaistPacketReqEqRes},
+    narrow_waist_packet::{NarrowWaistPacket, NarrowWaistPacketReqEqRes, NackReason, RequestSignature},
+    request_policy::{RequestPolicy, RequestVerifier, RequestCheck, ReplayWindow, unix_time},
     log::setup_logging,
     identity::{PublicIdentity, PrivateIdentityInterface, PublicIdentityInterface},
+    keystore::{KeyStore},
+    revocation::{RotationCertificate, RevocationRecord, RevocationList, ChildCertificate, revocation_hbfi, is_revocation_hbfi},
+    session::{LinkSession, SessionMode},
+    link_replay::{LinkReplayWindow},
+    cipher_suite::{CipherSuite},
 };
 pub use keynesis::{
     key::{ed25519::Signature, SharedSecret},
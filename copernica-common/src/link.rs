@@ -67,6 +67,16 @@ impl LinkId {
             }
         }
     }
+    pub fn with_remote_link_pid(&self, remote_link_pid: PublicIdentity) -> Result<Self> {
+        match self {
+            LinkId::Identity { lookup_id, link_sid, reply_to, .. } => {
+                Ok(LinkId::Identity { lookup_id: *lookup_id, link_sid: link_sid.clone(), remote_link_pid: PublicIdentityInterface::new(remote_link_pid), reply_to: reply_to.clone() })
+            },
+            LinkId::Choke => {
+                Err(anyhow!("Requesting a PublicIdentity when in state Choke. Not going to happen buddy"))
+            }
+        }
+    }
     pub fn reply_to(&self) -> Result<ReplyTo> {
         match self {
             LinkId::Identity { reply_to, .. } => {
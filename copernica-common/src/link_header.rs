@@ -0,0 +1,152 @@
+use {
+    crate::{
+        constants::{PROTOCOL_VERSION, MIN_PROTOCOL_VERSION, SESSION_PROTOCOL_VERSION, XNONCE_PROTOCOL_VERSION, SUITE_PROTOCOL_VERSION, LINK_HEADER_SIZE, SUITE_LINK_HEADER_SIZE},
+        hbfi::HBFI,
+        CipherSuite, NarrowWaistPacket, PublicIdentityInterface, DecodeError, ResponseData,
+    },
+    std::{
+        convert::TryFrom,
+        fmt,
+    },
+};
+/// How the bytes following the `LinkHeader` are framed on the link.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum LinkPacketKind {
+    ClearText = 0,
+    CypherText = 1,
+    // cyphertext keyed from ephemeral session keys, see `LinkSession`
+    Session = 2,
+}
+/// Which `NarrowWaistPacket` variant the link packet carries and whether
+/// its HBFI names a request identity (cyphertext) or not (cleartext).
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum NarrowWaistPacketKind {
+    CleartextRequest = 0,
+    CleartextResponse = 1,
+    CyphertextRequest = 2,
+    CyphertextResponse = 3,
+    CleartextNack = 4,
+    CyphertextNack = 5,
+    // response data sealed with XChaCha20Poly1305, from XNONCE_PROTOCOL_VERSION
+    XCyphertextResponse = 6,
+}
+/// The first bytes of every link packet: a protocol version followed by
+/// discriminants for the link framing and the narrow waist packet.
+/// Decoders dispatch on these instead of guessing from packet sizes.
+///
+/// From `SUITE_PROTOCOL_VERSION` the header also names the cipher suite the
+/// packet is sealed with and the suites the sender accepts in return,
+/// earlier versions imply the suite from the version.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct LinkHeader {
+    pub version: u8,
+    pub link_kind: LinkPacketKind,
+    pub nw_kind: NarrowWaistPacketKind,
+    pub suite: CipherSuite,
+    // a CipherSuite::mask
+    pub accepts: u8,
+}
+impl LinkHeader {
+    pub fn new(link_kind: LinkPacketKind, nw_kind: NarrowWaistPacketKind) -> Self {
+        Self::new_with_version(PROTOCOL_VERSION, link_kind, nw_kind)
+    }
+    // for a peer that only speaks up to `version`
+    pub fn new_with_version(version: u8, link_kind: LinkPacketKind, nw_kind: NarrowWaistPacketKind) -> Self {
+        let version = version.max(link_kind.min_version()).max(nw_kind.min_version());
+        Self { version, link_kind, nw_kind, suite: Self::implied_suite(version), accepts: CipherSuite::mask(&CipherSuite::all()) }
+    }
+    // name the suite on the wire, which takes SUITE_PROTOCOL_VERSION
+    pub fn with_suite(mut self, suite: CipherSuite, accepts: u8) -> Self {
+        self.version = self.version.max(SUITE_PROTOCOL_VERSION);
+        self.suite = suite;
+        self.accepts = accepts;
+        self
+    }
+    pub fn implied_suite(version: u8) -> CipherSuite {
+        if version >= XNONCE_PROTOCOL_VERSION {
+            CipherSuite::XChaCha20Poly1305
+        } else {
+            CipherSuite::ChaCha20Poly1305
+        }
+    }
+    pub fn cipher_suite(&self) -> CipherSuite {
+        self.suite
+    }
+    pub fn names_suite(&self) -> bool {
+        self.version >= SUITE_PROTOCOL_VERSION
+    }
+    pub fn size(&self) -> usize {
+        if self.names_suite() {
+            SUITE_LINK_HEADER_SIZE
+        } else {
+            LINK_HEADER_SIZE
+        }
+    }
+    pub fn is_supported_version(version: u8) -> bool {
+        version >= MIN_PROTOCOL_VERSION && version <= PROTOCOL_VERSION
+    }
+}
+impl NarrowWaistPacketKind {
+    pub fn from_narrow_waist(nw: &NarrowWaistPacket) -> Self {
+        let is_cyphertext = |hbfi: &HBFI| match hbfi.request_pid {
+            PublicIdentityInterface::Present { .. } => true,
+            PublicIdentityInterface::Absent => false,
+        };
+        match nw {
+            NarrowWaistPacket::Request { hbfi, .. } if is_cyphertext(hbfi) => NarrowWaistPacketKind::CyphertextRequest,
+            NarrowWaistPacket::Request { .. } => NarrowWaistPacketKind::CleartextRequest,
+            NarrowWaistPacket::Response { data: ResponseData::XCypherText { .. }, .. } => NarrowWaistPacketKind::XCyphertextResponse,
+            NarrowWaistPacket::Response { hbfi, .. } if is_cyphertext(hbfi) => NarrowWaistPacketKind::CyphertextResponse,
+            NarrowWaistPacket::Response { .. } => NarrowWaistPacketKind::CleartextResponse,
+            NarrowWaistPacket::Nack { hbfi, .. } if is_cyphertext(hbfi) => NarrowWaistPacketKind::CyphertextNack,
+            NarrowWaistPacket::Nack { .. } => NarrowWaistPacketKind::CleartextNack,
+        }
+    }
+    // the first protocol version able to carry this kind
+    pub fn min_version(&self) -> u8 {
+        match self {
+            NarrowWaistPacketKind::XCyphertextResponse => XNONCE_PROTOCOL_VERSION,
+            _ => MIN_PROTOCOL_VERSION,
+        }
+    }
+}
+impl LinkPacketKind {
+    // the first protocol version able to carry this framing
+    pub fn min_version(&self) -> u8 {
+        match self {
+            LinkPacketKind::Session => SESSION_PROTOCOL_VERSION,
+            _ => MIN_PROTOCOL_VERSION,
+        }
+    }
+}
+impl TryFrom<u8> for LinkPacketKind {
+    type Error = DecodeError;
+    fn try_from(kind: u8) -> Result<Self, DecodeError> {
+        match kind {
+            0 => Ok(LinkPacketKind::ClearText),
+            1 => Ok(LinkPacketKind::CypherText),
+            2 => Ok(LinkPacketKind::Session),
+            value => Err(DecodeError::UnknownKind { what: "LinkPacketKind", value }),
+        }
+    }
+}
+impl TryFrom<u8> for NarrowWaistPacketKind {
+    type Error = DecodeError;
+    fn try_from(kind: u8) -> Result<Self, DecodeError> {
+        match kind {
+            0 => Ok(NarrowWaistPacketKind::CleartextRequest),
+            1 => Ok(NarrowWaistPacketKind::CleartextResponse),
+            2 => Ok(NarrowWaistPacketKind::CyphertextRequest),
+            3 => Ok(NarrowWaistPacketKind::CyphertextResponse),
+            4 => Ok(NarrowWaistPacketKind::CleartextNack),
+            5 => Ok(NarrowWaistPacketKind::CyphertextNack),
+            6 => Ok(NarrowWaistPacketKind::XCyphertextResponse),
+            value => Err(DecodeError::UnknownKind { what: "NarrowWaistPacketKind", value }),
+        }
+    }
+}
+impl fmt::Display for LinkHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "v{} {:?} {:?} {:?}", self.version, self.link_kind, self.nw_kind, self.suite)
+    }
+}
@@ -0,0 +1,215 @@
+use {
+    crate::{constants, Nonce, PublicIdentity},
+    std::{
+        collections::{BTreeSet, HashMap, HashSet, VecDeque},
+        time::{SystemTime, UNIX_EPOCH},
+    },
+};
+pub fn unix_micros() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0)
+}
+// highest counter seen and a bitmap of the LINK_REPLAY_WINDOW_PACKETS below it
+#[derive(Clone, Copy, Default)]
+struct Window {
+    highest: u64,
+    bitmap: u64,
+}
+impl Window {
+    fn is_fresh(&self, counter: u64) -> bool {
+        if counter == 0 {
+            return false
+        }
+        if counter > self.highest {
+            return true
+        }
+        let offset = self.highest - counter;
+        offset < constants::LINK_REPLAY_WINDOW_PACKETS && self.bitmap & (1 << offset) == 0
+    }
+    fn admit(&mut self, counter: u64) {
+        if counter > self.highest {
+            let shift = counter - self.highest;
+            self.bitmap = if shift >= constants::LINK_REPLAY_WINDOW_PACKETS { 0 } else { self.bitmap << shift };
+            self.bitmap |= 1;
+            self.highest = counter;
+        } else {
+            self.bitmap |= 1 << (self.highest - counter);
+        }
+    }
+}
+// the highest epoch a remote has authenticated a packet under and the one before it
+#[derive(Clone, Copy, Default)]
+struct Epochs {
+    current: u32,
+    window: Window,
+    previous: Option<(u32, Window)>,
+}
+impl Epochs {
+    fn window(&self, epoch: u32) -> Option<&Window> {
+        match self.previous {
+            _ if epoch == self.current => Some(&self.window),
+            Some((previous, ref window)) if epoch == previous => Some(window),
+            _ => None,
+        }
+    }
+}
+/// Remembers which link packets have already been accepted.
+///
+/// From `TIMESTAMP_PROTOCOL_VERSION` every link nonce ends in the sender's
+/// clock in microseconds. A stamp is fresh when it's within
+/// `LINK_FRESHNESS_SECS` of our clock, not below `floor` and not seen before.
+/// The floor starts at our boot time, so packets recorded before a restart
+/// stay refused, and rises past any stamp pushed out of the bounded set.
+///
+/// Older peers number session nonces per ephemeral key, a sliding bitmap for
+/// each of the remote's last two epochs, and send static cyphertext with
+/// random nonces of which the most recent are kept. Epochs are only followed
+/// forward per remote static key, so a packet from an epoch before the last
+/// two, or keyed to our static key from before the current one, is refused.
+///
+/// Everything is checked before decrypting and only updated once a packet has
+/// authenticated, so forged packets can't push genuine ones out of the window.
+pub struct LinkReplayWindow {
+    epochs: HashMap<PublicIdentity, Epochs>,
+    stamps: BTreeSet<u64>,
+    floor: u64,
+    nonces: HashSet<Nonce>,
+    nonce_order: VecDeque<Nonce>,
+}
+impl Default for LinkReplayWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl LinkReplayWindow {
+    pub fn new() -> Self {
+        Self::new_with_floor(unix_micros())
+    }
+    pub fn new_with_floor(floor: u64) -> Self {
+        Self { epochs: HashMap::new(), stamps: BTreeSet::new(), floor, nonces: HashSet::new(), nonce_order: VecDeque::new() }
+    }
+    // a packet keyed to our static key (rx_epoch 0) comes from before the
+    // remote heard our ephemeral, so never from an epoch older than its newest
+    pub fn is_fresh_epoch(&self, remote: &PublicIdentity, tx_epoch: u32, rx_epoch: u32) -> bool {
+        match self.epochs.get(remote) {
+            Some(epochs) if rx_epoch == 0 => tx_epoch >= epochs.current,
+            Some(epochs) => tx_epoch > epochs.current || epochs.window(tx_epoch).is_some(),
+            None => true,
+        }
+    }
+    pub fn admit_epoch(&mut self, remote: &PublicIdentity, tx_epoch: u32) {
+        let epochs = self.epochs.entry(remote.clone()).or_insert_with(|| Epochs { current: tx_epoch, ..Epochs::default() });
+        if tx_epoch > epochs.current {
+            epochs.previous = Some((epochs.current, epochs.window));
+            epochs.current = tx_epoch;
+            epochs.window = Window::default();
+        }
+    }
+    pub fn is_fresh_counter(&self, remote: &PublicIdentity, tx_epoch: u32, counter: u64) -> bool {
+        match self.epochs.get(remote).and_then(|epochs| epochs.window(tx_epoch)) {
+            Some(window) => window.is_fresh(counter),
+            None => counter != 0,
+        }
+    }
+    pub fn admit_counter(&mut self, remote: &PublicIdentity, tx_epoch: u32, counter: u64) {
+        self.admit_epoch(remote, tx_epoch);
+        if let Some(epochs) = self.epochs.get_mut(remote) {
+            match epochs.previous {
+                Some((previous, ref mut window)) if tx_epoch == previous => window.admit(counter),
+                _ if tx_epoch == epochs.current => epochs.window.admit(counter),
+                _ => {},
+            }
+        }
+    }
+    pub fn is_fresh_stamp(&self, stamp: u64, now: u64) -> bool {
+        let freshness = constants::LINK_FRESHNESS_SECS * 1_000_000;
+        stamp >= self.floor
+            && stamp.saturating_add(freshness) >= now
+            && stamp <= now.saturating_add(freshness)
+            && !self.stamps.contains(&stamp)
+    }
+    pub fn admit_stamp(&mut self, stamp: u64, now: u64) {
+        self.stamps.insert(stamp);
+        let stale = now.saturating_sub(constants::LINK_FRESHNESS_SECS * 1_000_000);
+        self.stamps = self.stamps.split_off(&stale);
+        while self.stamps.len() > constants::LINK_REPLAY_NONCES {
+            if let Some(&oldest) = self.stamps.iter().next() {
+                self.stamps.remove(&oldest);
+                self.floor = self.floor.max(oldest + 1);
+            }
+        }
+    }
+    pub fn is_fresh_nonce(&self, nonce: &Nonce) -> bool {
+        !self.nonces.contains(nonce)
+    }
+    pub fn admit_nonce(&mut self, nonce: Nonce) {
+        if !self.nonces.insert(nonce.clone()) {
+            return
+        }
+        if self.nonce_order.len() >= constants::LINK_REPLAY_NONCES {
+            if let Some(oldest) = self.nonce_order.pop_front() {
+                self.nonces.remove(&oldest);
+            }
+        }
+        self.nonce_order.push_back(nonce);
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PrivateIdentityInterface;
+    #[test]
+    fn counters_slide_and_nonces_are_remembered() {
+        let mut replay = LinkReplayWindow::new();
+        let remote = PrivateIdentityInterface::new_key().public_id();
+        assert!(!replay.is_fresh_counter(&remote, 1, 0));
+        for &counter in &[1, 3, 2] {
+            assert!(replay.is_fresh_counter(&remote, 1, counter));
+            replay.admit_counter(&remote, 1, counter);
+            assert!(!replay.is_fresh_counter(&remote, 1, counter));
+        }
+        replay.admit_counter(&remote, 1, 10 + constants::LINK_REPLAY_WINDOW_PACKETS);
+        // too far behind to tell, so refused
+        assert!(!replay.is_fresh_counter(&remote, 1, 9));
+        assert!(replay.is_fresh_counter(&remote, 1, 11));
+        assert!(replay.is_fresh_counter(&PrivateIdentityInterface::new_key().public_id(), 1, 1));
+        assert!(replay.is_fresh_nonce(&Nonce([7; 8])));
+        replay.admit_nonce(Nonce([7; 8]));
+        assert!(!replay.is_fresh_nonce(&Nonce([7; 8])));
+    }
+    #[test]
+    fn epochs_only_move_forward() {
+        let mut replay = LinkReplayWindow::new();
+        let remote = PrivateIdentityInterface::new_key().public_id();
+        replay.admit_counter(&remote, 10, 1);
+        replay.admit_counter(&remote, 11, 1);
+        // the previous epoch keeps its window, anything older is gone for good
+        assert!(replay.is_fresh_epoch(&remote, 10, 5));
+        assert!(!replay.is_fresh_counter(&remote, 10, 1));
+        assert!(!replay.is_fresh_epoch(&remote, 9, 5));
+        replay.admit_counter(&remote, 12, 1);
+        assert!(!replay.is_fresh_epoch(&remote, 10, 5));
+        // keyed to our static key, so sent before the remote's newest epoch heard from us
+        assert!(!replay.is_fresh_epoch(&remote, 11, 0));
+        assert!(replay.is_fresh_epoch(&remote, 12, 0));
+        assert!(replay.is_fresh_epoch(&remote, 13, 0));
+    }
+    #[test]
+    fn stamps_are_fresh_once_within_the_window() {
+        let boot = 1_000_000_000_000;
+        let freshness = constants::LINK_FRESHNESS_SECS * 1_000_000;
+        let mut replay = LinkReplayWindow::new_with_floor(boot);
+        let now = boot + freshness * 2;
+        assert!(!replay.is_fresh_stamp(boot - 1, boot));
+        assert!(!replay.is_fresh_stamp(now - freshness - 1, now));
+        assert!(!replay.is_fresh_stamp(now + freshness + 1, now));
+        assert!(replay.is_fresh_stamp(now, now));
+        replay.admit_stamp(now, now);
+        assert!(!replay.is_fresh_stamp(now, now));
+        // stamps pushed out of the set raise the floor past them
+        for stamp in 1..=constants::LINK_REPLAY_NONCES as u64 {
+            replay.admit_stamp(now + stamp, now);
+        }
+        assert!(!replay.is_fresh_stamp(now, now));
+        assert!(!replay.is_fresh_stamp(now - 1, now));
+    }
+}
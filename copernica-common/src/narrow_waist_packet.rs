@@ -2,13 +2,16 @@ use {
     crate::{
         hbfi::HBFI,
         manifest, generate_nonce,
-        ResponseData, Nonce,
-        PrivateIdentityInterface,
-        Signature
+        CipherSuite, ResponseData, Nonce,
+        PrivateIdentityInterface, PublicIdentityInterface,
+        Signature, DecodeError,
+        constants,
+        request_policy::unix_time,
     },
     core::hash::{Hash, Hasher},
     std::{
         cmp::Ordering,
+        convert::TryFrom,
         fmt,
     },
     anyhow::{anyhow, Result},
@@ -19,6 +22,9 @@ pub enum NarrowWaistPacket {
     Request {
         hbfi: HBFI,
         nonce: Nonce,
+        lifetime: u16, // seconds a broker keeps the request pending, 0 leaves it to the broker
+        hop_limit: u8, // brokers the request may still pass through
+        signature: Option<RequestSignature>, // by the HBFI's request_pid, hop_limit isn't covered
     },
     Response {
         hbfi: HBFI,
@@ -26,14 +32,116 @@ pub enum NarrowWaistPacket {
         signature: Signature,
         data: ResponseData,
     },
+    Nack {
+        hbfi: HBFI,
+        nonce: Nonce, // the nonce of the request being refused
+        reason: NackReason,
+        signature: Option<Signature>, // brokers rely on the link, responders sign
+    },
+}
+/// A requester's signature over a `Request`, with the unix time it was made
+/// so brokers and responders can refuse replays.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct RequestSignature {
+    pub timestamp: u64,
+    pub signature: Signature,
+}
+/// Why a `Request` won't be answered.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum NackReason {
+    NoRoute = 0,
+    NotFound = 1,
+    Congestion = 2,
+    RateLimited = 3,
+    // resend the request signed
+    SignatureRequired = 4,
+}
+impl TryFrom<u8> for NackReason {
+    type Error = DecodeError;
+    fn try_from(reason: u8) -> Result<Self, DecodeError> {
+        match reason {
+            0 => Ok(NackReason::NoRoute),
+            1 => Ok(NackReason::NotFound),
+            2 => Ok(NackReason::Congestion),
+            3 => Ok(NackReason::RateLimited),
+            4 => Ok(NackReason::SignatureRequired),
+            value => Err(DecodeError::UnknownKind { what: "NackReason", value }),
+        }
+    }
+}
+impl fmt::Display for NackReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NackReason::NoRoute => write!(f, "no route"),
+            NackReason::NotFound => write!(f, "not found"),
+            NackReason::Congestion => write!(f, "congestion"),
+            NackReason::RateLimited => write!(f, "rate limited"),
+            NackReason::SignatureRequired => write!(f, "signature required"),
+        }
+    }
+}
+fn request_manifest_data(timestamp: u64, lifetime: u16) -> Vec<u8> {
+    [timestamp.to_be_bytes().to_vec(), lifetime.to_be_bytes().to_vec()].concat()
 }
 impl NarrowWaistPacket {
     pub fn request(hbfi: HBFI) -> Result<Self> {
+        Self::request_with_lifetime(hbfi, 0)
+    }
+    pub fn request_with_lifetime(hbfi: HBFI, lifetime: u16) -> Result<Self> {
+        Self::request_with_hop_limit(hbfi, lifetime, constants::REQUEST_HOP_LIMIT)
+    }
+    pub fn request_with_hop_limit(hbfi: HBFI, lifetime: u16, hop_limit: u8) -> Result<Self> {
         let mut rng = rand::thread_rng();
         let nonce: Nonce = generate_nonce(&mut rng);
-        Ok(NarrowWaistPacket::Request { hbfi, nonce })
+        Ok(NarrowWaistPacket::Request { hbfi, nonce, lifetime, hop_limit, signature: None })
+    }
+    pub fn signed_request(request_sid: &PrivateIdentityInterface, hbfi: HBFI) -> Result<Self> {
+        Self::request(hbfi)?.sign_request(request_sid)
+    }
+    // the same request under a fresh nonce and timestamp, signed by the request identity
+    pub fn sign_request(&self, request_sid: &PrivateIdentityInterface) -> Result<Self> {
+        match self {
+            NarrowWaistPacket::Request { hbfi, lifetime, hop_limit, .. } => {
+                if hbfi.request_pid != PublicIdentityInterface::new(request_sid.public_id()) {
+                    let msg = "The Request's Request Public Identity doesn't match the Public Identity used to sign the Request";
+                    error!("{}", msg);
+                    return Err(anyhow!(msg));
+                }
+                let mut rng = rand::thread_rng();
+                let nonce: Nonce = generate_nonce(&mut rng);
+                let timestamp = unix_time();
+                let manifest = manifest(request_manifest_data(timestamp, *lifetime), hbfi, &nonce)?;
+                let signature = request_sid.signing_key().sign(manifest);
+                Ok(NarrowWaistPacket::Request {
+                    hbfi: hbfi.clone(),
+                    nonce,
+                    lifetime: *lifetime,
+                    hop_limit: *hop_limit,
+                    signature: Some(RequestSignature { timestamp, signature }),
+                })
+            },
+            _ => Err(anyhow!("Only a NarrowWaistPacket::Request can be signed as a request")),
+        }
+    }
+    // the request as the next broker should see it, None once it has run out of hops
+    pub fn next_hop(&self) -> Option<Self> {
+        match self {
+            NarrowWaistPacket::Request { hbfi, nonce, lifetime, hop_limit, signature } if *hop_limit > 0 => {
+                Some(NarrowWaistPacket::Request {
+                    hbfi: hbfi.clone(),
+                    nonce: nonce.clone(),
+                    lifetime: *lifetime,
+                    hop_limit: hop_limit - 1,
+                    signature: signature.clone(),
+                })
+            },
+            _ => None,
+        }
     }
     pub fn response(response_sid: PrivateIdentityInterface, hbfi: HBFI, data: Vec<u8>) -> Result<Self> {
+        Self::response_with_suite(response_sid, hbfi, data, CipherSuite::ChaCha20Poly1305)
+    }
+    pub fn response_with_suite(response_sid: PrivateIdentityInterface, hbfi: HBFI, data: Vec<u8>, suite: CipherSuite) -> Result<Self> {
         if hbfi.response_pid != response_sid.public_id() {
             let msg = "The Request's Response Public Identity doesn't match the Public Identity used to sign or encypt the Response";
             error!("{}", msg);
@@ -42,23 +150,53 @@ impl NarrowWaistPacket {
         let mut rng = rand::thread_rng();
         let hbfi = hbfi.clone();
         let nonce: Nonce = generate_nonce(&mut rng);
-        let data = ResponseData::insert(response_sid.clone(), hbfi.request_pid.clone(), data, nonce.clone())?;
+        let data = ResponseData::insert_with_suite(response_sid.clone(), hbfi.request_pid.clone(), data, nonce.clone(), suite)?;
         let manifest = manifest(data.manifest_data(), &hbfi, &nonce)?;
         let response_signkey = response_sid.signing_key();
         let signature = response_signkey.sign(manifest);
         Ok(NarrowWaistPacket::Response { hbfi, nonce, data, signature })
     }
+    pub fn nack(hbfi: HBFI, nonce: Nonce, reason: NackReason) -> Self {
+        NarrowWaistPacket::Nack { hbfi, nonce, reason, signature: None }
+    }
+    pub fn signed_nack(response_sid: PrivateIdentityInterface, hbfi: HBFI, nonce: Nonce, reason: NackReason) -> Result<Self> {
+        if hbfi.response_pid != response_sid.public_id() {
+            let msg = "The Request's Response Public Identity doesn't match the Public Identity used to sign the Nack";
+            error!("{}", msg);
+            return Err(anyhow!(msg));
+        }
+        let manifest = manifest(vec![reason as u8], &hbfi, &nonce)?;
+        let signature = response_sid.signing_key().sign(manifest);
+        Ok(NarrowWaistPacket::Nack { hbfi, nonce, reason, signature: Some(signature) })
+    }
     pub fn verify(&self) -> Result<bool> {
         match self {
-            NarrowWaistPacket::Request {..} => {
+            NarrowWaistPacket::Request { signature: None, .. } => {
                 return Ok(true)
             },
+            NarrowWaistPacket::Request { hbfi, nonce, lifetime, signature: Some(RequestSignature { timestamp, signature }), .. } => {
+                let request_pid = match &hbfi.request_pid {
+                    PublicIdentityInterface::Present { public_identity } => public_identity,
+                    PublicIdentityInterface::Absent => return Ok(false),
+                };
+                let manifest = manifest(request_manifest_data(*timestamp, *lifetime), hbfi, nonce)?;
+                let verify_key = request_pid.verify_key()?;
+                return Ok(verify_key.verify(&signature, manifest));
+            },
             NarrowWaistPacket::Response { data, hbfi, signature, nonce} => {
                 let manifest = manifest(data.manifest_data(), hbfi, nonce)?;
                 let verify_key = hbfi.response_pid.verify_key()?;
                 let verified = verify_key.verify(&signature, manifest);
                 return Ok(verified);
             },
+            NarrowWaistPacket::Nack { signature: None, .. } => {
+                return Ok(true)
+            },
+            NarrowWaistPacket::Nack { hbfi, nonce, reason, signature: Some(signature) } => {
+                let manifest = manifest(vec![*reason as u8], hbfi, nonce)?;
+                let verify_key = hbfi.response_pid.verify_key()?;
+                return Ok(verify_key.verify(&signature, manifest));
+            },
         }
     }
     pub fn data(&self, request_sid: PrivateIdentityInterface) -> Result<Vec<u8>> {
@@ -72,6 +210,11 @@ impl NarrowWaistPacket {
                 return Ok(data.extract(request_sid, hbfi.request_pid.clone(), hbfi.response_pid.clone(), nonce.clone())?)
 
             },
+            NarrowWaistPacket::Nack { reason, .. } => {
+                let err_msg = format!("No data in a NarrowWaistPacket::Nack, the request was refused: {}", reason);
+                error!("{}", err_msg);
+                return Err(anyhow!(err_msg))
+            },
         }
     }
 }
@@ -81,7 +224,8 @@ impl Hash for NarrowWaistPacketReqEqRes {
     fn hash<H: Hasher>(&self, state: &mut H) {
         match &self.0 {
             NarrowWaistPacket::Request { hbfi, .. } => { hbfi.hash(state) },
-            NarrowWaistPacket::Response { hbfi, .. } => { hbfi.hash(state) }
+            NarrowWaistPacket::Response { hbfi, .. } => { hbfi.hash(state) },
+            NarrowWaistPacket::Nack { hbfi, .. } => { hbfi.hash(state) }
         }
     }
 }
@@ -89,11 +233,13 @@ impl PartialOrd for NarrowWaistPacketReqEqRes {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         let self_hbfi = match &self.0 {
             NarrowWaistPacket::Request { hbfi, .. } => { hbfi },
-            NarrowWaistPacket::Response { hbfi, .. } => { hbfi }
+            NarrowWaistPacket::Response { hbfi, .. } => { hbfi },
+            NarrowWaistPacket::Nack { hbfi, .. } => { hbfi }
         };
         let other_hbfi = match &other.0 {
             NarrowWaistPacket::Request { hbfi, .. } => { hbfi },
-            NarrowWaistPacket::Response { hbfi, .. } => { hbfi }
+            NarrowWaistPacket::Response { hbfi, .. } => { hbfi },
+            NarrowWaistPacket::Nack { hbfi, .. } => { hbfi }
         };
         Some(self_hbfi.cmp(other_hbfi))
     }
@@ -103,11 +249,13 @@ impl Ord for NarrowWaistPacketReqEqRes {
     fn cmp(&self, other: &Self) -> Ordering {
         let self_hbfi = match &self.0 {
             NarrowWaistPacket::Request { hbfi, .. } => { hbfi },
-            NarrowWaistPacket::Response { hbfi, .. } => { hbfi }
+            NarrowWaistPacket::Response { hbfi, .. } => { hbfi },
+            NarrowWaistPacket::Nack { hbfi, .. } => { hbfi }
         };
         let other_hbfi = match &other.0 {
             NarrowWaistPacket::Request { hbfi, .. } => { hbfi },
-            NarrowWaistPacket::Response { hbfi, .. } => { hbfi }
+            NarrowWaistPacket::Response { hbfi, .. } => { hbfi },
+            NarrowWaistPacket::Nack { hbfi, .. } => { hbfi }
         };
         self_hbfi.frm.cmp(&other_hbfi.frm)
     }
@@ -116,11 +264,13 @@ impl PartialEq for NarrowWaistPacketReqEqRes {
     fn eq(&self, other: &Self) -> bool {
         let self_hbfi = match &self.0 {
             NarrowWaistPacket::Request { hbfi, .. } => { hbfi },
-            NarrowWaistPacket::Response { hbfi, .. } => { hbfi }
+            NarrowWaistPacket::Response { hbfi, .. } => { hbfi },
+            NarrowWaistPacket::Nack { hbfi, .. } => { hbfi }
         };
         let other_hbfi = match &other.0 {
             NarrowWaistPacket::Request { hbfi, .. } => { hbfi },
-            NarrowWaistPacket::Response { hbfi, .. } => { hbfi }
+            NarrowWaistPacket::Response { hbfi, .. } => { hbfi },
+            NarrowWaistPacket::Nack { hbfi, .. } => { hbfi }
         };
         self_hbfi == other_hbfi
     }
@@ -131,19 +281,22 @@ impl fmt::Debug for NarrowWaistPacketReqEqRes {
         match &self.0 {
             NarrowWaistPacket::Request  { hbfi, .. } => write!(f, "NWEQ REQ {:?}", hbfi),
             NarrowWaistPacket::Response { hbfi, .. } => write!(f, "NWEQ RES {:?}", hbfi),
+            NarrowWaistPacket::Nack     { hbfi, .. } => write!(f, "NWEQ NACK {:?}", hbfi),
         }
     }
 }
 impl fmt::Debug for NarrowWaistPacket {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match &*self {
-            NarrowWaistPacket::Request { hbfi, .. } => write!(f, "NW REQ {:?}", hbfi),
+            NarrowWaistPacket::Request { hbfi, signature: None, .. } => write!(f, "NW REQ {:?}", hbfi),
+            NarrowWaistPacket::Request { hbfi, signature: Some(RequestSignature { timestamp, signature }), .. } => write!(f, "NW SIGNED REQ {:?} {} {}", hbfi, signature, timestamp),
             NarrowWaistPacket::Response {
                 hbfi,
                 signature,
                 nonce,
                 ..
             } => write!(f, "NW RES {:?} {} {:?}", hbfi, signature, nonce),
+            NarrowWaistPacket::Nack { hbfi, reason, nonce, .. } => write!(f, "NW NACK {:?} {} {:?}", hbfi, reason, nonce),
         }
     }
 }
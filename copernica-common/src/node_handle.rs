@@ -0,0 +1,146 @@
+use {
+    crate::constants::SHUTDOWN_POLL_INTERVAL_MS,
+    std::{
+        fmt,
+        sync::{Arc, atomic::{AtomicBool, Ordering}, mpsc::{SyncSender, TrySendError}},
+        thread::JoinHandle,
+        time::Duration,
+    },
+    anyhow::{anyhow, Result},
+    thiserror::Error,
+};
+/// Shared flag every thread of a node polls; once triggered the threads
+/// drain what is already queued and return.
+#[derive(Clone, Debug, Default)]
+pub struct Shutdown(Arc<AtomicBool>);
+impl Shutdown {
+    pub fn new() -> Self {
+        Shutdown(Arc::new(AtomicBool::new(false)))
+    }
+    pub fn trigger(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+    pub fn is_triggered(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+    // how long a thread may block on a channel or socket before checking the flag again
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_millis(SHUTDOWN_POLL_INTERVAL_MS)
+    }
+    // retries a full channel every poll interval like a blocking `SyncSender::send`,
+    // but gives up once shutdown is triggered rather than wait on a peer that may
+    // already have stopped
+    pub fn send<T>(&self, tx: &SyncSender<T>, mut t: T) -> Result<()> {
+        loop {
+            match tx.try_send(t) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(back)) if !self.is_triggered() => {
+                    t = back;
+                    std::thread::sleep(self.poll_interval());
+                },
+                Err(e) => return Err(anyhow!("{}", e)),
+            }
+        }
+    }
+}
+#[derive(Debug, Error)]
+pub struct ShutdownError {
+    pub errors: Vec<(String, anyhow::Error)>,
+}
+impl fmt::Display for ShutdownError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} thread(s) stopped with an error:", self.errors.len())?;
+        for (label, error) in &self.errors {
+            write!(f, "\n\t{}: {}", label, error)?;
+        }
+        Ok(())
+    }
+}
+/// Owns the threads started by a `Broker`, `Link` or `Protocol` `run`.
+/// Handles can be nested with `add` so a whole network stops with one call;
+/// every handle in the tree is triggered before any thread is joined, so a
+/// thread waiting on another node can't hold up the rest.
+pub struct NodeHandle {
+    label: String,
+    shutdown: Shutdown,
+    threads: Vec<JoinHandle<Result<()>>>,
+    children: Vec<NodeHandle>,
+}
+impl NodeHandle {
+    pub fn new(label: &str) -> Self {
+        Self {
+            label: label.to_string(),
+            shutdown: Shutdown::new(),
+            threads: vec![],
+            children: vec![],
+        }
+    }
+    pub fn label(&self) -> String {
+        self.label.clone()
+    }
+    pub fn shutdown_token(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+    pub fn spawn<F>(&mut self, f: F)
+    where
+        F: FnOnce(Shutdown) -> Result<()> + Send + 'static,
+    {
+        let shutdown = self.shutdown.clone();
+        self.threads.push(std::thread::spawn(move || f(shutdown)));
+    }
+    pub fn add(&mut self, child: NodeHandle) {
+        self.children.push(child);
+    }
+    fn trigger(&self) {
+        self.shutdown.trigger();
+        for child in &self.children {
+            child.trigger();
+        }
+    }
+    fn join(self, errors: &mut Vec<(String, anyhow::Error)>) {
+        for thread in self.threads {
+            match thread.join() {
+                Ok(Ok(())) => {},
+                Ok(Err(error)) => errors.push((self.label.clone(), error)),
+                Err(_) => errors.push((self.label.clone(), anyhow!("thread panicked"))),
+            }
+        }
+        for child in self.children {
+            child.join(errors);
+        }
+    }
+    pub fn shutdown(self) -> Result<(), ShutdownError> {
+        let mut errors = vec![];
+        self.trigger();
+        self.join(&mut errors);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ShutdownError { errors })
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::sync_channel;
+    #[test]
+    fn a_parent_waiting_on_its_child_still_stops() {
+        let (full_tx, _full_rx) = sync_channel::<()>(0);
+        let (done_tx, done_rx) = sync_channel::<()>(0);
+        let mut child = NodeHandle::new("child");
+        child.spawn(move |shutdown| {
+            // nobody receives, so this only returns once shutdown is triggered
+            while shutdown.send(&full_tx, ()).is_ok() {}
+            drop(done_tx);
+            Ok(())
+        });
+        let mut parent = NodeHandle::new("parent");
+        parent.spawn(move |_| {
+            let _ = done_rx.recv();
+            Ok(())
+        });
+        parent.add(child);
+        parent.shutdown().unwrap();
+    }
+}
@@ -119,6 +119,237 @@ impl Operations {
             Operations::Off => {}
         }
     }
+    pub fn decode_failure(&self, label: String) {
+        match self {
+            Operations::On { tx } => {
+                match tx.send(LogEntry::decode_failure(&label)) {
+                    Ok(_) => {},
+                    Err(_) => {},
+                }
+            },
+            Operations::Off => {}
+        }
+    }
+    pub fn pending_request_expired(&self, label: String) {
+        match self {
+            Operations::On { tx } => {
+                match tx.send(LogEntry::pending_request_expired(&label)) {
+                    Ok(_) => {},
+                    Err(_) => {},
+                }
+            },
+            Operations::Off => {}
+        }
+    }
+    pub fn forwarded_request_expired(&self, label: String) {
+        match self {
+            Operations::On { tx } => {
+                match tx.send(LogEntry::forwarded_request_expired(&label)) {
+                    Ok(_) => {},
+                    Err(_) => {},
+                }
+            },
+            Operations::Off => {}
+        }
+    }
+    pub fn defcon_rate_limited(&self, label: String) {
+        match self {
+            Operations::On { tx } => {
+                match tx.send(LogEntry::defcon_rate_limited(&label)) {
+                    Ok(_) => {},
+                    Err(_) => {},
+                }
+            },
+            Operations::Off => {}
+        }
+    }
+    pub fn defcon_unsigned_dropped(&self, label: String) {
+        match self {
+            Operations::On { tx } => {
+                match tx.send(LogEntry::defcon_unsigned_dropped(&label)) {
+                    Ok(_) => {},
+                    Err(_) => {},
+                }
+            },
+            Operations::Off => {}
+        }
+    }
+    pub fn defcon_dropped(&self, label: String) {
+        match self {
+            Operations::On { tx } => {
+                match tx.send(LogEntry::defcon_dropped(&label)) {
+                    Ok(_) => {},
+                    Err(_) => {},
+                }
+            },
+            Operations::Off => {}
+        }
+    }
+    pub fn defcon_link_choked(&self, label: String) {
+        match self {
+            Operations::On { tx } => {
+                match tx.send(LogEntry::defcon_link_choked(&label)) {
+                    Ok(_) => {},
+                    Err(_) => {},
+                }
+            },
+            Operations::Off => {}
+        }
+    }
+    pub fn nack_sent(&self, label: String) {
+        match self {
+            Operations::On { tx } => {
+                match tx.send(LogEntry::nack_sent(&label)) {
+                    Ok(_) => {},
+                    Err(_) => {},
+                }
+            },
+            Operations::Off => {}
+        }
+    }
+    pub fn nack_received(&self, label: String) {
+        match self {
+            Operations::On { tx } => {
+                match tx.send(LogEntry::nack_received(&label)) {
+                    Ok(_) => {},
+                    Err(_) => {},
+                }
+            },
+            Operations::Off => {}
+        }
+    }
+    pub fn forward_nack_downstream(&self, label: String) {
+        match self {
+            Operations::On { tx } => {
+                match tx.send(LogEntry::forward_nack_downstream(&label)) {
+                    Ok(_) => {},
+                    Err(_) => {},
+                }
+            },
+            Operations::Off => {}
+        }
+    }
+    pub fn duplicate_request_dropped(&self, label: String) {
+        match self {
+            Operations::On { tx } => {
+                match tx.send(LogEntry::duplicate_request_dropped(&label)) {
+                    Ok(_) => {},
+                    Err(_) => {},
+                }
+            },
+            Operations::Off => {}
+        }
+    }
+    pub fn hop_limit_exceeded(&self, label: String) {
+        match self {
+            Operations::On { tx } => {
+                match tx.send(LogEntry::hop_limit_exceeded(&label)) {
+                    Ok(_) => {},
+                    Err(_) => {},
+                }
+            },
+            Operations::Off => {}
+        }
+    }
+    pub fn unsigned_request_dropped(&self, label: String) {
+        match self {
+            Operations::On { tx } => {
+                match tx.send(LogEntry::unsigned_request_dropped(&label)) {
+                    Ok(_) => {},
+                    Err(_) => {},
+                }
+            },
+            Operations::Off => {}
+        }
+    }
+    pub fn forged_request_dropped(&self, label: String) {
+        match self {
+            Operations::On { tx } => {
+                match tx.send(LogEntry::forged_request_dropped(&label)) {
+                    Ok(_) => {},
+                    Err(_) => {},
+                }
+            },
+            Operations::Off => {}
+        }
+    }
+    pub fn replayed_request_dropped(&self, label: String) {
+        match self {
+            Operations::On { tx } => {
+                match tx.send(LogEntry::replayed_request_dropped(&label)) {
+                    Ok(_) => {},
+                    Err(_) => {},
+                }
+            },
+            Operations::Off => {}
+        }
+    }
+    pub fn response_verification_failed(&self, label: String) {
+        match self {
+            Operations::On { tx } => {
+                match tx.send(LogEntry::response_verification_failed(&label)) {
+                    Ok(_) => {},
+                    Err(_) => {},
+                }
+            },
+            Operations::Off => {}
+        }
+    }
+    pub fn revoked_response_dropped(&self, label: String) {
+        match self {
+            Operations::On { tx } => {
+                match tx.send(LogEntry::revoked_response_dropped(&label)) {
+                    Ok(_) => {},
+                    Err(_) => {},
+                }
+            },
+            Operations::Off => {}
+        }
+    }
+    pub fn link_pin_changed(&self, label: String) {
+        match self {
+            Operations::On { tx } => {
+                match tx.send(LogEntry::link_pin_changed(&label)) {
+                    Ok(_) => {},
+                    Err(_) => {},
+                }
+            },
+            Operations::Off => {}
+        }
+    }
+    pub fn link_replay_dropped(&self, label: String) {
+        match self {
+            Operations::On { tx } => {
+                match tx.send(LogEntry::link_replay_dropped(&label)) {
+                    Ok(_) => {},
+                    Err(_) => {},
+                }
+            },
+            Operations::Off => {}
+        }
+    }
+    pub fn link_suite_refused(&self, label: String) {
+        match self {
+            Operations::On { tx } => {
+                match tx.send(LogEntry::link_suite_refused(&label)) {
+                    Ok(_) => {},
+                    Err(_) => {},
+                }
+            },
+            Operations::Off => {}
+        }
+    }
+    pub fn content_store_failed(&self, label: String) {
+        match self {
+            Operations::On { tx } => {
+                match tx.send(LogEntry::content_store_failed(&label)) {
+                    Ok(_) => {},
+                    Err(_) => {},
+                }
+            },
+            Operations::Off => {}
+        }
+    }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
@@ -142,6 +373,69 @@ pub enum LogEntry {
     ForwardRequestUpstream {
         label: String,
     },
+    DecodeFailure {
+        label: String,
+    },
+    PendingRequestExpired {
+        label: String,
+    },
+    ForwardedRequestExpired {
+        label: String,
+    },
+    DefconRateLimited {
+        label: String,
+    },
+    DefconUnsignedDropped {
+        label: String,
+    },
+    DefconDropped {
+        label: String,
+    },
+    DefconLinkChoked {
+        label: String,
+    },
+    NackSent {
+        label: String,
+    },
+    NackReceived {
+        label: String,
+    },
+    ForwardNackDownstream {
+        label: String,
+    },
+    DuplicateRequestDropped {
+        label: String,
+    },
+    HopLimitExceeded {
+        label: String,
+    },
+    UnsignedRequestDropped {
+        label: String,
+    },
+    ForgedRequestDropped {
+        label: String,
+    },
+    ReplayedRequestDropped {
+        label: String,
+    },
+    ResponseVerificationFailed {
+        label: String,
+    },
+    RevokedResponseDropped {
+        label: String,
+    },
+    LinkPinChanged {
+        label: String,
+    },
+    LinkReplayDropped {
+        label: String,
+    },
+    LinkSuiteRefused {
+        label: String,
+    },
+    ContentStoreFailed {
+        label: String,
+    },
 }
 impl LogEntry {
     pub fn end() -> Self {
@@ -165,6 +459,69 @@ impl LogEntry {
     pub fn forward_response_downstream(label: &str) -> Self {
         LogEntry::ForwardResponseDownstream { label: format!("forwarded response from node: {}", &label)  }
     }
+    pub fn decode_failure(label: &str) -> Self {
+        LogEntry::DecodeFailure { label: format!("dropped undecodable packet at node: {}", &label)  }
+    }
+    pub fn pending_request_expired(label: &str) -> Self {
+        LogEntry::PendingRequestExpired { label: format!("pending request expired at node: {}", &label)  }
+    }
+    pub fn forwarded_request_expired(label: &str) -> Self {
+        LogEntry::ForwardedRequestExpired { label: format!("forwarded request expired at node: {}", &label)  }
+    }
+    pub fn defcon_rate_limited(label: &str) -> Self {
+        LogEntry::DefconRateLimited { label: format!("defcon rate limited a request at node: {}", &label)  }
+    }
+    pub fn defcon_unsigned_dropped(label: &str) -> Self {
+        LogEntry::DefconUnsignedDropped { label: format!("defcon dropped an unsigned request at node: {}", &label)  }
+    }
+    pub fn defcon_dropped(label: &str) -> Self {
+        LogEntry::DefconDropped { label: format!("defcon dropped a request at node: {}", &label)  }
+    }
+    pub fn defcon_link_choked(label: &str) -> Self {
+        LogEntry::DefconLinkChoked { label: format!("defcon choked a link at node: {}", &label)  }
+    }
+    pub fn nack_sent(label: &str) -> Self {
+        LogEntry::NackSent { label: format!("nack_sent: {}", &label)  }
+    }
+    pub fn nack_received(label: &str) -> Self {
+        LogEntry::NackReceived { label: format!("nack_received: {}", &label)  }
+    }
+    pub fn forward_nack_downstream(label: &str) -> Self {
+        LogEntry::ForwardNackDownstream { label: format!("forward_nack_downstream: {}", &label)  }
+    }
+    pub fn duplicate_request_dropped(label: &str) -> Self {
+        LogEntry::DuplicateRequestDropped { label: format!("duplicate_request_dropped: {}", &label)  }
+    }
+    pub fn hop_limit_exceeded(label: &str) -> Self {
+        LogEntry::HopLimitExceeded { label: format!("hop_limit_exceeded: {}", &label)  }
+    }
+    pub fn unsigned_request_dropped(label: &str) -> Self {
+        LogEntry::UnsignedRequestDropped { label: format!("unsigned_request_dropped: {}", &label)  }
+    }
+    pub fn forged_request_dropped(label: &str) -> Self {
+        LogEntry::ForgedRequestDropped { label: format!("forged_request_dropped: {}", &label)  }
+    }
+    pub fn replayed_request_dropped(label: &str) -> Self {
+        LogEntry::ReplayedRequestDropped { label: format!("replayed_request_dropped: {}", &label)  }
+    }
+    pub fn response_verification_failed(label: &str) -> Self {
+        LogEntry::ResponseVerificationFailed { label: format!("response_verification_failed: {}", &label)  }
+    }
+    pub fn revoked_response_dropped(label: &str) -> Self {
+        LogEntry::RevokedResponseDropped { label: format!("revoked_response_dropped: {}", &label)  }
+    }
+    pub fn link_pin_changed(label: &str) -> Self {
+        LogEntry::LinkPinChanged { label: format!("link_pin_changed: {}", &label)  }
+    }
+    pub fn link_replay_dropped(label: &str) -> Self {
+        LogEntry::LinkReplayDropped { label: format!("link_replay_dropped: {}", &label)  }
+    }
+    pub fn link_suite_refused(label: &str) -> Self {
+        LogEntry::LinkSuiteRefused { label: format!("link_suite_refused: {}", &label)  }
+    }
+    pub fn content_store_failed(label: &str) -> Self {
+        LogEntry::ContentStoreFailed { label: format!("content_store_failed: {}", &label)  }
+    }
 }
 impl fmt::Display for LogEntry {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -187,6 +544,69 @@ impl fmt::Display for LogEntry {
             LogEntry::ForwardRequestUpstream { label } => {
                 format!("{}", label)
             },
+            LogEntry::DecodeFailure { label } => {
+                format!("{}", label)
+            },
+            LogEntry::PendingRequestExpired { label } => {
+                format!("{}", label)
+            },
+            LogEntry::ForwardedRequestExpired { label } => {
+                format!("{}", label)
+            },
+            LogEntry::DefconRateLimited { label } => {
+                format!("{}", label)
+            },
+            LogEntry::DefconUnsignedDropped { label } => {
+                format!("{}", label)
+            },
+            LogEntry::DefconDropped { label } => {
+                format!("{}", label)
+            },
+            LogEntry::DefconLinkChoked { label } => {
+                format!("{}", label)
+            },
+            LogEntry::NackSent { label } => {
+                format!("{}", label)
+            },
+            LogEntry::NackReceived { label } => {
+                format!("{}", label)
+            },
+            LogEntry::ForwardNackDownstream { label } => {
+                format!("{}", label)
+            },
+            LogEntry::DuplicateRequestDropped { label } => {
+                format!("{}", label)
+            },
+            LogEntry::HopLimitExceeded { label } => {
+                format!("{}", label)
+            },
+            LogEntry::UnsignedRequestDropped { label } => {
+                format!("{}", label)
+            },
+            LogEntry::ForgedRequestDropped { label } => {
+                format!("{}", label)
+            },
+            LogEntry::ReplayedRequestDropped { label } => {
+                format!("{}", label)
+            },
+            LogEntry::ResponseVerificationFailed { label } => {
+                format!("{}", label)
+            },
+            LogEntry::RevokedResponseDropped { label } => {
+                format!("{}", label)
+            },
+            LogEntry::LinkPinChanged { label } => {
+                format!("{}", label)
+            },
+            LogEntry::LinkReplayDropped { label } => {
+                format!("{}", label)
+            },
+            LogEntry::LinkSuiteRefused { label } => {
+                format!("{}", label)
+            },
+            LogEntry::ContentStoreFailed { label } => {
+                format!("{}", label)
+            },
             LogEntry::End => {
                 format!("end")
             },
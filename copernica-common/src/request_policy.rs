@@ -0,0 +1,171 @@
+use {
+    crate::{
+        constants,
+        hbfi::{bloom_filter_index, BFI, HBFI},
+        Nonce, NarrowWaistPacket,
+    },
+    anyhow::Result,
+    std::{
+        collections::{BTreeSet, HashMap, HashSet},
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    },
+};
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+/// Which requests must be signed by their `HBFI::request_pid`, and how far a
+/// signed request's timestamp may stray from the local clock.
+#[derive(Clone, Debug)]
+pub struct RequestPolicy {
+    pub signed_apps: HashSet<BFI>,
+    pub replay_window: Duration,
+}
+impl Default for RequestPolicy {
+    fn default() -> Self {
+        Self {
+            signed_apps: HashSet::new(),
+            replay_window: Duration::from_secs(constants::REQUEST_REPLAY_WINDOW_SECS),
+        }
+    }
+}
+impl RequestPolicy {
+    pub fn require_signature(mut self, app: &str) -> Result<Self> {
+        self.signed_apps.insert(bloom_filter_index(app)?);
+        Ok(self)
+    }
+    pub fn requires_signature(&self, hbfi: &HBFI) -> bool {
+        self.signed_apps.contains(&hbfi.app)
+    }
+}
+/// Remembers the nonce of every signed request whose timestamp is still
+/// inside the window, so a captured request can't be sent again while fresh.
+/// At most `REQUEST_REPLAY_NONCES` are kept; the oldest are pushed out and
+/// `floor` rises past their timestamps so they stay refused.
+pub struct ReplayWindow {
+    window: u64,
+    seen: HashMap<Nonce, u64>,
+    order: BTreeSet<(u64, Nonce)>,
+    floor: u64,
+}
+impl ReplayWindow {
+    pub fn new(window: Duration) -> Self {
+        Self { window: window.as_secs(), seen: HashMap::new(), order: BTreeSet::new(), floor: 0 }
+    }
+    pub fn is_fresh(&self, timestamp: u64, now: u64) -> bool {
+        timestamp >= self.floor && timestamp.max(now) - timestamp.min(now) <= self.window
+    }
+    // false when the timestamp is outside the window or the nonce was already admitted
+    pub fn admit(&mut self, nonce: &Nonce, timestamp: u64, now: u64) -> bool {
+        if !self.is_fresh(timestamp, now) {
+            return false
+        }
+        while let Some((oldest, _)) = self.order.iter().next().cloned() {
+            if oldest.saturating_add(self.window) >= now {
+                break
+            }
+            self.forget_oldest();
+        }
+        if self.seen.contains_key(nonce) {
+            return false
+        }
+        self.seen.insert(nonce.clone(), timestamp);
+        self.order.insert((timestamp, nonce.clone()));
+        while self.seen.len() > constants::REQUEST_REPLAY_NONCES {
+            if let Some(oldest) = self.forget_oldest() {
+                self.floor = self.floor.max(oldest + 1);
+            }
+        }
+        true
+    }
+    fn forget_oldest(&mut self) -> Option<u64> {
+        let (timestamp, nonce) = self.order.iter().next().cloned()?;
+        self.order.remove(&(timestamp, nonce.clone()));
+        self.seen.remove(&nonce);
+        Some(timestamp)
+    }
+}
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RequestCheck {
+    Accepted,
+    // the policy wants a signature and there isn't one
+    Unsigned,
+    // signed, but not by the request's `request_pid`
+    Forged,
+    // signed too long ago, or already seen
+    Replayed,
+}
+/// Applies a `RequestPolicy` and a `ReplayWindow` to inbound requests.
+pub struct RequestVerifier {
+    policy: RequestPolicy,
+    replay: ReplayWindow,
+}
+impl RequestVerifier {
+    pub fn new(policy: RequestPolicy) -> Self {
+        let replay = ReplayWindow::new(policy.replay_window);
+        Self { policy, replay }
+    }
+    pub fn check(&mut self, nw: &NarrowWaistPacket) -> RequestCheck {
+        match nw {
+            NarrowWaistPacket::Request { hbfi, signature: None, .. } => {
+                if self.policy.requires_signature(hbfi) {
+                    RequestCheck::Unsigned
+                } else {
+                    RequestCheck::Accepted
+                }
+            },
+            NarrowWaistPacket::Request { nonce, signature: Some(signature), .. } => {
+                match nw.verify() {
+                    Ok(true) => {},
+                    Ok(false) | Err(_) => return RequestCheck::Forged,
+                }
+                if self.replay.admit(nonce, signature.timestamp, unix_time()) {
+                    RequestCheck::Accepted
+                } else {
+                    RequestCheck::Replayed
+                }
+            },
+            NarrowWaistPacket::Response { .. } | NarrowWaistPacket::Nack { .. } => RequestCheck::Accepted,
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PrivateIdentityInterface, PublicIdentityInterface};
+    #[test]
+    fn replays_and_stale_timestamps_are_refused() {
+        let mut window = ReplayWindow::new(Duration::from_secs(30));
+        let now = 1_000;
+        assert!(window.admit(&Nonce([0; 8]), now, now));
+        assert!(!window.admit(&Nonce([0; 8]), now, now));
+        assert!(!window.admit(&Nonce([1; 8]), now - 31, now));
+        assert!(window.admit(&Nonce([0; 8]), now + 40, now + 40));
+    }
+    #[test]
+    fn nonces_pushed_out_stay_refused() {
+        let mut window = ReplayWindow::new(Duration::from_secs(30));
+        let now = 1_000;
+        assert!(window.admit(&Nonce([0xff; 8]), now - 1, now));
+        for n in 0..constants::REQUEST_REPLAY_NONCES as u64 {
+            assert!(window.admit(&Nonce(n.to_be_bytes()), now, now));
+        }
+        assert_eq!(window.seen.len(), constants::REQUEST_REPLAY_NONCES);
+        assert!(!window.admit(&Nonce([0xff; 8]), now - 1, now));
+        assert!(!window.admit(&Nonce([0xfe; 8]), now - 1, now));
+        assert!(window.admit(&Nonce([0xfe; 8]), now, now));
+    }
+    #[test]
+    fn policy_requires_a_valid_signature() {
+        let requester = PrivateIdentityInterface::new_key();
+        let responder = PrivateIdentityInterface::new_key();
+        let request_pid = PublicIdentityInterface::new(requester.public_id());
+        let hbfi = HBFI::new(request_pid, responder.public_id(), "app", "m0d", "fun", "arg").unwrap();
+        let mut verifier = RequestVerifier::new(RequestPolicy::default().require_signature("app").unwrap());
+        let unsigned = NarrowWaistPacket::request(hbfi.clone()).unwrap();
+        assert_eq!(verifier.check(&unsigned), RequestCheck::Unsigned);
+        let signed = NarrowWaistPacket::signed_request(&requester, hbfi.clone()).unwrap();
+        assert_eq!(verifier.check(&signed), RequestCheck::Accepted);
+        assert_eq!(verifier.check(&signed), RequestCheck::Replayed);
+        assert!(NarrowWaistPacket::signed_request(&responder, hbfi).is_err());
+    }
+}
@@ -1,12 +1,14 @@
 use {
     crate::{
-        constants, Data, PublicIdentity, PublicIdentityInterface, PrivateIdentityInterface, Tag, Nonce
+        constants, CipherSuite, Data, PublicIdentity, PublicIdentityInterface, PrivateIdentityInterface, SharedSecret, Tag, Nonce, XNonce,
+        generate_xnonce,
     },
     std::fmt,
     serde::{Deserialize, Serialize},
     anyhow::{anyhow, Result},
     rand::Rng,
-    cryptoxide::{chacha20poly1305::{ChaCha20Poly1305}},
+    cryptoxide::{blake2b::Blake2b, chacha20poly1305::{ChaCha20Poly1305}, digest::Digest as _},
+    zeroize::Zeroizing,
     log::{error},
 };
 #[derive(Clone, Eq, Hash, PartialEq, PartialOrd, Ord, Serialize, Deserialize)]
@@ -18,12 +20,28 @@ pub enum ResponseData {
         data: Data,
         tag: Tag,
     },
+    // sealed with XChaCha20Poly1305 under its own random nonce
+    XCypherText {
+        data: Data,
+        tag: Tag,
+        nonce: XNonce,
+    },
+}
+const RESPONSE_KEY_PATH_V1: &[u8] = b"/copernica/v1/response";
+fn response_key(shared_secret: SharedSecret) -> Zeroizing<[u8; 32]> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    let mut b = Blake2b::new(32);
+    b.input(RESPONSE_KEY_PATH_V1);
+    b.input(shared_secret.as_ref());
+    b.result(&mut key[..]);
+    key
 }
 impl fmt::Display for ResponseData {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match &*self {
             ResponseData::ClearText { data } => write!(f, "RD::ClearText: {}", data),
             ResponseData::CypherText { data, tag } => write!(f, "RD::CypherText: {:?} Tag: {:?}", data, tag),
+            ResponseData::XCypherText { data, tag, nonce } => write!(f, "RD::XCypherText: {:?} Tag: {:?} Nonce: {:?}", data, tag, nonce),
         }
     }
 }
@@ -32,6 +50,7 @@ impl fmt::Debug for ResponseData {
         match &*self {
             ResponseData::ClearText { data } => write!(f, "RD::ClearText: {:?}", data),
             ResponseData::CypherText { data, tag } => write!(f, "RD::CypherText: {:?} Tag: {:?}", data, tag),
+            ResponseData::XCypherText { data, tag, nonce } => write!(f, "RD::XCypherText: {:?} Tag: {:?} Nonce: {:?}", data, tag, nonce),
         }
     }
 }
@@ -42,7 +61,14 @@ impl ResponseData {
     pub fn reconstitute_clear_text(data: Data) -> Self {
         ResponseData::ClearText { data }
     }
+    pub fn reconstitute_xcypher_text(nonce: XNonce, tag: Tag, data: Data) -> Self {
+        ResponseData::XCypherText { nonce, tag, data }
+    }
     pub fn insert(response_sid: PrivateIdentityInterface, request_pid: PublicIdentityInterface, data: Vec<u8>, nonce: Nonce) -> Result<Self> {
+        Self::insert_with_suite(response_sid, request_pid, data, nonce, CipherSuite::ChaCha20Poly1305)
+    }
+    // XChaCha20Poly1305 responses need peers at XNONCE_PROTOCOL_VERSION or later to carry them
+    pub fn insert_with_suite(response_sid: PrivateIdentityInterface, request_pid: PublicIdentityInterface, data: Vec<u8>, nonce: Nonce, suite: CipherSuite) -> Result<Self> {
         match request_pid {
             PublicIdentityInterface::Present { public_identity } => {
                 if data.len() > constants::DATA_SIZE {
@@ -60,6 +86,15 @@ impl ResponseData {
                 let flattened = data.into_iter().flatten().collect::<Vec<u8>>();
                 let mut data: [u8; constants::FRAGMENT_SIZE] = [0; constants::FRAGMENT_SIZE];
                 data.copy_from_slice(&flattened[0..constants::FRAGMENT_SIZE]);
+                if suite == CipherSuite::Aes256Gcm {
+                    return Err(anyhow!("Responses are sealed with ChaCha20Poly1305 or XChaCha20Poly1305, not {:?}", suite))
+                }
+                if suite == CipherSuite::XChaCha20Poly1305 {
+                    let key = response_key(response_sid.shared_secret(nonce, public_identity));
+                    let xnonce = generate_xnonce(&mut rng);
+                    let (encrypted, tag) = suite.seal(&key[..], &xnonce.0, &[], &data)?;
+                    return Ok(ResponseData::XCypherText { data: Data::new(encrypted)?, tag, nonce: xnonce })
+                }
                 let mut nonce_reverse = nonce.clone();
                 nonce_reverse.0.reverse();
                 let shared_secret = response_sid.shared_secret(nonce_reverse, public_identity);
@@ -116,6 +151,23 @@ impl ResponseData {
                     PublicIdentityInterface::Absent => Err(anyhow!("Cannot determine if the Request's PublicIdentity matches the PublicIdentity used to sign or decrypt the encrypted Response"))
                 }
             },
+            ResponseData::XCypherText { data, tag, nonce: xnonce } => {
+                match request_pid {
+                    PublicIdentityInterface::Present { public_identity } => {
+                        if public_identity != request_sid.public_id() {
+                            let err_msg = "The Response's Request_PublicIdentity doesn't match the Public Identity used to sign or decypt the Response";
+                            error!("{}", err_msg);
+                            return Err(anyhow!(err_msg));
+                        }
+                        let key = response_key(request_sid.shared_secret(nonce, response_pid));
+                        match CipherSuite::XChaCha20Poly1305.open(&key[..], &xnonce.0, &[], &data.raw_data(), &tag.0) {
+                            Some(decrypted) => Ok(Data::new(decrypted)?.data()?),
+                            None => Err(anyhow!("Couldn't decrypt the data")),
+                        }
+                    },
+                    PublicIdentityInterface::Absent => Err(anyhow!("Cannot determine if the Request's PublicIdentity matches the PublicIdentity used to sign or decrypt the encrypted Response"))
+                }
+            },
         }
     }
     pub fn manifest_data(&self) -> Vec<u8> {
@@ -124,6 +176,9 @@ impl ResponseData {
             ResponseData::CypherText { data, tag } => {
                 [data.raw_data(), tag.0[..].to_vec()].concat()
             },
+            ResponseData::XCypherText { data, tag, nonce } => {
+                [data.raw_data(), tag.0[..].to_vec(), nonce.0[..].to_vec()].concat()
+            },
         }
     }
 }
@@ -0,0 +1,310 @@
+use {
+    crate::{
+        constants::{CC_SIZE, ID_SIZE, SIG_SIZE, U64_SIZE},
+        request_policy::unix_time,
+        PrivateIdentityInterface, PublicIdentity, PublicIdentityInterface, Signature, HBFI, bloom_filter_index,
+    },
+    anyhow::{anyhow, Result},
+    log::warn,
+    std::{
+        collections::{HashMap, HashSet},
+        convert::TryFrom,
+    },
+};
+const ROTATION_PATH_V1: &[u8] = b"/copernica/v1/rotation";
+const REVOCATION_PATH_V1: &[u8] = b"/copernica/v1/revocation";
+const DELEGATION_PATH_V1: &[u8] = b"/copernica/v1/delegation";
+const PID_SIZE: usize = ID_SIZE + CC_SIZE;
+pub const ROTATION_CERTIFICATE_SIZE: usize = PID_SIZE + PID_SIZE + U64_SIZE + SIG_SIZE;
+pub const REVOCATION_RECORD_SIZE: usize = PID_SIZE + U64_SIZE + SIG_SIZE;
+pub const CHILD_CERTIFICATE_MIN_SIZE: usize = PID_SIZE + PID_SIZE + SIG_SIZE;
+fn pid_bytes(pid: &PublicIdentity) -> Vec<u8> {
+    let mut bytes = pid.key().as_ref().to_vec();
+    bytes.extend_from_slice(pid.chain_code().as_ref());
+    bytes
+}
+fn pid_from(data: &[u8]) -> PublicIdentity {
+    let mut bytes = [0u8; PID_SIZE];
+    bytes.copy_from_slice(&data[..PID_SIZE]);
+    PublicIdentity::from(bytes)
+}
+fn signature_from(data: &[u8]) -> Signature {
+    let mut bytes = [0u8; SIG_SIZE];
+    bytes.copy_from_slice(&data[..SIG_SIZE]);
+    Signature::from(bytes)
+}
+fn u64_from(data: &[u8]) -> u64 {
+    let mut bytes = [0u8; U64_SIZE];
+    bytes.copy_from_slice(&data[..U64_SIZE]);
+    u64::from_be_bytes(bytes)
+}
+/// The old identity vouching that `new` replaces it.
+#[derive(Clone, Eq, PartialEq)]
+pub struct RotationCertificate {
+    pub old: PublicIdentity,
+    pub new: PublicIdentity,
+    pub timestamp: u64,
+    pub signature: Signature,
+}
+impl RotationCertificate {
+    pub fn new(old_sid: &PrivateIdentityInterface, new: PublicIdentity) -> Self {
+        let old = old_sid.public_id();
+        let timestamp = unix_time();
+        let signature = old_sid.signing_key().sign(Self::manifest(&old, &new, timestamp));
+        Self { old, new, timestamp, signature }
+    }
+    fn manifest(old: &PublicIdentity, new: &PublicIdentity, timestamp: u64) -> Vec<u8> {
+        [ROTATION_PATH_V1.to_vec(), pid_bytes(old), pid_bytes(new), timestamp.to_be_bytes().to_vec()].concat()
+    }
+    pub fn verify(&self) -> bool {
+        match self.old.verify_key() {
+            Ok(verify_key) => verify_key.verify(&self.signature, Self::manifest(&self.old, &self.new, self.timestamp)),
+            Err(_) => false,
+        }
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [pid_bytes(&self.old), pid_bytes(&self.new), self.timestamp.to_be_bytes().to_vec(), self.signature.as_ref().to_vec()].concat()
+    }
+}
+impl TryFrom<&[u8]> for RotationCertificate {
+    type Error = anyhow::Error;
+    fn try_from(data: &[u8]) -> Result<Self> {
+        if data.len() != ROTATION_CERTIFICATE_SIZE {
+            return Err(anyhow!("a rotation certificate is {} bytes, not {}", ROTATION_CERTIFICATE_SIZE, data.len()))
+        }
+        Ok(Self {
+            old: pid_from(data),
+            new: pid_from(&data[PID_SIZE..]),
+            timestamp: u64_from(&data[PID_SIZE * 2..]),
+            signature: signature_from(&data[PID_SIZE * 2 + U64_SIZE..]),
+        })
+    }
+}
+/// A parent identity vouching that `child` is the child it derived under
+/// `name`. Children are derived hardened, so nothing else links the two.
+#[derive(Clone, Eq, PartialEq)]
+pub struct ChildCertificate {
+    pub parent: PublicIdentity,
+    pub child: PublicIdentity,
+    pub name: String,
+    pub signature: Signature,
+}
+impl ChildCertificate {
+    pub fn new(parent_sid: &PrivateIdentityInterface, name: &str) -> Self {
+        let parent = parent_sid.public_id();
+        let child = parent_sid.derive_child(name).public_id();
+        let signature = parent_sid.signing_key().sign(Self::manifest(&parent, &child, name));
+        Self { parent, child, name: name.to_string(), signature }
+    }
+    fn manifest(parent: &PublicIdentity, child: &PublicIdentity, name: &str) -> Vec<u8> {
+        [DELEGATION_PATH_V1.to_vec(), pid_bytes(parent), pid_bytes(child), name.as_bytes().to_vec()].concat()
+    }
+    pub fn verify(&self) -> bool {
+        match self.parent.verify_key() {
+            Ok(verify_key) => verify_key.verify(&self.signature, Self::manifest(&self.parent, &self.child, &self.name)),
+            Err(_) => false,
+        }
+    }
+    // the name is last so it needs no length
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [pid_bytes(&self.parent), pid_bytes(&self.child), self.signature.as_ref().to_vec(), self.name.as_bytes().to_vec()].concat()
+    }
+}
+impl TryFrom<&[u8]> for ChildCertificate {
+    type Error = anyhow::Error;
+    fn try_from(data: &[u8]) -> Result<Self> {
+        if data.len() < CHILD_CERTIFICATE_MIN_SIZE {
+            return Err(anyhow!("a child certificate is at least {} bytes, not {}", CHILD_CERTIFICATE_MIN_SIZE, data.len()))
+        }
+        Ok(Self {
+            parent: pid_from(data),
+            child: pid_from(&data[PID_SIZE..]),
+            signature: signature_from(&data[PID_SIZE * 2..]),
+            name: String::from_utf8(data[CHILD_CERTIFICATE_MIN_SIZE..].to_vec())?,
+        })
+    }
+}
+/// An identity declaring itself compromised, signed with its own key so
+/// anyone can check it and anyone can republish it.
+#[derive(Clone, Eq, PartialEq)]
+pub struct RevocationRecord {
+    pub revoked: PublicIdentity,
+    pub timestamp: u64,
+    pub signature: Signature,
+}
+impl RevocationRecord {
+    pub fn new(revoked_sid: &PrivateIdentityInterface) -> Self {
+        let revoked = revoked_sid.public_id();
+        let timestamp = unix_time();
+        let signature = revoked_sid.signing_key().sign(Self::manifest(&revoked, timestamp));
+        Self { revoked, timestamp, signature }
+    }
+    fn manifest(revoked: &PublicIdentity, timestamp: u64) -> Vec<u8> {
+        [REVOCATION_PATH_V1.to_vec(), pid_bytes(revoked), timestamp.to_be_bytes().to_vec()].concat()
+    }
+    pub fn verify(&self) -> bool {
+        match self.revoked.verify_key() {
+            Ok(verify_key) => verify_key.verify(&self.signature, Self::manifest(&self.revoked, self.timestamp)),
+            Err(_) => false,
+        }
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [pid_bytes(&self.revoked), self.timestamp.to_be_bytes().to_vec(), self.signature.as_ref().to_vec()].concat()
+    }
+    // where `publisher` serves this record, so it can be fetched like any other content
+    pub fn hbfi(&self, publisher: PublicIdentity) -> Result<HBFI> {
+        revocation_hbfi(publisher, &self.revoked)
+    }
+}
+impl TryFrom<&[u8]> for RevocationRecord {
+    type Error = anyhow::Error;
+    fn try_from(data: &[u8]) -> Result<Self> {
+        if data.len() != REVOCATION_RECORD_SIZE {
+            return Err(anyhow!("a revocation record is {} bytes, not {}", REVOCATION_RECORD_SIZE, data.len()))
+        }
+        Ok(Self {
+            revoked: pid_from(data),
+            timestamp: u64_from(&data[PID_SIZE..]),
+            signature: signature_from(&data[PID_SIZE + U64_SIZE..]),
+        })
+    }
+}
+const REVOCATION_APP: &str = "copernica";
+const REVOCATION_M0D: &str = "identity";
+const REVOCATION_FUN: &str = "revocation";
+pub fn revocation_hbfi(publisher: PublicIdentity, revoked: &PublicIdentity) -> Result<HBFI> {
+    HBFI::new(PublicIdentityInterface::Absent, publisher, REVOCATION_APP, REVOCATION_M0D, REVOCATION_FUN, &format!("{}", revoked))
+}
+// whether `hbfi` names a revocation record, whoever publishes it
+pub fn is_revocation_hbfi(hbfi: &HBFI) -> bool {
+    match (bloom_filter_index(REVOCATION_APP), bloom_filter_index(REVOCATION_M0D), bloom_filter_index(REVOCATION_FUN)) {
+        (Ok(app), Ok(m0d), Ok(fun)) => hbfi.app == app && hbfi.m0d == m0d && hbfi.fun == fun,
+        _ => false,
+    }
+}
+/// The revocations and rotations a node has accepted. Only records and
+/// certificates whose signature checks out are admitted.
+///
+/// An admitted certificate is never replaced. Its timestamp is chosen by the
+/// signer, so a thief holding the old key could backdate one, and two
+/// identities claiming to succeed the same one mean its key is out of the
+/// owner's hands: it's compromised and has no successor.
+#[derive(Clone, Default)]
+pub struct RevocationList {
+    revoked: HashMap<PublicIdentity, u64>,
+    rotations: HashMap<PublicIdentity, RotationCertificate>,
+    compromised: HashSet<PublicIdentity>,
+}
+impl RevocationList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn revoke(&mut self, record: RevocationRecord) -> Result<()> {
+        if !record.verify() {
+            return Err(anyhow!("the revocation record for {} isn't signed by it", record.revoked))
+        }
+        let timestamp = self.revoked.entry(record.revoked).or_insert(record.timestamp);
+        *timestamp = (*timestamp).min(record.timestamp);
+        Ok(())
+    }
+    pub fn rotate(&mut self, certificate: RotationCertificate) -> Result<()> {
+        if !certificate.verify() {
+            return Err(anyhow!("the rotation certificate from {} isn't signed by it", certificate.old))
+        }
+        match self.rotations.get(&certificate.old) {
+            Some(existing) if existing.new == certificate.new => {},
+            Some(_) => {
+                warn!("conflicting rotation certificates from {}, treating it as compromised", certificate.old);
+                self.compromised.insert(certificate.old);
+            },
+            None => { self.rotations.insert(certificate.old.clone(), certificate); },
+        }
+        Ok(())
+    }
+    pub fn is_revoked(&self, pid: &PublicIdentity) -> bool {
+        self.revoked.contains_key(pid) || self.compromised.contains(pid)
+    }
+    pub fn is_compromised(&self, pid: &PublicIdentity) -> bool {
+        self.compromised.contains(pid)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.revoked.is_empty() && self.rotations.is_empty() && self.compromised.is_empty()
+    }
+    // the current identity `pid` rotated to, skipping certificates issued
+    // after the issuer was revoked, none through a compromised identity
+    pub fn successor(&self, pid: &PublicIdentity) -> Option<PublicIdentity> {
+        let mut seen: HashSet<PublicIdentity> = HashSet::new();
+        let mut current = pid.clone();
+        while seen.insert(current.clone()) {
+            if self.compromised.contains(&current) {
+                break
+            }
+            let certificate = match self.rotations.get(&current) {
+                Some(certificate) => certificate,
+                None => break,
+            };
+            if let Some(revoked_at) = self.revoked.get(&current) {
+                if certificate.timestamp > *revoked_at {
+                    break
+                }
+            }
+            current = certificate.new.clone();
+        }
+        if current == *pid || self.is_revoked(&current) {
+            None
+        } else {
+            Some(current)
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn revoked_identities_point_at_their_successor() {
+        let old = PrivateIdentityInterface::new_key();
+        let new = PrivateIdentityInterface::new_key();
+        let thief = PrivateIdentityInterface::new_key();
+        let mut list = RevocationList::new();
+        let certificate = RotationCertificate::new(&old, new.public_id());
+        let certificate = RotationCertificate::try_from(&certificate.to_bytes()[..]).unwrap();
+        list.rotate(certificate.clone()).unwrap();
+        let record = RevocationRecord::new(&old);
+        let record = RevocationRecord::try_from(&record.to_bytes()[..]).unwrap();
+        assert!(is_revocation_hbfi(&record.hbfi(thief.public_id()).unwrap()));
+        list.revoke(record).unwrap();
+        assert!(list.is_revoked(&old.public_id()));
+        assert_eq!(list.successor(&old.public_id()), Some(new.public_id()));
+        let mut stolen = RotationCertificate::new(&old, thief.public_id());
+        stolen.timestamp = certificate.timestamp + 1;
+        stolen.signature = old.signing_key().sign(RotationCertificate::manifest(&old.public_id(), &thief.public_id(), stolen.timestamp));
+        list.rotate(stolen).unwrap();
+        assert!(list.is_compromised(&old.public_id()));
+        assert_eq!(list.successor(&old.public_id()), None);
+        let forged = RevocationRecord { revoked: new.public_id(), ..RevocationRecord::new(&thief) };
+        assert!(list.revoke(forged).is_err());
+        assert!(!list.is_revoked(&new.public_id()));
+    }
+    #[test]
+    fn backdated_certificates_never_replace_an_admitted_one() {
+        let old = PrivateIdentityInterface::new_key();
+        let new = PrivateIdentityInterface::new_key();
+        let thief = PrivateIdentityInterface::new_key();
+        let mut list = RevocationList::new();
+        let certificate = RotationCertificate::new(&old, new.public_id());
+        list.rotate(certificate.clone()).unwrap();
+        list.rotate(certificate.clone()).unwrap();
+        assert_eq!(list.successor(&old.public_id()), Some(new.public_id()));
+        assert!(!list.is_revoked(&old.public_id()));
+        let mut backdated = RotationCertificate::new(&old, thief.public_id());
+        backdated.timestamp = certificate.timestamp - 3600;
+        backdated.signature = old.signing_key().sign(RotationCertificate::manifest(&old.public_id(), &thief.public_id(), backdated.timestamp));
+        list.rotate(backdated).unwrap();
+        assert!(list.is_revoked(&old.public_id()));
+        assert_eq!(list.successor(&old.public_id()), None);
+        assert!(!list.is_revoked(&new.public_id()));
+        // nor is the thief's identity reachable through a later certificate
+        list.rotate(RotationCertificate::new(&old, thief.public_id())).unwrap();
+        assert_eq!(list.successor(&old.public_id()), None);
+    }
+}
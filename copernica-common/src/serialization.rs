@@ -3,13 +3,17 @@ use {
         constants::*,
         common::*,
         HBFI, ReplyTo, LinkId,
-        NarrowWaistPacket, ResponseData, LinkPacket, BFI,
-        PublicIdentityInterface, PublicIdentity, Signature
+        NarrowWaistPacket, NackReason, RequestSignature, ResponseData, LinkPacket, BFI,
+        PublicIdentityInterface, PublicIdentity, Signature,
+        LinkHeader, LinkPacketKind, NarrowWaistPacketKind, DecodeError,
+        LinkSession, SessionMode, CipherSuite,
+        link_replay::unix_micros,
     },
+    std::convert::TryFrom,
+    rand::RngCore as _,
     macaddr::{MacAddr6, MacAddr8},
-    cryptoxide::{chacha20poly1305::{ChaCha20Poly1305}},
-    log::{trace, error},
-    anyhow::{anyhow, Result},
+    log::{trace},
+    anyhow::{Result},
 };
 fn u16_to_u8(i: u16) -> [u8; 2] {
     [(i >> 8) as u8, i as u8]
@@ -51,6 +55,23 @@ pub fn u64_to_u8(x: u64) -> [u8; 8] {
     ((x  >> 8)  & 0xff) as u8,
     (x          & 0xff) as u8]
 }
+fn slice<'a>(data: &'a [u8], start: usize, end: usize, what: &'static str) -> Result<&'a [u8], DecodeError> {
+    if start > end || end > data.len() {
+        return Err(DecodeError::Truncated { what, needed: end, available: data.len() })
+    }
+    Ok(&data[start..end])
+}
+fn array<const N: usize>(data: &[u8], start: usize, what: &'static str) -> Result<[u8; N], DecodeError> {
+    let mut out = [0u8; N];
+    out.copy_from_slice(slice(data, start, start + N, what)?);
+    Ok(out)
+}
+fn exact_length(data: &[u8], expected: usize, what: &'static str) -> Result<(), DecodeError> {
+    if data.len() != expected {
+        return Err(DecodeError::BadLength { what, expected, found: data.len() })
+    }
+    Ok(())
+}
 pub fn serialize_response_data(rd: &ResponseData) -> (u16, Vec<u8>) {
     let mut buf: Vec<u8> = vec![];
     match rd {
@@ -63,16 +84,33 @@ pub fn serialize_response_data(rd: &ResponseData) -> (u16, Vec<u8>) {
             buf.extend_from_slice(&data.raw_data());
             (buf.len() as u16, buf)
         },
+        ResponseData::XCypherText { data, tag, nonce } => {
+            buf.extend_from_slice(&nonce.0);
+            buf.extend_from_slice(&tag.0);
+            buf.extend_from_slice(&data.raw_data());
+            (buf.len() as u16, buf)
+        },
     }
 }
-pub fn deserialize_cyphertext_response_data(data: &Vec<u8>) -> Result<ResponseData> {
-    let mut tag = Tag([0u8; TAG_SIZE]);
-    tag.0.clone_from_slice(&data[..TAG_SIZE]);
-    let data = Data::new(data[TAG_SIZE..].to_vec())?;
+pub fn deserialize_cyphertext_response_data(data: &[u8]) -> Result<ResponseData, DecodeError> {
+    exact_length(data, CYPHERTEXT_RESPONSE_DATA_SIZE, "cyphertext response data")?;
+    let tag = Tag(array::<TAG_SIZE>(data, 0, "response data tag")?);
+    let data = Data::new(data[TAG_SIZE..].to_vec())
+        .map_err(|_| DecodeError::BadLength { what: "response data", expected: FRAGMENT_SIZE, found: data.len() - TAG_SIZE })?;
     Ok(ResponseData::reconstitute_cypher_text(tag, data))
 }
-pub fn deserialize_cleartext_response_data(data: &Vec<u8>) -> Result<ResponseData> {
-    let data = Data::new(data[..].to_vec())?;
+pub fn deserialize_xcyphertext_response_data(data: &[u8]) -> Result<ResponseData, DecodeError> {
+    exact_length(data, XCYPHERTEXT_RESPONSE_DATA_SIZE, "xcyphertext response data")?;
+    let nonce = XNonce(array::<XNONCE_SIZE>(data, 0, "response data nonce")?);
+    let tag = Tag(array::<TAG_SIZE>(data, XNONCE_SIZE, "response data tag")?);
+    let data = Data::new(data[XNONCE_SIZE + TAG_SIZE..].to_vec())
+        .map_err(|_| DecodeError::BadLength { what: "response data", expected: FRAGMENT_SIZE, found: data.len() - XNONCE_SIZE - TAG_SIZE })?;
+    Ok(ResponseData::reconstitute_xcypher_text(nonce, tag, data))
+}
+pub fn deserialize_cleartext_response_data(data: &[u8]) -> Result<ResponseData, DecodeError> {
+    exact_length(data, CLEARTEXT_RESPONSE_DATA_SIZE, "cleartext response data")?;
+    let data = Data::new(data.to_vec())
+        .map_err(|_| DecodeError::BadLength { what: "response data", expected: FRAGMENT_SIZE, found: data.len() })?;
     Ok(ResponseData::reconstitute_clear_text(data))
 }
 pub fn serialize_hbfi(hbfi: &HBFI) -> Result<(u8, Vec<u8>)> {
@@ -116,122 +154,214 @@ pub fn serialize_hbfi(hbfi: &HBFI) -> Result<(u8, Vec<u8>)> {
     let size = res.len() + req.len() + app.len() + m0d.len() + fun.len() + arg.len() + frm.len() + ids_buf.len();
     Ok((size as u8, buf))
 }
-pub fn deserialize_cyphertext_hbfi(data: &Vec<u8>) -> Result<HBFI> {
+fn deserialize_hbfi_bfis(data: &[u8]) -> Result<(Vec<BFI>, u64), DecodeError> {
     let mut bfis: Vec<BFI> = Vec::with_capacity(BFI_COUNT);
     let mut count = 0;
     for _ in 0..BFI_COUNT {
-        let mut bbfi = [0u8; BFI_BYTE_SIZE];
-        bbfi.clone_from_slice(&data[count..count+BFI_BYTE_SIZE]);
+        let bbfi = array::<BFI_BYTE_SIZE>(data, count, "hbfi bfi")?;
         trace!("des \thbfi {}: \t\t{:?}", count, bbfi.as_ref());
         bfis.push(u8_to_bfi(bbfi));
         count += BFI_BYTE_SIZE;
     }
-    let mut frm = [0u8; U64_SIZE];
-    frm.clone_from_slice(&data[HBFI_OFFSET_START..HBFI_OFFSET_END]);
+    let frm = array::<U64_SIZE>(data, HBFI_OFFSET_START, "hbfi offset")?;
     trace!("des \toffset: \t\t{:?}", frm.as_ref());
-    let frm: u64 = u8_to_u64(frm);
-    let mut res_key = [0u8; ID_SIZE + CC_SIZE];
-    res_key.clone_from_slice(&data[HBFI_RESPONSE_KEY_START..HBFI_RESPONSE_KEY_END]);
+    Ok((bfis, u8_to_u64(frm)))
+}
+pub fn deserialize_cyphertext_hbfi(data: &[u8]) -> Result<HBFI, DecodeError> {
+    exact_length(data, CYPHERTEXT_HBFI_SIZE, "cyphertext hbfi")?;
+    let (bfis, frm) = deserialize_hbfi_bfis(data)?;
+    let res_key = array::<{ ID_SIZE + CC_SIZE }>(data, HBFI_RESPONSE_KEY_START, "hbfi response key")?;
     //trace!("des \tres_key: \t\t{:?}", res_key);
-    let mut req_key = [0u8; ID_SIZE + CC_SIZE];
-    req_key.clone_from_slice(&data[HBFI_REQUEST_KEY_START..HBFI_REQUEST_KEY_END]);
+    let req_key = array::<{ ID_SIZE + CC_SIZE }>(data, HBFI_REQUEST_KEY_START, "hbfi request key")?;
     //trace!("des \treq_key: \t\t{:?}", req_key);
     Ok(HBFI { response_pid: PublicIdentity::from(res_key)
             , request_pid: PublicIdentityInterface::new(PublicIdentity::from(req_key))
             , res: bfis[0], req: bfis[1], app: bfis[2], m0d: bfis[3], fun: bfis[4], arg: bfis[5]
             , frm})
 }
-pub fn deserialize_cleartext_hbfi(data: &Vec<u8>) -> Result<HBFI> {
-    let mut bfis: Vec<BFI> = Vec::with_capacity(BFI_COUNT);
-    let mut count = 0;
-    for _ in 0..BFI_COUNT {
-        let mut bbfi = [0u8; BFI_BYTE_SIZE];
-        bbfi.clone_from_slice(&data[count..count+BFI_BYTE_SIZE]);
-        trace!("des \thbfi {}: \t\t{:?}", count, bbfi.as_ref());
-        bfis.push(u8_to_bfi(bbfi));
-        count += BFI_BYTE_SIZE;
-    }
-    let mut frm = [0u8; U64_SIZE];
-    frm.clone_from_slice(&data[HBFI_OFFSET_START..HBFI_OFFSET_END]);
-    trace!("des \toffset: \t\t{:?}", frm.as_ref());
-    let frm: u64 = u8_to_u64(frm);
-    let mut res_key = [0u8; ID_SIZE + CC_SIZE];
-    res_key.clone_from_slice(&data[HBFI_RESPONSE_KEY_START..HBFI_RESPONSE_KEY_END]);
+pub fn deserialize_cleartext_hbfi(data: &[u8]) -> Result<HBFI, DecodeError> {
+    exact_length(data, CLEARTEXT_HBFI_SIZE, "cleartext hbfi")?;
+    let (bfis, frm) = deserialize_hbfi_bfis(data)?;
+    let res_key = array::<{ ID_SIZE + CC_SIZE }>(data, HBFI_RESPONSE_KEY_START, "hbfi response key")?;
     //trace!("des \tres_key: \t\t{:?}", res_key);
     Ok(HBFI { response_pid: PublicIdentity::from(res_key)
             , request_pid: PublicIdentityInterface::Absent
             , res: bfis[0], req: bfis[1], app: bfis[2], m0d: bfis[3], fun: bfis[4], arg: bfis[5]
             , frm})
 }
-pub fn deserialize_cyphertext_narrow_waist_packet_response(data: &Vec<u8>) -> Result<NarrowWaistPacket> {
-    let mut signature = [0u8; Signature::SIZE];
-    signature.clone_from_slice(&data[CYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_SIG_START..CYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_SIG_END]);
+pub fn deserialize_cyphertext_narrow_waist_packet_response(data: &[u8]) -> Result<NarrowWaistPacket, DecodeError> {
+    exact_length(data, CYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_SIZE, "cyphertext narrow waist response")?;
+    deserialize_sealed_narrow_waist_packet_response(data, CYPHERTEXT_RESPONSE_DATA_SIZE, deserialize_cyphertext_response_data)
+}
+pub fn deserialize_xcyphertext_narrow_waist_packet_response(data: &[u8]) -> Result<NarrowWaistPacket, DecodeError> {
+    exact_length(data, XCYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_SIZE, "xcyphertext narrow waist response")?;
+    deserialize_sealed_narrow_waist_packet_response(data, XCYPHERTEXT_RESPONSE_DATA_SIZE, deserialize_xcyphertext_response_data)
+}
+fn deserialize_sealed_narrow_waist_packet_response(data: &[u8], response_data_size: usize, response_data: fn(&[u8]) -> Result<ResponseData, DecodeError>) -> Result<NarrowWaistPacket, DecodeError> {
+    let signature = array::<{ Signature::SIZE }>(data, CYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_SIG_START, "response signature")?;
     trace!("des \tsignature: \t\t{:?}", signature.as_ref());
     let signature: Signature = Signature::from(signature);
-    let mut nonce = Nonce([0u8; NONCE_SIZE]);
-    nonce.0.clone_from_slice(&data[CYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_NONCE_START..CYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_NONCE_END]);
+    let nonce = Nonce(array::<NONCE_SIZE>(data, CYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_NONCE_START, "response nonce")?);
     trace!("des \tnonce: \t\t\t{:?}", nonce);
     let hbfi_end = CYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_NONCE_END + CYPHERTEXT_HBFI_SIZE;
-    let response_data_end = hbfi_end + CYPHERTEXT_RESPONSE_DATA_SIZE;
-    let hbfi: HBFI = deserialize_cyphertext_hbfi(&data[CYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_NONCE_END..hbfi_end].to_vec())?;
-    let data: ResponseData = deserialize_cyphertext_response_data(&data[hbfi_end..response_data_end].to_vec())?;
+    let response_data_end = hbfi_end + response_data_size;
+    let hbfi: HBFI = deserialize_cyphertext_hbfi(slice(data, CYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_NONCE_END, hbfi_end, "response hbfi")?)?;
+    let data: ResponseData = response_data(slice(data, hbfi_end, response_data_end, "response data")?)?;
     let nw: NarrowWaistPacket = NarrowWaistPacket::Response { hbfi, signature, nonce, data };
     Ok(nw)
 }
-pub fn deserialize_cleartext_narrow_waist_packet_response(data: &Vec<u8>) -> Result<NarrowWaistPacket> {
-    let mut signature = [0u8; Signature::SIZE];
-    signature.clone_from_slice(&data[CLEARTEXT_NARROW_WAIST_PACKET_RESPONSE_SIG_START..CLEARTEXT_NARROW_WAIST_PACKET_RESPONSE_SIG_END]);
+pub fn deserialize_cleartext_narrow_waist_packet_response(data: &[u8]) -> Result<NarrowWaistPacket, DecodeError> {
+    exact_length(data, CLEARTEXT_NARROW_WAIST_PACKET_RESPONSE_SIZE, "cleartext narrow waist response")?;
+    let signature = array::<{ Signature::SIZE }>(data, CLEARTEXT_NARROW_WAIST_PACKET_RESPONSE_SIG_START, "response signature")?;
     trace!("des \tsignature: \t\t{:?}", signature.as_ref());
     let signature: Signature = Signature::from(signature);
-    let mut nonce = Nonce([0u8; NONCE_SIZE]);
-    nonce.0.clone_from_slice(&data[CLEARTEXT_NARROW_WAIST_PACKET_RESPONSE_NONCE_START..CLEARTEXT_NARROW_WAIST_PACKET_RESPONSE_NONCE_END]);
+    let nonce = Nonce(array::<NONCE_SIZE>(data, CLEARTEXT_NARROW_WAIST_PACKET_RESPONSE_NONCE_START, "response nonce")?);
     trace!("des \tnonce: \t\t\t{:?}", nonce);
     let hbfi_end = CLEARTEXT_NARROW_WAIST_PACKET_RESPONSE_NONCE_END + CLEARTEXT_HBFI_SIZE;
     let response_data_end = hbfi_end + CLEARTEXT_RESPONSE_DATA_SIZE;
-    let hbfi: HBFI = deserialize_cleartext_hbfi(&data[CLEARTEXT_NARROW_WAIST_PACKET_RESPONSE_NONCE_END..hbfi_end].to_vec())?;
-    let data: ResponseData = deserialize_cleartext_response_data(&data[hbfi_end..response_data_end].to_vec())?;
+    let hbfi: HBFI = deserialize_cleartext_hbfi(slice(data, CLEARTEXT_NARROW_WAIST_PACKET_RESPONSE_NONCE_END, hbfi_end, "response hbfi")?)?;
+    let data: ResponseData = deserialize_cleartext_response_data(slice(data, hbfi_end, response_data_end, "response data")?)?;
     let nw: NarrowWaistPacket = NarrowWaistPacket::Response { hbfi, signature, nonce, data };
     Ok(nw)
 }
-pub fn deserialize_cyphertext_narrow_waist_packet_request(data: &Vec<u8>) -> Result<NarrowWaistPacket> {
-    let mut nonce = Nonce([0u8; NONCE_SIZE]);
-    nonce.0.clone_from_slice(&data[0..NONCE_SIZE]);
-    let hbfi: HBFI = deserialize_cyphertext_hbfi(&data[NONCE_SIZE..NONCE_SIZE+CYPHERTEXT_HBFI_SIZE].to_vec())?;
-    let nw: NarrowWaistPacket = NarrowWaistPacket::Request { hbfi, nonce };
-    Ok(nw)
+fn deserialize_request_signature(data: &[u8]) -> Result<Option<RequestSignature>, DecodeError> {
+    let signed = slice(data, NARROW_WAIST_PACKET_REQUEST_SIGNED_START, NARROW_WAIST_PACKET_REQUEST_SIGNED_END, "request signed")?[0];
+    let timestamp = u8_to_u64(array::<U64_SIZE>(data, NARROW_WAIST_PACKET_REQUEST_TIMESTAMP_START, "request timestamp")?);
+    let signature = array::<{ Signature::SIZE }>(data, NARROW_WAIST_PACKET_REQUEST_SIG_START, "request signature")?;
+    match signed {
+        0 => Ok(None),
+        1 => Ok(Some(RequestSignature { timestamp, signature: Signature::from(signature) })),
+        value => Err(DecodeError::UnknownKind { what: "request signed flag", value }),
+    }
+}
+pub fn deserialize_cyphertext_narrow_waist_packet_request(data: &[u8]) -> Result<NarrowWaistPacket, DecodeError> {
+    exact_length(data, CYPHERTEXT_NARROW_WAIST_PACKET_REQUEST_SIZE, "cyphertext narrow waist request")?;
+    deserialize_narrow_waist_packet_request(data, PROTOCOL_VERSION, CYPHERTEXT_HBFI_SIZE, deserialize_cyphertext_hbfi)
 }
-pub fn deserialize_cleartext_narrow_waist_packet_request(data: &Vec<u8>) -> Result<NarrowWaistPacket> {
-    let mut nonce = Nonce([0u8; NONCE_SIZE]);
-    nonce.0.clone_from_slice(&data[0..NONCE_SIZE]);
-    let hbfi: HBFI = deserialize_cleartext_hbfi(&data[NONCE_SIZE..NONCE_SIZE+CLEARTEXT_HBFI_SIZE].to_vec())?;
-    let nw: NarrowWaistPacket = NarrowWaistPacket::Request { hbfi, nonce };
+pub fn deserialize_cleartext_narrow_waist_packet_request(data: &[u8]) -> Result<NarrowWaistPacket, DecodeError> {
+    exact_length(data, CLEARTEXT_NARROW_WAIST_PACKET_REQUEST_SIZE, "cleartext narrow waist request")?;
+    deserialize_narrow_waist_packet_request(data, PROTOCOL_VERSION, CLEARTEXT_HBFI_SIZE, deserialize_cleartext_hbfi)
+}
+// requests from before SIGNED_REQUEST_PROTOCOL_VERSION have no signature
+// fields, the HBFI follows the hop limit
+fn deserialize_narrow_waist_packet_request(data: &[u8], version: u8, hbfi_size: usize, hbfi: fn(&[u8]) -> Result<HBFI, DecodeError>) -> Result<NarrowWaistPacket, DecodeError> {
+    let nonce = Nonce(array::<NONCE_SIZE>(data, NARROW_WAIST_PACKET_REQUEST_NONCE_START, "request nonce")?);
+    let lifetime = u8_to_u16(array::<REQUEST_LIFETIME_SIZE>(data, NARROW_WAIST_PACKET_REQUEST_LIFETIME_START, "request lifetime")?);
+    let hop_limit = slice(data, NARROW_WAIST_PACKET_REQUEST_HOP_LIMIT_START, NARROW_WAIST_PACKET_REQUEST_HOP_LIMIT_END, "request hop limit")?[0];
+    let (signature, hbfi_start) = match version < SIGNED_REQUEST_PROTOCOL_VERSION {
+        true => (None, NARROW_WAIST_PACKET_REQUEST_HOP_LIMIT_END),
+        false => (deserialize_request_signature(data)?, NARROW_WAIST_PACKET_REQUEST_SIG_END),
+    };
+    let hbfi: HBFI = hbfi(slice(data, hbfi_start, hbfi_start + hbfi_size, "request hbfi")?)?;
+    let nw: NarrowWaistPacket = NarrowWaistPacket::Request { hbfi, nonce, lifetime, hop_limit, signature };
     Ok(nw)
 }
-pub fn deserialize_narrow_waist_packet(data: &Vec<u8>) -> Result<NarrowWaistPacket> {
-    match data.len() {
-        CYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_SIZE => {
+fn deserialize_narrow_waist_packet_nack(data: &[u8], hbfi_size: usize) -> Result<(Nonce, NackReason, Option<Signature>, &[u8]), DecodeError> {
+    let nonce = Nonce(array::<NONCE_SIZE>(data, NARROW_WAIST_PACKET_NACK_NONCE_START, "nack nonce")?);
+    let reason = NackReason::try_from(slice(data, NARROW_WAIST_PACKET_NACK_REASON_START, NARROW_WAIST_PACKET_NACK_REASON_END, "nack reason")?[0])?;
+    let signed = slice(data, NARROW_WAIST_PACKET_NACK_SIGNED_START, NARROW_WAIST_PACKET_NACK_SIGNED_END, "nack signed")?[0];
+    let signature = array::<{ Signature::SIZE }>(data, NARROW_WAIST_PACKET_NACK_SIG_START, "nack signature")?;
+    let signature = match signed {
+        0 => None,
+        1 => Some(Signature::from(signature)),
+        value => return Err(DecodeError::UnknownKind { what: "nack signed flag", value }),
+    };
+    let hbfi = slice(data, NARROW_WAIST_PACKET_NACK_SIG_END, NARROW_WAIST_PACKET_NACK_SIG_END + hbfi_size, "nack hbfi")?;
+    Ok((nonce, reason, signature, hbfi))
+}
+pub fn deserialize_cyphertext_narrow_waist_packet_nack(data: &[u8]) -> Result<NarrowWaistPacket, DecodeError> {
+    exact_length(data, CYPHERTEXT_NARROW_WAIST_PACKET_NACK_SIZE, "cyphertext narrow waist nack")?;
+    let (nonce, reason, signature, hbfi) = deserialize_narrow_waist_packet_nack(data, CYPHERTEXT_HBFI_SIZE)?;
+    let hbfi: HBFI = deserialize_cyphertext_hbfi(hbfi)?;
+    Ok(NarrowWaistPacket::Nack { hbfi, nonce, reason, signature })
+}
+pub fn deserialize_cleartext_narrow_waist_packet_nack(data: &[u8]) -> Result<NarrowWaistPacket, DecodeError> {
+    exact_length(data, CLEARTEXT_NARROW_WAIST_PACKET_NACK_SIZE, "cleartext narrow waist nack")?;
+    let (nonce, reason, signature, hbfi) = deserialize_narrow_waist_packet_nack(data, CLEARTEXT_HBFI_SIZE)?;
+    let hbfi: HBFI = deserialize_cleartext_hbfi(hbfi)?;
+    Ok(NarrowWaistPacket::Nack { hbfi, nonce, reason, signature })
+}
+pub fn narrow_waist_packet_size(kind: NarrowWaistPacketKind) -> usize {
+    narrow_waist_packet_size_at(kind, PROTOCOL_VERSION)
+}
+// the size of the narrow waist layout a peer at `version` uses
+pub fn narrow_waist_packet_size_at(kind: NarrowWaistPacketKind, version: u8) -> usize {
+    let unsigned = version < SIGNED_REQUEST_PROTOCOL_VERSION;
+    match kind {
+        NarrowWaistPacketKind::CyphertextResponse => CYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_SIZE,
+        NarrowWaistPacketKind::XCyphertextResponse => XCYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_SIZE,
+        NarrowWaistPacketKind::CyphertextRequest if unsigned => CYPHERTEXT_NARROW_WAIST_PACKET_UNSIGNED_REQUEST_SIZE,
+        NarrowWaistPacketKind::CyphertextRequest => CYPHERTEXT_NARROW_WAIST_PACKET_REQUEST_SIZE,
+        NarrowWaistPacketKind::CleartextResponse => CLEARTEXT_NARROW_WAIST_PACKET_RESPONSE_SIZE,
+        NarrowWaistPacketKind::CleartextRequest if unsigned => CLEARTEXT_NARROW_WAIST_PACKET_UNSIGNED_REQUEST_SIZE,
+        NarrowWaistPacketKind::CleartextRequest => CLEARTEXT_NARROW_WAIST_PACKET_REQUEST_SIZE,
+        NarrowWaistPacketKind::CyphertextNack => CYPHERTEXT_NARROW_WAIST_PACKET_NACK_SIZE,
+        NarrowWaistPacketKind::CleartextNack => CLEARTEXT_NARROW_WAIST_PACKET_NACK_SIZE,
+    }
+}
+pub fn deserialize_narrow_waist_packet(kind: NarrowWaistPacketKind, data: &[u8]) -> Result<NarrowWaistPacket, DecodeError> {
+    deserialize_narrow_waist_packet_at(kind, PROTOCOL_VERSION, data)
+}
+pub fn deserialize_narrow_waist_packet_at(kind: NarrowWaistPacketKind, version: u8, data: &[u8]) -> Result<NarrowWaistPacket, DecodeError> {
+    exact_length(data, narrow_waist_packet_size_at(kind, version), "narrow waist packet")?;
+    match kind {
+        NarrowWaistPacketKind::CyphertextResponse => {
             deserialize_cyphertext_narrow_waist_packet_response(data)
         },
-        CYPHERTEXT_NARROW_WAIST_PACKET_REQUEST_SIZE => {
-            deserialize_cyphertext_narrow_waist_packet_request(data)
+        NarrowWaistPacketKind::XCyphertextResponse => {
+            deserialize_xcyphertext_narrow_waist_packet_response(data)
         },
-        CLEARTEXT_NARROW_WAIST_PACKET_RESPONSE_SIZE => {
+        NarrowWaistPacketKind::CyphertextRequest => {
+            deserialize_narrow_waist_packet_request(data, version, CYPHERTEXT_HBFI_SIZE, deserialize_cyphertext_hbfi)
+        },
+        NarrowWaistPacketKind::CleartextResponse => {
             deserialize_cleartext_narrow_waist_packet_response(data)
         },
-        CLEARTEXT_NARROW_WAIST_PACKET_REQUEST_SIZE => {
-            deserialize_cleartext_narrow_waist_packet_request(data)
+        NarrowWaistPacketKind::CleartextRequest => {
+            deserialize_narrow_waist_packet_request(data, version, CLEARTEXT_HBFI_SIZE, deserialize_cleartext_hbfi)
+        },
+        NarrowWaistPacketKind::CyphertextNack => {
+            deserialize_cyphertext_narrow_waist_packet_nack(data)
+        },
+        NarrowWaistPacketKind::CleartextNack => {
+            deserialize_cleartext_narrow_waist_packet_nack(data)
         },
-        _ => { return Err(anyhow!("Cannot deserialize this as the size is unknown")) }
     }
 }
 pub fn serialize_narrow_waist_packet(nw: &NarrowWaistPacket) -> Result<(u16, Vec<u8>)> {
+    serialize_narrow_waist_packet_at(nw, PROTOCOL_VERSION)
+}
+// in the layout a peer at `version` reads, a request's signature is left out
+// below SIGNED_REQUEST_PROTOCOL_VERSION
+pub fn serialize_narrow_waist_packet_at(nw: &NarrowWaistPacket, version: u8) -> Result<(u16, Vec<u8>)> {
     let mut buf: Vec<u8> = vec![];
     let size: u16;
     match nw {
-        NarrowWaistPacket::Request { hbfi, nonce } => {
+        NarrowWaistPacket::Request { hbfi, nonce, lifetime, hop_limit, .. } if version < SIGNED_REQUEST_PROTOCOL_VERSION => {
             let (hbfi_size, hbfi) = serialize_hbfi(&hbfi)?;
-            size = hbfi_size as u16 + nonce.0.len() as u16;
+            size = hbfi_size as u16 + NARROW_WAIST_PACKET_REQUEST_HOP_LIMIT_END as u16;
             buf.extend_from_slice(&nonce.0);
+            buf.extend_from_slice(&u16_to_u8(*lifetime));
+            buf.extend_from_slice(&[*hop_limit]);
+            buf.extend_from_slice(&hbfi);
+        },
+        NarrowWaistPacket::Request { hbfi, nonce, lifetime, hop_limit, signature } => {
+            let (hbfi_size, hbfi) = serialize_hbfi(&hbfi)?;
+            size = hbfi_size as u16 + NARROW_WAIST_PACKET_REQUEST_SIG_END as u16;
+            buf.extend_from_slice(&nonce.0);
+            buf.extend_from_slice(&u16_to_u8(*lifetime));
+            buf.extend_from_slice(&[*hop_limit]);
+            match signature {
+                Some(RequestSignature { timestamp, signature }) => {
+                    buf.extend_from_slice(&[1]);
+                    buf.extend_from_slice(&u64_to_u8(*timestamp));
+                    buf.extend_from_slice(signature.as_ref());
+                },
+                None => {
+                    buf.extend_from_slice(&[0]);
+                    buf.extend_from_slice(&[0; U64_SIZE + SIG_SIZE]);
+                },
+            }
             buf.extend_from_slice(&hbfi);
         },
         NarrowWaistPacket::Response { hbfi, signature, nonce, data } => {
@@ -248,6 +378,23 @@ pub fn serialize_narrow_waist_packet(nw: &NarrowWaistPacket) -> Result<(u16, Vec
             buf.extend_from_slice(&hbfi);
             buf.extend_from_slice(&response_data);
         },
+        NarrowWaistPacket::Nack { hbfi, nonce, reason, signature } => {
+            let (hbfi_size, hbfi) = serialize_hbfi(&hbfi)?;
+            size = hbfi_size as u16 + NARROW_WAIST_PACKET_NACK_SIG_END as u16;
+            buf.extend_from_slice(&nonce.0);
+            buf.extend_from_slice(&[*reason as u8]);
+            match signature {
+                Some(signature) => {
+                    buf.extend_from_slice(&[1]);
+                    buf.extend_from_slice(signature.as_ref());
+                },
+                None => {
+                    buf.extend_from_slice(&[0]);
+                    buf.extend_from_slice(&[0; SIG_SIZE]);
+                },
+            }
+            buf.extend_from_slice(&hbfi);
+        },
     }
     Ok((size, buf))
 }
@@ -286,57 +433,107 @@ fn serialize_reply_to(rt: &ReplyTo) -> Result<(u8, Vec<u8>)> {
     Ok((size, buf))
 }
 
-fn deserialize_reply_to(data: &Vec<u8>) -> Result<ReplyTo> {
+fn deserialize_reply_to(data: &[u8]) -> Result<ReplyTo, DecodeError> {
+    let bad_reply_to = |_| DecodeError::BadReplyTo { size: data.len() };
     let rt = match data.len() as usize {
         TO_REPLY_TO_MPSC => {
             ReplyTo::Mpsc
         },
         TO_REPLY_TO_UDPIP4 => {
-            let address = &data[..];
-            let address = bincode::deserialize(&address)?;
+            let address = bincode::deserialize(data).map_err(bad_reply_to)?;
             ReplyTo::UdpIp(address)
         },
         TO_REPLY_TO_UDPIP6 => {
-            let address = &data[..];
-            let address = bincode::deserialize(&address)?;
+            let address = bincode::deserialize(data).map_err(bad_reply_to)?;
             ReplyTo::UdpIp(address)
         },
         TO_REPLY_TO_MACADDR6 => {
-            let mut address = [0u8; 6];
-            address.copy_from_slice(&data[..]);
-            let address = MacAddr6::from(address);
+            let address = MacAddr6::from(array::<TO_REPLY_TO_MACADDR6>(data, 0, "reply_to macaddr6")?);
             ReplyTo::MacAddr6(address)
         },
         TO_REPLY_TO_MACADDR8 => {
-            let mut address = [0u8; 8];
-            address.copy_from_slice(&data[..]);
-            let address = MacAddr8::from(address);
+            let address = MacAddr8::from(array::<TO_REPLY_TO_MACADDR8>(data, 0, "reply_to macaddr8")?);
             ReplyTo::MacAddr8(address)
         },
         TO_REPLY_TO_RF => {
-            let address = &data[..];
-            let address = bincode::deserialize(&address)?;
+            let address = bincode::deserialize(data).map_err(bad_reply_to)?;
             ReplyTo::Rf(address)
         },
-        _ => return Err(anyhow!("Deserializing ReplyTo hit an unrecognised type or variation"))
+        size => return Err(DecodeError::BadReplyTo { size })
     };
     Ok(rt)
 }
 
+pub fn serialize_link_header(header: &LinkHeader) -> Vec<u8> {
+    let mut buf = vec![0u8; header.size()];
+    buf[LINK_HEADER_VERSION_START] = header.version;
+    buf[LINK_HEADER_LINK_KIND_START] = header.link_kind as u8;
+    buf[LINK_HEADER_NARROW_WAIST_KIND_START] = header.nw_kind as u8;
+    if header.names_suite() {
+        buf[LINK_HEADER_SUITE_START] = header.suite as u8;
+        buf[LINK_HEADER_ACCEPTS_START] = header.accepts;
+    }
+    buf
+}
+pub fn deserialize_link_header(data: &[u8]) -> Result<LinkHeader, DecodeError> {
+    let header = slice(data, LINK_HEADER_VERSION_START, LINK_HEADER_SIZE, "link header")?;
+    let version = header[LINK_HEADER_VERSION_START];
+    if !LinkHeader::is_supported_version(version) {
+        return Err(DecodeError::UnsupportedVersion { version, min: MIN_PROTOCOL_VERSION, max: PROTOCOL_VERSION })
+    }
+    let link_kind = LinkPacketKind::try_from(header[LINK_HEADER_LINK_KIND_START])?;
+    let nw_kind = NarrowWaistPacketKind::try_from(header[LINK_HEADER_NARROW_WAIST_KIND_START])?;
+    if link_kind.min_version() > version {
+        return Err(DecodeError::UnknownKind { what: "LinkPacketKind", value: link_kind as u8 })
+    }
+    if nw_kind.min_version() > version {
+        return Err(DecodeError::UnknownKind { what: "NarrowWaistPacketKind", value: nw_kind as u8 })
+    }
+    let mut header = LinkHeader::new_with_version(version, link_kind, nw_kind);
+    if header.names_suite() {
+        let suite = slice(data, LINK_HEADER_SUITE_START, SUITE_LINK_HEADER_SIZE, "link header suite")?;
+        header.suite = CipherSuite::try_from(suite[0])?;
+        header.accepts = suite[1];
+    }
+    trace!("des link_header: \t\t{}", header);
+    Ok(header)
+}
 pub fn serialize_link_packet(lp: &LinkPacket, link_id: LinkId) -> Result<Vec<u8>> {
+    serialize_link_packet_with_version(lp, link_id, PROTOCOL_VERSION)
+}
+// the 8 byte nonce the link keys are derived with, the tail of the wire nonce
+fn key_nonce(wire_nonce: &[u8]) -> Nonce {
+    let mut nonce = Nonce([0; NONCE_SIZE]);
+    nonce.0.copy_from_slice(&wire_nonce[wire_nonce.len() - NONCE_SIZE..]);
+    nonce
+}
+pub fn serialize_link_packet_with_version(lp: &LinkPacket, link_id: LinkId, version: u8) -> Result<Vec<u8>> {
+    serialize_link_packet_with_header(lp, link_id, unix_micros(), |link_kind, nw_kind| LinkHeader::new_with_version(version, link_kind, nw_kind))
+}
+// `stamp` ends every cyphertext nonce, peers from before
+// TIMESTAMP_PROTOCOL_VERSION take it as random like the rest
+fn serialize_link_packet_with_header<F>(lp: &LinkPacket, link_id: LinkId, stamp: u64, link_header: F) -> Result<Vec<u8>>
+where
+    F: Fn(LinkPacketKind, NarrowWaistPacketKind) -> LinkHeader,
+{
     let mut buf: Vec<u8> = vec![];
     let lnk_tx_pid = link_id.link_pid()?;
+    let nw_kind = NarrowWaistPacketKind::from_narrow_waist(&lp.narrow_waist());
     match link_id.remote_link_pid()? {
         PublicIdentityInterface::Absent => {
             let reply_to = lp.reply_to();
             let nw = lp.narrow_waist();
+            let link_header = link_header(LinkPacketKind::ClearText, nw_kind);
+            let header = serialize_link_header(&link_header);
+            buf.extend_from_slice(&header);
+            trace!("ser link_header: \t\t{:?}", header);
             buf.extend_from_slice(lnk_tx_pid.key().as_ref());
             trace!("ser link_key: \t\t\t{:?}", lnk_tx_pid.key().as_ref());
             buf.extend_from_slice(lnk_tx_pid.chain_code().as_ref());
             trace!("ser link_ccd: \t\t\t{:?}", lnk_tx_pid.chain_code().as_ref());
             let (reply_to_size, reply_to) = serialize_reply_to(&reply_to)?;
             trace!("ser reply_to_size: \t\t{:?}", reply_to_size);
-            let (nw_size, nw) = serialize_narrow_waist_packet(&nw)?;
+            let (nw_size, nw) = serialize_narrow_waist_packet_at(&nw, link_header.version)?;
             trace!("ser nw_size: \t\t\t{:?}", nw_size);
             buf.extend_from_slice(&[reply_to_size]);
             buf.extend_from_slice(&u16_to_u8(nw_size));
@@ -348,26 +545,29 @@ pub fn serialize_link_packet(lp: &LinkPacket, link_id: LinkId) -> Result<Vec<u8>
 
             let reply_to = lp.reply_to();
             let nw = lp.narrow_waist();
+    // Header
+            let link_header = link_header(LinkPacketKind::CypherText, nw_kind);
+            let suite = link_header.cipher_suite();
+            let header = serialize_link_header(&link_header);
+            buf.extend_from_slice(&header);
+            trace!("ser link_header: \t\t{:?}", header);
     // Link Pid
             buf.extend_from_slice(lnk_tx_pid.key().as_ref());
             trace!("ser link_tx_pk: \t\t{:?}", lnk_tx_pid.key().as_ref());
     // Link CC
             buf.extend_from_slice(lnk_tx_pid.chain_code().as_ref());
             trace!("ser link_cc_pk: \t\t{:?}", lnk_tx_pid.chain_code().as_ref());
-    // Nonce
-            let mut rng = rand::thread_rng();
-            let nonce: Nonce = generate_nonce(&mut rng);
-            buf.extend_from_slice(&nonce.0);
+    // Nonce, random bytes ahead of the stamp
+            let mut nonce = vec![0u8; suite.nonce_size() - NONCE_SIZE];
+            rand::thread_rng().fill_bytes(&mut nonce);
+            nonce.extend_from_slice(&stamp.to_be_bytes());
+            buf.extend_from_slice(&nonce);
             trace!("ser link_nonce: \t\t{:?}", nonce);
-    // Tag
-            let mut tag = Tag([0; TAG_SIZE]);
-            let shared_secret = link_id.shared_secret(nonce.clone(), lnk_rx_pid)?;
-            let mut ctx = ChaCha20Poly1305::new(&shared_secret.as_ref(), &nonce.0, &[]);
+    // Tag, the header is authenticated as associated data
+            let shared_secret = link_id.shared_secret(key_nonce(&nonce), lnk_rx_pid)?;
+            let (nws_size, nws) = serialize_narrow_waist_packet_at(&nw, link_header.version)?;
+            let (encrypted, tag) = suite.seal(shared_secret.as_ref(), &nonce, &header, &nws)?;
             drop(shared_secret);
-            let (nws_size, mut nws) = serialize_narrow_waist_packet(&nw)?;
-            let mut encrypted = vec![0u8; nws.len()];
-            ctx.encrypt(&nws, &mut encrypted[..], &mut tag.0);
-            nws.copy_from_slice(&encrypted[..]);
             buf.extend_from_slice(&tag.0);
             trace!("ser link_tag: \t\t\t{:?}", tag);
     // Reply To Size
@@ -376,157 +576,354 @@ pub fn serialize_link_packet(lp: &LinkPacket, link_id: LinkId) -> Result<Vec<u8>
             trace!("ser link_reply_to_size: \t{:?} actual_size: {}", [reply_to_size], reply_to.len());
     // Narrow Waist Size
             buf.extend_from_slice(&u16_to_u8(nws_size));
-            trace!("ser nw_size: \t\t\t{:?} as_u16: {} actual {}", u16_to_u8(nws_size), nws_size, nws.len());
+            trace!("ser nw_size: \t\t\t{:?} as_u16: {} actual {}", u16_to_u8(nws_size), nws_size, encrypted.len());
             buf.extend_from_slice(&reply_to);
 
     // Narrow Waist
-            buf.extend_from_slice(&nws);
+            buf.extend_from_slice(&encrypted);
         },
     }
     Ok(buf)
 }
 
-pub fn deserialize_cyphertext_link_packet(data: &Vec<u8>, link_id: LinkId) -> Result<(PublicIdentity, LinkPacket)> {
+pub fn deserialize_cyphertext_link_packet(data: &[u8], header: LinkHeader, link_id: LinkId) -> Result<(PublicIdentity, LinkPacket), DecodeError> {
+    let suite = header.cipher_suite();
+    let shift = header.size() - LINK_HEADER_SIZE;
 // Link Pid
-    let mut link_tx_pk_with_cc = [0u8; ID_SIZE + CC_SIZE];
-    link_tx_pk_with_cc.clone_from_slice(&data[CYPHERTEXT_LINK_TX_PK_START..CYPHERTEXT_LINK_TX_PK_END]);
+    let link_tx_pk_with_cc = array::<{ ID_SIZE + CC_SIZE }>(data, shift + CYPHERTEXT_LINK_TX_PK_START, "link tx public key")?;
     //trace!("des link_tx_pk: \t\t{:?}", link_tx_pk);
     let lnk_tx_pid: PublicIdentity = PublicIdentity::from(link_tx_pk_with_cc);
-// Nonce
-    let mut link_nonce = Nonce([0u8; NONCE_SIZE]);
-    link_nonce.0.clone_from_slice(&data[CYPHERTEXT_LINK_NONCE_START..CYPHERTEXT_LINK_NONCE_END]);
+// Nonce, its length depends on the cipher suite so everything after it is relative
+    let tag_start = shift + CYPHERTEXT_LINK_NONCE_START + suite.nonce_size();
+    let link_nonce = slice(data, shift + CYPHERTEXT_LINK_NONCE_START, tag_start, "link nonce")?;
     trace!("des link_nonce: \t\t{:?}", link_nonce);
 // Tag
-    let mut link_tag = [0u8; TAG_SIZE];
-    link_tag.clone_from_slice(&data[CYPHERTEXT_LINK_TAG_START..CYPHERTEXT_LINK_TAG_END]);
+    let link_tag = slice(data, tag_start, tag_start + TAG_SIZE, "link tag")?;
     trace!("des link_tag: \t\t\t{:?}", link_tag);
 // Reply To Length
-    let reply_to_size = &data[CYPHERTEXT_LINK_REPLY_TO_SIZE_START..CYPHERTEXT_LINK_REPLY_TO_SIZE_END];
+    let reply_to_size_start = tag_start + TAG_SIZE;
+    let reply_to_size = slice(data, reply_to_size_start, reply_to_size_start + ONE_BYTE, "reply_to size")?[0] as usize;
     trace!("des reply_to_size: \t\t{:?}", reply_to_size);
 // Narrow Waist Length
-    let mut nw_size = [0u8; 2];
-    nw_size.clone_from_slice(&data[CYPHERTEXT_LINK_NARROW_WAIST_SIZE_START..CYPHERTEXT_LINK_NARROW_WAIST_SIZE_END]);
+    let nw_size_start = reply_to_size_start + ONE_BYTE;
+    let nw_size = array::<TWO_BYTE>(data, nw_size_start, "narrow waist size")?;
     trace!("des nw_size: \t\t\t{:?} as_u16: {}", nw_size, u8_to_u16(nw_size));
     let nw_size: usize = u8_to_u16(nw_size) as usize;
-    let reply_to: ReplyTo = deserialize_reply_to(&data[CYPHERTEXT_LINK_NARROW_WAIST_SIZE_END..CYPHERTEXT_LINK_NARROW_WAIST_SIZE_END + reply_to_size[0] as usize].to_vec())?;
+    let expected_nw_size = narrow_waist_packet_size_at(header.nw_kind, header.version);
+    if nw_size != expected_nw_size {
+        return Err(DecodeError::BadLength { what: "cyphertext link narrow waist", expected: expected_nw_size, found: nw_size })
+    }
+    let reply_to_start = nw_size_start + TWO_BYTE;
+    let reply_to: ReplyTo = deserialize_reply_to(slice(data, reply_to_start, reply_to_start + reply_to_size, "reply_to")?)?;
     trace!("des reply_to: \t\t\t{:?}", reply_to);
-    let nw_start = CYPHERTEXT_LINK_NARROW_WAIST_SIZE_END + reply_to_size[0] as usize;
+    let nw_start = reply_to_start + reply_to_size;
     trace!("des nw_start: \t\t\t{:?}", nw_start);
-    let shared_secret = link_id.shared_secret(link_nonce.clone(), lnk_tx_pid.clone())?;
-    let mut ctx = ChaCha20Poly1305::new(&shared_secret.as_ref(), &link_nonce.0, &[]);
+    let encrypted = slice(data, nw_start, nw_start + nw_size, "cyphertext narrow waist")?;
+    let shared_secret = link_id.shared_secret(key_nonce(link_nonce), lnk_tx_pid.clone()).map_err(DecodeError::invalid_link)?;
+    let decrypted = suite.open(shared_secret.as_ref(), link_nonce, &serialize_link_header(&header), encrypted, link_tag);
     drop(shared_secret);
-    let nw: NarrowWaistPacket = match nw_size {
-        CYPHERTEXT_NARROW_WAIST_PACKET_REQUEST_SIZE => {
-            let mut decrypted = vec![0u8; nw_size];
-            let encrypted = &data[nw_start..nw_start + nw_size];
-            //trace!("des encrypted: actual_length: {} NARROW_WAIST_PACKET_ENCRYPTED_RESPONSE_SIZE {}\t\t\t{:?} ", encrypted.len(), CYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_SIZE, encrypted);
-            if !ctx.decrypt(encrypted, &mut decrypted, &link_tag) {
-                let err_msg = "failed to decrypt link packet";
-                error!("{}", err_msg);
-                return Err(anyhow!(err_msg))
-            };
-            deserialize_cyphertext_narrow_waist_packet_request(&decrypted.to_vec())?
-        },
-        CYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_SIZE => {
-            let mut decrypted = vec![0u8; nw_size];
-            let encrypted = &data[nw_start..nw_start + nw_size];
-            //trace!("des encrypted: actual_length: {} NARROW_WAIST_PACKET_ENCRYPTED_RESPONSE_SIZE {}\t\t\t{:?} ", encrypted.len(), CYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_SIZE, encrypted);
-            if !ctx.decrypt(encrypted, &mut decrypted, &link_tag) {
-                let err_msg = "failed to decrypt link packet";
-                error!("{}", err_msg);
-                return Err(anyhow!(err_msg))
-            };
-            deserialize_cyphertext_narrow_waist_packet_response(&decrypted.to_vec())?
-        },
-        CLEARTEXT_NARROW_WAIST_PACKET_REQUEST_SIZE => {
-            let mut decrypted = vec![0u8; nw_size];
-            let encrypted = &data[nw_start..nw_start + nw_size];
-            //trace!("des encrypted: actual_length: {} NARROW_WAIST_PACKET_ENCRYPTED_RESPONSE_SIZE {}\t\t\t{:?} ", encrypted.len(), CYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_SIZE, encrypted);
-            if !ctx.decrypt(encrypted, &mut decrypted, &link_tag) {
-                let err_msg = "failed to decrypt link packet";
-                error!("{}", err_msg);
-                return Err(anyhow!(err_msg))
-            };
-            deserialize_cleartext_narrow_waist_packet_request(&decrypted.to_vec())?
-        },
-        CLEARTEXT_NARROW_WAIST_PACKET_RESPONSE_SIZE => {
-            let mut decrypted = vec![0u8; nw_size];
-            let encrypted = &data[nw_start..nw_start + nw_size];
-            //trace!("des encrypted: actual_length: {} NARROW_WAIST_PACKET_ENCRYPTED_RESPONSE_SIZE {}\t\t\t{:?} ", encrypted.len(), CYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_SIZE, encrypted);
-            if !ctx.decrypt(encrypted, &mut decrypted, &link_tag) {
-                let err_msg = "failed to decrypt link packet";
-                error!("{}", err_msg);
-                return Err(anyhow!(err_msg))
-            };
-            deserialize_cleartext_narrow_waist_packet_response(&decrypted.to_vec())?
-        },
-        _ => {
-            let msg = format!("Cyphertext link level packet arrived with an unrecognised NarrowWaistPacket SIZE of {}, where supported sizes are: CYPHERTEXT_NARROW_WAIST_PACKET_REQUEST_SIZE {}, CYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_SIZE {}, CLEARTEXT_NARROW_WAIST_PACKET_REQUEST_SIZE {}, CLEARTEXT_NARROW_WAIST_PACKET_RESPONSE_SIZE {}", nw_size, CYPHERTEXT_NARROW_WAIST_PACKET_REQUEST_SIZE, CYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_SIZE, CLEARTEXT_NARROW_WAIST_PACKET_REQUEST_SIZE, CLEARTEXT_NARROW_WAIST_PACKET_RESPONSE_SIZE);
-            error!("{}", msg);
-            return Err(anyhow!(msg));
-        },
+    let decrypted = match decrypted {
+        Some(decrypted) => decrypted,
+        None => return Err(DecodeError::AuthFailure("failed to decrypt link packet")),
     };
+    let nw: NarrowWaistPacket = deserialize_narrow_waist_packet_at(header.nw_kind, header.version, &decrypted)?;
     //debug!("{:?}", nw);
-    if !nw.verify()? {
-        let err_msg = "The manifest signature check failed when extracting the data from a NarrowWaistPacket::Response";
-        error!("{}", err_msg);
-        return Err(anyhow!(err_msg))
+    match nw.verify() {
+        Ok(true) => {},
+        Ok(false) | Err(_) => {
+            return Err(DecodeError::AuthFailure("the manifest signature check failed on a NarrowWaistPacket::Response"))
+        },
     }
     Ok((lnk_tx_pid, LinkPacket::new(reply_to, nw)))
 }
-pub fn deserialize_cleartext_link_packet(data: &Vec<u8>) -> Result<(PublicIdentity, LinkPacket)> {
+pub fn deserialize_cleartext_link_packet(data: &[u8], header: LinkHeader) -> Result<(PublicIdentity, LinkPacket), DecodeError> {
+    let shift = header.size() - LINK_HEADER_SIZE;
 // Link Pid
-    let mut link_tx_pk = [0u8; ID_SIZE + CC_SIZE];
-    link_tx_pk.clone_from_slice(&data[CLEARTEXT_LINK_TX_PK_START..CLEARTEXT_LINK_TX_PK_END]);
+    let link_tx_pk = array::<{ ID_SIZE + CC_SIZE }>(data, shift + CLEARTEXT_LINK_TX_PK_START, "link tx public key")?;
     //trace!("des link_tx_pk: \t\t{:?}", link_tx_pk);
     let lnk_tx_pid: PublicIdentity = PublicIdentity::from(link_tx_pk);
 // Reply To Length
-    let reply_to_size = &data[CLEARTEXT_LINK_REPLY_TO_SIZE_START..CLEARTEXT_LINK_REPLY_TO_SIZE_END];
+    let reply_to_size = slice(data, shift + CLEARTEXT_LINK_REPLY_TO_SIZE_START, shift + CLEARTEXT_LINK_REPLY_TO_SIZE_END, "reply_to size")?[0] as usize;
     trace!("des reply_to_size: \t\t{:?}", reply_to_size);
 // Narrow Waist Length
-    let mut nw_size = [0u8; 2];
-    nw_size.clone_from_slice(&data[CLEARTEXT_LINK_NARROW_WAIST_SIZE_START..CLEARTEXT_LINK_NARROW_WAIST_SIZE_END]);
+    let nw_size = array::<TWO_BYTE>(data, shift + CLEARTEXT_LINK_NARROW_WAIST_SIZE_START, "narrow waist size")?;
     trace!("des nw_size: \t\t\t{:?} as_u16: {}", nw_size, u8_to_u16(nw_size));
     let nw_size: usize = u8_to_u16(nw_size) as usize;
+    let expected_nw_size = narrow_waist_packet_size_at(header.nw_kind, header.version);
+    if nw_size != expected_nw_size {
+        return Err(DecodeError::BadLength { what: "cleartext link narrow waist", expected: expected_nw_size, found: nw_size })
+    }
 
-    let reply_to: ReplyTo = deserialize_reply_to(&data[CLEARTEXT_LINK_NARROW_WAIST_SIZE_END..CLEARTEXT_LINK_NARROW_WAIST_SIZE_END + reply_to_size[0] as usize].to_vec())?;
+    let reply_to: ReplyTo = deserialize_reply_to(slice(data, shift + CLEARTEXT_LINK_NARROW_WAIST_SIZE_END, shift + CLEARTEXT_LINK_NARROW_WAIST_SIZE_END + reply_to_size, "reply_to")?)?;
     trace!("des reply_to: \t\t\t{:?}", reply_to);
-    let nw_start = CLEARTEXT_LINK_NARROW_WAIST_SIZE_END + reply_to_size[0] as usize;
-    let nw: NarrowWaistPacket = match nw_size {
-        CYPHERTEXT_NARROW_WAIST_PACKET_REQUEST_SIZE => {
-            let cleartext = &data[nw_start..nw_start + nw_size];
-            trace!("des cyphertext_nw: \t\t{:?}", cleartext);
-            deserialize_cyphertext_narrow_waist_packet_request(&cleartext.to_vec())?
-        },
-        CYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_SIZE => {
-            let cleartext = &data[nw_start..nw_start + nw_size];
-            trace!("des cyphertext_nw: \t\t{:?}", cleartext);
-            deserialize_cyphertext_narrow_waist_packet_response(&cleartext.to_vec())?
-        },
-        CLEARTEXT_NARROW_WAIST_PACKET_REQUEST_SIZE => {
-            let cleartext = &data[nw_start..nw_start + nw_size];
-            trace!("des cleartext_nw: \t\t{:?}", cleartext);
-            deserialize_cleartext_narrow_waist_packet_request(&cleartext.to_vec())?
-        },
-        CLEARTEXT_NARROW_WAIST_PACKET_RESPONSE_SIZE => {
-            let cleartext = &data[nw_start..nw_start + nw_size];
-            deserialize_cleartext_narrow_waist_packet_response(&cleartext.to_vec())?
+    let nw_start = shift + CLEARTEXT_LINK_NARROW_WAIST_SIZE_END + reply_to_size;
+    let cleartext = slice(data, nw_start, nw_start + nw_size, "cleartext narrow waist")?;
+    trace!("des cleartext_nw: \t\t{:?}", cleartext);
+    let nw: NarrowWaistPacket = deserialize_narrow_waist_packet_at(header.nw_kind, header.version, cleartext)?;
+    Ok((lnk_tx_pid, LinkPacket::new(reply_to, nw)))
+}
+pub fn deserialize_link_packet(data: &[u8], link_id: LinkId) -> Result<(PublicIdentity, LinkPacket), DecodeError> {
+    let header = deserialize_link_header(data)?;
+    match (header.link_kind, link_id.remote_link_pid().map_err(DecodeError::invalid_link)?) {
+        (LinkPacketKind::CypherText, PublicIdentityInterface::Present { .. }) => {
+            deserialize_cyphertext_link_packet(data, header, link_id)
         },
-        _ => {
-            let msg = format!("Cleartext link level packet arrived with an unrecognised NarrowWaistPacket SIZE of {}, where supported sizes are: CYPHERTEXT_NARROW_WAIST_PACKET_REQUEST_SIZE {}, CYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_SIZE {}, CLEARTEXT_NARROW_WAIST_PACKET_REQUEST_SIZE {}, CLEARTEXT_NARROW_WAIST_PACKET_RESPONSE_SIZE {}", nw_size, CYPHERTEXT_NARROW_WAIST_PACKET_REQUEST_SIZE, CYPHERTEXT_NARROW_WAIST_PACKET_RESPONSE_SIZE, CLEARTEXT_NARROW_WAIST_PACKET_REQUEST_SIZE, CLEARTEXT_NARROW_WAIST_PACKET_RESPONSE_SIZE);
-            error!("{}", msg);
-            return Err(anyhow!(msg));
+        (LinkPacketKind::ClearText, PublicIdentityInterface::Absent) => {
+            deserialize_cleartext_link_packet(data, header)
+        },
+        (link_kind, remote_link_pid) => {
+            Err(DecodeError::LinkKindMismatch { link_kind: format!("{:?}", link_kind), remote: format!("{}", remote_link_pid) })
+        },
+    }
+}
+pub fn serialize_session_link_packet(lp: &LinkPacket, link_id: LinkId, session: &mut LinkSession) -> Result<Vec<u8>> {
+    let mut buf: Vec<u8> = vec![];
+    let nw_kind = NarrowWaistPacketKind::from_narrow_waist(&lp.narrow_waist());
+    let link_header = session.link_header(LinkPacketKind::Session, nw_kind);
+    let suite = link_header.cipher_suite();
+    let header = serialize_link_header(&link_header);
+    buf.extend_from_slice(&header);
+    trace!("ser link_header: \t\t{:?}", header);
+    let stamped = link_header.version >= TIMESTAMP_PROTOCOL_VERSION;
+    let (tx_epoch, tx_ephemeral, rx_epoch, counter, key) = session.seal(&link_id, stamped)?;
+// Ephemeral Pid, the static one is already known to the remote
+    buf.extend_from_slice(tx_ephemeral.key().as_ref());
+    buf.extend_from_slice(tx_ephemeral.chain_code().as_ref());
+    trace!("ser link_tx_ephemeral: \t{}", tx_ephemeral);
+// Epochs
+    buf.extend_from_slice(&tx_epoch.to_be_bytes());
+    buf.extend_from_slice(&rx_epoch.to_be_bytes());
+    trace!("ser link_epochs: \t\t{} {}", tx_epoch, rx_epoch);
+// Nonce, the packet counter or stamp, behind random bytes when the suite takes a longer nonce
+    let mut nonce = vec![0u8; suite.nonce_size() - NONCE_SIZE];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    nonce.extend_from_slice(&counter.0);
+    buf.extend_from_slice(&nonce);
+    trace!("ser link_nonce: \t\t{:?}", nonce);
+// Tag, everything before it is authenticated as associated data
+    let (nws_size, nws) = serialize_narrow_waist_packet_at(&lp.narrow_waist(), link_header.version)?;
+    let (encrypted, tag) = suite.seal(&key[..], &nonce, &buf, &nws)?;
+    drop(key);
+    buf.extend_from_slice(&tag.0);
+    trace!("ser link_tag: \t\t\t{:?}", tag);
+// Reply To Size
+    let (reply_to_size, reply_to) = serialize_reply_to(&lp.reply_to())?;
+    buf.extend_from_slice(&[reply_to_size]);
+// Narrow Waist Size
+    buf.extend_from_slice(&u16_to_u8(nws_size));
+    buf.extend_from_slice(&reply_to);
+// Narrow Waist
+    buf.extend_from_slice(&encrypted);
+    Ok(buf)
+}
+pub fn deserialize_session_link_packet(data: &[u8], header: LinkHeader, link_id: LinkId, session: &mut LinkSession) -> Result<(PublicIdentity, LinkPacket), DecodeError> {
+    let suite = header.cipher_suite();
+    let shift = header.size() - LINK_HEADER_SIZE;
+    let lnk_tx_pid = match link_id.remote_link_pid().map_err(DecodeError::invalid_link)? {
+        PublicIdentityInterface::Present { public_identity } => public_identity,
+        PublicIdentityInterface::Absent => {
+            return Err(DecodeError::LinkKindMismatch { link_kind: format!("{:?}", header.link_kind), remote: format!("{}", PublicIdentityInterface::Absent) })
         },
     };
+// Ephemeral Pid
+    let tx_ephemeral = PublicIdentity::from(array::<{ ID_SIZE + CC_SIZE }>(data, shift + SESSION_LINK_TX_EPHEMERAL_START, "link tx ephemeral key")?);
+// Epochs
+    let tx_epoch = u32::from_be_bytes(array::<FOUR_BYTE>(data, shift + SESSION_LINK_TX_EPOCH_START, "link tx epoch")?);
+    let rx_epoch = u32::from_be_bytes(array::<FOUR_BYTE>(data, shift + SESSION_LINK_RX_EPOCH_START, "link rx epoch")?);
+    trace!("des link_epochs: \t\t{} {}", tx_epoch, rx_epoch);
+// Nonce, the counter or stamp is its last 8 bytes
+    let tag_start = shift + SESSION_LINK_NONCE_START + suite.nonce_size();
+    let link_nonce = slice(data, shift + SESSION_LINK_NONCE_START, tag_start, "link nonce")?;
+    let counter_nonce = key_nonce(link_nonce);
+    let counter = u64::from_be_bytes(counter_nonce.0);
+    // once both ends speak TIMESTAMP_PROTOCOL_VERSION unstamped session packets
+    // are replays, after a restart the remote starts over in static framing
+    let stamped = header.version >= TIMESTAMP_PROTOCOL_VERSION;
+    if !stamped && heard_stamped(session) {
+        return Err(DecodeError::Replayed("unstamped session"))
+    }
+    let now = unix_micros();
+    if !session.replay().is_fresh_epoch(&lnk_tx_pid, tx_epoch, rx_epoch) {
+        return Err(DecodeError::Replayed("stale session"))
+    }
+    let fresh = match stamped {
+        true => session.replay().is_fresh_stamp(counter, now),
+        false => session.replay().is_fresh_counter(&lnk_tx_pid, tx_epoch, counter),
+    };
+    if !fresh {
+        return Err(DecodeError::Replayed("session"))
+    }
+// Tag
+    let link_tag = slice(data, tag_start, tag_start + TAG_SIZE, "link tag")?;
+// Reply To Length
+    let reply_to_size_start = tag_start + TAG_SIZE;
+    let reply_to_size = slice(data, reply_to_size_start, reply_to_size_start + ONE_BYTE, "reply_to size")?[0] as usize;
+// Narrow Waist Length
+    let nw_size_start = reply_to_size_start + ONE_BYTE;
+    let nw_size = array::<TWO_BYTE>(data, nw_size_start, "narrow waist size")?;
+    let nw_size: usize = u8_to_u16(nw_size) as usize;
+    let expected_nw_size = narrow_waist_packet_size_at(header.nw_kind, header.version);
+    if nw_size != expected_nw_size {
+        return Err(DecodeError::BadLength { what: "session link narrow waist", expected: expected_nw_size, found: nw_size })
+    }
+    let reply_to_start = nw_size_start + TWO_BYTE;
+    let reply_to: ReplyTo = deserialize_reply_to(slice(data, reply_to_start, reply_to_start + reply_to_size, "reply_to")?)?;
+    let nw_start = reply_to_start + reply_to_size;
+    let encrypted = slice(data, nw_start, nw_start + nw_size, "session narrow waist")?;
+    let key = match session.open(&link_id, &counter_nonce, &tx_ephemeral, rx_epoch).map_err(DecodeError::invalid_link)? {
+        Some(key) => key,
+        None => return Err(DecodeError::AuthFailure("the link session epoch has expired")),
+    };
+    let decrypted = suite.open(&key[..], link_nonce, &data[..tag_start], encrypted, link_tag);
+    drop(key);
+    let decrypted = match decrypted {
+        Some(decrypted) => decrypted,
+        None => return Err(DecodeError::AuthFailure("failed to decrypt session link packet")),
+    };
+    let nw: NarrowWaistPacket = deserialize_narrow_waist_packet_at(header.nw_kind, header.version, &decrypted)?;
+    match nw.verify() {
+        Ok(true) => {},
+        Ok(false) | Err(_) => {
+            return Err(DecodeError::AuthFailure("the manifest signature check failed on a NarrowWaistPacket::Response"))
+        },
+    }
+    match stamped {
+        true => {
+            session.replay().admit_stamp(counter, now);
+            session.replay().admit_epoch(&lnk_tx_pid, tx_epoch);
+        },
+        false => session.replay().admit_counter(&lnk_tx_pid, tx_epoch, counter),
+    }
+    session.learn(lnk_tx_pid.clone(), tx_epoch, tx_ephemeral);
+    session.follow(&header, deserialize_version_advert(data, &header));
     Ok((lnk_tx_pid, LinkPacket::new(reply_to, nw)))
 }
-pub fn deserialize_link_packet(data: &Vec<u8>, link_id: LinkId) -> Result<(PublicIdentity, LinkPacket)> {
-    match link_id.remote_link_pid()? {
-        PublicIdentityInterface::Present { .. } => {
-            deserialize_cyphertext_link_packet(data, link_id)
+// both ends speak TIMESTAMP_PROTOCOL_VERSION, so the remote stamps its packets
+fn heard_stamped(session: &LinkSession) -> bool {
+    session.version() >= TIMESTAMP_PROTOCOL_VERSION
+}
+// static cyphertext nonces end in a stamp, always once both ends speak
+// TIMESTAMP_PROTOCOL_VERSION, even while a restart has the remote sending at
+// an older one. Older peers' are random, of which only the most recent are
+// remembered.
+fn deserialize_static_link_packet(data: &[u8], header: LinkHeader, link_id: LinkId, session: &mut LinkSession) -> Result<(PublicIdentity, LinkPacket), DecodeError> {
+    let nonce_start = header.size() - LINK_HEADER_SIZE + CYPHERTEXT_LINK_NONCE_START;
+    let wire_nonce = slice(data, nonce_start, nonce_start + header.cipher_suite().nonce_size(), "link nonce")?;
+    let nonce = key_nonce(wire_nonce);
+    let stamped = header.version >= TIMESTAMP_PROTOCOL_VERSION || heard_stamped(session);
+    let stamp = u64::from_be_bytes(nonce.0);
+    let now = unix_micros();
+    let fresh = match stamped {
+        true => session.replay().is_fresh_stamp(stamp, now),
+        false => session.replay().is_fresh_nonce(&nonce),
+    };
+    if !fresh {
+        return Err(DecodeError::Replayed("cyphertext"))
+    }
+    let decoded = deserialize_cyphertext_link_packet(data, header, link_id)?;
+    match stamped {
+        true => session.replay().admit_stamp(stamp, now),
+        false => session.replay().admit_nonce(nonce),
+    }
+    session.forget(&decoded.0);
+    session.follow(&header, deserialize_version_advert(data, &header));
+    Ok(decoded)
+}
+// where the framing ends, the sender's version advert follows it
+fn link_packet_end(data: &[u8], header: &LinkHeader) -> Result<usize, DecodeError> {
+    let shift = header.size() - LINK_HEADER_SIZE;
+    let reply_to_size_start = match header.link_kind {
+        LinkPacketKind::ClearText => shift + CLEARTEXT_LINK_REPLY_TO_SIZE_START,
+        LinkPacketKind::CypherText => shift + CYPHERTEXT_LINK_NONCE_START + header.cipher_suite().nonce_size() + TAG_SIZE,
+        LinkPacketKind::Session => shift + SESSION_LINK_NONCE_START + header.cipher_suite().nonce_size() + TAG_SIZE,
+    };
+    let reply_to_size = slice(data, reply_to_size_start, reply_to_size_start + ONE_BYTE, "reply_to size")?[0] as usize;
+    let nw_size = u8_to_u16(array::<TWO_BYTE>(data, reply_to_size_start + ONE_BYTE, "narrow waist size")?) as usize;
+    Ok(reply_to_size_start + ONE_BYTE + TWO_BYTE + reply_to_size + nw_size)
+}
+// the highest version the sender speaks, None from peers that predate the advert
+pub fn deserialize_version_advert(data: &[u8], header: &LinkHeader) -> Option<u8> {
+    link_packet_end(data, header).ok().and_then(|end| data.get(end).copied())
+}
+// session framing once both ends know each other's link identity and the
+// remote has shown it speaks SESSION_PROTOCOL_VERSION, a pinned remote that
+// hasn't answered in cyphertext yet gets static cyphertext, which carries
+// our link identity so it can pin us in turn. Every packet ends with
+// the highest version we speak, parsers from before it ignore trailing bytes.
+pub fn serialize_link_packet_with_session(lp: &LinkPacket, link_id: LinkId, session: &mut LinkSession) -> Result<Vec<u8>> {
+    let configured = link_id.remote_link_pid()? != PublicIdentityInterface::Absent;
+    let link_id = session.resolve(link_id)?;
+    let mut buf = match (link_id.remote_link_pid()?, session.mode()) {
+        (PublicIdentityInterface::Present { .. }, SessionMode::Ephemeral { .. })
+            if (configured || session.is_confirmed()) && session.version() >= SESSION_PROTOCOL_VERSION => {
+            serialize_session_link_packet(lp, link_id, session)?
         },
-        PublicIdentityInterface::Absent => {
-            deserialize_cleartext_link_packet(data)
+        _ => {
+            let stamp = session.stamp();
+            serialize_link_packet_with_header(lp, link_id, stamp, |link_kind, nw_kind| session.link_header(link_kind, nw_kind))?
+        },
+    };
+    buf.push(session.max_version());
+    Ok(buf)
+}
+// static cyphertext is still accepted, a peer may have fallen back to it.
+// A link configured without a remote identity pins the first one it hears
+// from and refuses any other, and any cleartext, after that.
+pub fn deserialize_link_packet_with_session(data: &[u8], link_id: LinkId, session: &mut LinkSession) -> Result<(PublicIdentity, LinkPacket), DecodeError> {
+    let header = deserialize_link_header(data)?;
+    if header.link_kind != LinkPacketKind::ClearText && !session.allows(header.cipher_suite()) {
+        session.follow_accepts(&header);
+        return Err(DecodeError::SuiteRefused(header.cipher_suite()))
+    }
+    if link_id.remote_link_pid().map_err(DecodeError::invalid_link)? != PublicIdentityInterface::Absent {
+        return match header.link_kind {
+            LinkPacketKind::Session => deserialize_session_link_packet(data, header, link_id, session),
+            LinkPacketKind::CypherText => deserialize_static_link_packet(data, header, link_id, session),
+            LinkPacketKind::ClearText => deserialize_link_packet(data, link_id),
+        }
+    }
+    let pin = |session: &mut LinkSession, presented: &PublicIdentity| -> Result<(), DecodeError> {
+        match session.pin(presented) {
+            true => Ok(()),
+            false => Err(DecodeError::PinMismatch {
+                pinned: session.pinned().map(|pinned| pinned.to_string()).unwrap_or_default(),
+                presented: presented.to_string(),
+            }),
+        }
+    };
+    match header.link_kind {
+        LinkPacketKind::ClearText => {
+            // nothing in cleartext is authenticated and the pinned key is public,
+            // so once pinned only cyphertext speaks for the remote
+            if let Some(pinned) = session.pinned() {
+                return Err(DecodeError::PinnedCleartext { pinned: pinned.to_string() })
+            }
+            let (lnk_tx_pid, lp) = deserialize_cleartext_link_packet(data, header)?;
+            pin(session, &lnk_tx_pid)?;
+            session.follow(&header, deserialize_version_advert(data, &header));
+            Ok((lnk_tx_pid, lp))
+        },
+        LinkPacketKind::CypherText => {
+            let lnk_tx_pid = PublicIdentity::from(array::<{ ID_SIZE + CC_SIZE }>(data, header.size() - LINK_HEADER_SIZE + CYPHERTEXT_LINK_TX_PK_START, "link tx public key")?);
+            if let Some(pinned) = session.pinned() {
+                if *pinned != lnk_tx_pid {
+                    return Err(DecodeError::PinMismatch { pinned: pinned.to_string(), presented: lnk_tx_pid.to_string() })
+                }
+            }
+            let pinned_link = link_id.with_remote_link_pid(lnk_tx_pid.clone()).map_err(DecodeError::invalid_link)?;
+            let decoded = deserialize_static_link_packet(data, header, pinned_link, session)?;
+            pin(session, &lnk_tx_pid)?;
+            session.confirm();
+            Ok(decoded)
+        },
+        LinkPacketKind::Session => {
+            let pinned_link = session.resolve(link_id).map_err(DecodeError::invalid_link)?;
+            if pinned_link.remote_link_pid().map_err(DecodeError::invalid_link)? == PublicIdentityInterface::Absent {
+                return Err(DecodeError::LinkKindMismatch { link_kind: format!("{:?}", header.link_kind), remote: format!("{}", PublicIdentityInterface::Absent) })
+            }
+            let decoded = deserialize_session_link_packet(data, header, pinned_link, session)?;
+            session.confirm();
+            Ok(decoded)
         },
     }
 }
@@ -585,4 +982,105 @@ mod tests {
         println!("expected: {:?}, actual: {:?}", expected, actual);
         assert_eq!(expected, actual);
     }
+    #[test]
+    fn test_link_header_to_fro_u8() {
+        let actual = LinkHeader::new(LinkPacketKind::CypherText, NarrowWaistPacketKind::CleartextResponse);
+        let expected = deserialize_link_header(&serialize_link_header(&actual).to_vec()).unwrap();
+        println!("expected: {:?}, actual: {:?}", expected, actual);
+        assert_eq!(expected, actual);
+        let actual = LinkHeader::new_with_version(MIN_PROTOCOL_VERSION, LinkPacketKind::Session, NarrowWaistPacketKind::CyphertextRequest)
+            .with_suite(CipherSuite::Aes256Gcm, CipherSuite::mask(&[CipherSuite::Aes256Gcm]));
+        let data = serialize_link_header(&actual);
+        assert_eq!(data.len(), SUITE_LINK_HEADER_SIZE);
+        assert_eq!(deserialize_link_header(&data).unwrap(), actual);
+        let old = LinkHeader::new_with_version(MIN_PROTOCOL_VERSION, LinkPacketKind::Session, NarrowWaistPacketKind::CyphertextRequest);
+        assert_eq!(serialize_link_header(&old).len(), LINK_HEADER_SIZE);
+        assert_eq!(deserialize_link_header(&serialize_link_header(&old)).unwrap().cipher_suite(), CipherSuite::ChaCha20Poly1305);
+    }
+    #[test]
+    fn test_link_header_rejects_unknown_version() {
+        let mut header = serialize_link_header(&LinkHeader::new(LinkPacketKind::ClearText, NarrowWaistPacketKind::CleartextRequest));
+        header[LINK_HEADER_VERSION_START] = PROTOCOL_VERSION + 1;
+        assert!(deserialize_link_header(&header.to_vec()).is_err());
+        header[LINK_HEADER_VERSION_START] = MIN_PROTOCOL_VERSION - 1;
+        assert!(deserialize_link_header(&header.to_vec()).is_err());
+    }
+    #[test]
+    fn test_link_header_rejects_unknown_kind() {
+        let mut header = serialize_link_header(&LinkHeader::new(LinkPacketKind::ClearText, NarrowWaistPacketKind::CleartextRequest));
+        header[LINK_HEADER_NARROW_WAIST_KIND_START] = u8::MAX;
+        assert!(deserialize_link_header(&header.to_vec()).is_err());
+        let mut header = serialize_link_header(&LinkHeader::new(LinkPacketKind::Session, NarrowWaistPacketKind::CleartextRequest));
+        header[LINK_HEADER_VERSION_START] = SESSION_PROTOCOL_VERSION - 1;
+        assert!(deserialize_link_header(&header.to_vec()).is_err());
+    }
+    #[test]
+    fn test_truncated_link_packet_is_an_error() {
+        let header = LinkHeader::new(LinkPacketKind::ClearText, NarrowWaistPacketKind::CleartextRequest);
+        let mut data = serialize_link_header(&header).to_vec();
+        for len in 0..header.size() - LINK_HEADER_SIZE + CLEARTEXT_LINK_NARROW_WAIST_SIZE_END + 1 {
+            data.truncate(header.size().min(len));
+            data.resize(len, 0);
+            assert!(deserialize_cleartext_link_packet(&data, header).is_err());
+        }
+    }
+    #[test]
+    fn test_request_lifetime_to_fro_u8() {
+        let sid = crate::PrivateIdentityInterface::new_key();
+        let hbfi = HBFI::new(PublicIdentityInterface::Absent, sid.public_id(), "app", "m0d", "fun", "arg").unwrap();
+        let actual = NarrowWaistPacket::request_with_lifetime(hbfi, 30).unwrap();
+        let (_, data) = serialize_narrow_waist_packet(&actual).unwrap();
+        let expected = deserialize_narrow_waist_packet(NarrowWaistPacketKind::CleartextRequest, &data).unwrap();
+        assert!(matches!(expected, NarrowWaistPacket::Request { lifetime: 30, .. }));
+        assert_eq!(expected, actual);
+    }
+    #[test]
+    fn test_request_hop_limit_to_fro_u8() {
+        let sid = crate::PrivateIdentityInterface::new_key();
+        let hbfi = HBFI::new(PublicIdentityInterface::Absent, sid.public_id(), "app", "m0d", "fun", "arg").unwrap();
+        let actual = NarrowWaistPacket::request_with_hop_limit(hbfi, 0, 1).unwrap().next_hop().unwrap();
+        let (_, data) = serialize_narrow_waist_packet(&actual).unwrap();
+        let expected = deserialize_narrow_waist_packet(NarrowWaistPacketKind::CleartextRequest, &data).unwrap();
+        assert!(matches!(expected, NarrowWaistPacket::Request { hop_limit: 0, .. }));
+        assert_eq!(expected, actual);
+        assert!(expected.next_hop().is_none());
+    }
+    #[test]
+    fn test_signed_request_to_fro_u8() {
+        let requester = crate::PrivateIdentityInterface::new_key();
+        let responder = crate::PrivateIdentityInterface::new_key();
+        let hbfi = HBFI::new(PublicIdentityInterface::new(requester.public_id()), responder.public_id(), "app", "m0d", "fun", "arg").unwrap();
+        let actual = NarrowWaistPacket::signed_request(&requester, hbfi).unwrap().next_hop().unwrap();
+        let (_, mut data) = serialize_narrow_waist_packet(&actual).unwrap();
+        let expected = deserialize_narrow_waist_packet(NarrowWaistPacketKind::CyphertextRequest, &data).unwrap();
+        assert!(expected.verify().unwrap());
+        assert_eq!(expected, actual);
+        data[NARROW_WAIST_PACKET_REQUEST_LIFETIME_START] ^= 1;
+        let tampered = deserialize_narrow_waist_packet(NarrowWaistPacketKind::CyphertextRequest, &data).unwrap();
+        assert!(!tampered.verify().unwrap());
+    }
+    #[test]
+    fn test_nack_to_fro_u8() {
+        let sid = crate::PrivateIdentityInterface::new_key();
+        let hbfi = HBFI::new(PublicIdentityInterface::Absent, sid.public_id(), "app", "m0d", "fun", "arg").unwrap();
+        let nonce = Nonce([7; NONCE_SIZE]);
+        let actual = NarrowWaistPacket::nack(hbfi.clone(), nonce.clone(), NackReason::NoRoute);
+        let (size, data) = serialize_narrow_waist_packet(&actual).unwrap();
+        assert_eq!(size as usize, CLEARTEXT_NARROW_WAIST_PACKET_NACK_SIZE);
+        let expected = deserialize_narrow_waist_packet(NarrowWaistPacketKind::CleartextNack, &data).unwrap();
+        assert_eq!(expected, actual);
+        let actual = NarrowWaistPacket::signed_nack(sid, hbfi, nonce, NackReason::NotFound).unwrap();
+        let (_, mut data) = serialize_narrow_waist_packet(&actual).unwrap();
+        let expected = deserialize_narrow_waist_packet(NarrowWaistPacketKind::CleartextNack, &data).unwrap();
+        assert!(expected.verify().unwrap());
+        assert_eq!(expected, actual);
+        data[NARROW_WAIST_PACKET_NACK_REASON_START] = NackReason::Congestion as u8;
+        let tampered = deserialize_narrow_waist_packet(NarrowWaistPacketKind::CleartextNack, &data).unwrap();
+        assert!(!tampered.verify().unwrap());
+    }
+    #[test]
+    fn test_bad_reply_to_is_an_error() {
+        let actual = deserialize_reply_to(&vec![0u8; 3]);
+        assert!(matches!(actual, Err(DecodeError::BadReplyTo { size: 3 })));
+    }
 }
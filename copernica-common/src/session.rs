@@ -0,0 +1,544 @@
+use {
+    crate::{constants, link_replay::unix_micros, unix_time, CipherSuite, LinkHeader, LinkPacketKind, NarrowWaistPacketKind, LinkId, LinkReplayWindow, Nonce, PrivateIdentityInterface, PublicIdentity, PublicIdentityInterface},
+    anyhow::{anyhow, Result},
+    cryptoxide::{blake2b::Blake2b, digest::Digest as _},
+    std::{
+        collections::HashMap,
+        time::{Duration, Instant},
+    },
+    zeroize::Zeroizing,
+    log::{debug, trace},
+};
+const SESSION_KEY_PATH_V1: &[u8] = b"/copernica/v1/session";
+/// How a link with a known remote identity keys its cyphertext.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SessionMode {
+    // every packet keyed from the long-term link identities, kept for peers
+    // that can't hold session state
+    Static,
+    // ephemeral keys exchanged in the packets themselves and replaced once
+    // either limit is reached, static until the remote is heard speaking
+    // SESSION_PROTOCOL_VERSION
+    Ephemeral { rekey_interval: Duration, rekey_packets: u64 },
+}
+impl Default for SessionMode {
+    fn default() -> Self {
+        SessionMode::Ephemeral {
+            rekey_interval: Duration::from_secs(constants::LINK_REKEY_INTERVAL_SECS),
+            rekey_packets: constants::LINK_REKEY_PACKETS,
+        }
+    }
+}
+struct Ephemeral {
+    epoch: u32,
+    sid: PrivateIdentityInterface,
+    created: Instant,
+}
+impl Ephemeral {
+    fn new(epoch: u32) -> Self {
+        Self { epoch, sid: PrivateIdentityInterface::new_key(), created: Instant::now() }
+    }
+}
+/// The ephemeral half of a link's keys. Every session packet carries the
+/// sender's current ephemeral `PublicIdentity` and epoch, and the epoch of the
+/// receiver's ephemeral it was keyed to, 0 until the sender has heard one.
+///
+/// A packet from X to Y is keyed with `DH(eX, eY) | DH(eX, sY) | DH(sX, eY)`,
+/// or `DH(eX, sY) | DH(sX, sY)` before X knows `eY`, so only the holders of the
+/// static link keys can open it and, once both sides rekey and drop the old
+/// ephemerals, a stolen static key no longer opens recorded traffic.
+/// The previous ephemeral is kept for one rekey interval for packets in flight.
+/// An epoch is the second its ephemeral was created, so a restarted remote
+/// comes back at a newer one and an older epoch is never followed again.
+/// Session nonces count the packets sent under each ephemeral, or from
+/// `TIMESTAMP_PROTOCOL_VERSION` carry a `stamp`, see `LinkReplayWindow`.
+///
+/// A link configured without a remote identity pins the first one it hears
+/// from and encrypts to it from then on, see `pin`.
+///
+/// Every packet ends with the highest version its sender speaks, a trailer
+/// older peers ignore. Packets are sent at the highest version both ends
+/// speak, `MIN_PROTOCOL_VERSION` until the remote has been heard, so a link
+/// reaches XChaCha20Poly1305 once both ends are configured for it and still
+/// talks to peers that predate it.
+///
+/// `suites` is the allowlist, most preferred first. Packets sealed with any
+/// other suite are refused. From `SUITE_PROTOCOL_VERSION` every header names
+/// its suite and the suites its sender accepts, and we seal with the first
+/// of ours the remote accepts. A refused packet's accepted suites are
+/// followed before it is authenticated, otherwise two links that disagree
+/// could never settle, so an attacker can steer a link between suites it
+/// allows but never to one outside the allowlist.
+pub struct LinkSession {
+    mode: SessionMode,
+    current: Ephemeral,
+    previous: Option<Ephemeral>,
+    sent: u64,
+    remotes: HashMap<PublicIdentity, (u32, PublicIdentity)>,
+    pinned: Option<PublicIdentity>,
+    // the pinned remote has sent us cyphertext, so it has pinned us too
+    confirmed: bool,
+    replay: LinkReplayWindow,
+    // the last nonce stamp we sent
+    stamped: u64,
+    // the highest version we speak
+    version: u8,
+    // the highest version the remote has told us it speaks
+    remote_version: Option<u8>,
+    suites: Vec<CipherSuite>,
+    // what the remote last said it accepts, a CipherSuite::mask
+    remote_accepts: Option<u8>,
+}
+impl LinkSession {
+    pub fn new(mode: SessionMode) -> Self {
+        Self { mode, current: Ephemeral::new(unix_time() as u32), previous: None, sent: 0, remotes: HashMap::new(), pinned: None, confirmed: false, replay: LinkReplayWindow::new(), stamped: 0, version: constants::PROTOCOL_VERSION, remote_version: None, suites: CipherSuite::all(), remote_accepts: None }
+    }
+    pub fn mode(&self) -> SessionMode {
+        self.mode
+    }
+    pub fn set_mode(&mut self, mode: SessionMode) {
+        self.mode = mode;
+    }
+    pub fn pinned(&self) -> Option<&PublicIdentity> {
+        self.pinned.as_ref()
+    }
+    // trust on first use, false when a different key is already pinned
+    pub fn pin(&mut self, remote_sid: &PublicIdentity) -> bool {
+        match &self.pinned {
+            Some(pinned) => pinned == remote_sid,
+            None => {
+                debug!("pinned remote link key {}", remote_sid);
+                self.pinned = Some(remote_sid.clone());
+                true
+            },
+        }
+    }
+    // accept whichever key the remote presents next, e.g. after it was rekeyed on purpose
+    pub fn unpin(&mut self) {
+        self.pinned = None;
+        self.confirmed = false;
+        self.remote_version = None;
+    }
+    pub fn is_confirmed(&self) -> bool {
+        self.confirmed
+    }
+    // only once the pinned remote has authenticated cyphertext to us
+    pub fn confirm(&mut self) {
+        self.confirmed = true;
+    }
+    // the configured remote, or the pinned one for a link configured without one
+    pub fn resolve(&self, link_id: LinkId) -> Result<LinkId> {
+        match (link_id.remote_link_pid()?, &self.pinned) {
+            (PublicIdentityInterface::Absent, Some(pinned)) => link_id.with_remote_link_pid(pinned.clone()),
+            _ => Ok(link_id),
+        }
+    }
+    // what the next packet goes out at
+    pub fn version(&self) -> u8 {
+        self.remote_version.map_or(constants::MIN_PROTOCOL_VERSION, |remote| remote.min(self.version))
+    }
+    pub fn max_version(&self) -> u8 {
+        self.version
+    }
+    pub fn set_version(&mut self, version: u8) -> Result<()> {
+        if !LinkHeader::is_supported_version(version) {
+            return Err(anyhow!("Protocol version {} is outside {}..={}", version, constants::MIN_PROTOCOL_VERSION, constants::PROTOCOL_VERSION))
+        }
+        self.version = version;
+        Ok(())
+    }
+    pub fn cipher_suites(&self) -> &[CipherSuite] {
+        &self.suites
+    }
+    pub fn set_cipher_suites(&mut self, suites: Vec<CipherSuite>) -> Result<()> {
+        if suites.is_empty() {
+            return Err(anyhow!("A link needs at least one cipher suite"))
+        }
+        self.suites = suites;
+        Ok(())
+    }
+    pub fn allows(&self, suite: CipherSuite) -> bool {
+        self.suites.contains(&suite)
+    }
+    // our most preferred suite the remote accepts, or just our most preferred
+    // until we know, the remote's refusal tells us what it accepts
+    pub fn suite(&self) -> CipherSuite {
+        let remote_accepts = self.remote_accepts;
+        self.suites.iter().copied()
+            .find(|suite| remote_accepts.map_or(true, |mask| suite.in_mask(mask)))
+            .unwrap_or(self.suites[0])
+    }
+    // the header the next packet goes out with, naming the suite once the
+    // remote has shown it reads such headers or the version's own suite isn't allowed
+    pub fn link_header(&self, link_kind: LinkPacketKind, nw_kind: NarrowWaistPacketKind) -> LinkHeader {
+        let header = LinkHeader::new_with_version(self.version(), link_kind, nw_kind);
+        if header.names_suite() || self.remote_accepts.is_some() || !self.allows(header.cipher_suite()) {
+            header.with_suite(self.suite(), CipherSuite::mask(&self.suites))
+        } else {
+            header
+        }
+    }
+    pub fn follow_accepts(&mut self, header: &LinkHeader) {
+        if header.names_suite() && self.remote_accepts != Some(header.accepts) {
+            debug!("remote link accepts cipher suites {:#05b}", header.accepts);
+            self.remote_accepts = Some(header.accepts);
+        }
+    }
+    // only called once a packet has authenticated. The advert trailer isn't
+    // covered by the tag, but the remote is never taken to speak less than it
+    // already has, so a downgrade is never followed
+    pub fn follow(&mut self, header: &LinkHeader, advert: Option<u8>) {
+        let heard = advert.unwrap_or(0).max(header.version).min(constants::PROTOCOL_VERSION);
+        if self.remote_version.map_or(true, |remote| heard > remote) {
+            debug!("remote link speaks protocol version {}", heard);
+            self.remote_version = Some(heard);
+        }
+        self.follow_accepts(header);
+    }
+    pub fn replay(&mut self) -> &mut LinkReplayWindow {
+        &mut self.replay
+    }
+    // our clock in microseconds, nudged forward so no two packets share a stamp
+    pub fn stamp(&mut self) -> u64 {
+        self.stamped = unix_micros().max(self.stamped + 1);
+        self.stamped
+    }
+    pub fn epoch(&self) -> u32 {
+        self.current.epoch
+    }
+    // forget every ephemeral, as if the link had restarted
+    pub fn rekey(&mut self) {
+        let epoch = self.current.epoch.saturating_add(1).max(unix_time() as u32);
+        trace!("link session rekey to epoch {}", epoch);
+        let previous = std::mem::replace(&mut self.current, Ephemeral::new(epoch));
+        self.previous = Some(previous);
+        self.sent = 0;
+    }
+    fn expire(&mut self, now: Instant) {
+        if let SessionMode::Ephemeral { rekey_interval, rekey_packets } = self.mode {
+            if now.duration_since(self.current.created) >= rekey_interval || self.sent >= rekey_packets {
+                self.rekey();
+            }
+            if let Some(previous) = &self.previous {
+                if now.duration_since(previous.created) >= rekey_interval * 2 {
+                    self.previous = None;
+                }
+            }
+        }
+    }
+    fn ephemeral(&self, epoch: u32) -> Option<&Ephemeral> {
+        if self.current.epoch == epoch {
+            return Some(&self.current)
+        }
+        self.previous.as_ref().filter(|previous| previous.epoch == epoch)
+    }
+    // (our epoch, our ephemeral, the remote epoch we keyed to, the nonce, the key),
+    // the nonce is a stamp rather than a packet count when `stamped`
+    pub fn seal(&mut self, link_id: &LinkId, stamped: bool) -> Result<(u32, PublicIdentity, u32, Nonce, Zeroizing<[u8; 32]>)> {
+        self.expire(Instant::now());
+        let (link_sid, remote_sid) = statics(link_id)?;
+        self.sent += 1;
+        let nonce = match stamped {
+            true => self.stamp(),
+            false => self.sent,
+        };
+        let nonce = &Nonce(nonce.to_be_bytes());
+        let local = &self.current;
+        let (rx_epoch, key) = match self.remotes.get(&remote_sid) {
+            Some((rx_epoch, remote_ephemeral)) => {
+                let key = session_key(&[
+                    local.sid.shared_secret(nonce.clone(), remote_ephemeral.clone()).as_ref(),
+                    local.sid.shared_secret(nonce.clone(), remote_sid.clone()).as_ref(),
+                    link_sid.shared_secret(nonce.clone(), remote_ephemeral.clone()).as_ref(),
+                ]);
+                (*rx_epoch, key)
+            },
+            None => {
+                let key = session_key(&[
+                    local.sid.shared_secret(nonce.clone(), remote_sid.clone()).as_ref(),
+                    link_sid.shared_secret(nonce.clone(), remote_sid.clone()).as_ref(),
+                ]);
+                (0, key)
+            },
+        };
+        Ok((local.epoch, local.sid.public_id(), rx_epoch, nonce.clone(), key))
+    }
+    // None when the packet was keyed to an ephemeral we no longer hold
+    pub fn open(&mut self, link_id: &LinkId, nonce: &Nonce, tx_ephemeral: &PublicIdentity, rx_epoch: u32) -> Result<Option<Zeroizing<[u8; 32]>>> {
+        self.expire(Instant::now());
+        let (link_sid, remote_sid) = statics(link_id)?;
+        if rx_epoch == 0 {
+            return Ok(Some(session_key(&[
+                link_sid.shared_secret(nonce.clone(), tx_ephemeral.clone()).as_ref(),
+                link_sid.shared_secret(nonce.clone(), remote_sid).as_ref(),
+            ])))
+        }
+        let local = match self.ephemeral(rx_epoch) {
+            Some(local) => local,
+            None => return Ok(None),
+        };
+        Ok(Some(session_key(&[
+            local.sid.shared_secret(nonce.clone(), tx_ephemeral.clone()).as_ref(),
+            link_sid.shared_secret(nonce.clone(), tx_ephemeral.clone()).as_ref(),
+            local.sid.shared_secret(nonce.clone(), remote_sid).as_ref(),
+        ])))
+    }
+    // the remote sent static cyphertext so it holds no session with us, maybe
+    // after a restart, key to its static key until it sends an ephemeral again
+    pub fn forget(&mut self, remote_sid: &PublicIdentity) {
+        self.remotes.remove(remote_sid);
+    }
+    // only called once a packet has authenticated, only a newer epoch replaces
+    // the one we key to, a restarted remote comes back at a newer one anyway
+    pub fn learn(&mut self, remote_sid: PublicIdentity, tx_epoch: u32, tx_ephemeral: PublicIdentity) {
+        let learn = match self.remotes.get(&remote_sid) {
+            Some((epoch, _)) => tx_epoch > *epoch,
+            None => true,
+        };
+        if learn {
+            self.remotes.insert(remote_sid, (tx_epoch, tx_ephemeral));
+        }
+    }
+}
+fn statics(link_id: &LinkId) -> Result<(PrivateIdentityInterface, PublicIdentity)> {
+    match link_id.remote_link_pid()? {
+        PublicIdentityInterface::Present { public_identity } => Ok((link_id.link_sid()?, public_identity)),
+        PublicIdentityInterface::Absent => Err(anyhow!("A link session needs the remote link identity")),
+    }
+}
+fn session_key(secrets: &[&[u8]]) -> Zeroizing<[u8; 32]> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    let mut b = Blake2b::new(32);
+    b.input(SESSION_KEY_PATH_V1);
+    for secret in secrets {
+        b.input(secret);
+    }
+    b.result(&mut key[..]);
+    key
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        HBFI, LinkPacket, NarrowWaistPacket, ReplyTo,
+        serialization::{serialize_link_packet_with_session, deserialize_link_packet_with_session},
+    };
+    #[test]
+    fn handshake_then_rekey() {
+        let a_sid = PrivateIdentityInterface::new_key();
+        let b_sid = PrivateIdentityInterface::new_key();
+        let a = LinkId::new(0, a_sid.clone(), PublicIdentityInterface::new(b_sid.public_id()), ReplyTo::Mpsc);
+        let b = LinkId::new(1, b_sid.clone(), PublicIdentityInterface::new(a_sid.public_id()), ReplyTo::Mpsc);
+        let mut a_session = LinkSession::new(SessionMode::default());
+        let mut b_session = LinkSession::new(SessionMode::default());
+        let hbfi = HBFI::new(PublicIdentityInterface::Absent, b_sid.public_id(), "app", "m0d", "fun", "arg").unwrap();
+        let lp = LinkPacket::new(ReplyTo::Mpsc, NarrowWaistPacket::request(hbfi).unwrap());
+        // static cyphertext until each end has heard the other speaks sessions
+        let hello = serialize_link_packet_with_session(&lp, a.clone(), &mut a_session).unwrap();
+        assert_eq!(hello[constants::LINK_HEADER_LINK_KIND_START], LinkPacketKind::CypherText as u8);
+        deserialize_link_packet_with_session(&hello, b.clone(), &mut b_session).unwrap();
+        let answer = serialize_link_packet_with_session(&lp, b.clone(), &mut b_session).unwrap();
+        assert_eq!(answer[constants::LINK_HEADER_LINK_KIND_START], LinkPacketKind::Session as u8);
+        deserialize_link_packet_with_session(&answer, a.clone(), &mut a_session).unwrap();
+        let first = serialize_link_packet_with_session(&lp, a.clone(), &mut a_session).unwrap();
+        let (tx_pid, received) = deserialize_link_packet_with_session(&first, b.clone(), &mut b_session).unwrap();
+        assert_eq!(tx_pid, a_sid.public_id());
+        assert_eq!(received.narrow_waist(), lp.narrow_waist());
+        let replies: Vec<Vec<u8>> = (0..3).map(|_| serialize_link_packet_with_session(&lp, b.clone(), &mut b_session).unwrap()).collect();
+        assert!(deserialize_link_packet_with_session(&replies[0], a.clone(), &mut a_session).is_ok());
+        match deserialize_link_packet_with_session(&replies[0], a.clone(), &mut a_session) {
+            Err(crate::DecodeError::Replayed(_)) => {},
+            _ => panic!("a replayed session packet must be refused"),
+        }
+        // a packet keyed to a's previous ephemeral still opens, one keyed two epochs back doesn't
+        a_session.rekey();
+        assert!(deserialize_link_packet_with_session(&replies[1], a.clone(), &mut a_session).is_ok());
+        a_session.rekey();
+        assert!(deserialize_link_packet_with_session(&replies[2], a.clone(), &mut a_session).is_err());
+        let mut eve_session = LinkSession::new(SessionMode::default());
+        let eve = LinkId::new(2, PrivateIdentityInterface::new_key(), PublicIdentityInterface::new(a_sid.public_id()), ReplyTo::Mpsc);
+        assert!(deserialize_link_packet_with_session(&first, eve, &mut eve_session).is_err());
+    }
+    #[test]
+    fn replays_stay_refused_across_restarts() {
+        let a_sid = PrivateIdentityInterface::new_key();
+        let b_sid = PrivateIdentityInterface::new_key();
+        let a = LinkId::new(0, a_sid.clone(), PublicIdentityInterface::new(b_sid.public_id()), ReplyTo::Mpsc);
+        let b = LinkId::new(1, b_sid.clone(), PublicIdentityInterface::new(a_sid.public_id()), ReplyTo::Mpsc);
+        let hbfi = HBFI::new(PublicIdentityInterface::Absent, b_sid.public_id(), "app", "m0d", "fun", "arg").unwrap();
+        let lp = LinkPacket::new(ReplyTo::Mpsc, NarrowWaistPacket::request(hbfi).unwrap());
+        let mut a_session = LinkSession::new(SessionMode::Static);
+        let mut b_session = LinkSession::new(SessionMode::default());
+        let hello = serialize_link_packet_with_session(&lp, a.clone(), &mut a_session).unwrap();
+        deserialize_link_packet_with_session(&hello, b.clone(), &mut b_session).unwrap();
+        let answer = serialize_link_packet_with_session(&lp, b.clone(), &mut b_session).unwrap();
+        assert_eq!(answer[constants::LINK_HEADER_VERSION_START], constants::PROTOCOL_VERSION);
+        deserialize_link_packet_with_session(&answer, a.clone(), &mut a_session).unwrap();
+        match deserialize_link_packet_with_session(&answer, a.clone(), &mut a_session) {
+            Err(crate::DecodeError::Replayed(_)) => {},
+            _ => panic!("a replayed session packet must be refused"),
+        }
+        let stamped = serialize_link_packet_with_session(&lp, a.clone(), &mut a_session).unwrap();
+        deserialize_link_packet_with_session(&stamped, b.clone(), &mut b_session).unwrap();
+        // a restarted b remembers nothing, but a's packet is stamped from before it booted
+        std::thread::sleep(Duration::from_millis(1));
+        let mut b_session = LinkSession::new(SessionMode::default());
+        match deserialize_link_packet_with_session(&stamped, b.clone(), &mut b_session) {
+            Err(crate::DecodeError::Replayed(_)) => {},
+            _ => panic!("a packet stamped before the restart must be refused"),
+        }
+        // and a takes the restarted b's static packets, at the oldest version, as stamped
+        let restarted = serialize_link_packet_with_session(&lp, b.clone(), &mut b_session).unwrap();
+        assert_eq!(restarted[constants::LINK_HEADER_VERSION_START], constants::MIN_PROTOCOL_VERSION);
+        deserialize_link_packet_with_session(&restarted, a.clone(), &mut a_session).unwrap();
+        let reply = serialize_link_packet_with_session(&lp, a, &mut a_session).unwrap();
+        deserialize_link_packet_with_session(&reply, b, &mut b_session).unwrap();
+        assert_eq!(b_session.version(), constants::PROTOCOL_VERSION);
+    }
+    #[test]
+    fn absent_links_pin_on_first_use() {
+        let a_sid = PrivateIdentityInterface::new_key();
+        let b_sid = PrivateIdentityInterface::new_key();
+        let a = LinkId::new(0, a_sid.clone(), PublicIdentityInterface::Absent, ReplyTo::Mpsc);
+        let b = LinkId::new(1, b_sid.clone(), PublicIdentityInterface::Absent, ReplyTo::Mpsc);
+        let mut a_session = LinkSession::new(SessionMode::default());
+        let mut b_session = LinkSession::new(SessionMode::default());
+        let hbfi = HBFI::new(PublicIdentityInterface::Absent, b_sid.public_id(), "app", "m0d", "fun", "arg").unwrap();
+        let lp = LinkPacket::new(ReplyTo::Mpsc, NarrowWaistPacket::request(hbfi).unwrap());
+        let hello = serialize_link_packet_with_session(&lp, a.clone(), &mut a_session).unwrap();
+        deserialize_link_packet_with_session(&hello, b.clone(), &mut b_session).unwrap();
+        assert_eq!(b_session.pinned(), Some(&a_sid.public_id()));
+        // b upgrades to static cyphertext until a answers in kind, then to a session
+        let upgraded = serialize_link_packet_with_session(&lp, b.clone(), &mut b_session).unwrap();
+        deserialize_link_packet_with_session(&upgraded, a.clone(), &mut a_session).unwrap();
+        assert!(a_session.is_confirmed());
+        let session = serialize_link_packet_with_session(&lp, a.clone(), &mut a_session).unwrap();
+        deserialize_link_packet_with_session(&session, b.clone(), &mut b_session).unwrap();
+        assert!(b_session.is_confirmed());
+        let mallory_sid = PrivateIdentityInterface::new_key();
+        let mallory = LinkId::new(2, mallory_sid.clone(), PublicIdentityInterface::new(b_sid.public_id()), ReplyTo::Mpsc);
+        let impostor = crate::serialization::serialize_link_packet(&lp, mallory).unwrap();
+        match deserialize_link_packet_with_session(&impostor, b.clone(), &mut b_session) {
+            Err(crate::DecodeError::PinMismatch { .. }) => {},
+            _ => panic!("a different link key must not replace the pinned one"),
+        }
+    }
+    #[test]
+    fn pinned_links_refuse_spoofed_cleartext() {
+        let a_sid = PrivateIdentityInterface::new_key();
+        let b_sid = PrivateIdentityInterface::new_key();
+        let a = LinkId::new(0, a_sid.clone(), PublicIdentityInterface::Absent, ReplyTo::Mpsc);
+        let b = LinkId::new(1, b_sid.clone(), PublicIdentityInterface::Absent, ReplyTo::Mpsc);
+        let mut a_session = LinkSession::new(SessionMode::default());
+        let mut b_session = LinkSession::new(SessionMode::default());
+        let hbfi = HBFI::new(PublicIdentityInterface::Absent, b_sid.public_id(), "app", "m0d", "fun", "arg").unwrap();
+        let lp = LinkPacket::new(ReplyTo::Mpsc, NarrowWaistPacket::request(hbfi).unwrap());
+        let hello = serialize_link_packet_with_session(&lp, a.clone(), &mut a_session).unwrap();
+        deserialize_link_packet_with_session(&hello, b.clone(), &mut b_session).unwrap();
+        let upgraded = serialize_link_packet_with_session(&lp, b.clone(), &mut b_session).unwrap();
+        deserialize_link_packet_with_session(&upgraded, a.clone(), &mut a_session).unwrap();
+        let session = serialize_link_packet_with_session(&lp, a, &mut a_session).unwrap();
+        deserialize_link_packet_with_session(&session, b.clone(), &mut b_session).unwrap();
+        assert!(b_session.is_confirmed());
+        // mallory claims a's public link key, cleartext carries nothing to disprove it
+        let mallory = LinkId::new(2, PrivateIdentityInterface::new_key(), PublicIdentityInterface::Absent, ReplyTo::Mpsc);
+        let mut spoofed = serialize_link_packet_with_session(&lp, mallory, &mut LinkSession::new(SessionMode::default())).unwrap();
+        let a_pid = a_sid.public_id();
+        let (key, chain_code): (&[u8], &[u8]) = (a_pid.key().as_ref(), a_pid.chain_code().as_ref());
+        spoofed[constants::CLEARTEXT_LINK_TX_PK_START..constants::CLEARTEXT_LINK_TX_PK_END].copy_from_slice(&[key, chain_code].concat());
+        match deserialize_link_packet_with_session(&spoofed, b, &mut b_session) {
+            Err(crate::DecodeError::PinnedCleartext { .. }) => {},
+            _ => panic!("cleartext claiming the pinned key must be refused"),
+        }
+        assert!(b_session.is_confirmed());
+    }
+    #[test]
+    fn xchacha_is_followed_once_heard() {
+        let a_sid = PrivateIdentityInterface::new_key();
+        let b_sid = PrivateIdentityInterface::new_key();
+        let a = LinkId::new(0, a_sid.clone(), PublicIdentityInterface::new(b_sid.public_id()), ReplyTo::Mpsc);
+        let b = LinkId::new(1, b_sid.clone(), PublicIdentityInterface::new(a_sid.public_id()), ReplyTo::Mpsc);
+        let hbfi = HBFI::new(PublicIdentityInterface::Absent, b_sid.public_id(), "app", "m0d", "fun", "arg").unwrap();
+        let lp = LinkPacket::new(ReplyTo::Mpsc, NarrowWaistPacket::request(hbfi).unwrap());
+        for &mode in &[SessionMode::Static, SessionMode::default()] {
+            let mut a_session = LinkSession::new(mode);
+            let mut b_session = LinkSession::new(mode);
+            a_session.set_version(constants::XNONCE_PROTOCOL_VERSION).unwrap();
+            let hello = serialize_link_packet_with_session(&lp, a.clone(), &mut a_session).unwrap();
+            assert_eq!(hello[constants::LINK_HEADER_VERSION_START], constants::MIN_PROTOCOL_VERSION);
+            deserialize_link_packet_with_session(&hello, b.clone(), &mut b_session).unwrap();
+            assert_eq!(b_session.version(), constants::XNONCE_PROTOCOL_VERSION);
+            let reply = serialize_link_packet_with_session(&lp, b.clone(), &mut b_session).unwrap();
+            assert_eq!(reply[constants::LINK_HEADER_VERSION_START], constants::XNONCE_PROTOCOL_VERSION);
+            let (_, received) = deserialize_link_packet_with_session(&reply, a.clone(), &mut a_session).unwrap();
+            assert_eq!(received.narrow_waist(), lp.narrow_waist());
+            assert_eq!(a_session.version(), constants::XNONCE_PROTOCOL_VERSION);
+        }
+        assert!(LinkSession::new(SessionMode::Static).set_version(constants::PROTOCOL_VERSION + 1).is_err());
+    }
+    #[test]
+    fn old_peers_are_spoken_to_at_their_version() {
+        let a_sid = PrivateIdentityInterface::new_key();
+        let b_sid = PrivateIdentityInterface::new_key();
+        let request_sid = PrivateIdentityInterface::new_key();
+        let a = LinkId::new(0, a_sid.clone(), PublicIdentityInterface::new(b_sid.public_id()), ReplyTo::Mpsc);
+        let b = LinkId::new(1, b_sid.clone(), PublicIdentityInterface::new(a_sid.public_id()), ReplyTo::Mpsc);
+        let hbfi = HBFI::new(PublicIdentityInterface::new(request_sid.public_id()), b_sid.public_id(), "app", "m0d", "fun", "arg").unwrap();
+        let lp = LinkPacket::new(ReplyTo::Mpsc, NarrowWaistPacket::signed_request(&request_sid, hbfi).unwrap());
+        let mut a_session = LinkSession::new(SessionMode::Static);
+        let mut b_session = LinkSession::new(SessionMode::Static);
+        a_session.set_version(constants::MIN_PROTOCOL_VERSION).unwrap();
+        // a peer from before the advert sends no trailer
+        let mut hello = serialize_link_packet_with_session(&lp, a.clone(), &mut a_session).unwrap();
+        hello.pop();
+        deserialize_link_packet_with_session(&hello, b.clone(), &mut b_session).unwrap();
+        assert_eq!(b_session.version(), constants::MIN_PROTOCOL_VERSION);
+        // b answers in the request layout a reads, without the signature
+        let reply = serialize_link_packet_with_session(&lp, b, &mut b_session).unwrap();
+        assert_eq!(reply[constants::LINK_HEADER_VERSION_START], constants::MIN_PROTOCOL_VERSION);
+        let (_, received) = deserialize_link_packet_with_session(&reply, a, &mut a_session).unwrap();
+        match received.narrow_waist() {
+            NarrowWaistPacket::Request { signature: None, .. } => {},
+            _ => panic!("a request in the old layout carries no signature"),
+        }
+        assert_eq!(a_session.version(), constants::MIN_PROTOCOL_VERSION);
+    }
+    #[test]
+    fn suites_are_negotiated_within_the_allowlists() {
+        let c_sid = PrivateIdentityInterface::new_key();
+        let d_sid = PrivateIdentityInterface::new_key();
+        let c = LinkId::new(0, c_sid.clone(), PublicIdentityInterface::new(d_sid.public_id()), ReplyTo::Mpsc);
+        let d = LinkId::new(1, d_sid.clone(), PublicIdentityInterface::new(c_sid.public_id()), ReplyTo::Mpsc);
+        let hbfi = HBFI::new(PublicIdentityInterface::Absent, d_sid.public_id(), "app", "m0d", "fun", "arg").unwrap();
+        let lp = LinkPacket::new(ReplyTo::Mpsc, NarrowWaistPacket::request(hbfi).unwrap());
+        let mut c_session = LinkSession::new(SessionMode::default());
+        let mut d_session = LinkSession::new(SessionMode::default());
+        c_session.set_version(constants::SUITE_PROTOCOL_VERSION).unwrap();
+        c_session.set_cipher_suites(vec![CipherSuite::XChaCha20Poly1305, CipherSuite::ChaCha20Poly1305]).unwrap();
+        d_session.set_cipher_suites(vec![CipherSuite::Aes256Gcm, CipherSuite::ChaCha20Poly1305]).unwrap();
+        // c hears d at the oldest version first and moves up to what both speak
+        let opener = serialize_link_packet_with_session(&lp, d.clone(), &mut d_session).unwrap();
+        deserialize_link_packet_with_session(&opener, c.clone(), &mut c_session).unwrap();
+        assert_eq!(c_session.version(), constants::SUITE_PROTOCOL_VERSION);
+        // d refuses c's first choice but learns what c accepts from it
+        let hello = serialize_link_packet_with_session(&lp, c.clone(), &mut c_session).unwrap();
+        match deserialize_link_packet_with_session(&hello, d.clone(), &mut d_session) {
+            Err(crate::DecodeError::SuiteRefused(CipherSuite::XChaCha20Poly1305)) => {},
+            _ => panic!("a suite outside the allowlist must be refused"),
+        }
+        let reply = serialize_link_packet_with_session(&lp, d.clone(), &mut d_session).unwrap();
+        deserialize_link_packet_with_session(&reply, c.clone(), &mut c_session).unwrap();
+        assert_eq!(c_session.suite(), CipherSuite::ChaCha20Poly1305);
+        let settled = serialize_link_packet_with_session(&lp, c.clone(), &mut c_session).unwrap();
+        deserialize_link_packet_with_session(&settled, d.clone(), &mut d_session).unwrap();
+        // an AES only link still seals with AES when its remote allows it
+        d_session.set_cipher_suites(vec![CipherSuite::Aes256Gcm]).unwrap();
+        c_session.set_cipher_suites(CipherSuite::all()).unwrap();
+        let aes = serialize_link_packet_with_session(&lp, d.clone(), &mut d_session).unwrap();
+        deserialize_link_packet_with_session(&aes, c.clone(), &mut c_session).unwrap();
+        assert_eq!(c_session.suite(), CipherSuite::Aes256Gcm);
+        let aes = serialize_link_packet_with_session(&lp, c, &mut c_session).unwrap();
+        deserialize_link_packet_with_session(&aes, d, &mut d_session).unwrap();
+        assert!(c_session.set_cipher_suites(vec![]).is_err());
+    }
+}
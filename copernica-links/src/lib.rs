@@ -9,23 +9,31 @@ pub use {
 use {
     copernica_common::{
         InterLinkPacket, LinkId, LinkPacket, PublicIdentity,
-        Operations, serialization::*
+        Operations, DecodeError, NodeHandle, LinkSession, SessionMode, CipherSuite, serialization::*
     },
     std::sync::mpsc::{Receiver, SyncSender},
     anyhow::{Result},
     reed_solomon::{Buffer, Encoder, Decoder},
 };
-pub fn decode(msg: Vec<u8>, link_id: LinkId) -> Result<(PublicIdentity, LinkPacket)> {
-    let dec = Decoder::new(6);
-    let reconstituted: Vec<_> = msg.chunks(255).map(|c| Buffer::from_slice(c, c.len())).map(|d| dec.correct(&d,None).unwrap()).collect();
-    let reconstituted: Vec<_> = reconstituted.iter().map(|d| d.data()).collect::<Vec<_>>().concat();
-    Ok(deserialize_link_packet(&reconstituted, link_id)?)
+const ECC_LENGTH: usize = 6;
+pub fn decode(msg: Vec<u8>, link_id: LinkId, session: &mut LinkSession) -> Result<(PublicIdentity, LinkPacket), DecodeError> {
+    let dec = Decoder::new(ECC_LENGTH);
+    let mut reconstituted: Vec<u8> = vec![];
+    for c in msg.chunks(255) {
+        if c.len() <= ECC_LENGTH {
+            return Err(DecodeError::Truncated { what: "reed-solomon block", needed: ECC_LENGTH + 1, available: c.len() })
+        }
+        let d = Buffer::from_slice(c, c.len());
+        let corrected = dec.correct(&d, None).map_err(|_| DecodeError::RsUncorrectable)?;
+        reconstituted.extend(corrected.data());
+    }
+    deserialize_link_packet_with_session(&reconstituted, link_id, session)
 }
-pub fn encode(lp: LinkPacket, link_id: LinkId) -> Result<Vec<u8>> {
+pub fn encode(lp: LinkPacket, link_id: LinkId, session: &mut LinkSession) -> Result<Vec<u8>> {
     let mut merged = vec![];
-    let enc = Encoder::new(6);
-    let nw: Vec<u8> = serialize_link_packet(&lp, link_id)?;
-    let cs = nw.chunks(255-6);
+    let enc = Encoder::new(ECC_LENGTH);
+    let nw: Vec<u8> = serialize_link_packet_with_session(&lp, link_id, session)?;
+    let cs = nw.chunks(255-ECC_LENGTH);
     for c in cs {
         let c = enc.encode(&c[..]);
         merged.extend(&**c);
@@ -33,6 +41,19 @@ pub fn encode(lp: LinkPacket, link_id: LinkId) -> Result<Vec<u8>> {
     Ok(merged)
 }
 pub trait Link {
-    fn run(&mut self) -> Result<()>;
+    fn run(&mut self) -> Result<NodeHandle>;
+    // links to a known remote use ephemeral session keys by default once the
+    // remote has shown it reads them, `SessionMode::Static` keeps to the
+    // long-term link keys
+    fn set_session_mode(&mut self, mode: SessionMode);
+    // a link configured without a remote identity pins the first one it hears
+    // and then refuses cleartext, forget it to accept a remote whose link key
+    // changed on purpose or that restarted and lost its pin on us
+    fn unpin(&mut self);
+    // the highest version this link speaks, packets go out at the highest
+    // the remote speaks too, XNONCE_PROTOCOL_VERSION and above seal with XChaCha20Poly1305
+    fn set_protocol_version(&mut self, version: u8) -> Result<()>;
+    // the suites this link seals with and accepts, most preferred first
+    fn set_cipher_suites(&mut self, suites: Vec<CipherSuite>) -> Result<()>;
     fn new(link: LinkId, ops: (String, Operations), router_in_and_out: ( SyncSender<InterLinkPacket> , Receiver<InterLinkPacket>)) -> Result<Self> where Self: Sized;
 }
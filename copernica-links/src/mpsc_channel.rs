@@ -1,10 +1,10 @@
 use {
     crate::{Link, decode, encode},
     copernica_common::{
-        InterLinkPacket, LinkId, ReplyTo, constants, Operations
+        InterLinkPacket, LinkId, ReplyTo, NodeHandle, constants, Operations, LinkSession, SessionMode, CipherSuite, DecodeError,
     },
     anyhow::{anyhow, Result},
-    std::sync::{Arc, Mutex, mpsc::{Receiver, SyncSender, sync_channel as channel}},
+    std::sync::{Arc, Mutex, mpsc::{Receiver, RecvTimeoutError, SyncSender, sync_channel as channel}},
     log::{trace, error },
 };
 #[allow(dead_code)]
@@ -15,6 +15,7 @@ pub struct MpscChannel {
     // t = tansport; c = copernic; 0 = this instance of t; 1 = the pair of same type
     l2bs_tx: SyncSender<InterLinkPacket>,
     bs2l_rx: Arc<Mutex<Receiver<InterLinkPacket>>>,
+    session: Arc<Mutex<LinkSession>>,
     l2l0_tx: SyncSender<Vec<u8>>,        // give
     l2l0_rx: Arc<Mutex<Receiver<Vec<u8>>>>,      // keep
     l2l1_tx: Option<Vec<SyncSender<Vec<u8>>>>,
@@ -48,6 +49,7 @@ impl Link for MpscChannel {
                         ops,
                         l2bs_tx,
                         bs2l_rx: Arc::new(Mutex::new(bs2l_rx)),
+                        session: Arc::new(Mutex::new(LinkSession::new(SessionMode::default()))),
                         l2l0_tx,
                         l2l0_rx: Arc::new(Mutex::new(l2l0_rx)),
                         l2l1_tx: None,
@@ -56,72 +58,109 @@ impl Link for MpscChannel {
             _ => return Err(anyhow!("MpscChannel Link expects a LinkId of type LinkId::Mpsc")),
         }
     }
-    #[allow(unreachable_code)]
-    fn run(&mut self) -> Result<()> {
+    fn set_session_mode(&mut self, mode: SessionMode) {
+        self.session.lock().unwrap().set_mode(mode);
+    }
+    fn unpin(&mut self) {
+        self.session.lock().unwrap().unpin();
+    }
+    fn set_protocol_version(&mut self, version: u8) -> Result<()> {
+        self.session.lock().unwrap().set_version(version)
+    }
+    fn set_cipher_suites(&mut self, suites: Vec<CipherSuite>) -> Result<()> {
+        self.session.lock().unwrap().set_cipher_suites(suites)
+    }
+    fn run(&mut self) -> Result<NodeHandle> {
+        let l2l1_tx = match self.l2l1_tx.clone() {
+            Some(l2l1_tx) => l2l1_tx,
+            None => return Err(anyhow!("You need to bind the transports before using them, i.e. t0.female(t1.male()); followed by: t1.female(t0.male());")),
+        };
+        let mut handle = NodeHandle::new(&self.label);
         let this_link = self.link_id.clone();
+        let session = self.session.clone();
         trace!("Started {:?}:", this_link);
         let l2l0_rx = self.l2l0_rx.clone();
         let l2bs_tx = self.l2bs_tx.clone();
         let ops = self.ops.clone();
         let label = self.label.clone();
-        std::thread::spawn(move || {
+        handle.spawn(move |shutdown| {
             match this_link.reply_to()? {
                 ReplyTo::Mpsc => {
                     let l2l0_rx = l2l0_rx.lock().unwrap();
                     loop {
-                        match l2l0_rx.recv() {
+                        match l2l0_rx.recv_timeout(shutdown.poll_interval()) {
                             Ok(msg) => {
-                                let (_lnk_tx_pid, lp) = decode(msg, this_link.clone())?;
+                                let (_lnk_tx_pid, lp) = match decode(msg, this_link.clone(), &mut session.lock().unwrap()) {
+                                    Ok(decoded) => decoded,
+                                    Err(error) => {
+                                        error!("{:?}: dropping packet: {}", this_link, error);
+                                        match error {
+                                            DecodeError::PinMismatch { .. } => ops.link_pin_changed(label.clone()),
+                                            DecodeError::Replayed(_) => ops.link_replay_dropped(label.clone()),
+                                            DecodeError::SuiteRefused(_) => ops.link_suite_refused(label.clone()),
+                                            _ => ops.decode_failure(label.clone()),
+                                        }
+                                        continue;
+                                    },
+                                };
                                 let link_id = LinkId::new(this_link.lookup_id()?, this_link.link_sid()?, this_link.remote_link_pid()?, lp.reply_to());
                                 let ilp = InterLinkPacket::new(link_id, lp.clone());
                                 trace!("\t\t|  |  link-to-broker-or-protocol");
                                 trace!("\t|  |  {}", this_link.lookup_id()?);
                                 ops.message_from(label.clone());
-                                match l2bs_tx.send(ilp) {
+                                match shutdown.send(&l2bs_tx, ilp) {
                                     Ok(_) => {},
                                     Err(e) => error!("mpsc_channel {:?}", e),
                                 }
                             },
-                            Err(error) => error!("{:?}: {}", this_link, error),
+                            Err(RecvTimeoutError::Timeout) if !shutdown.is_triggered() => continue,
+                            Err(_) => break,
                         };
                     }
                 },
                 _ => {},
             }
-            Ok::<(), anyhow::Error>(())
+            Ok(())
         });
         let this_link = self.link_id.clone();
+        let session = self.session.clone();
         let bs2l_rx = self.bs2l_rx.clone();
         let ops = self.ops.clone();
         let label = self.label.clone();
-        if let Some(l2l1_tx) = self.l2l1_tx.clone() {
-            std::thread::spawn(move || {
-                let bs2l_rx = bs2l_rx.lock().unwrap();
-                loop {
-                    match bs2l_rx.recv() {
-                        Ok(ilp) => {
-                            let lp = ilp.link_packet().change_origination(this_link.reply_to()?);
-                            let enc = encode(lp, this_link.clone())?;
-                            for s in l2l1_tx.clone() {
-                                trace!("\t\t|  |  broker-or-protocol-to-link");
-                                trace!("\t\t|  |  {}", this_link.lookup_id()?);
-                                ops.message_from(label.clone());
-                                match s.send(enc.clone()) {
-                                    Ok(_) => {},
-                                    Err(e) => error!("mpsc_channel outbound: {:?}", e),
-                                }
+        handle.spawn(move |shutdown| {
+            let bs2l_rx = bs2l_rx.lock().unwrap();
+            loop {
+                match bs2l_rx.recv_timeout(shutdown.poll_interval()) {
+                    Ok(ilp) => {
+                        let lp = ilp.link_packet().change_origination(this_link.reply_to()?);
+                        let enc = match encode(lp, this_link.clone(), &mut session.lock().unwrap()) {
+                            Ok(enc) => enc,
+                            Err(error) => {
+                                error!("{:?}: dropping packet: {}", this_link, error);
+                                continue;
+                            },
+                        };
+                        for s in l2l1_tx.clone() {
+                            trace!("\t\t|  |  broker-or-protocol-to-link");
+                            trace!("\t\t|  |  {}", this_link.lookup_id()?);
+                            ops.message_from(label.clone());
+                            match shutdown.send(&s, enc.clone()) {
+                                Ok(_) => {},
+                                Err(e) => error!("mpsc_channel outbound: {:?}", e),
                             }
-                        },
-                        Err(error) => error!("{:?}: {}", this_link, error),
-                    }
+                        }
+                    },
+                    Err(RecvTimeoutError::Timeout) if !shutdown.is_triggered() => continue,
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        trace!("{:?}: detached from the broker", this_link);
+                        break;
+                    },
                 }
-                Ok::<(), anyhow::Error>(())
-            });
-        } else {
-            return Err(anyhow!("You need to bind the transports before using them, i.e. t0.female(t1.male()); followed by: t1.female(t0.male());"))
-        }
-
-        Ok(())
+            }
+            Ok(())
+        });
+        Ok(handle)
     }
 }
 
@@ -1,9 +1,10 @@
 use {
     crate::{Link, encode, decode},
-    copernica_common::{ InterLinkPacket, LinkId, ReplyTo, Operations },
+    copernica_common::{ InterLinkPacket, LinkId, ReplyTo, NodeHandle, Operations, LinkSession, SessionMode, CipherSuite, DecodeError },
     anyhow::{anyhow, Result},
-    std::sync::mpsc::{Receiver, SyncSender},
+    std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender},
     futures_lite::{future},
+    async_io::Timer,
     log::{error, trace},
     std::{
       net::{SocketAddr, UdpSocket},
@@ -17,6 +18,7 @@ pub struct UdpIp {
     ops: Operations,
     l2bs_tx: SyncSender<InterLinkPacket>,
     bs2l_rx: Arc<Mutex<Receiver<InterLinkPacket>>>,
+    session: Arc<Mutex<LinkSession>>,
 }
 impl Link for UdpIp {
     fn new(link_id: LinkId
@@ -27,59 +29,90 @@ impl Link for UdpIp {
         trace!("LISTEN ON {:?}:", link_id);
         ops.register_link(label.clone());
         match link_id.reply_to()? {
-            ReplyTo::UdpIp(_) => return Ok(UdpIp { label, link_id, ops, l2bs_tx, bs2l_rx: Arc::new(Mutex::new(bs2l_rx)) }),
+            ReplyTo::UdpIp(_) => return Ok(UdpIp { label, link_id, ops, l2bs_tx, bs2l_rx: Arc::new(Mutex::new(bs2l_rx)), session: Arc::new(Mutex::new(LinkSession::new(SessionMode::default()))) }),
             _ => return Err(anyhow!("UdpIp Link expects a LinkId of type Link.ReplyTo::UdpIp(...)")),
         }
     }
-    #[allow(unreachable_code)]
-    fn run(&mut self) -> Result<()> {
+    fn set_session_mode(&mut self, mode: SessionMode) {
+        self.session.lock().unwrap().set_mode(mode);
+    }
+    fn unpin(&mut self) {
+        self.session.lock().unwrap().unpin();
+    }
+    fn set_protocol_version(&mut self, version: u8) -> Result<()> {
+        self.session.lock().unwrap().set_version(version)
+    }
+    fn set_cipher_suites(&mut self, suites: Vec<CipherSuite>) -> Result<()> {
+        self.session.lock().unwrap().set_cipher_suites(suites)
+    }
+    fn run(&mut self) -> Result<NodeHandle> {
+        let mut handle = NodeHandle::new(&self.label);
         let this_link = self.link_id.clone();
+        let session = self.session.clone();
         let l2bs_tx = self.l2bs_tx.clone();
         let ops = self.ops.clone();
         let label = self.label.clone();
-        std::thread::spawn(move || {
+        handle.spawn(move |shutdown| {
             match this_link.reply_to()? {
                 ReplyTo::UdpIp(addr) => {
                     match async_io::Async::<UdpSocket>::bind(addr) {
                         Ok(socket) => {
                             loop {
                                 let mut buf = vec![0u8; 1500];
-                                let data = future::block_on(async{ socket.recv_from(&mut buf).await });
+                                // wake up every poll interval so a quiet socket doesn't keep us alive
+                                let data = future::block_on(future::or(
+                                    async { Some(socket.recv_from(&mut buf).await) },
+                                    async { Timer::after(shutdown.poll_interval()).await; None },
+                                ));
                                 match data {
-                                    Ok((n, _peer)) => {
+                                    None if shutdown.is_triggered() => break,
+                                    None => continue,
+                                    Some(Ok((n, _peer))) => {
                                         trace!("\t\t\t|  |  link-to-broker-or-protocol");
                                         trace!("\t\t\t|  |  {}", this_link.lookup_id()?);
                                         ops.message_from(label.clone());
-                                        let (_lnk_tx_pid, lp) = decode(buf[..n].to_vec(), this_link.clone())?;
+                                        let (_lnk_tx_pid, lp) = match decode(buf[..n].to_vec(), this_link.clone(), &mut session.lock().unwrap()) {
+                                            Ok(decoded) => decoded,
+                                            Err(error) => {
+                                                error!("{:?}: dropping packet: {}", this_link, error);
+                                                match error {
+                                                    DecodeError::PinMismatch { .. } => ops.link_pin_changed(label.clone()),
+                                                    DecodeError::Replayed(_) => ops.link_replay_dropped(label.clone()),
+                                                    DecodeError::SuiteRefused(_) => ops.link_suite_refused(label.clone()),
+                                                    _ => ops.decode_failure(label.clone()),
+                                                }
+                                                continue;
+                                            },
+                                        };
                                         let link_id = LinkId::new(this_link.lookup_id()?, this_link.link_sid()?, this_link.remote_link_pid()?, lp.reply_to());
                                         let ilp = InterLinkPacket::new(link_id, lp);
-                                        match l2bs_tx.send(ilp) {
+                                        match shutdown.send(&l2bs_tx, ilp) {
                                             Ok(_) => {},
                                             Err(e) => error!("udp_ip link {:?}", e),
                                         }
                                     },
-                                    Err(error) => error!("{:?}: {}", this_link, error),
+                                    Some(Err(error)) => error!("{:?}: {}", this_link, error),
                                 };
-
                             }
                         },
-                        Err(error) => error!("{:?}: {}", this_link, error),
+                        Err(error) => return Err(anyhow!("{:?}: {}", this_link, error)),
                     }
                 },
                 _ => {},
             }
-            Ok::<(), anyhow::Error>(())
+            Ok(())
         });
         let this_link = self.link_id.clone();
+        let session = self.session.clone();
         let bs2l_rx = self.bs2l_rx.clone();
         let ops = self.ops.clone();
         let label = self.label.clone();
-        std::thread::spawn(move || {
+        handle.spawn(move |shutdown| {
             match async_io::Async::<UdpSocket>::bind(SocketAddr::new("127.0.0.1".parse()?, 0)) {
                 Ok(socket) => {
                     let bs2l_rx = bs2l_rx.lock().unwrap();
                     loop {
-                        match bs2l_rx.recv() {
+                        match bs2l_rx.recv_timeout(shutdown.poll_interval()) {
                             Ok(ilp) => {
                                 match ilp.reply_to()? {
                                     ReplyTo::UdpIp(remote_addr) => {
@@ -87,7 +120,13 @@ impl Link for UdpIp {
                                         trace!("\t\t\t|  |  broker-or-protocol-to-link");
                                         trace!("\t\t\t|  |  {}", this_link.lookup_id()?);
                                         ops.message_from(label.clone());
-                                        let enc = encode(lp, this_link.clone())?;
+                                        let enc = match encode(lp, this_link.clone(), &mut session.lock().unwrap()) {
+                                            Ok(enc) => enc,
+                                            Err(error) => {
+                                                error!("{:?}: dropping packet: {}", this_link, error);
+                                                continue;
+                                            },
+                                        };
                                         let data = future::block_on(async{ socket.send_to(&enc, remote_addr).await });
                                         match data {
                                             Ok(_) => {},
@@ -97,15 +136,20 @@ impl Link for UdpIp {
                                     _ => {},
                                 }
                             },
-                            Err(error) => error!("{:?}: {}", this_link, error),
+                            Err(RecvTimeoutError::Timeout) if !shutdown.is_triggered() => continue,
+                            Err(RecvTimeoutError::Timeout) => break,
+                            Err(RecvTimeoutError::Disconnected) => {
+                                trace!("{:?}: detached from the broker", this_link);
+                                break;
+                            },
                         }
                     }
                 },
-                Err(error) => error!("{:?}: {}", this_link, error),
+                Err(error) => return Err(anyhow!("{:?}: {}", this_link, error)),
             }
-            Ok::<(), anyhow::Error>(())
+            Ok(())
         });
-        Ok(())
+        Ok(handle)
     }
 }
 
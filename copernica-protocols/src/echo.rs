@@ -2,7 +2,8 @@ use {
     anyhow::{Result, anyhow},
     bincode,
     copernica_common::{
-        bloom_filter_index as bfi, NarrowWaistPacket, HBFI, PublicIdentity, PublicIdentityInterface, PrivateIdentityInterface, Operations
+        bloom_filter_index as bfi, NarrowWaistPacket, NackReason, RequestCheck, HBFI, PublicIdentity, PublicIdentityInterface, PrivateIdentityInterface, NodeHandle, Operations,
+        RevocationRecord,
     },
     crate::{Protocol, TxRx},
     log::{trace},
@@ -20,6 +21,12 @@ pub struct Echo {
     ops: Operations,
 }
 impl Echo {
+    pub fn publish_revocation(&self, record: RevocationRecord) -> Result<()> {
+        self.txrx.publish_revocation(record)
+    }
+    pub fn fetch_revocation(&self, publisher: PublicIdentity, revoked: &PublicIdentity) -> Result<RevocationRecord> {
+        self.txrx.fetch_revocation(publisher, revoked)
+    }
     pub fn unreliable_unordered_cleartext_ping(&mut self, response_pid: PublicIdentity) -> Result<String> {
         let hbfi = HBFI::new(PublicIdentityInterface::Absent, response_pid, "echo", "echo", "echo", UNRELIABLE_UNORDERED_ECHO)?;
         let echo: Vec<Vec<u8>> = self.txrx.unreliable_unordered_request(hbfi.clone(), 0, 3)?;
@@ -132,11 +139,12 @@ impl Protocol for Echo {
         }
     }
     #[allow(unreachable_code)]
-    fn run(&self) -> Result<()> {
+    fn run(&self) -> Result<NodeHandle> {
+        let mut handle = NodeHandle::new(&self.label);
         let txrx = self.txrx.clone();
         let ops = self.ops.clone();
         let label = self.label.clone();
-        std::thread::spawn(move || {
+        handle.spawn(move |shutdown| {
             match txrx {
                 TxRx::Initialized {
                     ref unreliable_unordered_response_tx,
@@ -150,13 +158,20 @@ impl Protocol for Echo {
                     let m0d_check = bfi("echo")?;
                     let fun_check = bfi("echo")?;
                     loop {
-                        match txrx.clone().next() {
-                            Ok(ilp) => {
+                        match txrx.try_next(shutdown.poll_interval()) {
+                            Ok(None) if !shutdown.is_triggered() => continue,
+                            Ok(None) => break,
+                            Ok(Some(ilp)) => {
                                 ops.message_from(label.clone());
                                 trace!("\t\t|  link-to-protocol");
                                 let nw: NarrowWaistPacket = ilp.narrow_waist();
                                 match nw.clone() {
-                                    NarrowWaistPacket::Request { hbfi, .. } => match hbfi {
+                                    NarrowWaistPacket::Request { .. } if txrx.check_request(&nw)? != RequestCheck::Accepted => {
+                                        trace!("\t\t|  REQUEST REFUSED");
+                                    },
+                                    NarrowWaistPacket::Request { .. } if txrx.serve_revocation(&nw)? => {},
+                                    _ if txrx.route_revocation(&ilp)? => {},
+                                    NarrowWaistPacket::Request { hbfi, nonce, .. } => match hbfi {
                                         HBFI { res, app, m0d, fun, arg, frm, .. }
                                             if (res == res_check)
                                                 && (app == app_check)
@@ -259,6 +274,26 @@ impl Protocol for Echo {
                                                         }
                                                         txrx.clone().respond(hbfi.clone(), echo)?;
                                                     },
+                                                    _ => {
+                                                        txrx.clone().nack(hbfi.clone(), nonce, NackReason::NotFound)?;
+                                                    }
+                                                }
+                                            }
+                                        _ => {}
+                                    },
+                                    NarrowWaistPacket::Nack { hbfi, reason, .. } => match hbfi {
+                                        HBFI { app, m0d, fun, arg, .. }
+                                            if (app == app_check)
+                                                && (m0d == m0d_check)
+                                                && (fun == fun_check)
+                                            => {
+                                                trace!("\t\t|  NACK {} ARRIVED", reason);
+                                                match arg {
+                                                    arg if arg == bfi(UNRELIABLE_UNORDERED_ECHO)? => unreliable_unordered_response_tx.send(ilp)?,
+                                                    arg if arg == bfi(UNRELIABLE_SEQUENCED_ECHO)? => unreliable_sequenced_response_tx.send(ilp)?,
+                                                    arg if arg == bfi(RELIABLE_UNORDERED_ECHO)? => reliable_unordered_response_tx.send(ilp)?,
+                                                    arg if arg == bfi(RELIABLE_ORDERED_ECHO)? => reliable_ordered_response_tx.send(ilp)?,
+                                                    arg if arg == bfi(RELIABLE_SEQUENCED_ECHO)? => reliable_sequenced_response_tx.send(ilp)?,
                                                     _ => {}
                                                 }
                                             }
@@ -303,15 +338,15 @@ impl Protocol for Echo {
                                     }
                                 }
                             }
-                            Err(_e) => {}
+                            Err(_e) => break,
                         }
                     }
                 },
-                TxRx::Inert => panic!("{}", anyhow!("You must peer with a link first")),
+                TxRx::Inert => return Err(anyhow!("You must peer with a link first")),
             };
-            Ok::<(), anyhow::Error>(())
+            Ok(())
         });
-        Ok(())
+        Ok(handle)
     }
     fn set_txrx(&mut self, txrx: TxRx) {
         self.txrx = txrx;
@@ -1,5 +1,5 @@
 use {
-    copernica_common::{LinkId, InterLinkPacket, PrivateIdentityInterface, constants, Operations},
+    copernica_common::{LinkId, InterLinkPacket, PrivateIdentityInterface, NodeHandle, constants, Operations},
     std::sync::mpsc::{sync_channel as channel, Receiver, SyncSender},
     crate::{TxRx},
     anyhow::{Result},
@@ -40,8 +40,7 @@ pub trait Protocol {
         self.set_txrx(txrx);
         Ok((l2p_tx, p2l_rx))
     }
-    #[allow(unreachable_code)]
-    fn run(&self) -> Result<()>;
+    fn run(&self) -> Result<NodeHandle>;
     fn new(protocol_sid: PrivateIdentityInterface, ops: (String, Operations)) -> Self where Self: Sized;
 }
 
@@ -1,14 +1,17 @@
 use {
     copernica_common::{
-        LinkId, NarrowWaistPacket, NarrowWaistPacketReqEqRes,
-        LinkPacket, InterLinkPacket, HBFI, HBFIExcludeFrame,
-        PrivateIdentityInterface, PublicIdentity, constants, Operations
+        LinkId, NarrowWaistPacket, NarrowWaistPacketReqEqRes, NackReason,
+        LinkPacket, InterLinkPacket, HBFI, HBFIExcludeFrame, Nonce, BFI,
+        PrivateIdentityInterface, PublicIdentity, PublicIdentityInterface, constants, Operations,
+        RequestCheck, RequestPolicy, RequestVerifier, RevocationList, RevocationRecord, RotationCertificate,
+        CipherSuite, revocation_hbfi, is_revocation_hbfi,
     },
     log::{trace, error},
     anyhow::{anyhow, Result},
     std::{
         time::{Duration},
-        sync::{mpsc::{sync_channel as channel, Receiver, SyncSender}, Arc, Mutex},
+        convert::TryFrom,
+        sync::{mpsc::{sync_channel as channel, Receiver, RecvTimeoutError, SyncSender}, Arc, Mutex},
         collections::{BTreeMap, BTreeSet, HashMap},
     },
 };
@@ -58,6 +61,13 @@ pub enum TxRx {
         p2l_tx: SyncSender<InterLinkPacket>,
         l2p_rx: Arc<Mutex<Receiver<InterLinkPacket>>>,
         incomplete_responses: Arc<Mutex<HashMap<HBFIExcludeFrame, BTreeMap<u64, NarrowWaistPacket>>>>,
+        requests: Arc<Mutex<RequestVerifier>>,
+        revocations: Arc<Mutex<RevocationList>>,
+        // revocation records this protocol serves, by the argument they're published under
+        published_revocations: Arc<Mutex<HashMap<BFI, RevocationRecord>>>,
+        revocation_response_tx: SyncSender<InterLinkPacket>,
+        revocation_response_rx: Arc<Mutex<Receiver<InterLinkPacket>>>,
+        response_suite: CipherSuite,
         unreliable_unordered_response_tx: SyncSender<InterLinkPacket>,
         unreliable_unordered_response_rx: Arc<Mutex<Receiver<InterLinkPacket>>>,
         unreliable_sequenced_response_tx: SyncSender<InterLinkPacket>,
@@ -82,6 +92,7 @@ impl TxRx {
         let (reliable_unordered_response_tx, reliable_unordered_response_rx) = channel::<InterLinkPacket>(constants::BOUNDED_BUFFER_SIZE);
         let (reliable_ordered_response_tx, reliable_ordered_response_rx) = channel::<InterLinkPacket>(constants::BOUNDED_BUFFER_SIZE);
         let (reliable_sequenced_response_tx, reliable_sequenced_response_rx) = channel::<InterLinkPacket>(constants::BOUNDED_BUFFER_SIZE);
+        let (revocation_response_tx, revocation_response_rx) = channel::<InterLinkPacket>(constants::BOUNDED_BUFFER_SIZE);
         TxRx::Initialized {
             label,
             ops,
@@ -90,6 +101,12 @@ impl TxRx {
             p2l_tx,
             l2p_rx: Arc::new(Mutex::new(l2p_rx)),
             incomplete_responses: Arc::new(Mutex::new(HashMap::new())),
+            requests: Arc::new(Mutex::new(RequestVerifier::new(RequestPolicy::default()))),
+            revocations: Arc::new(Mutex::new(RevocationList::new())),
+            published_revocations: Arc::new(Mutex::new(HashMap::new())),
+            revocation_response_tx,
+            revocation_response_rx: Arc::new(Mutex::new(revocation_response_rx)),
+            response_suite: CipherSuite::default(),
             unreliable_unordered_response_rx: Arc::new(Mutex::new(unreliable_unordered_response_rx)),
             unreliable_unordered_response_tx,
             unreliable_sequenced_response_rx: Arc::new(Mutex::new(unreliable_sequenced_response_rx)),
@@ -118,6 +135,128 @@ impl TxRx {
             TxRx::Inert => Err(anyhow!("You must peer with a link first"))
         }
     }
+    // which inbound requests a responder insists are signed
+    pub fn set_request_policy(&mut self, policy: RequestPolicy) -> Result<()> {
+        match self {
+            TxRx::Initialized { requests, .. } => {
+                *requests = Arc::new(Mutex::new(RequestVerifier::new(policy)));
+                Ok(())
+            },
+            TxRx::Inert => Err(anyhow!("You must peer with a link first"))
+        }
+    }
+    // responses to requesters are sealed with ChaCha20Poly1305 unless told otherwise,
+    // XChaCha20Poly1305 responses need links speaking XNONCE_PROTOCOL_VERSION
+    pub fn set_response_suite(&mut self, suite: CipherSuite) -> Result<()> {
+        match self {
+            TxRx::Initialized { response_suite, .. } => {
+                *response_suite = suite;
+                Ok(())
+            },
+            TxRx::Inert => Err(anyhow!("You must peer with a link first"))
+        }
+    }
+    pub fn check_request(&self, nw: &NarrowWaistPacket) -> Result<RequestCheck> {
+        match self {
+            TxRx::Initialized { requests, .. } => {
+                let mut requests = requests.lock().map_err(|_| anyhow!("TxRx request verifier is poisoned"))?;
+                Ok(requests.check(nw))
+            },
+            TxRx::Inert => Err(anyhow!("You must peer with a link first"))
+        }
+    }
+    // requests to a revoked responder fail instead of accepting its responses
+    pub fn revoke(&self, record: RevocationRecord) -> Result<()> {
+        match self {
+            TxRx::Initialized { revocations, .. } => {
+                let mut revocations = revocations.lock().map_err(|_| anyhow!("TxRx revocation list is poisoned"))?;
+                revocations.revoke(record)
+            },
+            TxRx::Inert => Err(anyhow!("You must peer with a link first"))
+        }
+    }
+    pub fn rotate(&self, certificate: RotationCertificate) -> Result<()> {
+        match self {
+            TxRx::Initialized { revocations, .. } => {
+                let mut revocations = revocations.lock().map_err(|_| anyhow!("TxRx revocation list is poisoned"))?;
+                revocations.rotate(certificate)
+            },
+            TxRx::Inert => Err(anyhow!("You must peer with a link first"))
+        }
+    }
+    // serves `record` to anyone asking this protocol for it, and applies it here too
+    pub fn publish_revocation(&self, record: RevocationRecord) -> Result<()> {
+        match self {
+            TxRx::Initialized { published_revocations, protocol_sid, .. } => {
+                self.revoke(record.clone())?;
+                let hbfi = record.hbfi(protocol_sid.public_id())?;
+                let mut published_revocations = published_revocations.lock().map_err(|_| anyhow!("TxRx published revocations are poisoned"))?;
+                published_revocations.insert(hbfi.arg, record);
+                Ok(())
+            },
+            TxRx::Inert => Err(anyhow!("You must peer with a link first"))
+        }
+    }
+    // answers a request for a revocation record this protocol publishes,
+    // false when `nw` asks for anything else
+    pub fn serve_revocation(&self, nw: &NarrowWaistPacket) -> Result<bool> {
+        match self {
+            TxRx::Initialized { published_revocations, protocol_sid, .. } => match nw {
+                NarrowWaistPacket::Request { hbfi, nonce, .. } if hbfi.response_pid == protocol_sid.public_id() && is_revocation_hbfi(hbfi) => {
+                    let record = {
+                        let published_revocations = published_revocations.lock().map_err(|_| anyhow!("TxRx published revocations are poisoned"))?;
+                        published_revocations.get(&hbfi.arg).cloned()
+                    };
+                    match record {
+                        Some(record) if hbfi.frm == 0 => self.clone().respond(hbfi.clone(), record.to_bytes())?,
+                        _ => self.clone().nack(hbfi.clone(), nonce.clone(), NackReason::NotFound)?,
+                    }
+                    Ok(true)
+                },
+                _ => Ok(false),
+            },
+            TxRx::Inert => Err(anyhow!("You must peer with a link first"))
+        }
+    }
+    // hands responses and refusals for revocation records to `fetch_revocation`,
+    // false when `ilp` carries anything else
+    pub fn route_revocation(&self, ilp: &InterLinkPacket) -> Result<bool> {
+        match self {
+            TxRx::Initialized { revocation_response_tx, ops, label, .. } => {
+                match ilp.narrow_waist() {
+                    NarrowWaistPacket::Response { hbfi, .. } if is_revocation_hbfi(&hbfi) => {
+                        trace!("\t\t|  REVOCATION ARRIVED");
+                        ops.response_arrived_downstream(label.clone());
+                    },
+                    NarrowWaistPacket::Nack { hbfi, .. } if is_revocation_hbfi(&hbfi) => {},
+                    _ => return Ok(false),
+                }
+                // nobody may be fetching, so never block the protocol on it
+                if revocation_response_tx.try_send(ilp.clone()).is_err() {
+                    trace!("\t\t|  REVOCATION DROPPED");
+                }
+                Ok(true)
+            },
+            TxRx::Inert => Err(anyhow!("You must peer with a link first"))
+        }
+    }
+    // asks `publisher` for the record revoking `revoked` and applies it
+    pub fn fetch_revocation(&self, publisher: PublicIdentity, revoked: &PublicIdentity) -> Result<RevocationRecord> {
+        match self {
+            TxRx::Initialized { revocation_response_rx, .. } => {
+                let hbfi = revocation_hbfi(publisher.clone(), revoked)?;
+                let data = self.request_on(hbfi, 0, 0, revocation_response_rx)?;
+                let bytes = data.first().ok_or_else(|| anyhow!("{} returned no revocation record for {}", publisher, revoked))?;
+                let record = RevocationRecord::try_from(&bytes[..])?;
+                if record.revoked != *revoked {
+                    return Err(anyhow!("{} returned a revocation record for {} instead of {}", publisher, record.revoked, revoked))
+                }
+                self.revoke(record.clone())?;
+                Ok(record)
+            },
+            TxRx::Inert => Err(anyhow!("You must peer with a link first"))
+        }
+    }
     fn register_hbfi(&self, hbfi: HBFI) -> Result<()> {
         match self {
             TxRx::Initialized { incomplete_responses, .. } => {
@@ -141,6 +280,20 @@ impl TxRx {
             TxRx::Inert => Err(anyhow!("You must peer with a link first"))
         }
     }
+    // like `next` but gives up after `timeout` so callers can check for shutdown
+    pub fn try_next(&self, timeout: Duration) -> Result<Option<InterLinkPacket>> {
+        match self {
+            TxRx::Initialized { l2p_rx, .. } => {
+                let l2p_rx_ref = l2p_rx.lock().unwrap();
+                match l2p_rx_ref.recv_timeout(timeout) {
+                    Ok(ilp) => Ok(Some(ilp)),
+                    Err(RecvTimeoutError::Timeout) => Ok(None),
+                    Err(error) => Err(error.into()),
+                }
+            },
+            TxRx::Inert => Err(anyhow!("You must peer with a link first"))
+        }
+    }
     fn send_and_receive(&self
         , nws: &BTreeSet<NarrowWaistPacketReqEqRes>
         , hbfi_seek: HBFI
@@ -148,11 +301,26 @@ impl TxRx {
         , window_timeout: Duration
         ) -> Result<AIMD> {
         match self {
-            TxRx::Initialized { ops, link_id, p2l_tx, .. } => {
+            TxRx::Initialized { ops, link_id, p2l_tx, protocol_sid, revocations, .. } => {
+                {
+                    let revocations = revocations.lock().map_err(|_| anyhow!("TxRx revocation list is poisoned"))?;
+                    if revocations.is_revoked(&hbfi_seek.response_pid) {
+                        return match revocations.successor(&hbfi_seek.response_pid) {
+                            Some(successor) => Err(anyhow!("Responder {} is revoked, it rotated to {}", hbfi_seek.response_pid, successor)),
+                            None => Err(anyhow!("Responder {} is revoked", hbfi_seek.response_pid)),
+                        }
+                    }
+                }
                 let total = nws.len();
                 let hbfi_seek_no_frame = HBFIExcludeFrame(hbfi_seek.clone());
+                let request_pid = PublicIdentityInterface::new(protocol_sid.public_id());
                 for nw in nws.clone() {
-                    let lp = LinkPacket::new(link_id.reply_to()?, nw.0.clone());
+                    // every transmission is signed afresh so a retransmission isn't taken for a replay
+                    let nw = match &nw.0 {
+                        NarrowWaistPacket::Request { hbfi, .. } if hbfi.request_pid == request_pid => nw.0.sign_request(protocol_sid)?,
+                        _ => nw.0.clone(),
+                    };
+                    let lp = LinkPacket::new(link_id.reply_to()?, nw);
                     let ilp = InterLinkPacket::new(link_id.clone(), lp);
                     trace!("\t\t|  protocol-to-link");
                     ops.message_from(self.label()?);
@@ -162,10 +330,10 @@ impl TxRx {
                         Err(e) => error!("protocol send error {:?}", e),
                     }
                 }
-                struct Data(pub BTreeSet<NarrowWaistPacketReqEqRes>, pub BTreeSet<NarrowWaistPacketReqEqRes>);
+                struct Data(pub BTreeSet<NarrowWaistPacketReqEqRes>, pub BTreeSet<NarrowWaistPacketReqEqRes>, pub bool);
                 let returned: BTreeSet<NarrowWaistPacketReqEqRes> = BTreeSet::new();
                 let unassociated: BTreeSet<NarrowWaistPacketReqEqRes> = BTreeSet::new();
-                let data_mutex: Arc<Mutex<Data>> = Arc::new(Mutex::new(Data(returned, unassociated)));
+                let data_mutex: Arc<Mutex<Data>> = Arc::new(Mutex::new(Data(returned, unassociated, false)));
                 let data_mutex_to_thread = Arc::clone(&data_mutex);
                 let (sender, receiver) = channel(1);
                 std::thread::spawn(move || {
@@ -178,6 +346,24 @@ impl TxRx {
                                 let inbound_hbfi = match nw.clone() {
                                     NarrowWaistPacket::Request {..} => {  continue },
                                     NarrowWaistPacket::Response {hbfi, ..} => { HBFIExcludeFrame(hbfi.clone()) },
+                                    // congestion anywhere on the path is a reason to back off and ask again
+                                    NarrowWaistPacket::Nack {hbfi, reason: NackReason::Congestion, ..} if hbfi_seek_no_frame == HBFIExcludeFrame(hbfi.clone()) => {
+                                        if let Ok(true) = nw.verify() {
+                                            data_mutex_to_thread.lock().unwrap().2 = true;
+                                            counter += 1;
+                                        }
+                                        continue
+                                    },
+                                    // only the responder can refuse a frame for good, anyone
+                                    // on the path could forge an unsigned refusal
+                                    NarrowWaistPacket::Nack {hbfi, signature: Some(_), ..} if hbfi_seek_no_frame == HBFIExcludeFrame(hbfi.clone()) => {
+                                        if let Ok(true) = nw.verify() {
+                                            data_mutex_to_thread.lock().unwrap().0.insert(NarrowWaistPacketReqEqRes(nw));
+                                            break
+                                        }
+                                        continue
+                                    },
+                                    NarrowWaistPacket::Nack {..} => {  continue },
                                 };
                                 let mut data = data_mutex_to_thread.lock().unwrap();
                                 if hbfi_seek_no_frame == inbound_hbfi {
@@ -197,6 +383,15 @@ impl TxRx {
                 });
                 receiver.recv_timeout(window_timeout)?;
                 let data = data_mutex.lock().unwrap();
+                for nw in data.0.iter() {
+                    if let NarrowWaistPacket::Nack { hbfi, reason, .. } = &nw.0 {
+                        return Err(anyhow!("Request for frame {} was refused: {}", hbfi.frm, reason))
+                    }
+                }
+                if data.2 {
+                    trace!("\t\t|  CONGESTION, BACKING OFF");
+                    std::thread::sleep(window_timeout);
+                }
                 let returned = data.0.clone();
                 let unassociated = data.1.clone();
                 let failed: BTreeSet<NarrowWaistPacketReqEqRes> = nws.difference(&returned).cloned().collect();
@@ -219,7 +414,7 @@ impl TxRx {
                         let mut incomplete_responses_ref = incomplete_responses_mutex.lock().unwrap();
                         for nw in returned {
                             match nw.clone().0 {
-                                NarrowWaistPacket::Request { .. } => { return },
+                                NarrowWaistPacket::Request { .. } | NarrowWaistPacket::Nack { .. } => { return },
                                 NarrowWaistPacket::Response { hbfi, .. } => {
                                     if let Some(entry) = incomplete_responses_ref.get_mut(&HBFIExcludeFrame(hbfi_seek.clone())) {
                                         entry.insert(hbfi.frm.clone(), nw.0.clone());
@@ -229,7 +424,7 @@ impl TxRx {
                         }
                         for nw in unassociated {
                             match nw.clone().0 {
-                                NarrowWaistPacket::Request { .. } => { return },
+                                NarrowWaistPacket::Request { .. } | NarrowWaistPacket::Nack { .. } => { return },
                                 NarrowWaistPacket::Response { hbfi, .. } => {
                                     if let Some(entry) = incomplete_responses_ref.get_mut(&HBFIExcludeFrame(hbfi.clone())) {
                                         entry.insert(hbfi.frm.clone(), nw.0.clone());
@@ -244,7 +439,7 @@ impl TxRx {
                         let mut incomplete_responses_ref = incomplete_responses_mutex.lock().unwrap();
                         for nw in returned {
                             match nw.clone().0 {
-                                NarrowWaistPacket::Request { .. } => { return },
+                                NarrowWaistPacket::Request { .. } | NarrowWaistPacket::Nack { .. } => { return },
                                 NarrowWaistPacket::Response { hbfi, .. } => {
                                     if let Some(entry) = incomplete_responses_ref.get_mut(&HBFIExcludeFrame(hbfi_seek.clone())) {
                                         entry.insert(hbfi.frm.clone(), nw.0.clone());
@@ -254,7 +449,7 @@ impl TxRx {
                         }
                         for nw in unassociated {
                             match nw.clone().0 {
-                                NarrowWaistPacket::Request { .. } => { return },
+                                NarrowWaistPacket::Request { .. } | NarrowWaistPacket::Nack { .. } => { return },
                                 NarrowWaistPacket::Response { hbfi, .. } => {
                                     if let Some(entry) = incomplete_responses_ref.get_mut(&HBFIExcludeFrame(hbfi.clone())) {
                                         entry.insert(hbfi.frm.clone(), nw.0.clone());
@@ -292,6 +487,15 @@ impl TxRx {
     pub fn unreliable_unordered_request(&self, hbfi_seek: HBFI, start: u64, end: u64) -> Result<Vec<Vec<u8>>> {
         match self {
             TxRx::Initialized { unreliable_unordered_response_rx, .. } => {
+                self.request_on(hbfi_seek, start, end, unreliable_unordered_response_rx)
+            },
+            TxRx::Inert => Err(anyhow!("You must peer with a link first"))
+        }
+    }
+    // requests frames `start..=end` of `hbfi_seek`, waiting for them on `response_rx`
+    fn request_on(&self, hbfi_seek: HBFI, start: u64, end: u64, response_rx: &Arc<Mutex<Receiver<InterLinkPacket>>>) -> Result<Vec<Vec<u8>>> {
+        match self {
+            TxRx::Initialized { .. } => {
                 self.register_hbfi(hbfi_seek.clone())?;
                 let window_timeout = Duration::new(1,0);
                 let mut pending_queue: BTreeSet<NarrowWaistPacketReqEqRes> = BTreeSet::new();
@@ -313,7 +517,7 @@ impl TxRx {
                             None => continue,
                         }
                     }
-                    let aimd = self.send_and_receive(&congestion_window, hbfi_seek.clone(), Arc::clone(&unreliable_unordered_response_rx), window_timeout)?;
+                    let aimd = self.send_and_receive(&congestion_window, hbfi_seek.clone(), Arc::clone(response_rx), window_timeout)?;
                     self.process_aimd(aimd, hbfi_seek.clone(), &mut congestion_window_size, &mut pending_queue);
                 }
                 let reconstructed = self.reconstruct_responses(hbfi_seek, start, end);
@@ -460,10 +664,33 @@ impl TxRx {
         data: Vec<u8>,
     ) -> Result<()> {
         match self {
-            TxRx::Initialized { ref p2l_tx, ref protocol_sid, ref link_id, ref ops, ref label, .. } => {
+            TxRx::Initialized { ref p2l_tx, ref protocol_sid, ref link_id, ref ops, ref label, response_suite, .. } => {
                 trace!("\t\t|  RESPONSE PACKET FOUND");
                 ops.found_response_upstream(label.clone());
-                let nw = NarrowWaistPacket::response(protocol_sid.clone(), hbfi.clone(), data)?;
+                let nw = NarrowWaistPacket::response_with_suite(protocol_sid.clone(), hbfi.clone(), data, response_suite)?;
+                let lp = LinkPacket::new(link_id.reply_to()?, nw);
+                let ilp = InterLinkPacket::new(link_id.clone(), lp);
+                trace!("\t\t|  protocol-to-link");
+                ops.message_from(label.clone());
+                match p2l_tx.send(ilp) {
+                    Ok(_) => {},
+                    Err(e) => error!("protocol send error {:?}", e),
+                }
+                Ok(())
+            },
+            TxRx::Inert => Err(anyhow!("You must peer with a link first"))
+        }
+    }
+    // tells the requester straight away instead of leaving it to time out
+    pub fn nack(self,
+        hbfi: HBFI,
+        nonce: Nonce,
+        reason: NackReason,
+    ) -> Result<()> {
+        match self {
+            TxRx::Initialized { ref p2l_tx, ref protocol_sid, ref link_id, ref ops, ref label, .. } => {
+                trace!("\t\t|  NACK {}", reason);
+                let nw = NarrowWaistPacket::signed_nack(protocol_sid.clone(), hbfi, nonce, reason)?;
                 let lp = LinkPacket::new(link_id.reply_to()?, nw);
                 let ilp = InterLinkPacket::new(link_id.clone(), lp);
                 trace!("\t\t|  protocol-to-link");
@@ -1,8 +1,11 @@
 use {
     anyhow::{Result, anyhow},
     copernica_protocols::{Echo, Protocol},
-    copernica_common::{LinkId, ReplyTo, PrivateIdentityInterface, PublicIdentityInterface, Operations, LogEntry},
-    copernica_broker::{Broker},
+    copernica_common::{LinkId, ReplyTo, PrivateIdentityInterface, PublicIdentityInterface, NodeHandle, Operations, LogEntry, RevocationRecord},
+    copernica_broker::{
+        Broker, BrokerConfig, ForwardingStrategy,
+        BayesStrategy, FloodStrategy, BestLinkStrategy, KBestStrategy, EpsilonGreedyStrategy,
+    },
     copernica_links::{Link, MpscChannel, MpscCorruptor, UdpIp},
     crate::process_network,
     scaffolding::{ group, single, Ordering, TestTree},
@@ -15,10 +18,120 @@ pub fn network_echo(ordering: Ordering) -> TestTree {
         ordering,
         [
             single!(|| { ping_pong() }),
+            single!(|| { forwarding_strategies() }),
+            single!(|| { revocations_are_fetched() }),
         ]
     )
 }
 pub fn ping_pong() -> Result<()> {
+    ping_pong_with(|| Box::new(BayesStrategy::new()), 50002)
+}
+// the same exchange under every strategy, one after the other so they can share ports
+pub fn forwarding_strategies() -> Result<()> {
+    let strategies: Vec<(&str, fn() -> Box<dyn ForwardingStrategy>)> = vec![
+        ("bayes", || Box::new(BayesStrategy::new())),
+        ("flood", || Box::new(FloodStrategy::new())),
+        ("best link", || Box::new(BestLinkStrategy::new())),
+        ("2 best", || Box::new(KBestStrategy::new(2))),
+        ("epsilon greedy", || Box::new(EpsilonGreedyStrategy::new(0.1))),
+    ];
+    for (name, strategy) in strategies {
+        ping_pong_with(strategy, 50004).map_err(|e| anyhow!("{} forwarding: {}", name, e))?;
+    }
+    Ok(())
+}
+// one protocol republishes a revocation record, another fetches it through a broker
+// and from then on refuses to request from the revoked identity
+pub fn revocations_are_fetched() -> Result<()> {
+    let router_0 = "router0";
+    let echo_protocol_0 = "echo_protocol0";
+    let echo_protocol_1 = "echo_protocol1";
+    let link_0 = "link0";
+    let link_1 = "link1";
+    let link_2 = "link2";
+    let link_3 = "link3";
+    let (sender, receiver) = channel::<LogEntry>();
+    let actual_behaviour = Operations::turned_on(sender);
+    let mut broker0 = Broker::new(actual_behaviour.label(router_0.clone()));
+    let echo_protocol_sid0 = PrivateIdentityInterface::new_key();
+    let echo_protocol_sid1 = PrivateIdentityInterface::new_key();
+    let revoked_sid = PrivateIdentityInterface::new_key();
+    let unrevoked_sid = PrivateIdentityInterface::new_key();
+    let mut echo_protocol0: Echo = Protocol::new(echo_protocol_sid0.clone(), actual_behaviour.label(echo_protocol_0.clone()));
+    let mut echo_protocol1: Echo = Protocol::new(echo_protocol_sid1.clone(), actual_behaviour.label(echo_protocol_1.clone()));
+    // echo_protocol0 to broker0
+    let link_sid0 = PrivateIdentityInterface::new_key();
+    let link_sid1 = PrivateIdentityInterface::new_key();
+    let link_id0 = LinkId::link_with_type(link_sid0.clone(), PublicIdentityInterface::Absent, ReplyTo::Mpsc);
+    let link_id1 = LinkId::link_with_type(link_sid1.clone(), PublicIdentityInterface::Absent, ReplyTo::Mpsc);
+    let mut link0: MpscChannel = Link::new(link_id0.clone(), actual_behaviour.label(link_0.clone()), broker0.peer_with_link(link_id0.clone())?)?;
+    let mut link1: MpscChannel = Link::new(link_id1.clone(), actual_behaviour.label(link_1.clone()), echo_protocol0.peer_with_link(link_id0.clone())?)?;
+    link0.female(link1.male());
+    link1.female(link0.male());
+    // broker0 to echo_protocol1
+    let link_sid2 = PrivateIdentityInterface::new_key();
+    let link_sid3 = PrivateIdentityInterface::new_key();
+    let link_id2 = LinkId::link_with_type(link_sid2.clone(), PublicIdentityInterface::Absent, ReplyTo::Mpsc);
+    let link_id3 = LinkId::link_with_type(link_sid3.clone(), PublicIdentityInterface::Absent, ReplyTo::Mpsc);
+    let mut link2: MpscChannel = Link::new(link_id2.clone(), actual_behaviour.label(link_2.clone()), broker0.peer_with_link(link_id2.clone())?)?;
+    let mut link3: MpscChannel = Link::new(link_id3.clone(), actual_behaviour.label(link_3.clone()), echo_protocol1.peer_with_link(link_id2.clone())?)?;
+    link2.female(link3.male());
+    link3.female(link2.male());
+    echo_protocol0.publish_revocation(RevocationRecord::new(&revoked_sid))?;
+    // a record that's found and one that isn't
+    let mut expected_behaviour: HashMap<LogEntry, i32> = HashMap::new();
+    expected_behaviour.insert(LogEntry::register(router_0.clone()), 1);
+    expected_behaviour.insert(LogEntry::register(echo_protocol_0.clone()), 1);
+    expected_behaviour.insert(LogEntry::register(echo_protocol_1.clone()), 1);
+    expected_behaviour.insert(LogEntry::register(link_0.clone()), 1);
+    expected_behaviour.insert(LogEntry::register(link_1.clone()), 1);
+    expected_behaviour.insert(LogEntry::register(link_2.clone()), 1);
+    expected_behaviour.insert(LogEntry::register(link_3.clone()), 1);
+    expected_behaviour.insert(LogEntry::message(echo_protocol_0.clone()), 4);
+    expected_behaviour.insert(LogEntry::message(echo_protocol_1.clone()), 4);
+    expected_behaviour.insert(LogEntry::message(link_0.clone()), 4);
+    expected_behaviour.insert(LogEntry::message(link_1.clone()), 4);
+    expected_behaviour.insert(LogEntry::message(link_2.clone()), 4);
+    expected_behaviour.insert(LogEntry::message(link_3.clone()), 4);
+    expected_behaviour.insert(LogEntry::message(router_0.clone()), 8);
+    expected_behaviour.insert(LogEntry::found_response_upstream(echo_protocol_0.clone()), 1);
+    expected_behaviour.insert(LogEntry::response_arrived_downstream(echo_protocol_1.clone()), 1);
+    expected_behaviour.insert(LogEntry::forward_response_downstream(router_0.clone()), 1);
+    expected_behaviour.insert(LogEntry::forward_request_upstream(router_0.clone()), 2);
+    let mut network = NodeHandle::new("network");
+    network.add(echo_protocol0.run()?);
+    network.add(link0.run()?);
+    network.add(link1.run()?);
+    network.add(broker0.run()?);
+    network.add(link2.run()?);
+    network.add(link3.run()?);
+    network.add(echo_protocol1.run()?);
+    let publisher = echo_protocol_sid0.public_id();
+    let revoked = revoked_sid.public_id();
+    let unrevoked = unrevoked_sid.public_id();
+    let fetched = std::thread::spawn(move || {
+        let record = echo_protocol1.fetch_revocation(publisher.clone(), &revoked);
+        let missing = echo_protocol1.fetch_revocation(publisher, &unrevoked);
+        let refused = echo_protocol1.unreliable_unordered_cleartext_ping(revoked.clone());
+        actual_behaviour.end();
+        (record, missing, refused)
+    });
+    process_network(expected_behaviour, receiver)?;
+    let (record, missing, refused) = fetched.join().expect("failed to extract data from JoinHandle");
+    network.shutdown()?;
+    if record?.revoked != revoked_sid.public_id() {
+        return Err(anyhow!("the fetched revocation record is for the wrong identity"))
+    }
+    if missing.is_ok() {
+        return Err(anyhow!("a revocation record that was never published was fetched"))
+    }
+    if refused.is_ok() {
+        return Err(anyhow!("a request to a revoked identity was answered"))
+    }
+    Ok(())
+}
+fn ping_pong_with(forwarding: fn() -> Box<dyn ForwardingStrategy>, port: u16) -> Result<()> {
+    let config = || BrokerConfig { forwarding: forwarding(), ..BrokerConfig::default() };
     let router_0 = "router0";
     let router_1 = "router1";
     let echo_protocol_0 = "echo_protocol0";
@@ -31,8 +144,8 @@ pub fn ping_pong() -> Result<()> {
     let link_5 = "link5";
     let (sender, receiver) = channel::<LogEntry>();
     let actual_behaviour = Operations::turned_on(sender);
-    let mut broker0 = Broker::new(actual_behaviour.label(router_0.clone()));
-    let mut broker1 = Broker::new(actual_behaviour.label(router_1.clone()));
+    let mut broker0 = Broker::new_with_config(actual_behaviour.label(router_0.clone()), config());
+    let mut broker1 = Broker::new_with_config(actual_behaviour.label(router_1.clone()), config());
     let echo_protocol_sid0 = PrivateIdentityInterface::new_key();
     let echo_protocol_sid1 = PrivateIdentityInterface::new_key();
     let mut echo_protocol0: Echo = Protocol::new(echo_protocol_sid0.clone(), actual_behaviour.label(echo_protocol_0.clone()));
@@ -58,8 +171,8 @@ pub fn ping_pong() -> Result<()> {
     // broker1 to echo_protocol1
     let link_sid4 = PrivateIdentityInterface::new_key();
     let link_sid5 = PrivateIdentityInterface::new_key();
-    let address4 = ReplyTo::UdpIp("127.0.0.1:50002".parse()?);
-    let address5 = ReplyTo::UdpIp("127.0.0.1:50003".parse()?);
+    let address4 = ReplyTo::UdpIp(format!("127.0.0.1:{}", port).parse()?);
+    let address5 = ReplyTo::UdpIp(format!("127.0.0.1:{}", port + 1).parse()?);
     let link_id4 = LinkId::link_with_type(link_sid4.clone(), PublicIdentityInterface::new(link_sid5.public_id()), address4.clone());
     let link_id5 = LinkId::link_with_type(link_sid5.clone(), PublicIdentityInterface::new(link_sid4.public_id()), address5.clone());
     let mut link4: UdpIp = Link::new(link_id4.clone(), actual_behaviour.label(link_4.clone()), broker1.peer_with_link(link_id4.remote(address5)?)?)?;
@@ -93,16 +206,17 @@ pub fn ping_pong() -> Result<()> {
     expected_behaviour.insert(LogEntry::forward_response_downstream(router_1.clone()), 4);
     expected_behaviour.insert(LogEntry::forward_request_upstream(router_0.clone()), 4);
     expected_behaviour.insert(LogEntry::forward_request_upstream(router_1.clone()), 4);
-    echo_protocol0.run()?;
-    link0.run()?;
-    link1.run()?;
-    broker0.run()?;
-    link2.run()?;
-    link3.run()?;
-    broker1.run()?;
-    link4.run()?;
-    link5.run()?;
-    echo_protocol1.run()?;
+    let mut network = NodeHandle::new("network");
+    network.add(echo_protocol0.run()?);
+    network.add(link0.run()?);
+    network.add(link1.run()?);
+    network.add(broker0.run()?);
+    network.add(link2.run()?);
+    network.add(link3.run()?);
+    network.add(broker1.run()?);
+    network.add(link4.run()?);
+    network.add(link5.run()?);
+    network.add(echo_protocol1.run()?);
     let response = std::thread::spawn(move || {
         let data: String = echo_protocol1.unreliable_unordered_cleartext_ping(echo_protocol_sid0.public_id()).unwrap();
         actual_behaviour.end();
@@ -110,6 +224,7 @@ pub fn ping_pong() -> Result<()> {
     });
     process_network(expected_behaviour, receiver)?;
     let actual_response = response.join().expect("failed to extract data from JoinHandle");
+    network.shutdown()?;
     let expected_response = "pong".to_string();
     if actual_response != expected_response{
         Err(anyhow!("actual returned data (1st under) didn't match expected returned data (2nd under):\n{}\n{}", actual_response, expected_response))